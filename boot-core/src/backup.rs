@@ -0,0 +1,39 @@
+use interface::bootinfo::{BootInfo, BOOT_INFO_REG, BOOT_INFO_SIZE};
+use interface::mailbox::{MailboxCommand, MAILBOX_REG, MAILBOX_SIZE};
+
+/// Access to the RTC backup registers, whose layout is defined in `interface::backup`.
+/// The bootloader implements this for the RTC peripheral, tests use `sim::SimBackupRegisters`.
+pub trait BackupRegisters {
+    fn read(&self, index: usize) -> u32;
+
+    fn write(&mut self, index: usize, value: u32);
+}
+
+/// Reads the command from the mailbox (see interface::mailbox) and clears the mailbox, so
+/// the command is executed at most once, even if executing it causes another reset.
+pub fn take_command<B: BackupRegisters>(backup: &mut B) -> Option<MailboxCommand> {
+    let mut registers = [0; MAILBOX_SIZE];
+    for (i, register) in registers.iter_mut().enumerate() {
+        *register = backup.read(MAILBOX_REG + i);
+        backup.write(MAILBOX_REG + i, 0);
+    }
+
+    MailboxCommand::decode(&registers)
+}
+
+/// Leaves the BootInfo for the OS (see interface::bootinfo)
+pub fn write_boot_info<B: BackupRegisters>(backup: &mut B, info: &BootInfo) {
+    for (i, value) in info.to_registers().into_iter().enumerate() {
+        backup.write(BOOT_INFO_REG + i, value);
+    }
+}
+
+/// The BootInfo the bootloader left for the OS, None if there is no intact one
+pub fn read_boot_info<B: BackupRegisters>(backup: &B) -> Option<BootInfo> {
+    let mut registers = [0; BOOT_INFO_SIZE];
+    for (i, register) in registers.iter_mut().enumerate() {
+        *register = backup.read(BOOT_INFO_REG + i);
+    }
+
+    BootInfo::from_registers(&registers)
+}
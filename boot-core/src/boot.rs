@@ -0,0 +1,1486 @@
+use core::sync::atomic::{fence, Ordering};
+
+use interface::backup::{BOOT_CONFIRM_MAGIC, BOOT_CONFIRM_REG};
+use interface::bootinfo::{
+    BootInfo, BootReason, MetadataStatus, SlotStatus, BOOT_INFO_FLASH_TIMEOUT, BOOT_INFO_NO_SLOT,
+    BOOT_INFO_WATCHDOG_TIMEOUT,
+};
+use interface::crc::calc_crc32;
+use interface::lz4;
+use interface::mailbox::MailboxCommand;
+use interface::reset::ResetCause;
+use interface::signature::PUBLIC_KEY_SIZE;
+use interface::{
+    ImageMetadata, Metadata, U32Ext, GOLDEN_SLOT_ADDR, NUMBER_OF_IMAGES, SLOT_ADDRS, SLOT_SIZE,
+};
+
+use crate::backup::{read_boot_info, take_command, write_boot_info, BackupRegisters};
+use crate::bootcount::BootAttempts;
+use crate::flash::{Error, FlashDevice};
+use crate::metadata::{golden_image, select_digests, select_image, select_metadata, verify_image};
+use crate::pages;
+use crate::policy::BootPolicy;
+use crate::signature::is_trusted;
+use crate::trailer::{
+    read_trailer, select_image_by_trailer, trailer_image_metadata, verify_trailer_image,
+};
+
+/// The watchdog must be fed regularly during long operations, e.g. copying an image.
+pub trait Watchdog {
+    fn feed(&mut self);
+
+    /// True if the watchdog didn't start in time, so it might not reset an image that hangs.
+    /// The timeout is reported in the BootInfo, see boot() for what happens to the image.
+    fn start_timed_out(&self) -> bool {
+        false
+    }
+}
+
+/// The result of the boot logic, telling the bootloader what to do next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootTarget {
+    /// The image in this slot was copied to RAM and should be started
+    Image(u32),
+    /// The image in this slot is an XIP image (see interface::IMAGE_FLAG_XIP), it was verified
+    /// in place and should be started from the slot
+    InPlace(u32),
+    /// There is no valid metadata or no image we could boot, so the golden image was copied to
+    /// RAM and should be started
+    Golden,
+    /// Neither the metadata nor the golden image are valid, there is nothing we could boot
+    Unbootable,
+    /// The OS requested the recovery mode (see interface::mailbox), nothing was copied to RAM
+    Recovery,
+    /// The flash or the watchdog didn't respond in time. The image was selected and its boot
+    /// attempt counted as usual, but the bootloader should reset instead of starting it.
+    Reset,
+}
+
+/// Runs the complete boot logic: executes the command from the mailbox, selects (and fixes) the
+/// metadata, selects an image, counts the boot attempt and copies the image into `ram`,
+/// unless it is an XIP image.
+/// If no image can be selected, `policy` decides whether to boot one without verification.
+/// With a `public_key`, only images with a valid signature are booted (see interface::signature).
+/// Without valid metadata, the newest slot with a valid image trailer is booted (see
+/// interface::trailer), and only if there is none, the golden image.
+/// `reset_flags` are the reset flags from RCC_CSR: after a watchdog reset, the last boot counts
+/// as failed, even if the OS confirmed it (see interface::reset).
+/// Before an image is started, a BootInfo with `reset_flags` and the reasons for the decision
+/// is written to the backup registers (see interface::bootinfo).
+/// A slot in which the flash reports an uncorrectable ECC error is skipped like a corrupt one,
+/// single-bit corrections are only counted (see FlashDevice::take_ecc_errors).
+/// If the flash or the watchdog doesn't respond in time, the timeout is reported in the BootInfo
+/// and the selected image is not started, see BootTarget::Reset. If the same timeout already
+/// happened in the previous boot, the reset didn't help, and the image is started anyway. The
+/// golden image is always started, as it is the last resort.
+pub fn boot<F: FlashDevice, B: BackupRegisters, W: Watchdog, P: BootPolicy>(
+    flash: &mut F, backup: &mut B, watchdog: &mut W, policy: &P,
+    public_key: Option<&[u8; PUBLIC_KEY_SIZE]>, reset_flags: u32, ram: &mut [u8],
+) -> BootTarget {
+    let mut info = BootInfo::new(
+        reset_flags,
+        BOOT_INFO_NO_SLOT,
+        BootReason::Golden,
+        0,
+        MetadataStatus::Invalid,
+        [SlotStatus::NotChecked; NUMBER_OF_IMAGES],
+    );
+
+    // Must be read before the BootInfo of this boot is written
+    let previous_timeouts = read_boot_info(backup).map_or(0, |previous| previous.timeouts);
+
+    if watchdog.start_timed_out() {
+        info.timeouts |= BOOT_INFO_WATCHDOG_TIMEOUT;
+    }
+
+    let target = select_target(flash, backup, watchdog, policy, public_key, ram, &mut info);
+
+    // The attempt is already counted. Without a watchdog, nothing would reset the image if it
+    // hangs, and a reset is the best chance to get the hardware working again. A timeout that
+    // survived a reset won't go away by resetting forever, though.
+    let new_timeouts = info.timeouts & !previous_timeouts;
+    let target = match target {
+        BootTarget::Image(_) | BootTarget::InPlace(_) if new_timeouts != 0 => BootTarget::Reset,
+        target => target,
+    };
+
+    // Nothing reads the BootInfo if we don't start an image, except the next boot after a reset
+    if let BootTarget::Image(_) | BootTarget::InPlace(_) | BootTarget::Golden | BootTarget::Reset =
+        target
+    {
+        info.set_crc();
+        write_boot_info(backup, &info);
+    }
+
+    target
+}
+
+// The boot logic, see boot(). Everything that ends up in the BootInfo is recorded in `info`,
+// the golden image is the default.
+fn select_target<F: FlashDevice, B: BackupRegisters, W: Watchdog, P: BootPolicy>(
+    flash: &mut F, backup: &mut B, watchdog: &mut W, policy: &P,
+    public_key: Option<&[u8; PUBLIC_KEY_SIZE]>, ram: &mut [u8], info: &mut BootInfo,
+) -> BootTarget {
+    // A confirmation through the mailbox counts like one in BOOT_CONFIRM_REG
+    let command = take_command(backup);
+    if command == Some(MailboxCommand::ConfirmBoot) {
+        backup.write(BOOT_CONFIRM_REG, BOOT_CONFIRM_MAGIC);
+    }
+
+    // Must be loaded before anything else is written to the backup registers,
+    // as it evaluates whether the OS confirmed the previous boot
+    let mut attempts = BootAttempts::load(backup, ResetCause::from_rcc_csr(info.reset_flags));
+
+    match command {
+        Some(MailboxCommand::EnterRecovery) => return BootTarget::Recovery,
+        Some(MailboxCommand::ClearBootCounters) => attempts.reset(),
+        Some(MailboxCommand::BootOnce { .. } | MailboxCommand::ConfirmBoot) | None => {}
+    }
+
+    // TODO: in case the second element is an Err(), then something went wrong while fixing up
+    // the older or corrupted metadata. Maybe we should try to fix this?
+    let (metadata, fix_result) = select_metadata(flash);
+    // Writing the metadata is the only flash operation that can time out here: the slots are
+    // only read, which never waits for the flash controller
+    if fix_result == Err(Error::Timeout) {
+        info.timeouts |= BOOT_INFO_FLASH_TIMEOUT;
+    }
+    if let Some(metadata) = &metadata {
+        info.metadata_version = metadata.version;
+        info.metadata_status = match fix_result {
+            Ok(false) => MetadataStatus::Valid,
+            Ok(true) => MetadataStatus::Repaired,
+            // Write protection won't go away by itself, unlike anything write_metadata retried
+            Err(Error::WriteProtected) => MetadataStatus::WriteProtected,
+            Err(_) => MetadataStatus::RepairFailed,
+        };
+    }
+
+    // A trial boot: boot the image once without making it permanent. The mailbox is already
+    // cleared, so if the image fails or never confirms, the next reset boots the preferred image.
+    // An image we can't verify is ignored, and we boot as if there was no request.
+    if let Some(MailboxCommand::BootOnce { slot }) = command {
+        let image_meta = verify_trial_image(flash, watchdog, public_key, metadata.as_ref(), slot);
+
+        let target =
+            image_meta.map(|image_meta| load_image(flash, watchdog, slot, &image_meta, ram));
+        if take_ecc_fault(flash, &mut attempts, info) != Some(slot) {
+            if let Some(Ok(target)) = target {
+                // Count this boot as well, so a confirmation by the OS is attributed to the right slot
+                attempts.record_boot(backup, slot);
+                info.slot_status[slot as usize] = SlotStatus::Valid;
+                info.slot = slot;
+                info.reason = BootReason::Trial;
+                return target;
+            }
+        }
+    }
+
+    //No valid metadata found, so we don't know which image to boot.
+    //The image trailers still tell us what is in the slots, and only then we use the golden image.
+    let Some(metadata) = metadata else {
+        for _ in 0..NUMBER_OF_IMAGES {
+            let Some((index, trailer)) = select_image_by_trailer(flash, &attempts) else {
+                break;
+            };
+
+            let slot_addr = SLOT_ADDRS[index as usize];
+            // The trailer was verified, so it has an ImageMetadata
+            let Some(image_meta) = trailer_image_metadata(flash, slot_addr, &trailer) else {
+                break;
+            };
+            if !is_trusted(flash, watchdog, public_key, slot_addr, trailer.length) {
+                info.slot_status[index as usize] = SlotStatus::Untrusted;
+                attempts.exhaust(index);
+                continue;
+            }
+
+            let target = load_image(flash, watchdog, index, &image_meta, ram);
+            if take_ecc_fault(flash, &mut attempts, info) == Some(index) {
+                continue;
+            }
+            let Ok(target) = target else {
+                info.slot_status[index as usize] = SlotStatus::LoadFailed;
+                attempts.exhaust(index);
+                continue;
+            };
+
+            attempts.record_boot(backup, index);
+            info.slot_status[index as usize] = SlotStatus::Valid;
+            info.slot = index;
+            info.reason = BootReason::Trailer;
+            return target;
+        }
+
+        return boot_golden_image(flash, watchdog, public_key, ram, info);
+    };
+    let digests = select_digests(flash, &metadata);
+
+    // Every image that is not signed by us is skipped like one that used up its attempts,
+    // so we need at most one round per slot to find a signed one
+    for _ in 0..NUMBER_OF_IMAGES {
+        //No image we could boot, not even an unverified one.
+        let Some(index) = select_image(
+            flash,
+            &metadata,
+            digests.as_ref(),
+            &mut attempts,
+            policy,
+            &mut info.slot_status,
+        ) else {
+            break;
+        };
+
+        let slot_addr = SLOT_ADDRS[index as usize];
+        let image_meta = metadata.images[index as usize];
+        if !is_trusted(flash, watchdog, public_key, slot_addr, image_meta.length) {
+            info.slot_status[index as usize] = SlotStatus::Untrusted;
+            attempts.exhaust(index);
+            continue;
+        }
+
+        let target = load_image(flash, watchdog, index, &image_meta, ram);
+        if take_ecc_fault(flash, &mut attempts, info) == Some(index) {
+            continue;
+        }
+        // The RAM copy is incomplete, so starting it would crash. Skip the slot like a corrupt one.
+        let Ok(target) = target else {
+            info.slot_status[index as usize] = SlotStatus::LoadFailed;
+            attempts.exhaust(index);
+            continue;
+        };
+
+        attempts.record_boot(backup, index);
+        info.slot = index;
+        // Only images that passed verification are marked as valid by select_image
+        info.reason = if info.slot_status[index as usize] != SlotStatus::Valid {
+            BootReason::Unverified
+        } else if index == metadata.preferred_image {
+            BootReason::Preferred
+        } else {
+            BootReason::Fallback
+        };
+        return target;
+    }
+
+    boot_golden_image(flash, watchdog, public_key, ram, info)
+}
+
+// Takes the ECC errors the flash reported while the last candidate was verified and loaded, and
+// records them in `info`. The slot of an uncorrectable error is treated like one with a corrupt
+// image: it is exhausted, so it isn't selected again. Returns that slot, or None if there was no
+// uncorrectable error or it was outside of the slots, e.g. in the metadata.
+fn take_ecc_fault<F: FlashDevice>(
+    flash: &mut F, attempts: &mut BootAttempts, info: &mut BootInfo,
+) -> Option<u32> {
+    let address = record_ecc_errors(flash, info)?;
+    let index = SLOT_ADDRS.iter().position(|&addr| in_slot(addr, address))?;
+    info.slot_status[index] = SlotStatus::EccError;
+    attempts.exhaust(index as u32);
+    Some(index as u32)
+}
+
+// Records the ECC errors the flash reported in `info` and returns the address of an
+// uncorrectable one. Corrections are counted once per call, the flash doesn't tell how many.
+fn record_ecc_errors<F: FlashDevice>(flash: &mut F, info: &mut BootInfo) -> Option<u32> {
+    let errors = flash.take_ecc_errors();
+    if errors.corrected {
+        info.ecc_corrections = info.ecc_corrections.saturating_add(1);
+    }
+    if let Some(address) = errors.uncorrectable {
+        info.ecc_fault_address = address;
+    }
+    errors.uncorrectable
+}
+
+fn in_slot(slot_addr: u32, address: u32) -> bool {
+    (slot_addr..slot_addr + SLOT_SIZE).contains(&address)
+}
+
+fn boot_golden_image<F: FlashDevice, W: Watchdog>(
+    flash: &mut F, watchdog: &mut W, public_key: Option<&[u8; PUBLIC_KEY_SIZE]>, ram: &mut [u8],
+    info: &mut BootInfo,
+) -> BootTarget {
+    let Some(golden) = golden_image(flash) else {
+        return BootTarget::Unbootable;
+    };
+
+    if !is_trusted(flash, watchdog, public_key, GOLDEN_SLOT_ADDR, golden.length) {
+        return BootTarget::Unbootable;
+    }
+
+    let copied =
+        copy_image_to_ram(flash, watchdog, GOLDEN_SLOT_ADDR, golden.length.to_usize(), ram);
+    // There is no other image left to fall back to
+    if record_ecc_errors(flash, info).is_some_and(|address| in_slot(GOLDEN_SLOT_ADDR, address)) {
+        return BootTarget::Unbootable;
+    }
+
+    match copied {
+        Ok(()) => BootTarget::Golden,
+        Err(()) => BootTarget::Unbootable,
+    }
+}
+
+// Prepares the start of the verified image in `slot`: an XIP image stays where it is, a
+// compressed image is decompressed into `ram` and any other image is copied into `ram`
+fn load_image<F: FlashDevice, W: Watchdog>(
+    flash: &F, watchdog: &mut W, slot: u32, image_meta: &ImageMetadata, ram: &mut [u8],
+) -> Result<BootTarget, ()> {
+    // An unverified image (see BootPolicy) didn't pass verify_image, so its length and flags
+    // can be corrupt as well. We must neither read beyond the slot nor guess how to start it.
+    if image_meta.length > SLOT_SIZE || !image_meta.has_valid_flags() {
+        return Err(());
+    }
+
+    if image_meta.is_xip() {
+        return Ok(BootTarget::InPlace(slot));
+    }
+
+    let slot_addr = SLOT_ADDRS[slot as usize];
+    let length = image_meta.length.to_usize();
+    if image_meta.is_compressed() {
+        decompress_image_to_ram(flash, watchdog, slot_addr, length, ram)?;
+    } else {
+        copy_image_to_ram(flash, watchdog, slot_addr, length, ram)?;
+    }
+    Ok(BootTarget::Image(slot))
+}
+
+// Verifies the image in `slot` for a trial boot and returns its metadata. With valid metadata,
+// the image must match it like any other image we boot, so the OS has to commit metadata for
+// the new image (without making it the preferred one) before it requests the trial boot.
+// Without valid metadata, the image must match its trailer instead.
+fn verify_trial_image<F: FlashDevice, W: Watchdog>(
+    flash: &F, watchdog: &mut W, public_key: Option<&[u8; PUBLIC_KEY_SIZE]>,
+    metadata: Option<&Metadata>, slot: u32,
+) -> Option<ImageMetadata> {
+    let slot_addr = SLOT_ADDRS[slot as usize];
+
+    let image_meta = match metadata {
+        Some(metadata) => {
+            let image_meta = metadata.images[slot as usize];
+            let digests = select_digests(flash, metadata);
+            let digest = digests.as_ref().and_then(|digests| digests.digest(slot as usize));
+            verify_image(flash, &image_meta, slot_addr, digest).then_some(image_meta)?
+        }
+        None => {
+            let trailer = read_trailer(flash, slot_addr)?;
+            if !verify_trailer_image(flash, slot_addr, &trailer) {
+                return None;
+            }
+            trailer_image_metadata(flash, slot_addr, &trailer)?
+        }
+    };
+
+    is_trusted(flash, watchdog, public_key, slot_addr, image_meta.length).then_some(image_meta)
+}
+
+fn copy_image_to_ram<F: FlashDevice, W: Watchdog>(
+    flash: &F, watchdog: &mut W, addr: u32, length: usize, ram: &mut [u8],
+) -> Result<(), ()> {
+    let image = flash.read(addr, length);
+    let crc_before = calc_crc32(image.as_ptr(), length);
+
+    let page_size = flash.page_size();
+
+    debug_assert!(addr.is_multiple_of(page_size), "Copy start address must be page aligned");
+    if length > ram.len() {
+        return Err(());
+    }
+
+    for _ in 0..3 {
+        fence(Ordering::SeqCst);
+
+        // Split the copy into chunks of pages. Reset the watchdog after each page.
+        for i in 0..pages::page_span(length as u32, page_size) {
+            // TODO: Make sure this is always < 30 seconds
+            let start = (i * page_size).to_usize();
+            let end = core::cmp::min(start + page_size.to_usize(), length);
+            ram[start..end].copy_from_slice(flash.read(addr + start as u32, end - start));
+            fence(Ordering::SeqCst);
+
+            watchdog.feed();
+        }
+
+        // Now read it back and calculate CRC again
+        if crc_before == calc_crc32(ram.as_ptr(), length) {
+            // Image in RAM and correct, nice!
+            return Ok(());
+        }
+    }
+
+    Err(())
+}
+
+// The compressed image was already verified in the slot, and the decompressed image is checked
+// against the length and CRC of its CompressedImageHeader. The watchdog is fed after every page.
+fn decompress_image_to_ram<F: FlashDevice, W: Watchdog>(
+    flash: &F, watchdog: &mut W, addr: u32, length: usize, ram: &mut [u8],
+) -> Result<(), ()> {
+    fence(Ordering::SeqCst);
+    let result = lz4::decompress_image(flash.read(addr, length), ram, || watchdog.feed());
+    fence(Ordering::SeqCst);
+
+    result.map(|_| ()).map_err(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec;
+    use std::vec::Vec;
+
+    use interface::backup::{BOOT_ATTEMPTS_REG, MAX_BOOT_ATTEMPTS};
+    use interface::bootinfo::BOOT_INFO_NO_ECC_FAULT;
+    use interface::digest::{ImageDigests, DIGESTS_OFFSET};
+    use interface::lz4::COMPRESSED_DATA_OFFSET;
+    use interface::mailbox::MAILBOX_REG;
+    use interface::reset::{RCC_CSR_BORRSTF, RCC_CSR_IWDGRSTF, RCC_CSR_PINRSTF};
+    use interface::sha256::sha256;
+    use interface::trailer::ImageTrailer;
+    use interface::{
+        FLASH_ADDR, IMAGE_FLAG_LZ4, IMAGE_FLAG_XIP, MAX_RAM_IMAGE_LENGTH, METADATA_1_ADDR,
+        METADATA_2_ADDR, METADATA_OFFSET, SLOT_SIZE,
+    };
+
+    use ed25519_compact::{KeyPair, Seed};
+
+    use super::*;
+    use crate::backup::read_boot_info;
+    use crate::policy::{GoldenOnly, NewestUnverified, PreferredUnverified};
+    use crate::sim::{lz4_literals, BankMode, SimBackupRegisters, SimFlash, SimWatchdog};
+
+    fn test_image(seed: u8, length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    fn send(backup: &mut SimBackupRegisters, command: MailboxCommand) {
+        for (i, value) in command.encode().into_iter().enumerate() {
+            backup.write(MAILBOX_REG + i, value);
+        }
+    }
+
+    fn run_boot(flash: &mut SimFlash, backup: &mut SimBackupRegisters) -> (BootTarget, Vec<u8>) {
+        run_boot_with_policy(flash, backup, &GoldenOnly)
+    }
+
+    fn run_boot_with_policy<P: BootPolicy>(
+        flash: &mut SimFlash, backup: &mut SimBackupRegisters, policy: &P,
+    ) -> (BootTarget, Vec<u8>) {
+        let mut ram = vec![0u8; SLOT_SIZE as usize];
+        let target = boot(flash, backup, &mut SimWatchdog::default(), policy, None, 0, &mut ram);
+        (target, ram)
+    }
+
+    #[test]
+    fn boots_preferred_image() {
+        for bank_mode in [BankMode::SingleBank, BankMode::DualBank] {
+            let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+            let (mut flash, _) =
+                SimFlash::with_images(bank_mode, [&images[0], &images[1], &images[2]]);
+            let mut backup = SimBackupRegisters::new();
+
+            let (target, ram) = run_boot(&mut flash, &mut backup);
+
+            assert_eq!(target, BootTarget::Image(0));
+            assert_eq!(&ram[..images[0].len()], &images[0][..]);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_valid_image() {
+        let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+        let (mut flash, _) =
+            SimFlash::with_images(BankMode::SingleBank, [&images[0], &images[1], &images[2]]);
+        flash.load(SLOT_ADDRS[0] + 0x10, &[0xde, 0xad]);
+
+        let (target, ram) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+
+        assert_eq!(target, BootTarget::Image(1));
+        assert_eq!(&ram[..images[1].len()], &images[1][..]);
+    }
+
+    #[test]
+    fn unbootable_without_metadata() {
+        let image = test_image(1, 0x100);
+        let (mut flash, _) = SimFlash::with_images(BankMode::DualBank, [&image, &image, &image]);
+        flash.load(METADATA_1_ADDR, &[0xff; 4]);
+        flash.load(METADATA_2_ADDR, &[0; 4]);
+
+        let (target, _) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::Unbootable);
+
+        // The image-builder zeroes the golden region if there is no golden image
+        flash.load(interface::GOLDEN_METADATA_ADDR, &[0; 16]);
+        let (target, _) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::Unbootable);
+    }
+
+    #[test]
+    fn boots_golden_image_without_metadata() {
+        for bank_mode in [BankMode::SingleBank, BankMode::DualBank] {
+            let image = test_image(1, 0x100);
+            let golden = test_image(7, 0x3456);
+            let (mut flash, _) = SimFlash::with_images(bank_mode, [&image, &image, &image]);
+            flash.load_golden_image(&golden);
+            flash.load(METADATA_1_ADDR, &[0; 4]);
+            flash.load(METADATA_2_ADDR, &[0; 4]);
+
+            let (target, ram) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+            assert_eq!(target, BootTarget::Golden);
+            assert_eq!(&ram[..golden.len()], &golden[..]);
+        }
+    }
+
+    #[test]
+    fn golden_image_is_only_used_without_metadata() {
+        let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+        let (mut flash, _) =
+            SimFlash::with_images(BankMode::SingleBank, [&images[0], &images[1], &images[2]]);
+        flash.load_golden_image(&test_image(7, 0x100));
+
+        let (target, _) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::Image(0));
+    }
+
+    #[test]
+    fn rejects_corrupted_golden_image() {
+        let image = test_image(1, 0x100);
+        let (mut flash, _) = SimFlash::with_images(BankMode::SingleBank, [&image, &image, &image]);
+        flash.load_golden_image(&test_image(7, 0x100));
+        flash.load(interface::GOLDEN_SLOT_ADDR + 0x20, &[0xde, 0xad]);
+        flash.load(METADATA_1_ADDR, &[0; 4]);
+        flash.load(METADATA_2_ADDR, &[0; 4]);
+
+        let (target, _) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::Unbootable);
+    }
+
+    #[test]
+    fn soft_reboot_boots_slot_once() {
+        let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+        let (mut flash, _) =
+            SimFlash::with_images(BankMode::SingleBank, [&images[0], &images[1], &images[2]]);
+        let mut backup = SimBackupRegisters::new();
+
+        send(&mut backup, MailboxCommand::BootOnce { slot: 2 });
+
+        let (target, ram) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(2));
+        assert_eq!(&ram[..images[2].len()], &images[2][..]);
+
+        // The request is consumed, so the next boot is a normal one
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(0));
+    }
+
+    #[test]
+    fn soft_reboot_ignores_invalid_slot() {
+        let image = test_image(1, 0x100);
+        let (mut flash, _) = SimFlash::with_images(BankMode::SingleBank, [&image, &image, &image]);
+        let mut backup = SimBackupRegisters::new();
+
+        send(&mut backup, MailboxCommand::BootOnce { slot: NUMBER_OF_IMAGES as u32 });
+
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(0));
+        assert_eq!(take_command(&mut backup), None);
+    }
+
+    #[test]
+    fn unconfirmed_image_falls_back_after_max_attempts() {
+        let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+        let (mut flash, _) =
+            SimFlash::with_images(BankMode::DualBank, [&images[0], &images[1], &images[2]]);
+        let mut backup = SimBackupRegisters::new();
+
+        // The preferred image never confirms its boot
+        for _ in 0..MAX_BOOT_ATTEMPTS {
+            let (target, _) = run_boot(&mut flash, &mut backup);
+            assert_eq!(target, BootTarget::Image(0));
+        }
+
+        let (target, ram) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(1));
+        assert_eq!(&ram[..images[1].len()], &images[1][..]);
+
+        // Image 1 confirms, so it keeps being booted
+        for _ in 0..2 * MAX_BOOT_ATTEMPTS {
+            backup.write(BOOT_CONFIRM_REG, BOOT_CONFIRM_MAGIC);
+            let (target, _) = run_boot(&mut flash, &mut backup);
+            assert_eq!(target, BootTarget::Image(1));
+        }
+    }
+
+    #[test]
+    fn watchdog_resets_count_as_failed_boots() {
+        let image = test_image(1, 0x100);
+        let (mut flash, _) = SimFlash::with_images(BankMode::SingleBank, [&image, &image, &image]);
+        let mut backup = SimBackupRegisters::new();
+        let mut run = |backup: &mut SimBackupRegisters, reset_flags: u32| {
+            let mut ram = vec![0u8; SLOT_SIZE as usize];
+            let watchdog = &mut SimWatchdog::default();
+            boot(&mut flash, backup, watchdog, &GoldenOnly, None, reset_flags, &mut ram)
+        };
+
+        // Power-on resets before the OS confirms are not the image's fault
+        for _ in 0..2 * MAX_BOOT_ATTEMPTS {
+            assert_eq!(run(&mut backup, RCC_CSR_BORRSTF | RCC_CSR_PINRSTF), BootTarget::Image(0));
+        }
+
+        // The image always confirms, but crashes later on. Together with the boot before,
+        // that's MAX_BOOT_ATTEMPTS failed boots.
+        for _ in 1..MAX_BOOT_ATTEMPTS {
+            backup.write(BOOT_CONFIRM_REG, BOOT_CONFIRM_MAGIC);
+            assert_eq!(run(&mut backup, RCC_CSR_IWDGRSTF | RCC_CSR_PINRSTF), BootTarget::Image(0));
+        }
+        backup.write(BOOT_CONFIRM_REG, BOOT_CONFIRM_MAGIC);
+        assert_eq!(run(&mut backup, RCC_CSR_IWDGRSTF | RCC_CSR_PINRSTF), BootTarget::Image(1));
+    }
+
+    #[test]
+    fn mailbox_commands_change_boot_attempts() {
+        let image = test_image(1, 0x100);
+        let (mut flash, _) = SimFlash::with_images(BankMode::SingleBank, [&image, &image, &image]);
+        let mut backup = SimBackupRegisters::new();
+
+        for _ in 0..MAX_BOOT_ATTEMPTS - 1 {
+            run_boot(&mut flash, &mut backup);
+        }
+        // Confirming through the mailbox works like writing BOOT_CONFIRM_REG
+        send(&mut backup, MailboxCommand::ConfirmBoot);
+        run_boot(&mut flash, &mut backup);
+        for _ in 0..MAX_BOOT_ATTEMPTS - 1 {
+            let (target, _) = run_boot(&mut flash, &mut backup);
+            assert_eq!(target, BootTarget::Image(0));
+        }
+
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(1));
+
+        // Slot 0 is exhausted, but the counters are cleared before the next boot
+        send(&mut backup, MailboxCommand::ClearBootCounters);
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(0));
+    }
+
+    #[test]
+    fn mailbox_enters_recovery_once() {
+        let image = test_image(1, 0x100);
+        let (mut flash, _) = SimFlash::with_images(BankMode::SingleBank, [&image, &image, &image]);
+        let mut backup = SimBackupRegisters::new();
+
+        send(&mut backup, MailboxCommand::EnterRecovery);
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Recovery);
+
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(0));
+    }
+
+    #[test]
+    fn retries_preferred_image_when_all_exhausted() {
+        let image = test_image(1, 0x100);
+        let (mut flash, _) = SimFlash::with_images(BankMode::SingleBank, [&image, &image, &image]);
+        let mut backup = SimBackupRegisters::new();
+
+        let booted: Vec<BootTarget> = (0..NUMBER_OF_IMAGES as u32 * MAX_BOOT_ATTEMPTS + 1)
+            .map(|_| run_boot(&mut flash, &mut backup).0)
+            .collect();
+
+        for (i, target) in booted.iter().take(booted.len() - 1).enumerate() {
+            assert_eq!(*target, BootTarget::Image(i as u32 / MAX_BOOT_ATTEMPTS));
+        }
+        // All images used up their attempts, so we start over with the preferred one
+        assert_eq!(booted.last(), Some(&BootTarget::Image(0)));
+    }
+
+    // Breaks the CRC of every slot
+    fn corrupt_all_images(flash: &mut SimFlash) {
+        for addr in SLOT_ADDRS {
+            flash.load(addr + 0x10, &[0xde, 0xad]);
+        }
+    }
+
+    #[test]
+    fn golden_image_without_valid_images() {
+        let image = test_image(1, 0x100);
+        let golden = test_image(7, 0x100);
+        let (mut flash, _) = SimFlash::with_images(BankMode::SingleBank, [&image, &image, &image]);
+        flash.load_golden_image(&golden);
+        corrupt_all_images(&mut flash);
+
+        let (target, ram) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::Golden);
+        assert_eq!(&ram[..golden.len()], &golden[..]);
+
+        // Without golden image, there is nothing left
+        flash.load(interface::GOLDEN_METADATA_ADDR, &[0; 16]);
+        let (target, _) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::Unbootable);
+    }
+
+    #[test]
+    fn preferred_unverified_boot_is_bounded() {
+        let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+        let (mut flash, _) =
+            SimFlash::with_images(BankMode::DualBank, [&images[0], &images[1], &images[2]]);
+        flash.load_golden_image(&test_image(7, 0x100));
+        corrupt_all_images(&mut flash);
+        let mut backup = SimBackupRegisters::new();
+
+        for _ in 0..MAX_BOOT_ATTEMPTS {
+            let (target, _) = run_boot_with_policy(&mut flash, &mut backup, &PreferredUnverified);
+            assert_eq!(target, BootTarget::Image(0));
+        }
+
+        // The preferred image never confirmed, so we give up on it
+        let (target, _) = run_boot_with_policy(&mut flash, &mut backup, &PreferredUnverified);
+        assert_eq!(target, BootTarget::Golden);
+    }
+
+    #[test]
+    fn preferred_unverified_rejects_corrupt_length_and_flags() {
+        for (length, flags) in [(u32::MAX, 0), (SLOT_SIZE + 1, 0), (0x100, 1 << 7)] {
+            let image = test_image(1, 0x100);
+            let (mut flash, mut metadata) =
+                SimFlash::with_images(BankMode::SingleBank, [&image, &image, &image]);
+            metadata.images[0].length = length;
+            metadata.images[0].flags = flags;
+            metadata.set_crc();
+            flash.load_metadata(METADATA_1_ADDR, &metadata);
+            flash.load_metadata(METADATA_2_ADDR, &metadata);
+            flash.load_golden_image(&test_image(7, 0x100));
+            corrupt_all_images(&mut flash);
+            let mut backup = SimBackupRegisters::new();
+
+            // The slot is skipped like one that fails to load, instead of panicking on every boot
+            let (target, _) = run_boot_with_policy(&mut flash, &mut backup, &PreferredUnverified);
+            assert_eq!(target, BootTarget::Golden);
+        }
+    }
+
+    #[test]
+    fn newest_unverified_boots_all_slots_by_version() {
+        let image = test_image(1, 0x100);
+        let (mut flash, mut metadata) =
+            SimFlash::with_images(BankMode::SingleBank, [&image, &image, &image]);
+        metadata.images[0].version = 3;
+        metadata.images[1].version = 4;
+        metadata.images[2].version = 2;
+        metadata.set_crc();
+        flash.load_metadata(METADATA_1_ADDR, &metadata);
+        flash.load_metadata(interface::METADATA_2_ADDR, &metadata);
+        corrupt_all_images(&mut flash);
+        let mut backup = SimBackupRegisters::new();
+
+        let booted: Vec<BootTarget> = (0..NUMBER_OF_IMAGES as u32 * MAX_BOOT_ATTEMPTS + 1)
+            .map(|_| run_boot_with_policy(&mut flash, &mut backup, &NewestUnverified).0)
+            .collect();
+
+        let attempts = MAX_BOOT_ATTEMPTS as usize;
+        assert!(booted[..attempts].iter().all(|&t| t == BootTarget::Image(1)));
+        assert!(booted[attempts..2 * attempts].iter().all(|&t| t == BootTarget::Image(0)));
+        assert!(booted[2 * attempts..3 * attempts].iter().all(|&t| t == BootTarget::Image(2)));
+        // No golden image either
+        assert_eq!(booted.last(), Some(&BootTarget::Unbootable));
+    }
+
+    #[test]
+    fn copy_feeds_watchdog_per_page() {
+        let image = test_image(1, 0x4001);
+        let (flash, _) = SimFlash::with_images(BankMode::DualBank, [&image, &image, &image]);
+        let mut watchdog = SimWatchdog::default();
+        let mut ram = vec![0u8; SLOT_SIZE as usize];
+
+        assert_eq!(
+            copy_image_to_ram(&flash, &mut watchdog, SLOT_ADDRS[0], image.len(), &mut ram),
+            Ok(())
+        );
+        assert_eq!(&ram[..image.len()], &image[..]);
+        assert_eq!(watchdog.feed_count, 5);
+    }
+
+    #[test]
+    fn boot_info_explains_decision() {
+        let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+        let (mut flash, metadata) =
+            SimFlash::with_images(BankMode::SingleBank, [&images[0], &images[1], &images[2]]);
+        let mut backup = SimBackupRegisters::new();
+        let mut ram = vec![0u8; SLOT_SIZE as usize];
+        let mut run = |flash: &mut SimFlash, backup: &mut SimBackupRegisters| {
+            let watchdog = &mut SimWatchdog::default();
+            boot(flash, backup, watchdog, &PreferredUnverified, None, 0x0400_0000, &mut ram);
+            read_boot_info(backup).expect("No BootInfo")
+        };
+
+        let info = run(&mut flash, &mut backup);
+        assert_eq!(info.reset_flags, 0x0400_0000);
+        assert_eq!((info.slot, info.reason), (0, BootReason::Preferred));
+        assert_eq!(info.metadata_version, metadata.version);
+        assert_eq!(info.metadata_status, MetadataStatus::Valid);
+        assert_eq!(
+            info.slot_status,
+            [SlotStatus::Valid, SlotStatus::NotChecked, SlotStatus::NotChecked]
+        );
+
+        // A broken preferred image and a broken metadata page
+        flash.load(SLOT_ADDRS[0] + 0x10, &[0xde, 0xad]);
+        flash.load(METADATA_1_ADDR + 3, &[0x42]);
+        let info = run(&mut flash, &mut backup);
+        assert_eq!((info.slot, info.reason), (1, BootReason::Fallback));
+        assert_eq!(info.metadata_status, MetadataStatus::Repaired);
+        assert_eq!(
+            info.slot_status,
+            [SlotStatus::Invalid, SlotStatus::Valid, SlotStatus::NotChecked]
+        );
+
+        send(&mut backup, MailboxCommand::BootOnce { slot: 2 });
+        let info = run(&mut flash, &mut backup);
+        assert_eq!((info.slot, info.reason), (2, BootReason::Trial));
+
+        // Nothing matches its CRC, so the policy boots the preferred image anyway
+        corrupt_all_images(&mut flash);
+        let info = run(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!((info.slot, info.reason), (0, BootReason::Unverified));
+        assert_eq!(info.slot_status, [SlotStatus::Invalid; NUMBER_OF_IMAGES]);
+
+        // Without metadata
+        flash.load_golden_image(&test_image(7, 0x100));
+        flash.load(METADATA_1_ADDR, &[0; 4]);
+        flash.load(METADATA_2_ADDR, &[0; 4]);
+        let info = run(&mut flash, &mut backup);
+        assert_eq!((info.slot, info.reason), (BOOT_INFO_NO_SLOT, BootReason::Golden));
+        assert_eq!((info.metadata_version, info.metadata_status), (0, MetadataStatus::Invalid));
+    }
+
+    #[test]
+    fn metadata_fixup_during_boot() {
+        let image = test_image(1, 0x100);
+        let (mut flash, metadata) =
+            SimFlash::with_images(BankMode::SingleBank, [&image, &image, &image]);
+        flash.load(METADATA_1_ADDR + 3, &[0x42]);
+
+        let (target, _) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::Image(0));
+
+        let size = METADATA_OFFSET as usize + core::mem::size_of::<Metadata>();
+        let fixed = flash.read(METADATA_1_ADDR, size);
+        let expected = flash.read(METADATA_2_ADDR, size);
+        assert_eq!(fixed, expected);
+        assert_eq!(metadata.crc.to_le_bytes(), fixed[size - 4..]);
+        assert!(flash.is_locked());
+    }
+
+    #[test]
+    fn metadata_fixup_retries_transient_errors() {
+        let image = test_image(1, 0x100);
+        let (mut flash, _) = SimFlash::with_images(BankMode::SingleBank, [&image, &image, &image]);
+        flash.load(METADATA_1_ADDR + 3, &[0x42]);
+        flash.fail_operations(Error::Busy, 2);
+
+        let mut backup = SimBackupRegisters::new();
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(0));
+        assert_eq!(read_boot_info(&backup).unwrap().metadata_status, MetadataStatus::Repaired);
+
+        let size = METADATA_OFFSET as usize + core::mem::size_of::<Metadata>();
+        assert_eq!(flash.read(METADATA_1_ADDR, size), flash.read(METADATA_2_ADDR, size));
+    }
+
+    #[test]
+    fn metadata_fixup_reports_write_protection() {
+        let image = test_image(1, 0x100);
+        let (mut flash, _) = SimFlash::with_images(BankMode::SingleBank, [&image, &image, &image]);
+        flash.load(METADATA_1_ADDR + 3, &[0x42]);
+        let broken = flash.read(METADATA_1_ADDR, 0x100).to_vec();
+
+        // Only the first attempt fails, so this shows that it is not retried
+        flash.fail_operations(Error::WriteProtected, 1);
+        let mut backup = SimBackupRegisters::new();
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(0));
+        let info = read_boot_info(&backup).unwrap();
+        assert_eq!(info.metadata_status, MetadataStatus::WriteProtected);
+        assert_eq!(flash.read(METADATA_1_ADDR, 0x100), &broken[..]);
+        assert!(flash.is_locked());
+
+        // Any other permanent error
+        flash.fail_operations(Error::Alignment, 1);
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(0));
+        assert_eq!(read_boot_info(&backup).unwrap().metadata_status, MetadataStatus::RepairFailed);
+    }
+
+    #[test]
+    fn flash_timeout_counts_boot_and_resets_once() {
+        let image = test_image(1, 0x100);
+        let (mut flash, _) = SimFlash::with_images(BankMode::SingleBank, [&image, &image, &image]);
+        flash.load(METADATA_1_ADDR + 3, &[0x42]);
+        let mut backup = SimBackupRegisters::new();
+
+        // The metadata fixup hangs, so the image is not started, but its attempt is counted
+        flash.fail_operations(Error::Timeout, 1);
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Reset);
+        assert_eq!(backup.read(BOOT_ATTEMPTS_REG), 1);
+        assert_eq!(read_boot_info(&backup).unwrap().timeouts, BOOT_INFO_FLASH_TIMEOUT);
+
+        // The reset didn't help, which must not keep us from booting
+        for attempt in 2..=MAX_BOOT_ATTEMPTS {
+            flash.fail_operations(Error::Timeout, 1);
+            let (target, _) = run_boot(&mut flash, &mut backup);
+            assert_eq!(target, BootTarget::Image(0));
+            assert_eq!(backup.read(BOOT_ATTEMPTS_REG), attempt);
+
+            let info = read_boot_info(&backup).unwrap();
+            assert_eq!(info.metadata_status, MetadataStatus::RepairFailed);
+            assert_eq!(info.timeouts, BOOT_INFO_FLASH_TIMEOUT);
+        }
+        backup.write(BOOT_CONFIRM_REG, BOOT_CONFIRM_MAGIC);
+
+        // Once the flash works again, the fixup succeeds
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(0));
+        let info = read_boot_info(&backup).unwrap();
+        assert_eq!((info.metadata_status, info.timeouts), (MetadataStatus::Repaired, 0));
+
+        // A new timeout resets again
+        flash.load(METADATA_2_ADDR + 3, &[0x42]);
+        flash.fail_operations(Error::Timeout, 1);
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Reset);
+    }
+
+    #[test]
+    fn watchdog_timeout_counts_boot_and_resets_once() {
+        let image = test_image(1, 0x100);
+        let golden = test_image(7, 0x100);
+        let (mut flash, _) = SimFlash::with_images(BankMode::SingleBank, [&image, &image, &image]);
+        let mut backup = SimBackupRegisters::new();
+        let mut watchdog = SimWatchdog { start_timeout: true, ..SimWatchdog::default() };
+        let mut run = |flash: &mut SimFlash, backup: &mut SimBackupRegisters| {
+            let mut ram = vec![0u8; SLOT_SIZE as usize];
+            boot(flash, backup, &mut watchdog, &GoldenOnly, None, 0, &mut ram)
+        };
+
+        assert_eq!(run(&mut flash, &mut backup), BootTarget::Reset);
+        assert_eq!(backup.read(BOOT_ATTEMPTS_REG), 1);
+        assert_eq!(read_boot_info(&backup).unwrap().timeouts, BOOT_INFO_WATCHDOG_TIMEOUT);
+
+        // Every boot is counted as usual, also once all images used up their attempts
+        for _ in 0..NUMBER_OF_IMAGES as u32 * MAX_BOOT_ATTEMPTS {
+            let target = run(&mut flash, &mut backup);
+            assert!(matches!(target, BootTarget::Image(_)), "{:?}", target);
+            assert_eq!(read_boot_info(&backup).unwrap().timeouts, BOOT_INFO_WATCHDOG_TIMEOUT);
+        }
+
+        // The golden image is the last resort, so it is always started
+        flash.load(METADATA_1_ADDR, &[0; 4]);
+        flash.load(METADATA_2_ADDR, &[0; 4]);
+        flash.load_golden_image(&golden);
+        let mut backup = SimBackupRegisters::new();
+        assert_eq!(run(&mut flash, &mut backup), BootTarget::Golden);
+    }
+
+    #[test]
+    fn uncorrectable_ecc_error_fails_slot() {
+        let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+        let (mut flash, _) =
+            SimFlash::with_images(BankMode::SingleBank, [&images[0], &images[1], &images[2]]);
+        // The image still matches its CRC, only the flash knows that the data was wrong
+        flash.inject_ecc_error(SLOT_ADDRS[0] + 0x1230, true);
+        flash.inject_ecc_error(SLOT_ADDRS[1] + 0x10, false);
+        let mut backup = SimBackupRegisters::new();
+
+        let (target, ram) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(1));
+        assert_eq!(&ram[..images[1].len()], &images[1][..]);
+        let info = read_boot_info(&backup).unwrap();
+        assert_eq!((info.slot, info.reason), (1, BootReason::Fallback));
+        assert_eq!(
+            info.slot_status,
+            [SlotStatus::EccError, SlotStatus::Valid, SlotStatus::NotChecked]
+        );
+        assert_eq!(info.ecc_fault_address, SLOT_ADDRS[0] + 0x1230);
+        assert_eq!(info.ecc_corrections, 1);
+
+        // A trial boot of a slot with an error boots as if there was no request
+        flash.inject_ecc_error(SLOT_ADDRS[2], true);
+        send(&mut backup, MailboxCommand::BootOnce { slot: 2 });
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(1));
+        let info = read_boot_info(&backup).unwrap();
+        assert_eq!(info.slot_status[2], SlotStatus::EccError);
+        assert_eq!(info.ecc_fault_address, SLOT_ADDRS[2]);
+    }
+
+    #[test]
+    fn uncorrectable_ecc_error_in_golden_image() {
+        let image = test_image(1, 0x100);
+        let (mut flash, _) = SimFlash::with_images(BankMode::SingleBank, [&image, &image, &image]);
+        flash.load_golden_image(&test_image(7, 0x100));
+        flash.load(METADATA_1_ADDR, &[0; 4]);
+        flash.load(METADATA_2_ADDR, &[0; 4]);
+        let mut backup = SimBackupRegisters::new();
+
+        // Only a correction: the golden image is fine
+        flash.inject_ecc_error(interface::GOLDEN_SLOT_ADDR + 0x20, false);
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Golden);
+        let info = read_boot_info(&backup).unwrap();
+        assert_eq!((info.ecc_fault_address, info.ecc_corrections), (BOOT_INFO_NO_ECC_FAULT, 1));
+
+        flash.inject_ecc_error(interface::GOLDEN_SLOT_ADDR + 0x40, true);
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Unbootable);
+    }
+
+    #[test]
+    fn skips_image_with_wrong_digest() {
+        let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+        let (mut flash, metadata) =
+            SimFlash::with_images(BankMode::SingleBank, [&images[0], &images[1], &images[2]]);
+
+        // Like an image that was changed without changing its CRC
+        let digests = ImageDigests::new(
+            &metadata,
+            [Some(sha256(&images[1])), Some(sha256(&images[1])), Some(sha256(&images[2]))],
+        );
+        flash.load_digests(METADATA_1_ADDR, &digests);
+        flash.load_digests(METADATA_2_ADDR, &digests);
+
+        let (target, ram) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::Image(1));
+        assert_eq!(&ram[..images[1].len()], &images[1][..]);
+
+        // Digests of another metadata version are ignored
+        let mut other_metadata = metadata;
+        other_metadata.version += 1;
+        other_metadata.set_crc();
+        let stale = ImageDigests::new(&other_metadata, [Some([0; 32]); NUMBER_OF_IMAGES]);
+        flash.load_digests(METADATA_1_ADDR, &stale);
+        flash.load_digests(METADATA_2_ADDR, &stale);
+
+        let (target, _) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::Image(0));
+    }
+
+    #[test]
+    fn metadata_fixup_keeps_digests() {
+        let image = test_image(1, 0x100);
+        let (mut flash, metadata) =
+            SimFlash::with_images(BankMode::DualBank, [&image, &image, &image]);
+        let digests = ImageDigests::new(&metadata, [Some(sha256(&image)); NUMBER_OF_IMAGES]);
+        flash.load_digests(METADATA_2_ADDR, &digests);
+        flash.load(METADATA_1_ADDR + 3, &[0x42]);
+
+        let (target, _) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::Image(0));
+
+        let size = DIGESTS_OFFSET as usize + core::mem::size_of::<ImageDigests>();
+        assert_eq!(flash.read(METADATA_1_ADDR, size), flash.read(METADATA_2_ADDR, size));
+    }
+
+    #[test]
+    fn upgrades_legacy_metadata() {
+        let image = test_image(1, 0x100);
+        let (mut flash, metadata) =
+            SimFlash::with_images(BankMode::SingleBank, [&image, &image, &image]);
+        let digests = ImageDigests::new(&metadata, [Some(sha256(&image)); NUMBER_OF_IMAGES]);
+        let page_size = flash.page_size() as usize;
+
+        // Pages written before there was a MetadataHeader, with an outdated second page
+        let mut outdated = metadata;
+        outdated.version -= 1;
+        outdated.set_crc();
+        flash.load(METADATA_1_ADDR, &vec![0xff; page_size]);
+        flash.load(METADATA_2_ADDR, &vec![0xff; page_size]);
+        flash.load_legacy_metadata(METADATA_1_ADDR, &metadata, Some(&digests));
+        flash.load_legacy_metadata(METADATA_2_ADDR, &outdated, None);
+
+        let (target, _) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::Image(0));
+
+        // Both pages now use the current layout and keep the digests
+        let mut expected = SimFlash::new(BankMode::SingleBank);
+        expected.load_metadata(METADATA_1_ADDR, &metadata);
+        expected.load_digests(METADATA_1_ADDR, &digests);
+        for addr in [METADATA_1_ADDR, METADATA_2_ADDR] {
+            assert_eq!(flash.read(addr, page_size), expected.read(METADATA_1_ADDR, page_size));
+        }
+        assert!(flash.is_locked());
+    }
+
+    #[test]
+    fn boots_newest_trailer_without_metadata() {
+        let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+        let golden = test_image(7, 0x3456);
+        let (mut flash, _) =
+            SimFlash::with_images(BankMode::SingleBank, [&images[0], &images[1], &images[2]]);
+        flash.load_golden_image(&golden);
+        for (i, version) in [(0, 1), (1, 3), (2, 2)] {
+            flash.load_trailer(SLOT_ADDRS[i], &images[i], version);
+        }
+        flash.load(METADATA_1_ADDR, &[0; 4]);
+        flash.load(METADATA_2_ADDR, &[0; 4]);
+        let mut backup = SimBackupRegisters::new();
+
+        let (target, ram) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(1));
+        assert_eq!(&ram[..images[1].len()], &images[1][..]);
+
+        // Boots without metadata are counted as well
+        for _ in 1..MAX_BOOT_ATTEMPTS {
+            run_boot(&mut flash, &mut backup);
+        }
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(2));
+
+        // Images that don't match their trailer are never booted
+        flash.load(SLOT_ADDRS[0] + 0x10, &[0; 4]);
+        flash.load(SLOT_ADDRS[2] + 0x10, &[0; 4]);
+        let (target, ram) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::Image(1));
+        assert_eq!(&ram[..images[1].len()], &images[1][..]);
+
+        flash.load(SLOT_ADDRS[1] + 0x10, &[0; 4]);
+        let (target, _) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::Golden);
+    }
+
+    #[test]
+    fn soft_reboot_uses_trailer() {
+        let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+        let (mut flash, _) =
+            SimFlash::with_images(BankMode::SingleBank, [&images[0], &images[1], &images[2]]);
+        flash.load_trailer(SLOT_ADDRS[0], &images[0], 1);
+        flash.load_trailer(SLOT_ADDRS[2], &images[2], 1);
+        // Without metadata, the trailer is all we can verify the image with
+        flash.load(METADATA_1_ADDR, &[0; 4]);
+        flash.load(METADATA_2_ADDR, &[0; 4]);
+        let mut backup = SimBackupRegisters::new();
+
+        send(&mut backup, MailboxCommand::BootOnce { slot: 2 });
+        let mut ram = vec![0x55u8; SLOT_SIZE as usize];
+        let target = boot(
+            &mut flash,
+            &mut backup,
+            &mut SimWatchdog::default(),
+            &GoldenOnly,
+            None,
+            0,
+            &mut ram,
+        );
+        assert_eq!(target, BootTarget::Image(2));
+        assert_eq!(&ram[..images[2].len()], &images[2][..]);
+        // Only the image itself is copied
+        assert!(ram[images[2].len()..].iter().all(|&b| b == 0x55));
+
+        // A broken image is not booted, even if it is requested
+        flash.load(SLOT_ADDRS[2], &[0; 4]);
+        send(&mut backup, MailboxCommand::BootOnce { slot: 2 });
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(0));
+
+        // Neither metadata nor a trailer, so there is nothing to verify the image with
+        send(&mut backup, MailboxCommand::BootOnce { slot: 1 });
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(0));
+    }
+
+    #[test]
+    fn trial_boot_verifies_metadata() {
+        let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+        let (mut flash, metadata) =
+            SimFlash::with_images(BankMode::DualBank, [&images[0], &images[1], &images[2]]);
+        let mut backup = SimBackupRegisters::new();
+
+        // A new image with a matching trailer, but the metadata still describes the old one
+        let new_image = test_image(4, 0x180);
+        flash.load(SLOT_ADDRS[1], &new_image);
+        flash.load_trailer(SLOT_ADDRS[1], &new_image, 2);
+        send(&mut backup, MailboxCommand::BootOnce { slot: 1 });
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(0));
+
+        // Once the metadata describes it, the new image can be tried without preferring it
+        let mut staged = metadata;
+        staged.version += 1;
+        staged.images[1] = ImageMetadata {
+            version: 2,
+            crc: calc_crc32(new_image.as_ptr(), new_image.len()),
+            flags: 0,
+            length: new_image.len() as u32,
+        };
+        staged.set_crc();
+        flash.load_metadata(METADATA_1_ADDR, &staged);
+        flash.load_metadata(METADATA_2_ADDR, &staged);
+
+        send(&mut backup, MailboxCommand::BootOnce { slot: 1 });
+        let (target, ram) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(1));
+        assert_eq!(&ram[..new_image.len()], &new_image[..]);
+
+        // It didn't confirm, so we are back to the preferred image
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(0));
+    }
+
+    #[test]
+    fn boots_xip_image_in_place() {
+        let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+        let (mut flash, mut metadata) =
+            SimFlash::with_images(BankMode::SingleBank, [&images[0], &images[1], &images[2]]);
+        metadata.images[0].flags = IMAGE_FLAG_XIP;
+        metadata.images[1].flags = IMAGE_FLAG_XIP;
+        metadata.set_crc();
+        flash.load_metadata(METADATA_1_ADDR, &metadata);
+        flash.load_metadata(METADATA_2_ADDR, &metadata);
+
+        // The image is verified, but not copied
+        let mut ram = vec![0x55u8; SLOT_SIZE as usize];
+        let mut backup = SimBackupRegisters::new();
+        let target = boot(
+            &mut flash,
+            &mut backup,
+            &mut SimWatchdog::default(),
+            &GoldenOnly,
+            None,
+            0,
+            &mut ram,
+        );
+        assert_eq!(target, BootTarget::InPlace(0));
+        assert!(ram.iter().all(|&b| b == 0x55));
+        assert_eq!(read_boot_info(&backup).map(|info| info.slot), Some(0));
+
+        // A broken XIP image is skipped like any other
+        flash.load(SLOT_ADDRS[0] + 0x10, &[0xde, 0xad]);
+        let (target, _) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::InPlace(1));
+
+        // We don't know how to start an image with an unknown flag
+        metadata.images[1].flags = 1 << 31;
+        metadata.set_crc();
+        flash.load_metadata(METADATA_1_ADDR, &metadata);
+        flash.load_metadata(METADATA_2_ADDR, &metadata);
+        let (target, ram) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::Image(2));
+        assert_eq!(&ram[..images[2].len()], &images[2][..]);
+    }
+
+    #[test]
+    fn boots_xip_trailer_without_metadata() {
+        let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+        let (mut flash, _) =
+            SimFlash::with_images(BankMode::SingleBank, [&images[0], &images[1], &images[2]]);
+        flash.load_trailer(SLOT_ADDRS[0], &images[0], 1);
+        let load_address = FLASH_ADDR + SLOT_ADDRS[1];
+        flash.load_image_trailer(
+            SLOT_ADDRS[1],
+            &ImageTrailer::with_load_address(&images[1], 2, load_address),
+        );
+        // Linked against slot 1, so it can't run from slot 2
+        flash.load_image_trailer(
+            SLOT_ADDRS[2],
+            &ImageTrailer::with_load_address(&images[2], 3, load_address),
+        );
+        flash.load(METADATA_1_ADDR, &[0; 4]);
+        flash.load(METADATA_2_ADDR, &[0; 4]);
+
+        let (target, _) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::InPlace(1));
+
+        let mut backup = SimBackupRegisters::new();
+        send(&mut backup, MailboxCommand::BootOnce { slot: 2 });
+        let (target, _) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::InPlace(1));
+
+        send(&mut backup, MailboxCommand::BootOnce { slot: 0 });
+        let (target, ram) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(0));
+        assert_eq!(&ram[..images[0].len()], &images[0][..]);
+    }
+
+    #[test]
+    fn boots_compressed_image() {
+        let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+        let stored = lz4_literals(&images[0]);
+        let (mut flash, mut metadata) =
+            SimFlash::with_images(BankMode::DualBank, [&stored, &images[1], &images[2]]);
+        metadata.images[0].flags = IMAGE_FLAG_LZ4;
+        metadata.set_crc();
+        flash.load_metadata(METADATA_1_ADDR, &metadata);
+        flash.load_metadata(METADATA_2_ADDR, &metadata);
+
+        let (target, ram) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::Image(0));
+        assert_eq!(&ram[..images[0].len()], &images[0][..]);
+
+        // The stored bytes are verified before they are decompressed
+        flash.load(SLOT_ADDRS[0] + 0x100, &[0xde, 0xad]);
+        let (target, ram) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::Image(1));
+        assert_eq!(&ram[..images[1].len()], &images[1][..]);
+    }
+
+    #[test]
+    fn falls_back_when_decompression_fails() {
+        let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+        // The stored bytes match the metadata, but not the CRC of the decompressed image
+        let mut stored = lz4_literals(&images[0]);
+        stored[12] ^= 0xff;
+        let (mut flash, mut metadata) =
+            SimFlash::with_images(BankMode::DualBank, [&stored, &images[1], &images[2]]);
+        metadata.images[0].flags = IMAGE_FLAG_LZ4;
+        metadata.set_crc();
+        flash.load_metadata(METADATA_1_ADDR, &metadata);
+        flash.load_metadata(METADATA_2_ADDR, &metadata);
+
+        let mut backup = SimBackupRegisters::new();
+        let (target, ram) = run_boot(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(1));
+        assert_eq!(&ram[..images[1].len()], &images[1][..]);
+
+        let info = read_boot_info(&backup).unwrap();
+        assert_eq!(info.reason, BootReason::Fallback);
+        assert_eq!(
+            info.slot_status,
+            [SlotStatus::LoadFailed, SlotStatus::Valid, SlotStatus::NotChecked]
+        );
+    }
+
+    #[test]
+    fn decompress_feeds_watchdog_per_page() {
+        // Larger than a slot: a single byte, repeated by a match at offset 1
+        let image = vec![0x5au8; SLOT_SIZE as usize + 0x1000];
+        let mut stored = lz4_literals(&image);
+        stored.truncate(COMPRESSED_DATA_OFFSET);
+        stored.extend_from_slice(&[0x1f, 0x5a, 0x01, 0x00]);
+        let mut match_length = image.len() - 1 - 4 - 0xf;
+        while match_length >= 0xff {
+            stored.push(0xff);
+            match_length -= 0xff;
+        }
+        stored.push(match_length as u8);
+
+        let mut flash = SimFlash::new(BankMode::DualBank);
+        flash.load(SLOT_ADDRS[0], &stored);
+        let mut watchdog = SimWatchdog::default();
+        let mut ram = vec![0u8; MAX_RAM_IMAGE_LENGTH as usize];
+
+        assert_eq!(
+            decompress_image_to_ram(&flash, &mut watchdog, SLOT_ADDRS[0], stored.len(), &mut ram),
+            Ok(())
+        );
+        assert_eq!(&ram[..image.len()], &image[..]);
+        assert_eq!(watchdog.feed_count, image.len() / lz4::PROGRESS_INTERVAL);
+
+        // Doesn't fit
+        let mut ram = vec![0u8; SLOT_SIZE as usize];
+        assert_eq!(
+            decompress_image_to_ram(&flash, &mut watchdog, SLOT_ADDRS[0], stored.len(), &mut ram),
+            Err(())
+        );
+    }
+
+    #[test]
+    fn boots_compressed_trailer_without_metadata() {
+        let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+        let stored = lz4_literals(&images[0]);
+        let (mut flash, _) =
+            SimFlash::with_images(BankMode::SingleBank, [&stored, &images[1], &images[2]]);
+        flash.load_trailer(SLOT_ADDRS[0], &stored, 3);
+        flash.load_trailer(SLOT_ADDRS[1], &images[1], 2);
+        flash.load(METADATA_1_ADDR, &[0; 4]);
+        flash.load(METADATA_2_ADDR, &[0; 4]);
+
+        // The trailer doesn't know about the compression, the header in the slot tells
+        let (target, ram) = run_boot(&mut flash, &mut SimBackupRegisters::new());
+        assert_eq!(target, BootTarget::Image(0));
+        assert_eq!(&ram[..images[0].len()], &images[0][..]);
+    }
+
+    fn run_signed_boot(
+        flash: &mut SimFlash, backup: &mut SimBackupRegisters, key_pair: &KeyPair,
+    ) -> (BootTarget, Vec<u8>) {
+        let mut ram = vec![0u8; SLOT_SIZE as usize];
+        let target = boot(
+            flash,
+            backup,
+            &mut SimWatchdog::default(),
+            &GoldenOnly,
+            Some(&key_pair.pk),
+            0,
+            &mut ram,
+        );
+        (target, ram)
+    }
+
+    #[test]
+    fn boots_only_signed_images() {
+        let key_pair = KeyPair::from_seed(Seed::new([1; 32]));
+        let other_key = KeyPair::from_seed(Seed::new([2; 32]));
+
+        let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+        let (mut flash, _) =
+            SimFlash::with_images(BankMode::SingleBank, [&images[0], &images[1], &images[2]]);
+        let mut backup = SimBackupRegisters::new();
+
+        // Nothing is signed, so there is nothing we may boot
+        let (target, _) = run_signed_boot(&mut flash, &mut backup, &key_pair);
+        assert_eq!(target, BootTarget::Unbootable);
+
+        // The preferred image is signed by someone else
+        flash.load_signature(SLOT_ADDRS[0], &other_key.sk, images[0].len() as u32);
+        flash.load_signature(SLOT_ADDRS[2], &key_pair.sk, images[2].len() as u32);
+        let (target, ram) = run_signed_boot(&mut flash, &mut backup, &key_pair);
+        assert_eq!(target, BootTarget::Image(2));
+        assert_eq!(&ram[..images[2].len()], &images[2][..]);
+
+        // The signature must cover the whole image from the metadata
+        flash.load_signature(SLOT_ADDRS[0], &key_pair.sk, images[0].len() as u32 - 1);
+        let (target, _) = run_signed_boot(&mut flash, &mut SimBackupRegisters::new(), &key_pair);
+        assert_eq!(target, BootTarget::Image(2));
+
+        flash.load_signature(SLOT_ADDRS[0], &key_pair.sk, images[0].len() as u32);
+        let (target, _) = run_signed_boot(&mut flash, &mut SimBackupRegisters::new(), &key_pair);
+        assert_eq!(target, BootTarget::Image(0));
+    }
+
+    #[test]
+    fn soft_reboot_requires_signature() {
+        let key_pair = KeyPair::from_seed(Seed::new([1; 32]));
+        let images = [test_image(1, 0x4321), test_image(2, 0x100), test_image(3, 0x2000)];
+        let (mut flash, _) =
+            SimFlash::with_images(BankMode::SingleBank, [&images[0], &images[1], &images[2]]);
+        flash.load_signature(SLOT_ADDRS[0], &key_pair.sk, images[0].len() as u32);
+        let mut backup = SimBackupRegisters::new();
+
+        send(&mut backup, MailboxCommand::BootOnce { slot: 1 });
+        let (target, _) = run_signed_boot(&mut flash, &mut backup, &key_pair);
+        assert_eq!(target, BootTarget::Image(0));
+
+        flash.load_signature(SLOT_ADDRS[1], &key_pair.sk, images[1].len() as u32);
+        send(&mut backup, MailboxCommand::BootOnce { slot: 1 });
+        let (target, ram) = run_signed_boot(&mut flash, &mut backup, &key_pair);
+        assert_eq!(target, BootTarget::Image(1));
+        assert_eq!(&ram[..images[1].len()], &images[1][..]);
+    }
+
+    #[test]
+    fn golden_image_requires_signature() {
+        let key_pair = KeyPair::from_seed(Seed::new([1; 32]));
+        let golden = test_image(9, 0x1234);
+        let mut flash = SimFlash::new(BankMode::DualBank);
+        flash.load_golden_image(&golden);
+
+        let (target, _) = run_signed_boot(&mut flash, &mut SimBackupRegisters::new(), &key_pair);
+        assert_eq!(target, BootTarget::Unbootable);
+
+        flash.load_signature(GOLDEN_SLOT_ADDR, &key_pair.sk, golden.len() as u32);
+        let (target, ram) = run_signed_boot(&mut flash, &mut SimBackupRegisters::new(), &key_pair);
+        assert_eq!(target, BootTarget::Golden);
+        assert_eq!(&ram[..golden.len()], &golden[..]);
+    }
+}
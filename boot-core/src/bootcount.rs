@@ -0,0 +1,242 @@
+use interface::backup::{
+    BOOT_ATTEMPTS_REG, BOOT_CONFIRM_MAGIC, BOOT_CONFIRM_REG, BOOT_SLOT_REG, BOOT_STATE_MAGIC,
+    BOOT_STATE_REG, MAX_BOOT_ATTEMPTS,
+};
+use interface::reset::ResetCause;
+use interface::NUMBER_OF_IMAGES;
+
+use crate::backup::BackupRegisters;
+
+/// Number of unconfirmed boot attempts per slot.
+/// The counters live in the RTC backup registers, so counting boots does not cause flash wear.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootAttempts {
+    attempts: [u32; NUMBER_OF_IMAGES],
+}
+
+#[cfg(kani)]
+impl kani::Arbitrary for BootAttempts {
+    fn any() -> Self {
+        BootAttempts { attempts: kani::any() }
+    }
+}
+
+impl BootAttempts {
+    /// Loads the counters from the backup registers.
+    /// If the OS confirmed the last boot, the counter of the last booted slot is reset, unless
+    /// the image failed afterwards (see ResetCause::is_failed_boot). After a power-on reset,
+    /// the last boot doesn't count, as it was not the image's fault.
+    pub fn load<B: BackupRegisters>(backup: &B, reset: ResetCause) -> Self {
+        let mut attempts = [0; NUMBER_OF_IMAGES];
+
+        // After a power-on reset the backup registers don't contain anything useful,
+        // so we start counting from zero
+        if backup.read(BOOT_STATE_REG) != BOOT_STATE_MAGIC {
+            return BootAttempts { attempts };
+        }
+
+        for (i, count) in attempts.iter_mut().enumerate() {
+            *count = backup.read(BOOT_ATTEMPTS_REG + i);
+        }
+
+        let confirmed = backup.read(BOOT_CONFIRM_REG) == BOOT_CONFIRM_MAGIC;
+        let last_slot = backup.read(BOOT_SLOT_REG);
+
+        Self::from_registers(attempts, confirmed, last_slot, reset)
+    }
+
+    // This is the part of load() without side effects, so we can verify it with Kani.
+    fn from_registers(
+        mut attempts: [u32; NUMBER_OF_IMAGES], confirmed: bool, last_slot: u32, reset: ResetCause,
+    ) -> Self {
+        let Some(count) = attempts.get_mut(last_slot as usize) else {
+            return BootAttempts { attempts };
+        };
+
+        if reset.is_failed_boot() {
+            // The image crashed or hung, even if it confirmed the boot before. The attempt
+            // was counted before the jump, so it just stays.
+        } else if confirmed {
+            // The OS told us the last boot worked - that image is fine
+            *count = 0;
+        } else if reset == ResetCause::PowerOn {
+            // The power was cut before the OS could confirm, give the attempt back
+            *count = count.saturating_sub(1);
+        }
+
+        BootAttempts { attempts }
+    }
+
+    /// Whether the image in the given slot used up all of its boot attempts without the OS
+    /// ever confirming a successful boot.
+    pub fn is_exhausted(&self, slot: usize) -> bool {
+        match self.attempts.get(slot) {
+            Some(&count) => count >= MAX_BOOT_ATTEMPTS,
+            None => true,
+        }
+    }
+
+    /// Marks the slot as exhausted, e.g. because its image must not be booted at all.
+    pub fn exhaust(&mut self, slot: u32) {
+        if let Some(count) = self.attempts.get_mut(slot as usize) {
+            *count = MAX_BOOT_ATTEMPTS;
+        }
+    }
+
+    /// Forget about all previous attempts, e.g. if every image has used up its attempts.
+    pub fn reset(&mut self) {
+        self.attempts = [0; NUMBER_OF_IMAGES];
+    }
+
+    /// Counts another boot attempt of `slot` and persists all counters.
+    /// This must be called right before jumping to the image.
+    pub fn record_boot<B: BackupRegisters>(&mut self, backup: &mut B, slot: u32) {
+        if let Some(count) = self.attempts.get_mut(slot as usize) {
+            *count = count.saturating_add(1);
+        }
+
+        for (i, &count) in self.attempts.iter().enumerate() {
+            backup.write(BOOT_ATTEMPTS_REG + i, count);
+        }
+        backup.write(BOOT_SLOT_REG, slot);
+        // The OS has to confirm this boot again
+        backup.write(BOOT_CONFIRM_REG, 0);
+        backup.write(BOOT_STATE_REG, BOOT_STATE_MAGIC);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimBackupRegisters;
+
+    #[test]
+    fn power_on_starts_at_zero() {
+        let mut backup = SimBackupRegisters::new();
+        for i in 0..NUMBER_OF_IMAGES {
+            backup.write(BOOT_ATTEMPTS_REG + i, MAX_BOOT_ATTEMPTS);
+        }
+
+        // Without the state magic, the counters are garbage and must be ignored
+        let attempts = BootAttempts::load(&backup, ResetCause::Unknown);
+        for i in 0..NUMBER_OF_IMAGES {
+            assert!(!attempts.is_exhausted(i));
+        }
+    }
+
+    #[test]
+    fn unconfirmed_boots_exhaust_slot() {
+        let mut backup = SimBackupRegisters::new();
+
+        for _ in 0..MAX_BOOT_ATTEMPTS {
+            let mut attempts = BootAttempts::load(&backup, ResetCause::Unknown);
+            assert!(!attempts.is_exhausted(1));
+            attempts.record_boot(&mut backup, 1);
+        }
+
+        let attempts = BootAttempts::load(&backup, ResetCause::Unknown);
+        assert!(attempts.is_exhausted(1));
+        assert!(!attempts.is_exhausted(0));
+        assert!(!attempts.is_exhausted(2));
+    }
+
+    #[test]
+    fn confirmed_boot_resets_slot() {
+        let mut backup = SimBackupRegisters::new();
+
+        for _ in 0..MAX_BOOT_ATTEMPTS {
+            BootAttempts::load(&backup, ResetCause::Unknown).record_boot(&mut backup, 0);
+        }
+        assert!(BootAttempts::load(&backup, ResetCause::Unknown).is_exhausted(0));
+
+        // The OS confirms the last boot
+        backup.write(BOOT_CONFIRM_REG, BOOT_CONFIRM_MAGIC);
+        let mut attempts = BootAttempts::load(&backup, ResetCause::Unknown);
+        assert!(!attempts.is_exhausted(0));
+
+        // Booting again requires a new confirmation
+        attempts.record_boot(&mut backup, 0);
+        assert_eq!(backup.read(BOOT_CONFIRM_REG), 0);
+        assert_eq!(backup.read(BOOT_SLOT_REG), 0);
+    }
+
+    #[test]
+    fn reset_cause_changes_counting() {
+        let mut backup = SimBackupRegisters::new();
+        BootAttempts::load(&backup, ResetCause::Unknown).record_boot(&mut backup, 0);
+        BootAttempts::load(&backup, ResetCause::Unknown).record_boot(&mut backup, 0);
+
+        // The image confirmed, but the watchdog reset it later on
+        backup.write(BOOT_CONFIRM_REG, BOOT_CONFIRM_MAGIC);
+        let attempts = BootAttempts::load(&backup, ResetCause::IndependentWatchdog);
+        assert_eq!(attempts.attempts[0], 2);
+        let attempts = BootAttempts::load(&backup, ResetCause::Software);
+        assert_eq!(attempts.attempts[0], 0);
+
+        // The power was cut before the image could confirm
+        backup.write(BOOT_CONFIRM_REG, 0);
+        let attempts = BootAttempts::load(&backup, ResetCause::PowerOn);
+        assert_eq!(attempts.attempts[0], 1);
+        let attempts = BootAttempts::load(&backup, ResetCause::Pin);
+        assert_eq!(attempts.attempts[0], 2);
+    }
+}
+
+#[cfg(kani)]
+mod verification {
+    use super::*;
+    use kani::*;
+
+    #[kani::proof]
+    fn confirmed_boot_resets_counter() {
+        let attempts: [u32; NUMBER_OF_IMAGES] = any();
+        let last_slot: u32 = any();
+        assume((last_slot as usize) < NUMBER_OF_IMAGES);
+
+        let result = BootAttempts::from_registers(attempts, true, last_slot, ResetCause::Unknown);
+        assert!(!result.is_exhausted(last_slot as usize));
+
+        // The other counters must not change
+        for i in 0..NUMBER_OF_IMAGES {
+            if i != last_slot as usize {
+                assert_eq!(result.attempts[i], attempts[i]);
+            }
+        }
+    }
+
+    #[kani::proof]
+    fn unconfirmed_boot_keeps_counters() {
+        let attempts: [u32; NUMBER_OF_IMAGES] = any();
+        let last_slot: u32 = any();
+
+        let result = BootAttempts::from_registers(attempts, false, last_slot, ResetCause::Unknown);
+        assert_eq!(result.attempts, attempts);
+    }
+
+    #[kani::proof]
+    fn failed_boot_ignores_confirmation() {
+        let attempts: [u32; NUMBER_OF_IMAGES] = any();
+        let last_slot: u32 = any();
+        let confirmed: bool = any();
+
+        let result = BootAttempts::from_registers(
+            attempts,
+            confirmed,
+            last_slot,
+            ResetCause::IndependentWatchdog,
+        );
+        assert_eq!(result.attempts, attempts);
+    }
+
+    #[kani::proof]
+    fn garbage_slot_index_is_ignored() {
+        let attempts: [u32; NUMBER_OF_IMAGES] = any();
+        let last_slot: u32 = any();
+        assume((last_slot as usize) >= NUMBER_OF_IMAGES);
+
+        // Must not panic and must not change anything
+        let result = BootAttempts::from_registers(attempts, true, last_slot, ResetCause::Unknown);
+        assert_eq!(result.attempts, attempts);
+        assert!(result.is_exhausted(last_slot as usize));
+    }
+}
@@ -0,0 +1,161 @@
+use core::ops::Deref;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    // The unlock sequence was rejected, the flash stays locked until the next reset
+    UnlockFailed,
+    // The flash controller is still busy with an earlier operation
+    Busy,
+    // The flash controller didn't finish an operation in time, it probably hangs
+    Timeout,
+    InvalidPage,
+    // The flash controller didn't report the end of a program operation (EOP)
+    NotProgrammed,
+    // The programmed data doesn't read back as it was written, even after retrying
+    VerifyFailed,
+
+    // One for each error flag in FLASH_SR:
+    // The address is write protected (WRPERR)
+    WriteProtected,
+    // The address is not aligned to a double-word, or to a row for fast programming (PGAERR)
+    Alignment,
+    // The double-word was not erased before it was programmed (PROGERR)
+    NotErased,
+    // Less than a double-word was written before programming started (SIZERR)
+    Size,
+    // The program or erase sequence was not followed, e.g. error flags were still set (PGSERR)
+    Sequence,
+    // The second word of a double-word or the next double-word of a row came too late (MISERR)
+    DataMiss,
+    // Fast programming was interrupted, e.g. by an access to the flash in between (FASTERR)
+    FastProgramming,
+    // An operation failed without any of the other flags (OPERR)
+    OperationFailed,
+    // A read from a PCROP protected area (RDERR)
+    ReadProtected,
+}
+
+impl Error {
+    /// Whether the same operation might succeed if it is simply started again, as the cause
+    /// was only temporary. For everything else, something has to change first: the address,
+    /// the protection, or the page has to be erased again.
+    pub fn is_retryable(self) -> bool {
+        match self {
+            Error::Busy
+            | Error::NotProgrammed
+            | Error::Sequence
+            | Error::DataMiss
+            | Error::FastProgramming
+            | Error::OperationFailed => true,
+            Error::UnlockFailed
+            | Error::Timeout
+            | Error::InvalidPage
+            | Error::VerifyFailed
+            | Error::WriteProtected
+            | Error::Alignment
+            | Error::NotErased
+            | Error::Size
+            | Error::ReadProtected => false,
+        }
+    }
+}
+
+/// The ECC errors the flash reported while it was read, see `FlashDevice::take_ecc_errors()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EccErrors {
+    /// The address of a double-bit error, the data read from there was wrong
+    pub uncorrectable: Option<u32>,
+    /// Whether a single-bit error was corrected. The data was right, but the flash wears out.
+    pub corrected: bool,
+}
+
+/// Everything the boot logic needs from the flash memory.
+/// The bootloader implements this for the STM32L4R5 flash controller, tests use `sim::SimFlash`.
+///
+/// All addresses are offsets from the start of the flash, e.g. `METADATA_1_ADDR`.
+/// The flash can only be erased and programmed through the `UnlockedFlash` from `unlock()`.
+pub trait FlashDevice {
+    /// The size of an erasable page, which depends on single- or dual-bank mode.
+    fn page_size(&self) -> u32;
+
+    /// Returns `length` bytes of flash memory starting at `address`.
+    fn read(&self, address: u32, length: usize) -> &[u8];
+
+    /// Returns the ECC errors reported since the last call and clears them. Like the flash
+    /// controller, this only keeps the first uncorrectable error until it is cleared.
+    /// Without ECC, there are never any errors.
+    fn take_ecc_errors(&mut self) -> EccErrors {
+        EccErrors::default()
+    }
+
+    /// Unlocks the flash for erase and program operations, which are only available on the
+    /// returned guard. The flash is locked again when the guard is dropped, even after an error.
+    fn unlock(&mut self) -> Result<UnlockedFlash<'_, Self>, Error>
+    where
+        Self: Sized,
+    {
+        let token = UnlockToken(());
+        self.unlock_controller(&token)?;
+        Ok(UnlockedFlash { flash: self, token })
+    }
+
+    /// Only called by `unlock()`: runs the unlock sequence of the flash controller.
+    fn unlock_controller(&mut self, token: &UnlockToken) -> Result<(), Error>;
+
+    /// Only called when an UnlockedFlash is dropped: locks the flash controller, protecting the
+    /// flash from accidental writes.
+    fn lock_controller(&mut self, token: &UnlockToken);
+
+    /// Only called by UnlockedFlash, see there.
+    fn erase_page(&mut self, token: &UnlockToken, page_number: u32) -> Result<(), Error>;
+
+    /// Only called by UnlockedFlash, see there.
+    fn write_dwords(
+        &mut self, token: &UnlockToken, address: u32, data: &[u64],
+    ) -> Result<(), Error>;
+
+    /// Returns the page number for a given address.
+    fn address_to_page_number(&self, address: u32) -> u32 {
+        debug_assert!(address < interface::FLASH_SIZE, "Address out of range");
+
+        address / self.page_size()
+    }
+}
+
+/// Proof that a call comes from `FlashDevice::unlock()` or the UnlockedFlash it returned. Nothing
+/// else can create one, so the flash can't be unlocked without a guard that locks it again, and
+/// the erase and program operations of a FlashDevice can't be called without it either.
+pub struct UnlockToken(());
+
+/// The unlocked flash, returned by `FlashDevice::unlock()`. It locks the flash when it is
+/// dropped. Everything else of the FlashDevice, e.g. `read`, is available through Deref.
+pub struct UnlockedFlash<'a, F: FlashDevice> {
+    flash: &'a mut F,
+    token: UnlockToken,
+}
+
+impl<F: FlashDevice> UnlockedFlash<'_, F> {
+    pub fn erase_page(&mut self, page_number: u32) -> Result<(), Error> {
+        self.flash.erase_page(&self.token, page_number)
+    }
+
+    /// The target page(s) must have been erased before.
+    /// The data is read back after it was programmed, so an `Ok` means it is in the flash.
+    pub fn write_dwords(&mut self, address: u32, data: &[u64]) -> Result<(), Error> {
+        self.flash.write_dwords(&self.token, address, data)
+    }
+}
+
+impl<F: FlashDevice> Deref for UnlockedFlash<'_, F> {
+    type Target = F;
+
+    fn deref(&self) -> &F {
+        self.flash
+    }
+}
+
+impl<F: FlashDevice> Drop for UnlockedFlash<'_, F> {
+    fn drop(&mut self) {
+        self.flash.lock_controller(&self.token);
+    }
+}
@@ -0,0 +1,24 @@
+#![no_std]
+
+// The hardware-independent part of the bootloader.
+// Everything in here only talks to the hardware through the traits in `flash`, `backup`
+// and `boot`, so the whole boot flow can be tested on the host against the simulated
+// hardware in `sim`.
+
+#[cfg(any(test, feature = "sim"))]
+extern crate std;
+
+pub mod backup;
+pub mod boot;
+pub mod bootcount;
+pub mod flash;
+pub mod metadata;
+pub mod pages;
+pub mod policy;
+#[cfg(test)]
+mod power_loss;
+pub mod recovery;
+pub mod signature;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+pub mod trailer;
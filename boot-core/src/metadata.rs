@@ -0,0 +1,508 @@
+use core::sync::atomic::{fence, Ordering};
+
+use interface::bootinfo::SlotStatus;
+use interface::crc::calc_crc32;
+use interface::digest::{ImageDigests, DIGESTS_OFFSET};
+use interface::sha256::{sha256, DIGEST_SIZE};
+use interface::{
+    ImageMetadata, Metadata, MetadataHeader, MetadataLayout, U32Ext, GOLDEN_METADATA_ADDR,
+    GOLDEN_SLOT_ADDR, METADATA_1_ADDR, METADATA_2_ADDR, METADATA_OFFSET, NUMBER_OF_IMAGES,
+    SLOT_ADDRS, SLOT_SIZE,
+};
+
+use crate::bootcount::BootAttempts;
+use crate::flash::{Error, FlashDevice, UnlockedFlash};
+use crate::policy::BootPolicy;
+
+/// The layout of the metadata page at `addr`, None if we don't know its layout revision.
+fn read_layout<F: FlashDevice>(flash: &F, addr: u32) -> Option<MetadataLayout> {
+    fence(Ordering::SeqCst);
+
+    let bytes = flash.read(addr, core::mem::size_of::<MetadataHeader>());
+    let header = unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const MetadataHeader) };
+    fence(Ordering::SeqCst);
+
+    MetadataLayout::of(&header)
+}
+
+fn read_metadata<F: FlashDevice>(flash: &F, addr: u32, layout: MetadataLayout) -> Metadata {
+    fence(Ordering::SeqCst);
+
+    let bytes = flash.read(addr + layout.metadata_offset(), core::mem::size_of::<Metadata>());
+    // The flash read is not necessarily aligned when it doesn't come from the actual hardware
+    let meta = unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const Metadata) };
+    fence(Ordering::SeqCst);
+
+    meta
+}
+
+fn read_digests<F: FlashDevice>(flash: &F, addr: u32, layout: MetadataLayout) -> ImageDigests {
+    fence(Ordering::SeqCst);
+
+    let bytes = flash.read(addr + layout.digests_offset(), core::mem::size_of::<ImageDigests>());
+    let digests = unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const ImageDigests) };
+    fence(Ordering::SeqCst);
+
+    digests
+}
+
+/// Returns the image digests that belong to `meta`, if any of the metadata pages has them
+/// (see interface::digest). Without digests, images are only verified with their CRC.
+pub fn select_digests<F: FlashDevice>(flash: &F, meta: &Metadata) -> Option<ImageDigests> {
+    [METADATA_1_ADDR, METADATA_2_ADDR]
+        .into_iter()
+        .filter_map(|addr| read_layout(flash, addr).map(|layout| read_digests(flash, addr, layout)))
+        .find(|digests| digests.is_valid_for(meta))
+}
+
+/// Reads the metadata page at `addr` in either layout.
+/// Returns None if the page is corrupted or uses a layout revision we don't know.
+pub fn read_valid_metadata<F: FlashDevice>(flash: &F, addr: u32) -> Option<Metadata> {
+    let layout = read_layout(flash, addr)?;
+    Some(read_metadata(flash, addr, layout)).filter(Metadata::is_valid)
+}
+
+// How often write_metadata erases and programs the page if that fails with a retryable error
+const MAX_WRITE_ATTEMPTS: usize = 3;
+
+/// Writes the metadata to the page at `addr` in the current layout (MetadataHeader, then the
+/// Metadata), followed by its image digests if there are any.
+pub fn write_metadata<F: FlashDevice>(
+    flash: &mut F, meta: &Metadata, digests: Option<&ImageDigests>, addr: u32,
+) -> Result<Metadata, Error> {
+    fence(Ordering::SeqCst);
+
+    static_assertions::const_assert_eq!(
+        core::mem::size_of::<MetadataHeader>(),
+        core::mem::size_of::<[u64; 1]>(),
+    );
+    static_assertions::const_assert_eq!(
+        core::mem::align_of::<MetadataHeader>(),
+        core::mem::align_of::<[u64; 1]>(),
+    );
+    let header = MetadataHeader::current();
+    let header = unsafe { core::mem::transmute::<&MetadataHeader, &[u64; 1]>(&header) };
+
+    static_assertions::const_assert_eq!(
+        core::mem::size_of::<Metadata>(),
+        core::mem::size_of::<[u64; 8]>(),
+    );
+    static_assertions::const_assert_eq!(
+        core::mem::align_of::<Metadata>(),
+        core::mem::align_of::<[u64; 8]>(),
+    );
+    let array = unsafe { core::mem::transmute::<&Metadata, &[u64; 8]>(meta) };
+
+    let digests = digests.map(|digests| {
+        static_assertions::const_assert_eq!(
+            core::mem::size_of::<ImageDigests>(),
+            core::mem::size_of::<[u64; 15]>(),
+        );
+        static_assertions::const_assert_eq!(
+            core::mem::align_of::<ImageDigests>(),
+            core::mem::align_of::<[u64; 15]>(),
+        );
+        unsafe { core::mem::transmute::<&ImageDigests, &[u64; 15]>(digests) }
+    });
+
+    // Erase and program the flash.
+    // If this error happens, we can't really do anything but a reset.
+    // FLASH_CR Lock bit 31: "In case of an unsuccessful unlock operation,
+    // this bit remains set until the next system reset."
+    // The flash is locked again when `unlocked` is dropped, also if we return early
+    let mut unlocked = flash.unlock()?;
+
+    // A transient error (e.g. the flash was still busy) is worth another attempt, starting over
+    // with the erase. Write protection or a wrong address won't go away, so we give up right away.
+    let mut result = program_metadata_page(&mut unlocked, addr, header, array, digests);
+    for _ in 1..MAX_WRITE_ATTEMPTS {
+        match result {
+            Err(error) if error.is_retryable() => {
+                result = program_metadata_page(&mut unlocked, addr, header, array, digests);
+            }
+            _ => break,
+        }
+    }
+    result?;
+
+    // Lock the flash again
+    drop(unlocked);
+
+    fence(Ordering::SeqCst);
+
+    // TODO: Either directly return MetaData (and thus trust that writing worked),
+    // or read it back and with a correctness check (and maybe loop write it?)
+    Ok(read_metadata(flash, addr, MetadataLayout::Current))
+}
+
+fn program_metadata_page<F: FlashDevice>(
+    flash: &mut UnlockedFlash<F>, addr: u32, header: &[u64; 1], meta: &[u64; 8],
+    digests: Option<&[u64; 15]>,
+) -> Result<(), Error> {
+    // Erase the page where addr is on
+    // An error should only happen if we gave an invalid page address,
+    // which is not possible if addr is in 0 <= addr < FLASH_SIZE
+    // The page number depends on the flash page size and dual/single-bank mode,
+    // which is why FlashDevice calculates it for us
+    flash.erase_page(flash.address_to_page_number(addr))?;
+
+    // Write the actual data
+    flash.write_dwords(addr, header)?;
+    flash.write_dwords(addr + METADATA_OFFSET, meta)?;
+    if let Some(digests) = digests {
+        flash.write_dwords(addr + DIGESTS_OFFSET, digests)?;
+    }
+
+    Ok(())
+}
+
+/// Selects the slot to boot: the preferred image if it is valid and did not use up its boot
+/// attempts, otherwise the first other valid image that still has attempts left.
+/// Images with a digest in `digests` must match it as well.
+/// If no image matches its CRC, `policy` decides whether a slot is booted without verification.
+/// None means that the golden image should be booted instead.
+/// What was found out about each slot on the way is recorded in `slot_status`.
+pub fn select_image<F: FlashDevice, P: BootPolicy>(
+    flash: &F, meta: &Metadata, digests: Option<&ImageDigests>, attempts: &mut BootAttempts,
+    policy: &P, slot_status: &mut [SlotStatus; NUMBER_OF_IMAGES],
+) -> Option<u32> {
+    if let Some(index) = first_bootable_image(flash, meta, digests, attempts, slot_status) {
+        return Some(index);
+    }
+
+    // Every valid image has used up its boot attempts. Instead of giving up, we start
+    // counting from zero again - maybe one of the failures was caused by something else
+    let exhausted = *attempts;
+    attempts.reset();
+    if let Some(index) = first_bootable_image(flash, meta, digests, attempts, slot_status) {
+        return Some(index);
+    }
+
+    // No image matches its CRC at all, so resetting didn't help. The policy needs the
+    // real counters to eventually give up on images that don't boot.
+    *attempts = exhausted;
+
+    // Don't trust the policy with our slot array
+    policy.select_unverified(meta, attempts).filter(|&index| (index as usize) < NUMBER_OF_IMAGES)
+}
+
+fn first_bootable_image<F: FlashDevice>(
+    flash: &F, meta: &Metadata, digests: Option<&ImageDigests>, attempts: &BootAttempts,
+    slot_status: &mut [SlotStatus; NUMBER_OF_IMAGES],
+) -> Option<u32> {
+    let mut is_bootable = |i: usize| {
+        if attempts.is_exhausted(i) {
+            // Keep what we found out before the slot was exhausted, e.g. that it is untrusted
+            if slot_status[i] == SlotStatus::NotChecked {
+                slot_status[i] = SlotStatus::Exhausted;
+            }
+            return false;
+        }
+
+        let digest = digests.and_then(|digests| digests.digest(i));
+        let valid = verify_image(flash, &meta.images[i], SLOT_ADDRS[i], digest);
+        slot_status[i] = if valid { SlotStatus::Valid } else { SlotStatus::Invalid };
+        valid
+    };
+
+    let preferred = meta.preferred_image as usize;
+    if preferred < NUMBER_OF_IMAGES && is_bootable(preferred) {
+        //If crc is okay and it still has attempts left. Jump to our preferred image.
+        return Some(meta.preferred_image);
+    }
+
+    //Try to find another image with a valid crc.
+    (0..NUMBER_OF_IMAGES).filter(|&i| i != preferred).find(|&i| is_bootable(i)).map(|i| i as u32)
+}
+
+// TODO: prefer to put into an ImageMetadata impl block, and make naming a bit more clear: e.g. is_valid
+/// Checks the CRC of the image at `addr` and, if given, its SHA-256 digest.
+pub fn verify_image<F: FlashDevice>(
+    flash: &F, image_meta: &ImageMetadata, addr: u32, digest: Option<&[u8; DIGEST_SIZE]>,
+) -> bool {
+    // A corrupted length must not make us read beyond the slot
+    if image_meta.length > SLOT_SIZE {
+        return false;
+    }
+
+    // We don't know how to start an image with a flag we don't know
+    if !image_meta.has_valid_flags() {
+        return false;
+    }
+
+    let image = flash.read(addr, image_meta.length.to_usize());
+    let crc = calc_crc32(image.as_ptr(), image.len());
+    if crc != image_meta.crc {
+        return false;
+    }
+
+    // The CRC is much faster, so only hash images that are likely intact
+    match digest {
+        Some(digest) => sha256(image) == *digest,
+        None => true,
+    }
+}
+
+/// Returns the metadata of the golden image, if one was provisioned and it matches its CRC.
+pub fn golden_image<F: FlashDevice>(flash: &F) -> Option<ImageMetadata> {
+    fence(Ordering::SeqCst);
+
+    let bytes = flash.read(GOLDEN_METADATA_ADDR, core::mem::size_of::<ImageMetadata>());
+    let image_meta = unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const ImageMetadata) };
+
+    // Without a golden image, the region is either erased or zeroed by the image-builder.
+    // An empty image would always match its CRC, so we must not accept it.
+    // The golden image is always copied to RAM as it is, so it can't have any flags.
+    if image_meta.length == 0 || image_meta.flags != 0 {
+        return None;
+    }
+
+    if verify_image(flash, &image_meta, GOLDEN_SLOT_ADDR, None) {
+        Some(image_meta)
+    } else {
+        None
+    }
+}
+
+/// Selects which metadata to use and only returns valid metadata.
+/// In case one metadata is invalid or outdated, it will be fixed automatically.
+/// Valid pages in the legacy layout are upgraded to the current layout as well.
+/// If both are invalid, it will return None.
+/// The second tuple element returns whether or not fixing metadata was successful:
+/// Ok(true) if a page was rewritten, Ok(false) if it was not necessary or possible.
+pub fn select_metadata<F: FlashDevice>(flash: &mut F) -> (Option<Metadata>, Result<bool, Error>) {
+    let layout_one = read_layout(flash, METADATA_1_ADDR);
+    let layout_two = read_layout(flash, METADATA_2_ADDR);
+
+    // A page with a layout revision we don't know is treated like a corrupted one
+    let metadata_one =
+        read_metadata(flash, METADATA_1_ADDR, layout_one.unwrap_or(MetadataLayout::Current));
+    let metadata_two =
+        read_metadata(flash, METADATA_2_ADDR, layout_two.unwrap_or(MetadataLayout::Current));
+    let valid_one = layout_one.is_some() && metadata_one.is_valid();
+    let valid_two = layout_two.is_some() && metadata_two.is_valid();
+
+    let MetadataSelectResult { meta, write_addr } =
+        internal_select(metadata_one, valid_one, metadata_two, valid_two);
+
+    // If we got metadata, that is good
+    let Some(metadata) = meta else {
+        return (None, Ok(false));
+    };
+
+    // The digests must be copied as well, they are only on the page we keep
+    let digests = select_digests(flash, &metadata);
+
+    // We might have to overwrite write_addr, as the metadata there is outdated or corrupted
+    let mut result = match write_addr {
+        Some(write_addr) => {
+            write_metadata(flash, &metadata, digests.as_ref(), write_addr as u32).map(|_| true)
+        }
+        // Nothing to do -- all metadata is valid
+        None => Ok(false),
+    };
+
+    // Legacy pages that hold the selected metadata are rewritten one after the other, and only
+    // if everything before worked. This way, the other page always holds a valid copy.
+    for (addr, layout, valid) in
+        [(METADATA_1_ADDR, layout_one, valid_one), (METADATA_2_ADDR, layout_two, valid_two)]
+    {
+        let is_legacy = valid && layout == Some(MetadataLayout::Legacy);
+        if result.is_ok() && is_legacy && write_addr != Some(addr as usize) {
+            result = write_metadata(flash, &metadata, digests.as_ref(), addr).map(|_| true);
+        }
+    }
+
+    (Some(metadata), result)
+}
+
+struct MetadataSelectResult {
+    // meta is the metadata that should be used for image selection.
+    // If this is None, no valid metadata was found.
+    meta: Option<Metadata>,
+
+    // If write_addr is not None, we should write `meta` to write_addr.
+    // This could be due to:
+    //   - meta having a newer version than metadata at write_addr
+    //   - meta being the only valid metadata
+    write_addr: Option<usize>,
+}
+
+// This function implements the core logic of which metadata block to use.
+// It is without side effects so we can verify it with Kani.
+// If you find out how to make Kani assume something about method calls, we can
+// move the is_valid calls into this function.
+fn internal_select(
+    md1: Metadata, md1_valid: bool, md2: Metadata, md2_valid: bool,
+) -> MetadataSelectResult {
+    match (md1_valid, md2_valid) {
+        (true, true) => {
+            // Both are valid - if one of them is newer, we use it and overwrite the old one
+            if md1.version > md2.version {
+                //Metadata one is newer. Use it.
+                MetadataSelectResult {
+                    meta: Some(md1),
+                    // Overwrite metadata two
+                    write_addr: Some(METADATA_2_ADDR as usize),
+                }
+            } else if md1.version < md2.version {
+                //Metadata two is newer. Use it.
+                MetadataSelectResult {
+                    meta: Some(md2),
+                    // Overwrite metadata one
+                    write_addr: Some(METADATA_1_ADDR as usize),
+                }
+            } else {
+                debug_assert!(md1.version == md2.version, "Both metadata versions are the same");
+
+                //Both have to same version. All good.
+                MetadataSelectResult {
+                    meta: Some(md1),
+                    // No need to overwrite anything
+                    write_addr: None,
+                }
+            }
+        }
+        (true, false) => {
+            //Only metadata one is valid. Use it to overwrite two
+            MetadataSelectResult { meta: Some(md1), write_addr: Some(METADATA_2_ADDR as usize) }
+        }
+        (false, true) => {
+            //Only metadata two is valid. Use it to overwrite one
+            MetadataSelectResult { meta: Some(md2), write_addr: Some(METADATA_1_ADDR as usize) }
+        }
+        (false, false) => {
+            // This is bad - we need to trigger a failsafe boot
+            MetadataSelectResult { meta: None, write_addr: None }
+        }
+    }
+}
+
+#[cfg(kani)]
+mod verification {
+    use super::*;
+    use kani::*;
+
+    // These are a bit more in the style of typical tests
+
+    // First of all: all metadata is valid - try equal, less and greater version
+    #[kani::proof]
+    fn metadata_both_valid_same_version() {
+        let md1: Metadata = any();
+        let md2: Metadata = any();
+        assume(md1.version == md2.version);
+
+        // If both are valid, we should use md1 and not overwrite anything
+        let result = internal_select(md1, true, md2, true);
+        assert_eq!(result.meta, Some(md1));
+
+        // This is important to save flash cycles
+        assert_eq!(result.write_addr, None);
+    }
+
+    #[kani::proof]
+    fn metadata_both_valid_diff_version_md1_newer() {
+        let md1: Metadata = any();
+        let md2: Metadata = any();
+        assume(md1.version > md2.version);
+
+        // If both are valid, we should use the newer one
+        let result = internal_select(md1, true, md2, true);
+        assert_eq!(result.meta, Some(md1));
+        // ... and overwrite the older one
+        assert_eq!(result.write_addr, Some(METADATA_2_ADDR as usize));
+    }
+
+    #[kani::proof]
+    fn metadata_both_valid_diff_version_md2_newer() {
+        let md1: Metadata = any();
+        let md2: Metadata = any();
+        assume(md1.version < md2.version);
+
+        // Now we must use md2
+        let result = internal_select(md1, true, md2, true);
+        assert_eq!(result.meta, Some(md2));
+        assert_eq!(result.write_addr, Some(METADATA_1_ADDR as usize));
+    }
+
+    // Now for cases where md1 is broken, again with equal, less and greater version
+    #[kani::proof]
+    fn metadata_one_broken_same_version() {
+        let md1: Metadata = any();
+        let md2: Metadata = any();
+        assume(md1.version == md2.version);
+
+        let result = internal_select(md1, false, md2, true);
+        assert_eq!(result.meta, Some(md2));
+        // We should fix metadata one
+        assert_eq!(result.write_addr, Some(METADATA_1_ADDR as usize));
+    }
+
+    #[kani::proof]
+    fn metadata_one_broken_diff_version_md1_newer() {
+        let md1: Metadata = any();
+        let md2: Metadata = any();
+        assume(md1.version > md2.version);
+
+        let result = internal_select(md1, false, md2, true);
+        assert_eq!(result.meta, Some(md2));
+        assert_eq!(result.write_addr, Some(METADATA_1_ADDR as usize));
+    }
+
+    #[kani::proof]
+    fn metadata_one_broken_diff_version_md2_newer() {
+        let md1: Metadata = any();
+        let md2: Metadata = any();
+        assume(md1.version < md2.version);
+
+        let result = internal_select(md1, false, md2, true);
+        assert_eq!(result.meta, Some(md2));
+        assert_eq!(result.write_addr, Some(METADATA_1_ADDR as usize));
+    }
+
+    // Now for cases where md2 is broken, again with equal, less and greater version
+    #[kani::proof]
+    fn metadata_two_broken_same_version() {
+        let md1: Metadata = any();
+        let md2: Metadata = any();
+        assume(md1.version == md2.version);
+
+        let result = internal_select(md1, true, md2, false);
+        assert_eq!(result.meta, Some(md1));
+        // We should fix metadata two
+        assert_eq!(result.write_addr, Some(METADATA_2_ADDR as usize));
+    }
+
+    #[kani::proof]
+    fn metadata_two_broken_diff_version_md1_newer() {
+        let md1: Metadata = any();
+        let md2: Metadata = any();
+        assume(md1.version > md2.version);
+
+        let result = internal_select(md1, true, md2, false);
+        assert_eq!(result.meta, Some(md1));
+        assert_eq!(result.write_addr, Some(METADATA_2_ADDR as usize));
+    }
+
+    #[kani::proof]
+    fn metadata_two_broken_diff_version_md2_newer() {
+        let md1: Metadata = any();
+        let md2: Metadata = any();
+        assume(md1.version < md2.version);
+
+        let result = internal_select(md1, true, md2, false);
+        assert_eq!(result.meta, Some(md1));
+        assert_eq!(result.write_addr, Some(METADATA_2_ADDR as usize));
+    }
+
+    // When both are broken, we should not return any metadata
+    #[kani::proof]
+    fn metadata_both_broken() {
+        let md1: Metadata = any();
+        let md2: Metadata = any();
+
+        let result = internal_select(md1, false, md2, false);
+        assert_eq!(result.meta, None);
+        assert_eq!(result.write_addr, None);
+    }
+}
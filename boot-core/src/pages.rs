@@ -1,12 +1,10 @@
-use core::ops::Range;
-
 /// Calculate the number of pages that is spanned by a given start address and length.
 /// Since the start address is assumed to be page aligned, we only need the length.
 /// Basically, if you have start=0x4000 and length=0x3000, you will get 2 pages.
 pub const fn page_span(length: u32, page_size: u32) -> u32 {
     debug_assert!(length <= interface::FLASH_SIZE, "length must be <= FLASH_SIZE");
 
-    (length + page_size - 1) / page_size
+    length.div_ceil(page_size)
 }
 
 mod asserts {
@@ -28,13 +26,19 @@ mod asserts {
     const_assert_eq!(page_span(0x3000, MAX_PAGE_SIZE), 2);
     const_assert_eq!(page_span(0x4000, MAX_PAGE_SIZE), 2);
     const_assert_eq!(page_span(0x4001, MAX_PAGE_SIZE), 3);
-    const_assert_eq!((SLOT_2_ADDR - SLOT_1_ADDR) / MAX_PAGE_SIZE, page_span(SLOT_2_ADDR - SLOT_1_ADDR, MAX_PAGE_SIZE));
+    const_assert_eq!(
+        (SLOT_2_ADDR - SLOT_1_ADDR) / MAX_PAGE_SIZE,
+        page_span(SLOT_2_ADDR - SLOT_1_ADDR, MAX_PAGE_SIZE)
+    );
 
     const_assert_eq!(page_span(1, MIN_PAGE_SIZE), 1);
     const_assert_eq!(page_span(0x1800, MIN_PAGE_SIZE), 2);
     const_assert_eq!(page_span(0x2000, MIN_PAGE_SIZE), 2);
     const_assert_eq!(page_span(0x2001, MIN_PAGE_SIZE), 3);
-    const_assert_eq!((SLOT_2_ADDR - SLOT_1_ADDR) / MIN_PAGE_SIZE, page_span(SLOT_2_ADDR - SLOT_1_ADDR, MIN_PAGE_SIZE));
+    const_assert_eq!(
+        (SLOT_2_ADDR - SLOT_1_ADDR) / MIN_PAGE_SIZE,
+        page_span(SLOT_2_ADDR - SLOT_1_ADDR, MIN_PAGE_SIZE)
+    );
 }
 
 #[cfg(kani)]
@@ -0,0 +1,192 @@
+use interface::{Metadata, NUMBER_OF_IMAGES};
+
+use crate::bootcount::BootAttempts;
+
+/// Decides what happens if none of the slots passes its CRC check.
+///
+/// Booting an image that doesn't match its CRC is risky, but it might still be better than not
+/// booting anything, e.g. if only a few unused bytes at the end of an image flipped. Which trade-off
+/// is right depends on the mission, so the bootloader chooses a policy at compile time.
+pub trait BootPolicy {
+    /// Returns the slot to boot without verification, or None to give up on the slots and boot
+    /// the golden image instead. The returned slot MUST be smaller than NUMBER_OF_IMAGES.
+    ///
+    /// Every unverified boot is counted in `attempts` like any other boot, so a policy should
+    /// skip exhausted slots to make sure it eventually gives up on images that don't work.
+    fn select_unverified(&self, meta: &Metadata, attempts: &BootAttempts) -> Option<u32>;
+}
+
+/// Never boots an unverified image, the golden image is booted instead
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GoldenOnly;
+
+impl BootPolicy for GoldenOnly {
+    fn select_unverified(&self, _meta: &Metadata, _attempts: &BootAttempts) -> Option<u32> {
+        None
+    }
+}
+
+/// Boots the preferred slot without verification until it used up its boot attempts
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PreferredUnverified;
+
+impl BootPolicy for PreferredUnverified {
+    fn select_unverified(&self, meta: &Metadata, attempts: &BootAttempts) -> Option<u32> {
+        let preferred = meta.preferred_image as usize;
+
+        // is_exhausted also rejects indices that are out of range
+        if attempts.is_exhausted(preferred) {
+            None
+        } else {
+            Some(meta.preferred_image)
+        }
+    }
+}
+
+/// Boots the slots without verification, the one with the highest image version first.
+/// Once a slot used up its boot attempts, the next older one is tried.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NewestUnverified;
+
+impl BootPolicy for NewestUnverified {
+    fn select_unverified(&self, meta: &Metadata, attempts: &BootAttempts) -> Option<u32> {
+        let mut newest: Option<usize> = None;
+
+        // On equal versions, the lower slot wins, so the order is always the same
+        for i in 0..NUMBER_OF_IMAGES {
+            if attempts.is_exhausted(i) {
+                continue;
+            }
+
+            match newest {
+                Some(n) if meta.images[n].version >= meta.images[i].version => {}
+                _ => newest = Some(i),
+            }
+        }
+
+        newest.map(|i| i as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use interface::backup::MAX_BOOT_ATTEMPTS;
+    use interface::reset::ResetCause;
+    use interface::ImageMetadata;
+
+    use super::*;
+    use crate::sim::SimBackupRegisters;
+
+    fn metadata(preferred_image: u32, versions: [u32; NUMBER_OF_IMAGES]) -> Metadata {
+        let mut images = [ImageMetadata::default(); NUMBER_OF_IMAGES];
+        for (image, version) in images.iter_mut().zip(versions) {
+            image.version = version;
+        }
+
+        Metadata { version: 1, bootcounter: 0, preferred_image, images, crc: 0 }
+    }
+
+    // Exhausts the given slot by booting it without confirmation
+    fn exhaust(attempts: &mut BootAttempts, backup: &mut SimBackupRegisters, slot: u32) {
+        for _ in 0..MAX_BOOT_ATTEMPTS {
+            attempts.record_boot(backup, slot);
+        }
+    }
+
+    #[test]
+    fn golden_only_never_boots_unverified() {
+        let attempts = BootAttempts::load(&SimBackupRegisters::new(), ResetCause::Unknown);
+        assert_eq!(GoldenOnly.select_unverified(&metadata(0, [1, 2, 3]), &attempts), None);
+    }
+
+    #[test]
+    fn preferred_unverified_gives_up() {
+        let mut backup = SimBackupRegisters::new();
+        let mut attempts = BootAttempts::load(&backup, ResetCause::Unknown);
+        let meta = metadata(2, [1, 1, 1]);
+
+        assert_eq!(PreferredUnverified.select_unverified(&meta, &attempts), Some(2));
+
+        exhaust(&mut attempts, &mut backup, 2);
+        assert_eq!(PreferredUnverified.select_unverified(&meta, &attempts), None);
+
+        // A broken preferred index never leads to a boot
+        let meta = metadata(NUMBER_OF_IMAGES as u32, [1, 1, 1]);
+        assert_eq!(PreferredUnverified.select_unverified(&meta, &attempts), None);
+    }
+
+    #[test]
+    fn newest_unverified_tries_by_version() {
+        let mut backup = SimBackupRegisters::new();
+        let mut attempts = BootAttempts::load(&backup, ResetCause::Unknown);
+        let meta = metadata(0, [2, 5, 2]);
+
+        assert_eq!(NewestUnverified.select_unverified(&meta, &attempts), Some(1));
+
+        exhaust(&mut attempts, &mut backup, 1);
+        // Same version, so the lower slot comes first
+        assert_eq!(NewestUnverified.select_unverified(&meta, &attempts), Some(0));
+
+        exhaust(&mut attempts, &mut backup, 0);
+        assert_eq!(NewestUnverified.select_unverified(&meta, &attempts), Some(2));
+
+        exhaust(&mut attempts, &mut backup, 2);
+        assert_eq!(NewestUnverified.select_unverified(&meta, &attempts), None);
+    }
+}
+
+#[cfg(kani)]
+mod verification {
+    use super::*;
+    use kani::*;
+
+    // Every policy must return a slot that exists and still has attempts left.
+    // As every unverified boot is counted, this also means that a policy eventually gives up.
+    fn check_policy<P: BootPolicy>(policy: P) {
+        let meta: Metadata = any();
+        let attempts: BootAttempts = any();
+
+        if let Some(slot) = policy.select_unverified(&meta, &attempts) {
+            assert!((slot as usize) < NUMBER_OF_IMAGES);
+            assert!(!attempts.is_exhausted(slot as usize));
+        }
+    }
+
+    #[kani::proof]
+    #[kani::unwind(4)]
+    fn golden_only_in_bounds() {
+        check_policy(GoldenOnly);
+    }
+
+    #[kani::proof]
+    #[kani::unwind(4)]
+    fn preferred_unverified_in_bounds() {
+        check_policy(PreferredUnverified);
+    }
+
+    #[kani::proof]
+    #[kani::unwind(4)]
+    fn newest_unverified_in_bounds() {
+        check_policy(NewestUnverified);
+    }
+
+    #[kani::proof]
+    #[kani::unwind(4)]
+    fn newest_unverified_picks_newest() {
+        let meta: Metadata = any();
+        let attempts: BootAttempts = any();
+
+        if let Some(slot) = NewestUnverified.select_unverified(&meta, &attempts) {
+            for i in 0..NUMBER_OF_IMAGES {
+                if !attempts.is_exhausted(i) {
+                    assert!(meta.images[i].version <= meta.images[slot as usize].version);
+                }
+            }
+        } else {
+            // Giving up is only allowed once all slots are exhausted
+            for i in 0..NUMBER_OF_IMAGES {
+                assert!(attempts.is_exhausted(i));
+            }
+        }
+    }
+}
@@ -0,0 +1,253 @@
+// Power-loss fault injection for everything that writes the flash.
+//
+// Each scenario is first run without interruption to count its flash operations (page erases
+// and double-word programs). Then it is run again once for every possible power cut: between
+// two operations as well as in the middle of each operation, which leaves half-erased pages or
+// half-programmed double-words behind. After every power cut, we boot again and make sure that
+// the bootloader still finds valid metadata, repairs the other metadata page and boots an
+// image that matches its CRC.
+
+use std::vec;
+use std::vec::Vec;
+
+use interface::{
+    Metadata, U32Ext, METADATA_1_ADDR, METADATA_2_ADDR, METADATA_OFFSET, NUMBER_OF_IMAGES,
+    SLOT_ADDRS, SLOT_SIZE,
+};
+
+use crate::boot::{boot, BootTarget};
+use crate::flash::FlashDevice;
+use crate::metadata::{select_metadata, write_metadata};
+use crate::pages::page_span;
+use crate::policy::GoldenOnly;
+use crate::sim::{BankMode, Interruption, SimBackupRegisters, SimFlash, SimWatchdog};
+
+const BANK_MODES: [BankMode; 2] = [BankMode::SingleBank, BankMode::DualBank];
+const INTERRUPTIONS: [Interruption; 2] =
+    [Interruption::BetweenOperations, Interruption::DuringOperation];
+
+fn test_image(seed: u8, length: usize) -> Vec<u8> {
+    (0..length).map(|i| (i as u8).wrapping_mul(13).wrapping_add(seed)).collect()
+}
+
+fn read_metadata(flash: &SimFlash, addr: u32) -> Metadata {
+    let bytes = flash.read(addr + METADATA_OFFSET, core::mem::size_of::<Metadata>());
+    unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const Metadata) }
+}
+
+/// Runs `scenario` on a copy of `initial` once for every possible power cut and passes the
+/// flash content at the time of the power cut to `check`.
+fn for_each_power_cut(
+    initial: &SimFlash, scenario: impl Fn(&mut SimFlash), check: impl Fn(SimFlash),
+) {
+    let mut uninterrupted = initial.clone();
+    scenario(&mut uninterrupted);
+    let operations = uninterrupted.operation_count() - initial.operation_count();
+
+    for cut in 0..=operations {
+        for interruption in INTERRUPTIONS {
+            let mut flash = initial.clone();
+            flash.cut_power_after(cut, interruption);
+            scenario(&mut flash);
+
+            check(flash.power_cycle());
+        }
+    }
+}
+
+/// Boots after a power cut and checks that the bootloader recovered: it used one of the
+/// `expected` metadata versions, both metadata pages are valid and equal again, and the image
+/// in RAM matches the CRC from the metadata.
+fn assert_recovers(mut flash: SimFlash, expected: &[Metadata]) {
+    let mut ram = vec![0u8; SLOT_SIZE as usize];
+    let target = boot(
+        &mut flash,
+        &mut SimBackupRegisters::new(),
+        &mut SimWatchdog::default(),
+        &GoldenOnly,
+        None,
+        0,
+        &mut ram,
+    );
+
+    let index = match target {
+        BootTarget::Image(index) | BootTarget::InPlace(index) => index as usize,
+        BootTarget::Golden | BootTarget::Unbootable | BootTarget::Recovery => {
+            panic!("No valid metadata after power cut")
+        }
+        BootTarget::Reset => panic!("Flash timed out"),
+    };
+
+    let metadata_one = read_metadata(&flash, METADATA_1_ADDR);
+    let metadata_two = read_metadata(&flash, METADATA_2_ADDR);
+    assert!(metadata_one.is_valid(), "Metadata 1 was not repaired");
+    assert_eq!(metadata_one, metadata_two, "Metadata pages differ after boot");
+    assert!(expected.contains(&metadata_one), "Unexpected metadata {:?}", metadata_one);
+    assert!(flash.is_locked(), "Flash was left unlocked");
+
+    let image_meta = &metadata_one.images[index];
+    let length = image_meta.length.to_usize();
+    assert_eq!(
+        interface::crc::calc_crc32(ram.as_ptr(), length),
+        image_meta.crc,
+        "Booted image {} does not match its CRC",
+        index
+    );
+}
+
+fn flash_with_images(bank_mode: BankMode) -> (SimFlash, Metadata) {
+    let images = [test_image(1, 0x1234), test_image(2, 0x800), test_image(3, 0x2100)];
+    SimFlash::with_images(bank_mode, [&images[0], &images[1], &images[2]])
+}
+
+fn next_metadata(metadata: &Metadata) -> Metadata {
+    let mut next = *metadata;
+    next.version += 1;
+    next.preferred_image = (metadata.preferred_image + 1) % NUMBER_OF_IMAGES as u32;
+    next.set_crc();
+    next
+}
+
+/// Writes an image into a slot like an OS update would: erase the pages, then program them
+fn write_image(flash: &mut SimFlash, slot: usize, image: &[u8]) {
+    let page_size = flash.page_size();
+    let first_page = flash.address_to_page_number(SLOT_ADDRS[slot]);
+
+    let mut flash = flash.unlock().unwrap();
+    for page in first_page..first_page + page_span(image.len() as u32, page_size) {
+        flash.erase_page(page).unwrap();
+    }
+
+    let dwords: Vec<u64> = image
+        .chunks(8)
+        .map(|chunk| {
+            let mut bytes = [0xff; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            u64::from_le_bytes(bytes)
+        })
+        .collect();
+    flash.write_dwords(SLOT_ADDRS[slot], &dwords).unwrap();
+}
+
+#[test]
+fn metadata_update() {
+    for bank_mode in BANK_MODES {
+        let (flash, metadata) = flash_with_images(bank_mode);
+        let next = next_metadata(&metadata);
+
+        for_each_power_cut(
+            &flash,
+            |flash| {
+                let _ = write_metadata(flash, &next, None, METADATA_1_ADDR);
+            },
+            |flash| assert_recovers(flash, &[metadata, next]),
+        );
+    }
+}
+
+#[test]
+fn corrupted_metadata_fixup() {
+    for bank_mode in BANK_MODES {
+        for (broken, other) in
+            [(METADATA_1_ADDR, METADATA_2_ADDR), (METADATA_2_ADDR, METADATA_1_ADDR)]
+        {
+            let (mut flash, metadata) = flash_with_images(bank_mode);
+            flash.load(broken + 5, &[0xff; 17]);
+
+            for_each_power_cut(
+                &flash,
+                |flash| {
+                    let (selected, _) = select_metadata(flash);
+                    assert_eq!(selected, Some(metadata));
+                },
+                |flash| {
+                    // The intact copy must never be touched
+                    assert_eq!(read_metadata(&flash, other), metadata);
+                    assert_recovers(flash, &[metadata]);
+                },
+            );
+        }
+    }
+}
+
+#[test]
+fn outdated_metadata_fixup() {
+    for bank_mode in BANK_MODES {
+        let (mut flash, metadata) = flash_with_images(bank_mode);
+        let next = next_metadata(&metadata);
+        flash.load_metadata(METADATA_2_ADDR, &next);
+
+        for_each_power_cut(
+            &flash,
+            |flash| {
+                let (selected, _) = select_metadata(flash);
+                assert_eq!(selected, Some(next));
+            },
+            |flash| {
+                assert_eq!(read_metadata(&flash, METADATA_2_ADDR), next);
+                assert_recovers(flash, &[next]);
+            },
+        );
+    }
+}
+
+#[test]
+fn legacy_metadata_upgrade() {
+    for bank_mode in BANK_MODES {
+        let (mut flash, metadata) = flash_with_images(bank_mode);
+        for addr in [METADATA_1_ADDR, METADATA_2_ADDR] {
+            flash.load(addr, &[0xff; METADATA_OFFSET as usize + core::mem::size_of::<Metadata>()]);
+            flash.load_legacy_metadata(addr, &metadata, None);
+        }
+
+        for_each_power_cut(
+            &flash,
+            |flash| {
+                let (selected, _) = select_metadata(flash);
+                assert_eq!(selected, Some(metadata));
+            },
+            |flash| assert_recovers(flash, &[metadata]),
+        );
+    }
+}
+
+#[test]
+fn fixup_survives_repeated_power_cuts() {
+    for bank_mode in BANK_MODES {
+        let (mut flash, metadata) = flash_with_images(bank_mode);
+        flash.load(METADATA_1_ADDR, &[0; 8]);
+
+        let fixup = |flash: &mut SimFlash| {
+            let _ = select_metadata(flash);
+        };
+
+        // The power is cut during the fixup, and again during the fixup on the next boot
+        for_each_power_cut(&flash, fixup, |flash| {
+            for_each_power_cut(&flash, fixup, |flash| assert_recovers(flash, &[metadata]));
+        });
+    }
+}
+
+#[test]
+fn image_update() {
+    for bank_mode in BANK_MODES {
+        let (flash, metadata) = flash_with_images(bank_mode);
+        let new_image = test_image(42, 0x1234);
+
+        // The OS writes a new image into slot 1 and then commits metadata that prefers it
+        let mut next = next_metadata(&metadata);
+        next.preferred_image = 1;
+        next.images[1].length = new_image.len() as u32;
+        next.images[1].crc = interface::crc::calc_crc32(new_image.as_ptr(), new_image.len());
+        next.set_crc();
+
+        for_each_power_cut(
+            &flash,
+            |flash| {
+                write_image(flash, 1, &new_image);
+                let _ = write_metadata(flash, &next, None, METADATA_1_ADDR);
+            },
+            |flash| assert_recovers(flash, &[metadata, next]),
+        );
+    }
+}
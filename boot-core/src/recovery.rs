@@ -0,0 +1,386 @@
+use interface::recovery::{
+    Command, FrameDecoder, NackReason, Response, MAX_CHUNK_SIZE, MAX_RESPONSE_FRAME_SIZE,
+};
+use interface::{ImageMetadata, Metadata, METADATA_1_ADDR, METADATA_2_ADDR, NUMBER_OF_IMAGES};
+use interface::{U32Ext, SLOT_ADDRS, SLOT_SIZE};
+
+use crate::boot::Watchdog;
+use crate::flash::{Error, FlashDevice, UnlockedFlash};
+use crate::metadata::{verify_image, write_metadata};
+use crate::pages::page_span;
+
+/// A byte-oriented serial connection, e.g. a UART
+pub trait Serial {
+    /// Returns the next received byte, or None if nothing was received yet
+    fn read_byte(&mut self) -> Option<u8>;
+
+    /// Sends all bytes, blocking until they are written
+    fn write_all(&mut self, data: &[u8]);
+}
+
+/// Runs the recovery protocol (see interface::recovery) until the host sends a Reset command.
+/// The watchdog is fed while waiting, so we can stay in recovery mode for as long as needed.
+pub fn run_recovery<F: FlashDevice, S: Serial, W: Watchdog>(
+    flash: &mut F, serial: &mut S, watchdog: &mut W,
+) {
+    let mut decoder = FrameDecoder::new();
+    let mut response_buffer = [0u8; MAX_RESPONSE_FRAME_SIZE];
+
+    loop {
+        watchdog.feed();
+
+        let Some(byte) = serial.read_byte() else {
+            continue;
+        };
+        let Some(frame) = decoder.push(byte) else {
+            continue;
+        };
+
+        let command = frame.and_then(|frame| Command::decode(&frame));
+        let response = match command {
+            Ok(command) => handle_command(flash, watchdog, &command),
+            Err(error) => Response::Nack(error.into()),
+        };
+
+        // A response always fits into the buffer
+        if let Ok(length) = response.encode(&mut response_buffer) {
+            serial.write_all(&response_buffer[..length]);
+        }
+
+        if command == Ok(Command::Reset) {
+            return;
+        }
+    }
+}
+
+fn handle_command<F: FlashDevice, W: Watchdog>(
+    flash: &mut F, watchdog: &mut W, command: &Command<'_>,
+) -> Response {
+    let result = match *command {
+        Command::Ping | Command::Reset => Ok(()),
+        Command::EraseSlot { slot } => erase_slot(flash, watchdog, slot),
+        Command::WriteChunk { slot, offset, data } => write_chunk(flash, slot, offset, data),
+        Command::Verify { slot, length, crc } => verify_slot(flash, slot, length, crc),
+        Command::CommitMetadata { metadata } => commit_metadata(flash, &metadata),
+    };
+
+    match result {
+        Ok(()) => Response::Ack,
+        Err(reason) => Response::Nack(reason),
+    }
+}
+
+fn slot_address(slot: u32) -> Result<u32, NackReason> {
+    SLOT_ADDRS.get(slot.to_usize()).copied().ok_or(NackReason::InvalidArgument)
+}
+
+// Runs `operation` on the unlocked flash, which is locked again afterwards
+fn with_unlocked<F: FlashDevice>(
+    flash: &mut F, operation: impl FnOnce(&mut UnlockedFlash<F>) -> Result<(), Error>,
+) -> Result<(), NackReason> {
+    let mut unlocked = flash.unlock().map_err(nack_reason)?;
+
+    operation(&mut unlocked).map_err(nack_reason)
+}
+
+// A timeout has its own reason, as the host can't fix it by sending the command again
+fn nack_reason(error: Error) -> NackReason {
+    match error {
+        Error::Timeout => NackReason::FlashTimeout,
+        _ => NackReason::FlashError,
+    }
+}
+
+fn erase_slot<F: FlashDevice, W: Watchdog>(
+    flash: &mut F, watchdog: &mut W, slot: u32,
+) -> Result<(), NackReason> {
+    let first_page = flash.address_to_page_number(slot_address(slot)?);
+    let pages = page_span(SLOT_SIZE, flash.page_size());
+
+    with_unlocked(flash, |flash| {
+        for page in first_page..first_page + pages {
+            flash.erase_page(page)?;
+            watchdog.feed();
+        }
+        Ok(())
+    })
+}
+
+fn write_chunk<F: FlashDevice>(
+    flash: &mut F, slot: u32, offset: u32, data: &[u8],
+) -> Result<(), NackReason> {
+    let address = slot_address(slot)?;
+
+    // We can only program whole double-words, and never outside of the slot
+    let fits_slot = offset.checked_add(data.len() as u32).is_some_and(|end| end <= SLOT_SIZE);
+    let aligned = offset.is_multiple_of(8) && data.len().is_multiple_of(8);
+    if !fits_slot || !aligned || data.len() > MAX_CHUNK_SIZE {
+        return Err(NackReason::InvalidArgument);
+    }
+
+    let mut dwords = [0u64; MAX_CHUNK_SIZE / 8];
+    for (dword, bytes) in dwords.iter_mut().zip(data.chunks_exact(8)) {
+        *dword = u64::from_le_bytes([
+            bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+        ]);
+    }
+
+    with_unlocked(flash, |flash| flash.write_dwords(address + offset, &dwords[..data.len() / 8]))
+}
+
+fn verify_slot<F: FlashDevice>(
+    flash: &F, slot: u32, length: u32, crc: u32,
+) -> Result<(), NackReason> {
+    let address = slot_address(slot)?;
+    if length > SLOT_SIZE {
+        return Err(NackReason::InvalidArgument);
+    }
+
+    let image_meta = ImageMetadata { version: 0, crc, flags: 0, length };
+    if verify_image(flash, &image_meta, address, None) {
+        Ok(())
+    } else {
+        Err(NackReason::VerifyFailed)
+    }
+}
+
+fn commit_metadata<F: FlashDevice>(flash: &mut F, metadata: &Metadata) -> Result<(), NackReason> {
+    // These versions look like erased flash, see Metadata
+    let valid_version = metadata.version != 0 && metadata.version != u32::MAX;
+    let valid_preferred = metadata.preferred_image.to_usize() < NUMBER_OF_IMAGES;
+    if !metadata.is_valid() || !valid_version || !valid_preferred {
+        return Err(NackReason::InvalidMetadata);
+    }
+
+    // Both pages are overwritten. If we lose power in between, the fixup on the next boot
+    // completes the commit, as the first page then holds the newer version.
+    for addr in [METADATA_1_ADDR, METADATA_2_ADDR] {
+        match write_metadata(flash, metadata, None, addr) {
+            Ok(written) if written == *metadata => {}
+            Ok(_) => return Err(NackReason::FlashError),
+            Err(error) => return Err(nack_reason(error)),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::vec;
+    use std::vec::Vec;
+
+    use interface::crc::calc_crc32;
+    use interface::recovery::{Frame, MAX_FRAME_SIZE};
+
+    use super::*;
+    use crate::boot::{boot, BootTarget};
+    use crate::policy::GoldenOnly;
+    use crate::sim::{BankMode, SimBackupRegisters, SimFlash, SimWatchdog};
+
+    // Replays the received bytes and collects everything that is sent
+    struct SimSerial {
+        received: VecDeque<u8>,
+        sent: Vec<u8>,
+    }
+
+    impl Serial for SimSerial {
+        fn read_byte(&mut self) -> Option<u8> {
+            self.received.pop_front()
+        }
+
+        fn write_all(&mut self, data: &[u8]) {
+            self.sent.extend_from_slice(data);
+        }
+    }
+
+    // Runs recovery with the given commands (Reset is appended) and returns all responses
+    fn run_commands(flash: &mut SimFlash, commands: &[Command<'_>]) -> Vec<Response> {
+        let mut received = VecDeque::new();
+        for command in commands.iter().chain(&[Command::Reset]) {
+            let mut buffer = [0u8; MAX_FRAME_SIZE];
+            let length = command.encode(&mut buffer).unwrap();
+            received.extend(&buffer[..length]);
+        }
+
+        let mut serial = SimSerial { received, sent: Vec::new() };
+        run_recovery(flash, &mut serial, &mut SimWatchdog::default());
+        assert!(serial.received.is_empty(), "Recovery stopped before the Reset command");
+
+        let mut responses = Vec::new();
+        let mut decoder = FrameDecoder::new();
+        for byte in serial.sent {
+            if let Some(frame) = decoder.push(byte) {
+                responses.push(Response::decode(&frame.unwrap()).unwrap());
+            }
+        }
+
+        // The response to Reset
+        assert_eq!(responses.pop(), Some(Response::Ack));
+        responses
+    }
+
+    fn test_image(seed: u8, length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i as u8).wrapping_mul(17).wrapping_add(seed)).collect()
+    }
+
+    fn padded_chunks(image: &[u8]) -> Vec<Vec<u8>> {
+        image
+            .chunks(MAX_CHUNK_SIZE)
+            .map(|chunk| {
+                let mut chunk = chunk.to_vec();
+                chunk.resize(chunk.len().next_multiple_of(8), 0xff);
+                chunk
+            })
+            .collect()
+    }
+
+    fn metadata_for(images: &[Vec<u8>; NUMBER_OF_IMAGES]) -> Metadata {
+        let mut metadata = Metadata {
+            version: 1,
+            bootcounter: 0,
+            preferred_image: 0,
+            images: [ImageMetadata::default(); NUMBER_OF_IMAGES],
+            crc: 0,
+        };
+        for (meta, image) in metadata.images.iter_mut().zip(images) {
+            *meta = ImageMetadata {
+                version: 1,
+                crc: calc_crc32(image.as_ptr(), image.len()),
+                flags: 0,
+                length: image.len() as u32,
+            };
+        }
+        metadata.set_crc();
+        metadata
+    }
+
+    #[test]
+    fn recovers_unbootable_flash() {
+        for bank_mode in [BankMode::SingleBank, BankMode::DualBank] {
+            // Nothing valid on the flash at all
+            let mut flash = SimFlash::from_bytes(bank_mode, &[0u8; 0x4000]);
+            let images = [test_image(1, 0x1234), test_image(2, 0x803), test_image(3, 0x10)];
+            let metadata = metadata_for(&images);
+
+            let chunks: Vec<Vec<Vec<u8>>> = images.iter().map(|i| padded_chunks(i)).collect();
+            let mut commands = vec![Command::Ping];
+            for (slot, image) in images.iter().enumerate() {
+                let slot = slot as u32;
+                commands.push(Command::EraseSlot { slot });
+                for (i, chunk) in chunks[slot as usize].iter().enumerate() {
+                    let offset = (i * MAX_CHUNK_SIZE) as u32;
+                    commands.push(Command::WriteChunk { slot, offset, data: chunk });
+                }
+                let crc = calc_crc32(image.as_ptr(), image.len());
+                commands.push(Command::Verify { slot, length: image.len() as u32, crc });
+            }
+            commands.push(Command::CommitMetadata { metadata });
+
+            let responses = run_commands(&mut flash, &commands);
+            assert!(responses.iter().all(|&r| r == Response::Ack), "{:?}", responses);
+            assert!(flash.is_locked());
+
+            let mut ram = vec![0u8; SLOT_SIZE as usize];
+            let target = boot(
+                &mut flash,
+                &mut SimBackupRegisters::new(),
+                &mut SimWatchdog::default(),
+                &GoldenOnly,
+                None,
+                0,
+                &mut ram,
+            );
+            assert_eq!(target, BootTarget::Image(0));
+            assert_eq!(&ram[..images[0].len()], &images[0][..]);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        let mut flash = SimFlash::new(BankMode::SingleBank);
+        let data = [0u8; 16];
+
+        let responses = run_commands(
+            &mut flash,
+            &[
+                Command::EraseSlot { slot: NUMBER_OF_IMAGES as u32 },
+                Command::WriteChunk { slot: 0, offset: 4, data: &data },
+                Command::WriteChunk { slot: 0, offset: 0, data: &data[..12] },
+                Command::WriteChunk { slot: 0, offset: SLOT_SIZE - 8, data: &data },
+                Command::Verify { slot: 1, length: SLOT_SIZE + 1, crc: 0 },
+            ],
+        );
+
+        assert!(responses.iter().all(|&r| r == Response::Nack(NackReason::InvalidArgument)));
+        assert_eq!(flash.memory(), SimFlash::new(BankMode::SingleBank).memory());
+    }
+
+    #[test]
+    fn reports_flash_and_verify_errors() {
+        let image = test_image(1, 0x100);
+        let (mut flash, mut metadata) =
+            SimFlash::with_images(BankMode::DualBank, [&image, &image, &image]);
+
+        // The slot was not erased, so programming fails
+        let data = [0u8; 8];
+        let write = Command::WriteChunk { slot: 0, offset: 0, data: &data };
+        let verify = Command::Verify { slot: 0, length: 0x100, crc: metadata.images[0].crc ^ 1 };
+
+        metadata.version = 0;
+        metadata.set_crc();
+        let commit = Command::CommitMetadata { metadata };
+
+        let responses = run_commands(&mut flash, &[write, verify, commit]);
+        assert_eq!(
+            responses,
+            [
+                Response::Nack(NackReason::FlashError),
+                Response::Nack(NackReason::VerifyFailed),
+                Response::Nack(NackReason::InvalidMetadata)
+            ]
+        );
+        assert!(flash.is_locked());
+    }
+
+    #[test]
+    fn reports_flash_timeout() {
+        let mut flash = SimFlash::new(BankMode::SingleBank);
+        flash.fail_operations(Error::Timeout, 1);
+
+        let erase = Command::EraseSlot { slot: 1 };
+        let responses = run_commands(&mut flash, &[erase, erase]);
+        assert_eq!(responses, [Response::Nack(NackReason::FlashTimeout), Response::Ack]);
+        assert!(flash.is_locked());
+    }
+
+    #[test]
+    fn rejects_corrupted_frame() {
+        let mut flash = SimFlash::new(BankMode::SingleBank);
+
+        let mut buffer = [0u8; MAX_FRAME_SIZE];
+        let length = Command::EraseSlot { slot: 0 }.encode(&mut buffer).unwrap();
+        buffer[5] ^= 1;
+        assert!(Frame::parse(&buffer[..length]).is_err());
+
+        let mut reset = [0u8; MAX_FRAME_SIZE];
+        let reset_length = Command::Reset.encode(&mut reset).unwrap();
+
+        let mut received: VecDeque<u8> = buffer[..length].iter().copied().collect();
+        received.extend(&reset[..reset_length]);
+        let mut serial = SimSerial { received, sent: Vec::new() };
+        run_recovery(&mut flash, &mut serial, &mut SimWatchdog::default());
+
+        let mut decoder = FrameDecoder::new();
+        let responses: Vec<Response> = serial
+            .sent
+            .iter()
+            .filter_map(|&byte| decoder.push(byte).map(|f| Response::decode(&f.unwrap()).unwrap()))
+            .collect();
+        assert_eq!(responses, [Response::Nack(NackReason::InvalidFrame), Response::Ack]);
+
+        // Nothing was erased
+        assert_eq!(flash.operation_count(), 0);
+    }
+}
@@ -0,0 +1,118 @@
+use core::sync::atomic::{fence, Ordering};
+
+use ed25519_compact::{PublicKey, Signature};
+use interface::signature::{
+    ImageSignature, MAX_SIGNED_IMAGE_LENGTH, PUBLIC_KEY_SIZE, SIGNATURE_MAGIC, SIGNATURE_OFFSET,
+};
+use interface::U32Ext;
+
+use crate::boot::Watchdog;
+use crate::flash::FlashDevice;
+
+/// Checks the signature trailer of the slot at `slot_addr` (see interface::signature).
+/// Returns the length of the signed image if the signature is valid for `public_key`.
+pub fn verify_signature<F: FlashDevice, W: Watchdog>(
+    flash: &F, watchdog: &mut W, public_key: &[u8; PUBLIC_KEY_SIZE], slot_addr: u32,
+) -> Option<u32> {
+    fence(Ordering::SeqCst);
+
+    let bytes = flash.read(slot_addr + SIGNATURE_OFFSET, core::mem::size_of::<ImageSignature>());
+    let trailer = unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const ImageSignature) };
+
+    // A corrupted length must not make us hash the trailer or beyond the slot
+    if trailer.magic != SIGNATURE_MAGIC || trailer.length > MAX_SIGNED_IMAGE_LENGTH {
+        return None;
+    }
+
+    let public_key = PublicKey::new(*public_key);
+    let mut state = public_key.verify_incremental(&Signature::new(trailer.signature)).ok()?;
+
+    // Hashing a whole slot takes a few seconds, so feed the watchdog in between
+    let page_size = flash.page_size();
+    let mut offset = 0;
+    while offset < trailer.length {
+        let chunk = core::cmp::min(page_size, trailer.length - offset);
+        state.absorb(flash.read(slot_addr + offset, chunk.to_usize()));
+        offset += chunk;
+
+        watchdog.feed();
+    }
+
+    state.verify().ok().map(|_| trailer.length)
+}
+
+/// Whether the image at `slot_addr` with `length` may be booted: either no public key is
+/// configured, or the image has a valid signature covering exactly `length` bytes.
+pub fn is_trusted<F: FlashDevice, W: Watchdog>(
+    flash: &F, watchdog: &mut W, public_key: Option<&[u8; PUBLIC_KEY_SIZE]>, slot_addr: u32,
+    length: u32,
+) -> bool {
+    match public_key {
+        Some(public_key) => {
+            verify_signature(flash, watchdog, public_key, slot_addr) == Some(length)
+        }
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use ed25519_compact::{KeyPair, Seed};
+    use interface::{SLOT_ADDRS, SLOT_SIZE};
+
+    use super::*;
+    use crate::sim::{BankMode, SimFlash, SimWatchdog};
+
+    // Writes the image and its signature trailer to slot 0
+    fn signed_flash(key_pair: &KeyPair, image: &[u8]) -> SimFlash {
+        let mut flash = SimFlash::new(BankMode::SingleBank);
+        flash.load(SLOT_ADDRS[0], image);
+        flash.load_signature(SLOT_ADDRS[0], &key_pair.sk, image.len() as u32);
+        flash
+    }
+
+    #[test]
+    fn accepts_valid_signature() {
+        let key_pair = KeyPair::from_seed(Seed::new([7; 32]));
+        let image: Vec<u8> = (0..0x4567u32).map(|i| i as u8).collect();
+        let flash = signed_flash(&key_pair, &image);
+
+        let mut watchdog = SimWatchdog::default();
+        let length = verify_signature(&flash, &mut watchdog, &key_pair.pk, SLOT_ADDRS[0]);
+        assert_eq!(length, Some(image.len() as u32));
+        assert!(watchdog.feed_count >= 2);
+
+        assert!(is_trusted(&flash, &mut watchdog, None, SLOT_ADDRS[1], 0x1234));
+        assert!(is_trusted(&flash, &mut watchdog, Some(&key_pair.pk), SLOT_ADDRS[0], 0x4567));
+        // The metadata must describe exactly the signed image
+        assert!(!is_trusted(&flash, &mut watchdog, Some(&key_pair.pk), SLOT_ADDRS[0], 0x4568));
+    }
+
+    #[test]
+    fn rejects_invalid_signature() {
+        let key_pair = KeyPair::from_seed(Seed::new([7; 32]));
+        let other_key = KeyPair::from_seed(Seed::new([8; 32]));
+        let image = [0x42u8; 0x100];
+        let mut watchdog = SimWatchdog::default();
+
+        // Signed by someone else
+        let flash = signed_flash(&other_key, &image);
+        assert_eq!(verify_signature(&flash, &mut watchdog, &key_pair.pk, SLOT_ADDRS[0]), None);
+
+        // Modified image
+        let mut flash = signed_flash(&key_pair, &image);
+        flash.load(SLOT_ADDRS[0] + 0x80, &[0x43]);
+        assert_eq!(verify_signature(&flash, &mut watchdog, &key_pair.pk, SLOT_ADDRS[0]), None);
+
+        // Not signed at all
+        let flash = SimFlash::new(BankMode::SingleBank);
+        assert_eq!(verify_signature(&flash, &mut watchdog, &key_pair.pk, SLOT_ADDRS[0]), None);
+
+        // A length that would include the trailer
+        let mut flash = signed_flash(&key_pair, &image);
+        flash.load(SLOT_ADDRS[0] + SIGNATURE_OFFSET + 4, &SLOT_SIZE.to_le_bytes());
+        assert_eq!(verify_signature(&flash, &mut watchdog, &key_pair.pk, SLOT_ADDRS[0]), None);
+    }
+}
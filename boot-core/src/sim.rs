@@ -0,0 +1,645 @@
+// Simulated hardware for running the boot logic on the host.
+// Only available in tests or with the "sim" feature, as it needs std.
+
+use std::cell::Cell;
+use std::vec;
+use std::vec::Vec;
+
+use ed25519_compact::SecretKey;
+use interface::backup::NUMBER_OF_BACKUP_REGISTERS;
+use interface::digest::{ImageDigests, DIGESTS_OFFSET};
+use interface::lz4::{CompressedImageHeader, COMPRESSED_DATA_OFFSET};
+use interface::signature::{ImageSignature, SIGNATURE_MAGIC, SIGNATURE_OFFSET};
+use interface::trailer::{ImageTrailer, IMAGE_TRAILER_OFFSET};
+use interface::{
+    ImageMetadata, Metadata, MetadataHeader, MetadataLayout, DUAL_BANK_PAGE_SIZE, FLASH_SIZE,
+    GOLDEN_METADATA_ADDR, GOLDEN_SLOT_ADDR, METADATA_1_ADDR, METADATA_2_ADDR, METADATA_OFFSET,
+    NUMBER_OF_IMAGES, SINGLE_BANK_PAGE_SIZE, SLOT_ADDRS, SLOT_SIZE,
+};
+
+use crate::backup::BackupRegisters;
+use crate::boot::Watchdog;
+use crate::flash::{EccErrors, Error, FlashDevice, UnlockToken};
+
+const ERASED_BYTE: u8 = 0xff;
+
+/// The page layout of the flash. On the real chip this is selected by the DBANK option bit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BankMode {
+    /// 256 pages with size 0x2000 bytes
+    SingleBank,
+    /// 512 pages with size 0x1000 bytes, 256 in each bank
+    DualBank,
+}
+
+impl BankMode {
+    pub fn page_size(self) -> u32 {
+        match self {
+            BankMode::SingleBank => SINGLE_BANK_PAGE_SIZE,
+            BankMode::DualBank => DUAL_BANK_PAGE_SIZE,
+        }
+    }
+}
+
+/// How a power cut affects the erase or program operation it happens during.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interruption {
+    /// Power is lost between two operations, so every operation is either done or not started
+    BetweenOperations,
+    /// Power is lost while an operation is running: an erase only erases the first half of
+    /// the page, and a double-word program only programs the lower word
+    DuringOperation,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PowerCut {
+    remaining_operations: usize,
+    interruption: Interruption,
+}
+
+enum Operation {
+    Complete,
+    Interrupted,
+    NoPower,
+}
+
+/// An in-memory flash that behaves like the STM32L4R5 flash where it matters for the boot logic:
+/// - Erasing sets a whole page to 0xff, with the page size depending on the `BankMode`
+/// - Programming requires the flash to be unlocked, double-word alignment and erased double-words
+///
+/// For fault injection, the power can be cut after a number of erase or double-word program
+/// operations. Afterwards, the flash content doesn't change anymore, just like a chip without power.
+/// Erase and program calls can also be made to fail with a flash error, see `fail_operations`.
+#[derive(Debug, Clone)]
+pub struct SimFlash {
+    memory: Vec<u8>,
+    bank_mode: BankMode,
+    locked: bool,
+    operation_count: usize,
+    power_cut: Option<PowerCut>,
+    powered: bool,
+    // The error and how many more erase or program calls fail with it
+    injected_error: Option<(Error, usize)>,
+    // Addresses that report an ECC error when they are read, and whether it is uncorrectable
+    ecc_faults: Vec<(u32, bool)>,
+    // What was reported since the last take_ecc_errors(), set by read()
+    ecc_errors: Cell<EccErrors>,
+}
+
+impl SimFlash {
+    /// Creates a completely erased flash
+    pub fn new(bank_mode: BankMode) -> Self {
+        SimFlash {
+            memory: vec![ERASED_BYTE; FLASH_SIZE as usize],
+            bank_mode,
+            locked: true,
+            operation_count: 0,
+            power_cut: None,
+            powered: true,
+            injected_error: None,
+            ecc_faults: Vec::new(),
+            ecc_errors: Cell::new(EccErrors::default()),
+        }
+    }
+
+    /// Creates a flash with the given contents, e.g. an image generated by the image-builder
+    pub fn from_bytes(bank_mode: BankMode, data: &[u8]) -> Self {
+        let mut flash = SimFlash::new(bank_mode);
+        flash.load(0, data);
+        flash
+    }
+
+    /// Creates a flash with the given images in their slots and identical metadata (version 1)
+    /// in both metadata pages, like the image-builder would.
+    pub fn with_images(bank_mode: BankMode, images: [&[u8]; NUMBER_OF_IMAGES]) -> (Self, Metadata) {
+        let mut flash = SimFlash::new(bank_mode);
+        let mut image_metadata = [ImageMetadata::default(); NUMBER_OF_IMAGES];
+
+        for (i, image) in images.iter().enumerate() {
+            assert!(image.len() <= SLOT_SIZE as usize, "Image {} does not fit into its slot", i);
+
+            flash.load(SLOT_ADDRS[i], image);
+            image_metadata[i] = metadata_for_image(image);
+        }
+
+        let mut metadata = Metadata {
+            version: 1,
+            bootcounter: 0,
+            preferred_image: 0,
+            images: image_metadata,
+            crc: 0,
+        };
+        metadata.set_crc();
+
+        flash.load_metadata(METADATA_1_ADDR, &metadata);
+        flash.load_metadata(METADATA_2_ADDR, &metadata);
+
+        (flash, metadata)
+    }
+
+    /// Writes the golden image and its metadata, like the image-builder would
+    pub fn load_golden_image(&mut self, image: &[u8]) -> ImageMetadata {
+        assert!(image.len() <= SLOT_SIZE as usize, "Golden image does not fit into its slot");
+
+        let metadata = metadata_for_image(image);
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                &metadata as *const ImageMetadata as *const u8,
+                core::mem::size_of::<ImageMetadata>(),
+            )
+        };
+        self.load(GOLDEN_METADATA_ADDR, bytes);
+        self.load(GOLDEN_SLOT_ADDR, image);
+
+        metadata
+    }
+
+    /// Signs the first `length` bytes of the slot at `slot_addr` and writes the signature trailer,
+    /// like the image-builder would
+    pub fn load_signature(&mut self, slot_addr: u32, secret_key: &SecretKey, length: u32) {
+        let start = slot_addr as usize;
+        let signature = secret_key.sign(&self.memory[start..start + length as usize], None);
+
+        let trailer = ImageSignature { magic: SIGNATURE_MAGIC, length, signature: *signature };
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                &trailer as *const ImageSignature as *const u8,
+                core::mem::size_of::<ImageSignature>(),
+            )
+        };
+        self.load(slot_addr + SIGNATURE_OFFSET, bytes);
+    }
+
+    /// Writes the trailer for `image` into the slot at `slot_addr`, like the image-builder would
+    pub fn load_trailer(
+        &mut self, slot_addr: u32, image: &[u8], image_version: u32,
+    ) -> ImageTrailer {
+        let trailer = ImageTrailer::new(image, image_version);
+        self.load_image_trailer(slot_addr, &trailer);
+
+        trailer
+    }
+
+    /// Writes `trailer` into the slot at `slot_addr`, e.g. the trailer of an XIP image
+    pub fn load_image_trailer(&mut self, slot_addr: u32, trailer: &ImageTrailer) {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                trailer as *const ImageTrailer as *const u8,
+                core::mem::size_of::<ImageTrailer>(),
+            )
+        };
+        self.load(slot_addr + IMAGE_TRAILER_OFFSET, bytes);
+    }
+
+    /// Writes `data` to `address` without any flash semantics, like a debugger would
+    pub fn load(&mut self, address: u32, data: &[u8]) {
+        let start = address as usize;
+        self.memory[start..start + data.len()].copy_from_slice(data);
+    }
+
+    /// Writes metadata to `address` without any flash semantics, like a debugger would
+    pub fn load_metadata(&mut self, address: u32, metadata: &Metadata) {
+        let header = MetadataHeader::current();
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                &header as *const MetadataHeader as *const u8,
+                core::mem::size_of::<MetadataHeader>(),
+            )
+        };
+        self.load(address, bytes);
+        self.load(address + METADATA_OFFSET, metadata_bytes(metadata));
+    }
+
+    /// Writes metadata and digests to `address` in the legacy layout, as written by
+    /// bootloaders and image-builders before there was a MetadataHeader
+    pub fn load_legacy_metadata(
+        &mut self, address: u32, metadata: &Metadata, digests: Option<&ImageDigests>,
+    ) {
+        self.load(address, metadata_bytes(metadata));
+
+        if let Some(digests) = digests {
+            let bytes = unsafe {
+                core::slice::from_raw_parts(
+                    digests as *const ImageDigests as *const u8,
+                    core::mem::size_of::<ImageDigests>(),
+                )
+            };
+            self.load(address + MetadataLayout::Legacy.digests_offset(), bytes);
+        }
+    }
+
+    /// Writes image digests to the metadata page at `address` without any flash semantics
+    pub fn load_digests(&mut self, address: u32, digests: &ImageDigests) {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                digests as *const ImageDigests as *const u8,
+                core::mem::size_of::<ImageDigests>(),
+            )
+        };
+        self.load(address + DIGESTS_OFFSET, bytes);
+    }
+
+    /// The whole flash content
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn bank_mode(&self) -> BankMode {
+        self.bank_mode
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Number of erase and double-word program operations that were completed
+    pub fn operation_count(&self) -> usize {
+        self.operation_count
+    }
+
+    /// Cuts the power after `operations` more erase or double-word program operations.
+    pub fn cut_power_after(&mut self, operations: usize, interruption: Interruption) {
+        self.power_cut = Some(PowerCut { remaining_operations: operations, interruption });
+    }
+
+    /// Whether the power was cut
+    pub fn is_powered(&self) -> bool {
+        self.powered
+    }
+
+    /// Restores the power: the flash content is kept, everything else is reset
+    pub fn power_cycle(self) -> SimFlash {
+        SimFlash {
+            locked: true,
+            operation_count: 0,
+            power_cut: None,
+            powered: true,
+            injected_error: None,
+            ecc_errors: Cell::new(EccErrors::default()),
+            ..self
+        }
+    }
+
+    /// The next `calls` erase or program calls fail with `error` and leave the flash as it is,
+    /// e.g. `Error::Busy` for a transient failure or `Error::WriteProtected` for a permanent one.
+    pub fn fail_operations(&mut self, error: Error, calls: usize) {
+        self.injected_error = Some((error, calls));
+    }
+
+    /// Every read that includes `address` reports an ECC error from now on: a double-bit error
+    /// if `uncorrectable`, otherwise a corrected single-bit error. The data stays as it is.
+    pub fn inject_ecc_error(&mut self, address: u32, uncorrectable: bool) {
+        self.ecc_faults.push((address, uncorrectable));
+    }
+
+    fn take_injected_error(&mut self) -> Result<(), Error> {
+        match &mut self.injected_error {
+            Some((error, calls)) if *calls > 0 => {
+                *calls -= 1;
+                Err(*error)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn page_count(&self) -> u32 {
+        FLASH_SIZE / self.page_size()
+    }
+
+    fn begin_operation(&mut self) -> Operation {
+        if !self.powered {
+            return Operation::NoPower;
+        }
+
+        if let Some(cut) = &mut self.power_cut {
+            if cut.remaining_operations == 0 {
+                self.powered = false;
+                return match cut.interruption {
+                    Interruption::BetweenOperations => Operation::NoPower,
+                    Interruption::DuringOperation => Operation::Interrupted,
+                };
+            }
+            cut.remaining_operations -= 1;
+        }
+
+        self.operation_count += 1;
+        Operation::Complete
+    }
+}
+
+fn metadata_bytes(metadata: &Metadata) -> &[u8] {
+    unsafe {
+        core::slice::from_raw_parts(
+            metadata as *const Metadata as *const u8,
+            core::mem::size_of::<Metadata>(),
+        )
+    }
+}
+
+/// Stores `image` like a compressed slot (see interface::lz4), but without actually compressing
+/// it: a single LZ4 sequence of literals is a valid block as well.
+pub fn lz4_literals(image: &[u8]) -> Vec<u8> {
+    let header = CompressedImageHeader::new(image);
+    let mut stored = unsafe {
+        core::slice::from_raw_parts(
+            &header as *const CompressedImageHeader as *const u8,
+            COMPRESSED_DATA_OFFSET,
+        )
+    }
+    .to_vec();
+
+    stored.push(0xf0);
+    let mut length = image.len() - 0xf;
+    while length >= 0xff {
+        stored.push(0xff);
+        length -= 0xff;
+    }
+    stored.push(length as u8);
+    stored.extend_from_slice(image);
+
+    stored
+}
+
+fn metadata_for_image(image: &[u8]) -> ImageMetadata {
+    ImageMetadata {
+        version: 1,
+        crc: interface::crc::calc_crc32(image.as_ptr(), image.len()),
+        flags: 0,
+        length: image.len() as u32,
+    }
+}
+
+impl FlashDevice for SimFlash {
+    fn page_size(&self) -> u32 {
+        self.bank_mode.page_size()
+    }
+
+    fn read(&self, address: u32, length: usize) -> &[u8] {
+        let start = address as usize;
+        let range = address..address + length as u32;
+        for &(fault, uncorrectable) in self.ecc_faults.iter().filter(|(a, _)| range.contains(a)) {
+            let mut errors = self.ecc_errors.get();
+            if !uncorrectable {
+                errors.corrected = true;
+            } else if errors.uncorrectable.is_none() {
+                errors.uncorrectable = Some(fault);
+            }
+            self.ecc_errors.set(errors);
+        }
+        &self.memory[start..start + length]
+    }
+
+    fn take_ecc_errors(&mut self) -> EccErrors {
+        self.ecc_errors.take()
+    }
+
+    fn unlock_controller(&mut self, _token: &UnlockToken) -> Result<(), Error> {
+        self.locked = false;
+        Ok(())
+    }
+
+    fn lock_controller(&mut self, _token: &UnlockToken) {
+        self.locked = true;
+    }
+
+    fn erase_page(&mut self, _token: &UnlockToken, page_number: u32) -> Result<(), Error> {
+        // After a power cut nothing is executed anymore, so the caller never sees an error
+        if !self.powered {
+            return Ok(());
+        }
+        self.take_injected_error()?;
+        if self.locked {
+            return Err(Error::WriteProtected);
+        }
+        if page_number >= self.page_count() {
+            return Err(Error::InvalidPage);
+        }
+
+        let page_size = self.page_size() as usize;
+        let start = page_number as usize * page_size;
+        let end = match self.begin_operation() {
+            Operation::Complete => start + page_size,
+            Operation::Interrupted => start + page_size / 2,
+            Operation::NoPower => return Ok(()),
+        };
+        self.memory[start..end].fill(ERASED_BYTE);
+
+        Ok(())
+    }
+
+    fn write_dwords(
+        &mut self, _token: &UnlockToken, address: u32, data: &[u64],
+    ) -> Result<(), Error> {
+        // Like the hardware, we reject writes when locked (WRPERR), unaligned writes (PGAERR)
+        // and writes to double-words that have not been erased (PROGERR)
+        if !self.powered {
+            return Ok(());
+        }
+        self.take_injected_error()?;
+        if self.locked {
+            return Err(Error::WriteProtected);
+        }
+        if !address.is_multiple_of(8) {
+            return Err(Error::Alignment);
+        }
+
+        let start = address as usize;
+        let end = start + data.len() * 8;
+        if end > self.memory.len() {
+            return Err(Error::InvalidPage);
+        }
+
+        for (i, dword) in data.iter().enumerate() {
+            if !self.powered {
+                return Ok(());
+            }
+
+            let offset = start + i * 8;
+            let target = &mut self.memory[offset..offset + 8];
+            if target.iter().any(|&b| b != ERASED_BYTE) {
+                return Err(Error::NotErased);
+            }
+
+            let length = match self.begin_operation() {
+                Operation::Complete => 8,
+                Operation::Interrupted => 4,
+                Operation::NoPower => return Ok(()),
+            };
+            self.memory[offset..offset + length].copy_from_slice(&dword.to_le_bytes()[..length]);
+        }
+
+        Ok(())
+    }
+}
+
+/// Backup registers that start out zeroed, like after a power-on reset
+#[derive(Debug, Clone, Default)]
+pub struct SimBackupRegisters {
+    registers: [u32; NUMBER_OF_BACKUP_REGISTERS],
+}
+
+impl SimBackupRegisters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl BackupRegisters for SimBackupRegisters {
+    fn read(&self, index: usize) -> u32 {
+        self.registers[index]
+    }
+
+    fn write(&mut self, index: usize, value: u32) {
+        self.registers[index] = value;
+    }
+}
+
+/// A watchdog that only counts how often it was fed
+#[derive(Debug, Clone, Default)]
+pub struct SimWatchdog {
+    pub feed_count: usize,
+    // Reported by start_timed_out()
+    pub start_timeout: bool,
+}
+
+impl Watchdog for SimWatchdog {
+    fn feed(&mut self) {
+        self.feed_count += 1;
+    }
+
+    fn start_timed_out(&self) -> bool {
+        self.start_timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlocked_until_dropped() {
+        let mut flash = SimFlash::new(BankMode::SingleBank);
+        assert!(flash.is_locked());
+
+        let mut unlocked = flash.unlock().unwrap();
+        assert!(!unlocked.is_locked());
+        assert_eq!(unlocked.write_dwords(METADATA_1_ADDR, &[1]), Ok(()));
+        assert_eq!(unlocked.read(METADATA_1_ADDR, 8), &1u64.to_le_bytes());
+        drop(unlocked);
+        assert!(flash.is_locked());
+
+        // Also after an error
+        let mut unlocked = flash.unlock().unwrap();
+        assert_eq!(unlocked.write_dwords(METADATA_1_ADDR, &[2]), Err(Error::NotErased));
+        drop(unlocked);
+        assert!(flash.is_locked());
+    }
+
+    #[test]
+    fn program_requires_erased_aligned_dwords() {
+        let mut flash = SimFlash::new(BankMode::SingleBank);
+        let mut flash = flash.unlock().unwrap();
+
+        assert_eq!(flash.write_dwords(METADATA_1_ADDR + 4, &[1]), Err(Error::Alignment));
+
+        flash.write_dwords(METADATA_1_ADDR, &[1]).unwrap();
+        assert_eq!(flash.write_dwords(METADATA_1_ADDR, &[2]), Err(Error::NotErased));
+
+        flash.erase_page(flash.address_to_page_number(METADATA_1_ADDR)).unwrap();
+        assert_eq!(flash.write_dwords(METADATA_1_ADDR, &[2]), Ok(()));
+    }
+
+    #[test]
+    fn erase_single_bank_page() {
+        let mut flash = SimFlash::from_bytes(BankMode::SingleBank, &[0u8; 0x6000]);
+        let mut flash = flash.unlock().unwrap();
+
+        assert_eq!(flash.address_to_page_number(0x2fff), 1);
+        flash.erase_page(1).unwrap();
+
+        assert!(flash.memory()[..0x2000].iter().all(|&b| b == 0));
+        assert!(flash.memory()[0x2000..0x4000].iter().all(|&b| b == ERASED_BYTE));
+        assert!(flash.memory()[0x4000..0x6000].iter().all(|&b| b == 0));
+
+        assert_eq!(flash.erase_page(255), Ok(()));
+        assert_eq!(flash.erase_page(256), Err(Error::InvalidPage));
+    }
+
+    #[test]
+    fn erase_dual_bank_page() {
+        let mut flash = SimFlash::from_bytes(BankMode::DualBank, &[0u8; 0x6000]);
+        let mut flash = flash.unlock().unwrap();
+
+        assert_eq!(flash.address_to_page_number(0x2fff), 2);
+        flash.erase_page(2).unwrap();
+
+        assert!(flash.memory()[..0x2000].iter().all(|&b| b == 0));
+        assert!(flash.memory()[0x2000..0x3000].iter().all(|&b| b == ERASED_BYTE));
+        assert!(flash.memory()[0x3000..0x6000].iter().all(|&b| b == 0));
+
+        assert_eq!(flash.erase_page(511), Ok(()));
+        assert_eq!(flash.erase_page(512), Err(Error::InvalidPage));
+    }
+
+    #[test]
+    fn power_cut_between_operations() {
+        let mut flash = SimFlash::new(BankMode::SingleBank);
+        flash.cut_power_after(2, Interruption::BetweenOperations);
+        let mut unlocked = flash.unlock().unwrap();
+
+        unlocked.write_dwords(METADATA_1_ADDR, &[1, 2, 3]).unwrap();
+
+        assert!(!unlocked.is_powered());
+        assert_eq!(unlocked.operation_count(), 2);
+        assert_eq!(unlocked.read(METADATA_1_ADDR, 8), &1u64.to_le_bytes());
+        assert_eq!(unlocked.read(METADATA_1_ADDR + 8, 8), &2u64.to_le_bytes());
+        assert_eq!(unlocked.read(METADATA_1_ADDR + 16, 8), &[ERASED_BYTE; 8]);
+
+        // Nothing happens without power
+        unlocked.erase_page(1).unwrap();
+        assert_eq!(unlocked.read(METADATA_1_ADDR, 8), &1u64.to_le_bytes());
+        drop(unlocked);
+
+        let flash = flash.power_cycle();
+        assert!(flash.is_powered());
+        assert!(flash.is_locked());
+        assert_eq!(flash.read(METADATA_1_ADDR + 8, 8), &2u64.to_le_bytes());
+    }
+
+    #[test]
+    fn power_cut_during_operation() {
+        let mut flash = SimFlash::from_bytes(BankMode::DualBank, &[0u8; 0x4000]);
+        flash.cut_power_after(1, Interruption::DuringOperation);
+        let mut unlocked = flash.unlock().unwrap();
+
+        unlocked.erase_page(2).unwrap();
+        unlocked.write_dwords(METADATA_1_ADDR, &[u64::MAX - 1]).unwrap();
+
+        // The program operation only got to the lower word
+        assert_eq!(unlocked.read(METADATA_1_ADDR, 4), &(u64::MAX - 1).to_le_bytes()[..4]);
+        assert_eq!(unlocked.read(METADATA_1_ADDR + 4, 4), &[ERASED_BYTE; 4]);
+        drop(unlocked);
+
+        let mut flash = flash.power_cycle();
+        flash.cut_power_after(0, Interruption::DuringOperation);
+        flash.unlock().unwrap().erase_page(3).unwrap();
+
+        // Only the first half of the page was erased
+        assert!(flash.read(0x3000, 0x800).iter().all(|&b| b == ERASED_BYTE));
+        assert!(flash.read(0x3800, 0x800).iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn with_images_has_valid_metadata() {
+        let (flash, metadata) =
+            SimFlash::with_images(BankMode::SingleBank, [&[1; 16], &[2; 32], &[3; 8]]);
+
+        assert!(metadata.is_valid());
+        assert_eq!(metadata.images[1].length, 32);
+        assert_eq!(flash.read(SLOT_ADDRS[1], 32), &[2; 32]);
+        assert_eq!(
+            flash.read(METADATA_1_ADDR, core::mem::size_of::<Metadata>()),
+            flash.read(METADATA_2_ADDR, core::mem::size_of::<Metadata>())
+        );
+    }
+}
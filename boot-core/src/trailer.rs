@@ -0,0 +1,148 @@
+use core::sync::atomic::{fence, Ordering};
+
+use interface::lz4::{CompressedImageHeader, COMPRESSED_DATA_OFFSET};
+use interface::trailer::{ImageTrailer, IMAGE_TRAILER_OFFSET};
+use interface::{ImageMetadata, U32Ext, IMAGE_FLAG_LZ4, NUMBER_OF_IMAGES, SLOT_ADDRS};
+
+use crate::bootcount::BootAttempts;
+use crate::flash::FlashDevice;
+use crate::metadata::verify_image;
+
+/// Reads the trailer of the slot at `slot_addr` (see interface::trailer).
+/// Returns None if the slot has no intact trailer, e.g. because it was written without one.
+pub fn read_trailer<F: FlashDevice>(flash: &F, slot_addr: u32) -> Option<ImageTrailer> {
+    fence(Ordering::SeqCst);
+
+    let bytes = flash.read(slot_addr + IMAGE_TRAILER_OFFSET, core::mem::size_of::<ImageTrailer>());
+    let trailer = unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const ImageTrailer) };
+    fence(Ordering::SeqCst);
+
+    if trailer.is_valid() {
+        Some(trailer)
+    } else {
+        None
+    }
+}
+
+/// The ImageMetadata of the image in the slot, as described by its trailer.
+/// The trailer has no flags, so a compressed image is recognized by its CompressedImageHeader.
+/// This is safe, as the header is covered by the CRC and digest of the trailer like the rest of
+/// the image, and no uncompressed image starts with it (it is not a valid stack pointer).
+pub fn trailer_image_metadata<F: FlashDevice>(
+    flash: &F, slot_addr: u32, trailer: &ImageTrailer,
+) -> Option<ImageMetadata> {
+    let mut image_meta = trailer.image_metadata(slot_addr)?;
+
+    let header = flash.read(slot_addr, COMPRESSED_DATA_OFFSET.min(trailer.length.to_usize()));
+    if !image_meta.is_xip() && CompressedImageHeader::read(header).is_some() {
+        image_meta.flags |= IMAGE_FLAG_LZ4;
+    }
+
+    Some(image_meta)
+}
+
+/// Whether the image in the slot matches the CRC and digest of its trailer.
+/// We can only boot images that are meant to be copied to the start of RAM or to run in place.
+pub fn verify_trailer_image<F: FlashDevice>(
+    flash: &F, slot_addr: u32, trailer: &ImageTrailer,
+) -> bool {
+    trailer_image_metadata(flash, slot_addr, trailer).is_some_and(|image_meta| {
+        verify_image(flash, &image_meta, slot_addr, Some(&trailer.digest))
+    })
+}
+
+/// Selects a slot by the trailers alone, for when there is no valid metadata: the image with
+/// the highest version that matches its trailer and did not use up its boot attempts.
+pub fn select_image_by_trailer<F: FlashDevice>(
+    flash: &F, attempts: &BootAttempts,
+) -> Option<(u32, ImageTrailer)> {
+    let mut candidates = [None; NUMBER_OF_IMAGES];
+    for (i, candidate) in candidates.iter_mut().enumerate() {
+        if !attempts.is_exhausted(i) {
+            *candidate = read_trailer(flash, SLOT_ADDRS[i]);
+        }
+    }
+
+    // Verifying an image is slow, so we only verify the newest one until we found a valid one
+    while let Some(index) = newest(&candidates) {
+        let trailer = candidates[index].take()?;
+        if verify_trailer_image(flash, SLOT_ADDRS[index], &trailer) {
+            return Some((index as u32, trailer));
+        }
+    }
+
+    None
+}
+
+fn newest(candidates: &[Option<ImageTrailer>; NUMBER_OF_IMAGES]) -> Option<usize> {
+    (0..NUMBER_OF_IMAGES)
+        .filter_map(|i| candidates[i].map(|trailer| (i, trailer.image_version)))
+        // max_by_key returns the last maximum, but on a tie we prefer the first slot
+        .rev()
+        .max_by_key(|&(_, version)| version)
+        .map(|(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use interface::backup::MAX_BOOT_ATTEMPTS;
+    use interface::reset::ResetCause;
+    use interface::trailer::MAX_IMAGE_LENGTH;
+
+    use super::*;
+    use crate::sim::{BankMode, SimBackupRegisters, SimFlash};
+
+    fn test_image(seed: u8, length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    #[test]
+    fn reads_only_valid_trailers() {
+        let image = test_image(1, 0x1234);
+        let mut flash = SimFlash::new(BankMode::SingleBank);
+        assert_eq!(read_trailer(&flash, SLOT_ADDRS[0]), None);
+
+        flash.load(SLOT_ADDRS[0], &image);
+        let trailer = flash.load_trailer(SLOT_ADDRS[0], &image, 3);
+        assert_eq!(read_trailer(&flash, SLOT_ADDRS[0]), Some(trailer));
+        assert!(verify_trailer_image(&flash, SLOT_ADDRS[0], &trailer));
+
+        // The trailer belongs to a different image
+        flash.load(SLOT_ADDRS[0] + 0x42, &[0]);
+        assert!(!verify_trailer_image(&flash, SLOT_ADDRS[0], &trailer));
+
+        // A corrupted trailer
+        flash.load(SLOT_ADDRS[0] + IMAGE_TRAILER_OFFSET + 8, &MAX_IMAGE_LENGTH.to_le_bytes());
+        assert_eq!(read_trailer(&flash, SLOT_ADDRS[0]), None);
+    }
+
+    #[test]
+    fn selects_newest_valid_image() {
+        let images = [test_image(1, 0x100), test_image(2, 0x200), test_image(3, 0x300)];
+        let mut flash = SimFlash::new(BankMode::DualBank);
+        for (i, version) in [(0, 2), (1, 5), (2, 5)] {
+            flash.load(SLOT_ADDRS[i], &images[i]);
+            flash.load_trailer(SLOT_ADDRS[i], &images[i], version);
+        }
+
+        let mut attempts = BootAttempts::load(&SimBackupRegisters::new(), ResetCause::Unknown);
+        let selected = select_image_by_trailer(&flash, &attempts).map(|(index, _)| index);
+        assert_eq!(selected, Some(1));
+
+        // Slot 1 is corrupted, so we take the other image with the same version
+        flash.load(SLOT_ADDRS[1], &[0xff; 4]);
+        let selected = select_image_by_trailer(&flash, &attempts).map(|(index, _)| index);
+        assert_eq!(selected, Some(2));
+
+        for _ in 0..MAX_BOOT_ATTEMPTS {
+            attempts.record_boot(&mut SimBackupRegisters::new(), 2);
+        }
+        let selected = select_image_by_trailer(&flash, &attempts).map(|(index, _)| index);
+        assert_eq!(selected, Some(0));
+
+        attempts.exhaust(0);
+        assert_eq!(select_image_by_trailer(&flash, &attempts), None);
+    }
+}
@@ -3,8 +3,11 @@ MEMORY
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
   /* TODO Adjust these memory regions to match your device memory layout */
   /* These values correspond to the LM3S6965, one of the few devices QEMU can emulate */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 8K /* For debugging bigger, for real world usage should be 4K */
-  RAM : ORIGIN = 0x20000000, LENGTH = 640K
+  FLASH : ORIGIN = 0x08000000, LENGTH = 64K /* Same as BOOTLOADER_SIZE in interface/src/lib.rs, large enough for debug builds that verify signatures */
+  /* Only the last BOOTLOADER_STACK_SIZE bytes (interface/src/lib.rs) of the 640K RAM at 0x20000000.
+     Images are copied or decompressed in front of it, so they don't overwrite our static variables,
+     e.g. the ECC error latched by the NMI handler */
+  RAM : ORIGIN = 0x20098000, LENGTH = 32K
 }
 
 /* This is where the call stack will be allocated. */
@@ -0,0 +1,37 @@
+use boot_core::backup::BackupRegisters;
+use interface::backup::NUMBER_OF_BACKUP_REGISTERS;
+use stm32l4::stm32l4r5::{PWR, RCC, RTC};
+
+/// Access to the RTC backup registers (RTC_BKPxR), see "RTC backup registers" in the reference manual.
+pub struct RtcBackupRegisters {
+    rtc: RTC,
+}
+
+impl RtcBackupRegisters {
+    /// Enables access to the backup domain and takes ownership of the RTC.
+    pub fn new(rtc: RTC, rcc: &RCC, pwr: &PWR) -> Self {
+        // The PWR and RTC register interfaces need their APB clock, otherwise
+        // reads return zero and writes are ignored
+        rcc.apb1enr1.modify(|_, w| w.pwren().set_bit().rtcapben().set_bit());
+
+        // After reset, the backup domain is write protected. Setting DBP in PWR_CR1
+        // is required before the backup registers can be written.
+        pwr.cr1.modify(|_, w| w.dbp().set_bit());
+
+        RtcBackupRegisters { rtc }
+    }
+}
+
+impl BackupRegisters for RtcBackupRegisters {
+    fn read(&self, index: usize) -> u32 {
+        debug_assert!(index < NUMBER_OF_BACKUP_REGISTERS, "Backup register index out of range");
+
+        self.rtc.bkpr[index].read().bits()
+    }
+
+    fn write(&mut self, index: usize, value: u32) {
+        debug_assert!(index < NUMBER_OF_BACKUP_REGISTERS, "Backup register index out of range");
+
+        self.rtc.bkpr[index].write(|w| unsafe { w.bits(value) });
+    }
+}
@@ -1,14 +1,11 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use boot_core::flash::{EccErrors, Error, FlashDevice, UnlockToken};
 use interface::{DUAL_BANK_PAGE_SIZE, FLASH_SIZE, SINGLE_BANK_PAGE_SIZE};
 use static_assertions::{const_assert, const_assert_eq};
 use stm32l4::stm32l4r5;
 
-#[derive(Debug)]
-pub enum Error {
-    UnlockFailed,
-    Busy,
-    Illegal,
-    InvalidPage,
-}
+use crate::timeout;
 
 // TODO: Make sure this part from the documentation is fine for us:
 // The Flash erase and programming is only
@@ -19,14 +16,58 @@ pub struct Flash {
     flash: stm32l4r5::FLASH,
 }
 
+// The address of the double-bit ECC error latched by Flash::latch_ecc_fault(), or NO_ECC_FAULT.
+// Our static variables are at the end of RAM (see memory.x), so copying an image to RAM doesn't
+// overwrite it.
+const NO_ECC_FAULT: u32 = u32::MAX;
+static ECC_FAULT: AtomicU32 = AtomicU32::new(NO_ECC_FAULT);
+
 impl Flash {
     const FLASH_KEY1: u32 = 0x4567_0123;
     const FLASH_KEY2: u32 = 0xCDEF_89AB;
 
+    // Fast programming always programs a whole row of 64 double-words
+    const ROW_DWORDS: usize = 64;
+    const ROW_SIZE: u32 = Flash::ROW_DWORDS as u32 * 8;
+
+    // How often a double-word is programmed before we give up on it
+    const MAX_PROGRAM_ATTEMPTS: u32 = 3;
+
+    // Erasing a page takes about 25 ms at most, programming a row about 5 ms (datasheet, "Flash
+    // memory characteristics"). If the flash is still busy after this, it won't finish anymore.
+    const TIMEOUT_US: u32 = 100_000;
+
     pub fn new(flash: stm32l4r5::FLASH) -> Self {
         Flash { flash }
     }
 
+    /// Called by the NMI handler, see main.rs. A double-bit ECC error keeps the NMI pending as
+    /// long as ECCD is set, so the error is latched for take_ecc_errors() and ECCD is cleared.
+    pub fn latch_ecc_fault() {
+        let eccr = unsafe { &(*stm32l4r5::FLASH::ptr()).eccr };
+        let r = eccr.read();
+        if r.eccd().bit_is_clear() {
+            return;
+        }
+
+        // The address is an offset in the bank given by BK_ECC. An error in the system memory
+        // is not in any of our addresses, but it is still cleared.
+        // Only the first error is kept, just like ADDR_ECC does until ECCD is cleared.
+        if r.sysf_ecc().bit_is_clear() {
+            let bank_offset = if r.bk_ecc().bit_is_set() { FLASH_SIZE / 2 } else { 0 };
+            let address = bank_offset + r.addr_ecc().bits();
+            let _ = ECC_FAULT.compare_exchange(
+                NO_ECC_FAULT,
+                address,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+
+        // Cleared by writing 1. A 0 keeps ECCC for take_ecc_errors(), and ECCIE is kept as it is
+        eccr.modify(|_, w| w.eccc().clear_bit().eccd().set_bit());
+    }
+
     pub fn is_dualbank(&self) -> bool {
         // Since we are on an 2MB device, we need to care about the DBANK bit (Bit 22),
         // while <= 1MB devices would have to check DB1M (Bit 21)
@@ -34,21 +75,204 @@ impl Flash {
         // Note that it does have one for DB1M named "dualbank", which is the wrong one
         // to check on a 2MB device.
         const BIT_22_BITMASK: u32 = 1 << 22;
-        static_assertions::const_assert!(
-            BIT_22_BITMASK == 0x00400000,
-        );
+        static_assertions::const_assert!(BIT_22_BITMASK == 0x00400000,);
         let dual_bank_bit = self.flash.optr.read().bits() & BIT_22_BITMASK;
 
         // Make sure we get a compile error here in case
         // this gets built for a different chip in the future
-        const_assert!(
-            FLASH_SIZE > 0x100000,
-        );
+        const_assert!(FLASH_SIZE > 0x100000,);
 
         return dual_bank_bit != 0;
     }
 
-    pub fn page_size(&self) -> u32 {
+    /// Decodes FLASH_SR into an error for the first flag that is set
+    pub fn status(&self) -> Result<(), Error> {
+        let sr = self.flash.sr.read();
+
+        let flags = [
+            (sr.bsy().bit_is_set(), Error::Busy),
+            (sr.wrperr().bit_is_set(), Error::WriteProtected),
+            (sr.pgaerr().bit_is_set(), Error::Alignment),
+            (sr.progerr().bit_is_set(), Error::NotErased),
+            (sr.sizerr().bit_is_set(), Error::Size),
+            (sr.pgserr().bit_is_set(), Error::Sequence),
+            (sr.miserr().bit_is_set(), Error::DataMiss),
+            (sr.fasterr().bit_is_set(), Error::FastProgramming),
+            (sr.rderr().bit_is_set(), Error::ReadProtected),
+            (sr.operr().bit_is_set(), Error::OperationFailed),
+        ];
+        match flags.iter().find(|(is_set, _)| *is_set) {
+            Some(&(_, error)) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    fn clear_programming_flags(&mut self) {
+        // Page 131, "Programming errors"
+        // The error flags are cleared by writing 1, writing 0 leaves them as they are
+        self.flash.sr.write(|w| {
+            w.operr()
+                .set_bit()
+                .progerr()
+                .set_bit()
+                .wrperr()
+                .set_bit()
+                .pgaerr()
+                .set_bit()
+                .sizerr()
+                .set_bit()
+                .pgserr()
+                .set_bit()
+                .miserr()
+                .set_bit()
+                .fasterr()
+                .set_bit()
+                .rderr()
+                .set_bit()
+        });
+    }
+
+    // Waits until no Flash memory operation is ongoing anymore
+    fn wait_idle(&self) -> Result<(), Error> {
+        timeout::poll_until(Flash::TIMEOUT_US, || self.flash.sr.read().bsy().bit_is_clear())
+            .map_err(|_| Error::Timeout)
+    }
+
+    pub fn wait(&mut self) -> Result<(), Error> {
+        self.wait_idle()?;
+        self.status()
+    }
+
+    // Checks that the last operation has succeeded and clears EOP again.
+    // EOP is only set if EOPIE is set, which is why we set it while programming. The interrupt
+    // itself is never enabled in the NVIC.
+    fn end_of_operation(&mut self) -> Result<(), Error> {
+        self.wait()?;
+
+        if self.flash.sr.read().eop().bit_is_clear() {
+            return Err(Error::NotProgrammed);
+        }
+        // EOP is cleared by writing 1
+        self.flash.sr.write(|w| w.eop().set_bit());
+
+        Ok(())
+    }
+
+    // Writes a double-word while PG or FSTPG is set, which starts programming it.
+    // The two words must be written one after the other, low word first.
+    unsafe fn write_dword(address: *mut u32, dword: u64) {
+        core::ptr::write_volatile(address, dword as u32);
+        core::ptr::write_volatile(address.add(1), (dword >> 32) as u32);
+    }
+
+    fn read_dword(&self, address: u32) -> u64 {
+        let address = address as *const u32;
+        unsafe {
+            let low = core::ptr::read_volatile(address);
+            let high = core::ptr::read_volatile(address.add(1));
+            (low as u64) | ((high as u64) << 32)
+        }
+    }
+
+    // "Standard programming" of a single double-word
+    fn program_dword(&mut self, address: u32, dword: u64) -> Result<(), Error> {
+        // 1. Check that no Flash main memory operation is ongoing
+        self.wait_idle()?;
+
+        // 2. Check and clear all error programming flags due to a previous programming
+        self.clear_programming_flags();
+
+        // 3. Set the PG bit in the FLASH_CR register
+        self.flash.cr.modify(|_, w| w.pg().set_bit().eopie().set_bit());
+
+        // 4. Perform the data write operation at the desired memory address
+        unsafe { Flash::write_dword(address as *mut u32, dword) };
+
+        // 5. Wait until the BSY bit is cleared in the FLASH_SR register
+        // 6. Check that EOP flag is set in the FLASH_SR register, and clear it by software
+        let result = self.end_of_operation();
+
+        // 7. Clear the PG bit in the FLASH_CR register
+        self.flash.cr.modify(|_, w| w.pg().clear_bit().eopie().clear_bit());
+
+        result
+    }
+
+    // "Fast programming" of a whole row, which is a lot faster than programming its
+    // double-words one by one. If the controller rejects it, the row is still erased, and
+    // `verify` programs it with standard programming instead.
+    fn program_row(&mut self, address: u32, row: &[u64]) -> Result<(), Error> {
+        debug_assert!(address.is_multiple_of(Flash::ROW_SIZE) && row.len() == Flash::ROW_DWORDS);
+
+        // 1. Check that no Flash main memory operation is ongoing
+        self.wait_idle()?;
+
+        // 2. Check and clear all error programming flags due to a previous programming
+        self.clear_programming_flags();
+
+        // 3. Set the FSTPG bit in the FLASH_CR register
+        self.flash.cr.modify(|_, w| w.fstpg().set_bit().eopie().set_bit());
+
+        // 4. Write the 64 double-words of the row.
+        // The controller aborts the row (MISERR, FASTERR) if the writes don't follow each other
+        // quickly enough, so no interrupt may delay them.
+        cortex_m::interrupt::free(|_| {
+            let mut address = address as *mut u32;
+            for dword in row {
+                unsafe {
+                    Flash::write_dword(address, *dword);
+                    address = address.add(2);
+                }
+            }
+        });
+
+        // 5. Wait until the BSY bit is cleared in the FLASH_SR register
+        // 6. Check that EOP flag is set in the FLASH_SR register, and clear it by software
+        let result = self.end_of_operation();
+
+        // 7. Clear the FSTPG bit in the FLASH_CR register
+        self.flash.cr.modify(|_, w| w.fstpg().clear_bit().eopie().clear_bit());
+
+        result
+    }
+
+    // Reads back the double-words that were just programmed at `address`, `result` being the
+    // result of programming them.
+    // A double-word that still reads as erased was never programmed, so we try it again with
+    // standard programming, up to MAX_PROGRAM_ATTEMPTS in total, unless the error tells us that
+    // this can't work (e.g. write protection). Anything else can't be fixed, programming a
+    // double-word that is not erased only sets PROGERR.
+    fn verify(
+        &mut self, address: u32, data: &[u64], result: Result<(), Error>,
+    ) -> Result<(), Error> {
+        for (index, &dword) in data.iter().enumerate() {
+            let address = address + index as u32 * 8;
+            let mut result = result;
+            let mut attempts = 1;
+
+            loop {
+                let stored = self.read_dword(address);
+                if stored == dword {
+                    break;
+                } else if stored == u64::MAX
+                    && attempts < Flash::MAX_PROGRAM_ATTEMPTS
+                    && result.err().is_none_or(Error::is_retryable)
+                {
+                    result = self.program_dword(address, dword);
+                    attempts += 1;
+                } else {
+                    // Report why programming failed, if the controller told us
+                    return Err(result.err().unwrap_or(Error::VerifyFailed));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FlashDevice for Flash {
+    fn page_size(&self) -> u32 {
         if self.is_dualbank() {
             DUAL_BANK_PAGE_SIZE
         } else {
@@ -56,23 +280,34 @@ impl Flash {
         }
     }
 
-    pub fn status(&self) -> Result<(), Error> {
-        let sr = self.flash.sr.read();
+    fn read(&self, address: u32, length: usize) -> &[u8] {
+        // The flash is aliased at address 0, which is where the addresses from the interface
+        // crate point to. Address 0 itself is the bootloader, which we never read this way.
+        unsafe { core::slice::from_raw_parts(address as *const u8, length) }
+    }
 
-        if sr.bsy().bit_is_set() {
-            Err(Error::Busy)
-        } else if sr.pgaerr().bit_is_set() || sr.progerr().bit_is_set() || sr.wrperr().bit_is_set()
-        {
-            Err(Error::Illegal)
-        } else {
-            Ok(())
-        }
+    // See "3.3.4 Error code correction (ECC)": a double-bit error sets ECCD and raises an NMI,
+    // whose handler already took it from FLASH_ECCR (see latch_ecc_fault()). A single-bit error
+    // only sets ECCC, which is still in FLASH_ECCR.
+    fn take_ecc_errors(&mut self) -> EccErrors {
+        let fault = ECC_FAULT.swap(NO_ECC_FAULT, Ordering::Relaxed);
+        let uncorrectable = (fault != NO_ECC_FAULT).then_some(fault);
+        let corrected = self.flash.eccr.read().eccc().bit_is_set();
+
+        // Cleared by writing 1. ECCD is left to the NMI handler, and ECCIE is kept as it is
+        self.flash.eccr.modify(|_, w| w.eccc().set_bit().eccd().clear_bit());
+
+        EccErrors { uncorrectable, corrected }
     }
 
     /// Unlock the flash according to the unlock sequence (see 3.3.5 Flash program and erase operations).
-    /// This **MUST** be called before any flash write or erase operation!
-    /// Afterwards, the user **SHOULD** lock the flash.
-    pub fn unlock_flash(&mut self) -> Result<(), Error> {
+    fn unlock_controller(&mut self, _token: &UnlockToken) -> Result<(), Error> {
+        // Writing the keys while FLASH_CR is already unlocked is not part of the sequence, and a
+        // wrong sequence locks it until the next reset
+        if self.flash.cr.read().lock().bit_is_clear() {
+            return Ok(());
+        }
+
         unsafe {
             self.flash.keyr.write(|w| w.keyr().bits(Flash::FLASH_KEY1));
             self.flash.keyr.write(|w| w.keyr().bits(Flash::FLASH_KEY2));
@@ -90,42 +325,21 @@ impl Flash {
         }
     }
 
-    /// Locks the flash after a write or erase operation, protecting it from accidental writes.
-    pub fn lock_flash(&mut self) {
+    fn lock_controller(&mut self, _token: &UnlockToken) {
         // From the documentation:
         // > The FLASH_CR register cannot be written when the BSY bit in the Flash status register
         // > (FLASH_SR) is set. Any attempt to write to it with the BSY bit set will cause the AHB bus to
         // > stall until the BSY bit is cleared
         // This is fine for us, since we would want to wait for the flash to finish anyway.
-        self.flash.cr.modify(|_, w| w.lock().clear_bit());
-    }
-
-    fn clear_programming_flags(&mut self) {
-        // Page 131, "Programming errors"
-        self.flash.sr.modify(|_, w| {
-            w
-                .progerr().clear_bit()
-                .sizerr().clear_bit()
-                .pgaerr().clear_bit()
-                .pgserr().clear_bit()
-                .wrperr().clear_bit()
-                .miserr().clear_bit()
-                .fasterr().clear_bit()
-          });
-    }
-
-    /// Returns the page number for a given address.
-    pub fn address_to_page_number(&self, address: u32) -> u32 {
-        debug_assert!(address < FLASH_SIZE, "Address out of range");
-
-        address / self.page_size()
+        // Setting LOCK locks FLASH_CR until the next unlock sequence.
+        self.flash.cr.modify(|_, w| w.lock().set_bit());
     }
 
-    pub fn erase_page(&mut self, page_number: u32) -> Result<(), Error> {
+    fn erase_page(&mut self, _token: &UnlockToken, page_number: u32) -> Result<(), Error> {
         // According to "3.3.6 Flash main memory erase sequences"
 
         // 1. Check that no Flash memory operation is ongoing by checking the BSY bit in FLASH_SR
-        self.wait()?;
+        self.wait_idle()?;
 
         // 2. Check and clear all error programming flags due to a previous programming. If not, PGSERR is set
         self.clear_programming_flags();
@@ -154,11 +368,14 @@ impl Flash {
             // We are in Dual-Bank mode, pages are 0x1000 bytes long
             self.flash.cr.modify(|_, w| unsafe {
                 // set the PER bit
-                w.per().set_bit()
-                // Select the bank (false => Bank 1, true => Bank 2)
-                .bker().bit(bank == 1)
-                // and select the page to erase (PNB)
-                .pnb().bits(page_number as u8)
+                w.per()
+                    .set_bit()
+                    // Select the bank (false => Bank 1, true => Bank 2)
+                    .bker()
+                    .bit(bank == 1)
+                    // and select the page to erase (PNB)
+                    .pnb()
+                    .bits(page_number as u8)
             });
         } else {
             // Single-Bank mode, we have 256 pages with size 0x2000 bytes
@@ -171,11 +388,14 @@ impl Flash {
             self.flash.cr.modify(|_, w| unsafe {
                 w
                     // Set the PER bit
-                    .per().set_bit()
+                    .per()
+                    .set_bit()
                     // Select the page to erase
-                    .pnb().bits(page_number as u8)
+                    .pnb()
+                    .bits(page_number as u8)
                     // The BKER bit [...] must be kept cleared
-                    .bker().clear_bit()
+                    .bker()
+                    .clear_bit()
             });
         }
 
@@ -192,49 +412,30 @@ impl Flash {
         result
     }
 
-    /// This must only be called when the following is true:
-    /// - The flash is unlocked
-    /// - The target page(s) have been erased before
-    pub fn write_dwords(&mut self, mut address: *mut usize, array: &[u64]) -> Result<(), Error> {
+    fn write_dwords(
+        &mut self, _token: &UnlockToken, address: u32, data: &[u64],
+    ) -> Result<(), Error> {
         // See reference manual, "3.3.7 Flash main memory programming sequences"
-        // We do "Standard programming"
-
-        // 1. Check that no Flash main memory operation is ongoing
-        self.wait()?;
-
-        // 2. Check and clear all error programming flags due to a previous programming
-        self.clear_programming_flags();
-
-        // 3. Set the PG bit in the FLASH_CR register
-        self.flash.cr.modify(|_, w| w.pg().set_bit());
-
-        // 4. Perform the data write operation at the desired memory address, inside main memory block or OTP area
-        for dword in array {
-            unsafe {
-                core::ptr::write_volatile(address, *dword as usize);
-                core::ptr::write_volatile(address.add(1), (*dword >> 32) as usize);
-                address = address.add(2);
-            }
-
-            // 5. Wait until the BSY bit is cleared in the FLASH_SR register
-            self.wait()?;
-
-            // 6. Check that EOP flag is set in the FLASH_SR register
-            // (meaning that the programming operation has succeed), and clear it by software.
-            // TODO: what do we do if this is not set? Try again? Give up?
-            if self.flash.sr.read().eop().bit_is_set() {
-                self.flash.sr.modify(|_, w| w.eop().clear_bit());
-            }
+        // Whole rows are programmed with "Fast programming", everything else with
+        // "Standard programming". Either way, the data is read back afterwards.
+        let mut address = address;
+        let mut data = data;
+
+        while !data.is_empty() {
+            let is_row = address.is_multiple_of(Flash::ROW_SIZE) && data.len() >= Flash::ROW_DWORDS;
+            let (chunk, rest) = data.split_at(if is_row { Flash::ROW_DWORDS } else { 1 });
+
+            let result = if is_row {
+                self.program_row(address, chunk)
+            } else {
+                self.program_dword(address, chunk[0])
+            };
+            self.verify(address, chunk, result)?;
+
+            address += chunk.len() as u32 * 8;
+            data = rest;
         }
 
-        // 7. Clear the PG bit in the FLASH_SR register if there no more programming request anymore.
-        self.flash.cr.modify(|_, w| w.pg().clear_bit());
-
         Ok(())
     }
-
-    pub fn wait(&mut self) -> Result<(), Error> {
-        while self.flash.sr.read().bsy().bit_is_set() {}
-        self.status()
-    }
 }
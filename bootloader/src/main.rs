@@ -1,14 +1,21 @@
 #![no_std]
 #![no_main]
 
-use cortex_m_rt::entry;
+use cortex_m_rt::{entry, exception};
 
+use backup::RtcBackupRegisters;
+use boot_core::boot::{boot, BootTarget};
+use boot_core::policy::GoldenOnly;
+use boot_core::recovery::run_recovery;
 use flash::Flash;
+use interface::signature::PUBLIC_KEY_SIZE;
 use interface::{
-    crc::calc_crc32, U32Ext, NUMBER_OF_IMAGES, RAM_ADDR, SLOT_ADDRS, SLOT_SIZE,
+    is_valid_stack_pointer, U32Ext, FLASH_ADDR, FLASH_SIZE, MAX_RAM_IMAGE_LENGTH, RAM_ADDR,
+    SLOT_ADDRS,
 };
+use uart::Lpuart;
+use watchdog::IndependentWatchdog;
 
-use metadata::{select_image, select_metadata};
 // pick a panicking behavior
 // use panic_halt as _; // you can put a breakpoint on `rust_begin_unwind` to catch panics
 // use panic_itm as _; // logs messages over ITM; requires ITM support
@@ -27,144 +34,127 @@ fn panic(_info: &core::panic::PanicInfo) -> ! {
     }
 }
 
-// TODO: use #[exception] to overwrite exception handlers, as otherwise:
+// TODO: use #[exception] to overwrite the other exception handlers, as otherwise:
 // "If not overridden all exception handlers default to an infinite loop." - https://docs.rs/cortex-m-rt/latest/cortex_m_rt/#features
 
-use stm32l4::stm32l4r5::{self, Peripherals, RTC}; // logs messages to the host stderr; requires a debugger
+// A double-bit ECC error in the flash raises an NMI. The read still completes, just with the
+// wrong data. The NMI stays pending as long as FLASH_ECCR.ECCD is set, so the handler latches the
+// error and clears ECCD (see Flash::latch_ecc_fault). Once boot_core checks the slot, it takes
+// the error and falls back to the next slot, instead of hanging until the watchdog resets us.
+#[exception]
+unsafe fn NonMaskableInt() {
+    Flash::latch_ecc_fault();
+}
+
+use stm32l4::stm32l4r5;
 
+mod backup;
 mod flash;
-mod metadata;
-mod pages;
+mod timeout;
+mod uart;
 mod watchdog;
 
-fn failsafe_boot() -> ! {
-    todo!("Failsafe boot not implemented yet.");
+// What to do if none of the images matches its CRC, see boot_core::policy.
+// By default, we never run an image we couldn't verify and boot the golden image instead.
+const BOOT_POLICY: GoldenOnly = GoldenOnly;
+
+// If set, only images with a valid Ed25519 signature for this key are booted (see
+// interface::signature). Generate a key pair with `image-builder keygen` and paste the printed
+// public key here, then sign the images with `image-builder write --signing-key`.
+const SIGNATURE_KEY: Option<[u8; PUBLIC_KEY_SIZE]> = None;
+
+// Neither the metadata nor the golden image are valid. There is nothing left we could boot, so
+// we wait for new images over UART (see interface::recovery). Once the host is done, we reset -
+// this also helps if we only failed to read the flash correctly, the next attempt might succeed.
+fn failsafe_boot(flash: &mut Flash, mut serial: Lpuart, watchdog: &mut IndependentWatchdog) -> ! {
+    run_recovery(flash, &mut serial, watchdog);
+
+    cortex_m::peripheral::SCB::sys_reset();
 }
 
-fn jump_to_image(core: &mut cortex_m::Peripherals) -> ! {
+// Starts the image whose vector table is at `vector_table`: the start of RAM for a copied image
+// or the slot in the flash for an XIP image (see interface::IMAGE_FLAG_XIP).
+fn jump_to_image(core: &mut cortex_m::Peripherals, vector_table: u32) -> ! {
     cortex_m::asm::dmb();
 
+    // The image's own linker script defines its stack (e.g. `_estack = 0x20050000;` in
+    // stm32l4r5-ram.ld), and the linker puts it into word 0 of the vector table.
+    // A stack pointer outside of RAM would fault right away, so we reset instead. The boot
+    // was already counted, so after MAX_BOOT_ATTEMPTS the next slot is booted.
+    let sp = unsafe { *(vector_table as *const u32) };
+    if !is_valid_stack_pointer(sp) {
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+
     unsafe {
-        //Jump to begin of ram.
-        let exec = *((RAM_ADDR + 4) as *const usize);
-        //Set vtable to begin of the flash memory.
-        core.SCB.vtor.write(RAM_ADDR as u32);
+        //Jump to the reset handler of the image.
+        let exec = *((vector_table + 4) as *const usize);
+        //Set vtable to the vector table of the image.
+        core.SCB.vtor.write(vector_table);
 
         // "Privileged software can write to the VTOR to relocate the vector table start
         // address to a different memory location, in the range 0x00000080 to 0x3FFFFF80"
+        // The slots are page aligned, which also satisfies the alignment the VTOR requires.
         static_assertions::const_assert!(RAM_ADDR < 0x3FFFFF80);
+        static_assertions::const_assert!(FLASH_ADDR + FLASH_SIZE < 0x3FFFFF80);
 
-        // The initial stack pointer is defined in the linker script (e.g. stm32l4r5-ram.ld) like this:
-        //    /* Highest address of the user mode stack */
-        //    _estack = 0x20050000;
-        // So here we just write the msp the OS image will expect, then we jump to exec
-        cortex_m::asm::bootstrap(0x20050000 as *const u32, exec as *const u32);
-    }
-}
-
-fn copy_image_to_ram(flash: &Flash, addr: u32, length: usize) -> Result<(), ()> {
-    let crc_before = calc_crc32(addr as *const u8, length);
-
-    let page_size = flash.page_size();
-
-    debug_assert!(addr % page_size == 0, "Copy start address must be page aligned");
-
-    for _ in 0..3 {
-        cortex_m::asm::dmb();
-
-        let src_start = addr;
-        let dst_start = RAM_ADDR;
-
-        // Split the copy into chunks of pages. Reset the watchdog after each page.
-        for i in 0..pages::page_span(length as u32, page_size) {
-            // TODO: Make sure this is always < 30 seconds
-            unsafe {
-                core::ptr::copy_nonoverlapping(
-                    (src_start + (i * page_size)) as *const u8,
-                    (dst_start + (i * page_size)) as *mut u8,
-                    page_size as usize,
-                );
-            }
-            cortex_m::asm::dmb();
-
-            watchdog::feed();
-        }
-
-        // Now read it back and calculate CRC again
-        if crc_before == calc_crc32(RAM_ADDR as *mut u8, length) {
-            // Image in RAM and correct, nice!
-            return Ok(());
-        }
-    }
-
-    return Err(());
-}
-
-//Check rtc backup register for index + magic value. If it is there, we return the index and clear the register.
-fn is_soft(rtc: &RTC) -> Option<u32> {
-    //Check if register contains magic value.
-    if rtc.bkpr[0].read().bits() == 0x5457 {
-        //Get index and clear register.
-        let index = rtc.bkpr[1].read().bits();
-        rtc.bkpr[0].write(|w| unsafe { w.bits(0) });
-        rtc.bkpr[1].write(|w| unsafe { w.bits(0) });
-
-        //Check if index is valid.
-        if index < NUMBER_OF_IMAGES as u32 {
-            Some(index)
-        } else {
-            None
-        }
-    } else {
-        None
+        // Set the msp the OS image expects, then jump to exec
+        cortex_m::asm::bootstrap(sp as *const u32, exec as *const u32);
     }
 }
 
 // TODO: look into:
 // - BFB2 bit in flash optr register
 fn run() -> ! {
-    //Make sure that we know where we are.
-    watchdog::setup_and_start();
-
     let mut core_peripherals = stm32l4r5::CorePeripherals::take().unwrap();
     let peripherals = stm32l4r5::Peripherals::take().unwrap();
 
-    let mut flash = Flash::new(peripherals.FLASH);
+    // Every loop that polls the hardware has a timeout, which needs the cycle counter
+    timeout::start_cycle_counter(&mut core_peripherals.DCB, &mut core_peripherals.DWT);
 
-    //First check if we are in a soft reboot. e.g. "reboot into image without setting it permanent."
-    if let Some(index) = is_soft(&peripherals.RTC) {
-        copy_image_to_ram(&flash, SLOT_ADDRS[index as usize], SLOT_SIZE as usize);
-        jump_to_image(&mut core_peripherals);
+    //Make sure that we know where we are.
+    let mut watchdog =
+        IndependentWatchdog { start_timed_out: watchdog::setup_and_start().is_err() };
 
-        // We should never reach this after jump_to_image
-        #[allow(unreachable_code)]
-        {
-            unreachable!("Jump to image failed.");
+    let mut flash = Flash::new(peripherals.FLASH);
+    let mut backup = RtcBackupRegisters::new(peripherals.RTC, &peripherals.RCC, &peripherals.PWR);
+
+    // The image is copied or decompressed to the start of RAM, unless it is executed in place.
+    // Our stack and static variables are at the end of RAM (see memory.x), which is not part of
+    // this slice.
+    let ram = unsafe {
+        core::slice::from_raw_parts_mut(RAM_ADDR as *mut u8, MAX_RAM_IMAGE_LENGTH.to_usize())
+    };
+
+    // Passed on to the OS in the BootInfo
+    let reset_flags = watchdog::take_reset_flags(&peripherals.RCC);
+
+    let target = boot(
+        &mut flash,
+        &mut backup,
+        &mut watchdog,
+        &BOOT_POLICY,
+        SIGNATURE_KEY.as_ref(),
+        reset_flags,
+        ram,
+    );
+
+    match target {
+        BootTarget::Image(_) | BootTarget::Golden => jump_to_image(&mut core_peripherals, RAM_ADDR),
+        BootTarget::InPlace(slot) => {
+            jump_to_image(&mut core_peripherals, FLASH_ADDR + SLOT_ADDRS[slot as usize])
         }
-    }
-
-    let (metadata, fix_result) = select_metadata(&mut flash);
-    if let Err(_) = fix_result {
-        // TODO: Think about what we should do
-    }
-
-    // TODO: in case fix_result is an Err(), then something went wrong while fixing up
-    // the older or corrupted metadata. Maybe we should try to fix this?
-
-    match metadata {
-        Some(metadata) => {
-            let index = select_image(&metadata);
-
-            copy_image_to_ram(
-                &flash,
-                SLOT_ADDRS[index as usize],
-                metadata.images[index as usize].length.to_usize(),
+        // The flash or the watchdog hangs, we try again after a reset
+        BootTarget::Reset => cortex_m::peripheral::SCB::sys_reset(),
+        // The recovery mode is the same, whether we have nothing to boot or the OS requested it
+        BootTarget::Unbootable | BootTarget::Recovery => {
+            let serial = Lpuart::new(
+                peripherals.LPUART1,
+                peripherals.GPIOG,
+                &peripherals.RCC,
+                &peripherals.PWR,
             );
-            jump_to_image(&mut core_peripherals);
-        }
-        None => {
-            //No valid metadata found. Try to boot failsafe image.
-            failsafe_boot();
+            failsafe_boot(&mut flash, serial, &mut watchdog)
         }
     }
 
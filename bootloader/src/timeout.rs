@@ -0,0 +1,37 @@
+use cortex_m::peripheral::{DCB, DWT};
+
+// Bounds for the loops that poll the hardware. If the hardware never reports that it is done,
+// we would otherwise spin until the watchdog resets us, without knowing what happened.
+// The time is measured with the cycle counter of the DWT, which counts core clock cycles.
+
+// After reset, the system runs from the 4 MHz MSI clock, and we never change that
+const CORE_CLOCK: u32 = 4_000_000;
+const CYCLES_PER_MICROSECOND: u32 = CORE_CLOCK / 1_000_000;
+
+/// The hardware didn't get done in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+/// Starts the cycle counter, which `poll_until` needs. Must be called before anything polls the
+/// hardware, including watchdog::setup_and_start().
+pub fn start_cycle_counter(dcb: &mut DCB, dwt: &mut DWT) {
+    // The DWT is part of the trace unit, which is off unless a debugger enabled it
+    dcb.enable_trace();
+    dwt.enable_cycle_counter();
+}
+
+/// Polls `done` until it returns true, for at most `microseconds`
+pub fn poll_until(microseconds: u32, mut done: impl FnMut() -> bool) -> Result<(), TimedOut> {
+    let start = DWT::cycle_count();
+    let cycles = microseconds * CYCLES_PER_MICROSECOND;
+
+    loop {
+        if done() {
+            return Ok(());
+        }
+        // The counter wraps around after about 18 minutes, which wrapping_sub takes care of
+        if DWT::cycle_count().wrapping_sub(start) >= cycles {
+            return Err(TimedOut);
+        }
+    }
+}
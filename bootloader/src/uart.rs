@@ -0,0 +1,70 @@
+use boot_core::recovery::Serial;
+use interface::recovery::RECOVERY_BAUD_RATE;
+use stm32l4::stm32l4r5::{GPIOG, LPUART1, PWR, RCC};
+
+use crate::timeout;
+
+// After reset, the system runs from the 4 MHz MSI clock, which also clocks LPUART1 (RCC_CCIPR)
+const LPUART_CLOCK: u32 = 4_000_000;
+
+// "LPUARTDIV = 256 * fck / baud", see "LPUART baud rate generation" in the reference manual.
+// The result must be at least 0x300.
+const LPUART_BRR: u32 = ((256 * LPUART_CLOCK as u64 + RECOVERY_BAUD_RATE as u64 / 2)
+    / RECOVERY_BAUD_RATE as u64) as u32;
+static_assertions::const_assert!(LPUART_BRR >= 0x300);
+
+// How long we wait for the transmitter to take the next byte
+const TX_TIMEOUT_US: u32 = 10_000;
+
+/// LPUART1 on PG7 (TX) and PG8 (RX), configured as 8N1 with RECOVERY_BAUD_RATE.
+/// On the Nucleo board these pins are connected to the virtual COM port of the ST-LINK.
+pub struct Lpuart {
+    lpuart: LPUART1,
+}
+
+impl Lpuart {
+    pub fn new(lpuart: LPUART1, gpiog: GPIOG, rcc: &RCC, pwr: &PWR) -> Self {
+        rcc.apb1enr2.modify(|_, w| w.lpuart1en().set_bit());
+        rcc.ahb2enr.modify(|_, w| w.gpiogen().set_bit());
+        rcc.apb1enr1.modify(|_, w| w.pwren().set_bit());
+
+        // PG2 to PG15 are supplied by VDDIO2, which is isolated until IOSV is set
+        pwr.cr2.modify(|_, w| w.iosv().set_bit());
+
+        // Both pins use alternate function 8 (LPUART1)
+        gpiog.afrl.modify(|_, w| w.afrl7().af8());
+        gpiog.afrh.modify(|_, w| w.afrh8().af8());
+        gpiog.moder.modify(|_, w| w.moder7().alternate().moder8().alternate());
+
+        lpuart.brr.write(|w| w.brr().bits(LPUART_BRR));
+        // We poll the UART and might miss bytes while erasing or programming the flash.
+        // Without this, an overrun stops the reception until the flag is cleared.
+        lpuart.cr3.write(|w| w.ovrdis().set_bit());
+        lpuart.cr1.write(|w| w.ue().set_bit().te().set_bit().re().set_bit());
+
+        Lpuart { lpuart }
+    }
+}
+
+impl Serial for Lpuart {
+    fn read_byte(&mut self) -> Option<u8> {
+        if self.lpuart.isr.read().rxne().bit_is_set() {
+            Some(self.lpuart.rdr.read().rdr().bits() as u8)
+        } else {
+            None
+        }
+    }
+
+    fn write_all(&mut self, data: &[u8]) {
+        for &byte in data {
+            // A byte takes less than 100us at RECOVERY_BAUD_RATE. If the transmitter doesn't
+            // get ready at all, we drop the rest, and the host has to repeat its command.
+            let ready =
+                timeout::poll_until(TX_TIMEOUT_US, || self.lpuart.isr.read().txe().bit_is_set());
+            if ready.is_err() {
+                return;
+            }
+            self.lpuart.tdr.write(|w| w.tdr().bits(byte as u16));
+        }
+    }
+}
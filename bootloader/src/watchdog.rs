@@ -1,4 +1,8 @@
-use stm32l4::stm32l4r5::{self, Peripherals}; // logs messages to the host stderr; requires a debugger
+use boot_core::boot::Watchdog;
+use interface::reset::RCC_CSR_RESET_FLAGS;
+use stm32l4::stm32l4r5;
+
+use crate::timeout::{self, TimedOut};
 
 // Notes:
 // This implementation is based on two PDFs:
@@ -22,10 +26,13 @@ use stm32l4::stm32l4r5::{self, Peripherals}; // logs messages to the host stderr
 /// So basically in this function the WVU, RVU and PVU bits are assumed to be
 /// zero on entry.
 ///
-/// I would prefer not to add loops that wait for them to be cleared, even if
-/// that would be a bit more correct - because if those happen to run infinitely,
-/// we will not get reset by the watchdog, which would be very bad
-pub fn setup_and_start() {
+/// The only loop waits for the new prescaler and reload value to be applied, and gives up after
+/// a timeout: if it ran infinitely, we would never be reset by the watchdog, which would be very bad.
+/// On a timeout, the watchdog may still run with the reset values, but we can't rely on it.
+/// The boot logic resets once more and reports the timeout in the BootInfo (see
+/// IndependentWatchdog::start_timed_out and boot_core::boot::boot).
+/// timeout::start_cycle_counter() must be called before.
+pub fn setup_and_start() -> Result<(), TimedOut> {
     // We want to set up the watchdog as described in "Configuring the IWDG when the window option is disabled"
     // TODO: not sure if the "Hardware watchdog" feature is enabled in the device option bits
     //   - if it is, the watchdog is already enabled at power on
@@ -56,24 +63,23 @@ pub fn setup_and_start() {
     iwdg.rlr.modify(|_, w| w.rl().bits(0xFFF));
 
     // 5. Wait for the registers to be updated (IWDG_SR = 0x0000 0000).
-    // This can take "up to five LSI/Prescale clock cycles"
-    // TODO: Calculate how long this actually is and maybe reset (or continue) if we grossly exceed a counter
-    // That way we would prevent an endless loop in case an update never happens (which would be a hardware fault?)
-    loop {
+    // This can take "up to five LSI/Prescale clock cycles", which is 5 * 256 / 29.5kHz = ~43ms
+    // with the slowest LSI. If it takes a lot longer, the update never happens (a hardware fault).
+    const UPDATE_TIMEOUT_US: u32 = 200_000;
+    timeout::poll_until(UPDATE_TIMEOUT_US, || {
         let r = iwdg.sr.read();
         // We want all updates to complete.
         // TODO: Technically we don't need WVU because we never set it.
         // Decide whether to keep its check or not
-        if r.pvu().bit_is_clear() && r.rvu().bit_is_clear() && r.wvu().bit_is_clear() {
-            // Now the whole IWDG_SR register is 0
-            break;
-        }
-    }
+        r.pvu().bit_is_clear() && r.rvu().bit_is_clear() && r.wvu().bit_is_clear()
+    })?;
 
     // Now 6. Refresh the counter value, which is equivalent to a normal watchdog feed
     // Note: as outlined in 44.3.5 Register access protection, we enabled modification in step 2.
     // Now we write a different value to KR, which locks our registers (PR, RLR, WINR) again!
     iwdg.kr.write(|w| w.key().reset());
+
+    Ok(())
 }
 
 /// Tell the watchdog that we are still alive by resetting it to the IWDG_RLR value.
@@ -86,5 +92,28 @@ pub fn feed() {
     iwdg.kr.write(|w| w.key().reset());
 }
 
-// TODO: There is a way to tell whether the last reset was triggered by the watchdog (RCC_CSR, Bit 29).
-// If we have a use for this information, we could add a helper function
+/// The independent watchdog as set up by setup_and_start(), for code that is generic over the watchdog
+pub struct IndependentWatchdog {
+    // setup_and_start() returned an error
+    pub start_timed_out: bool,
+}
+
+impl Watchdog for IndependentWatchdog {
+    fn feed(&mut self) {
+        feed();
+    }
+
+    fn start_timed_out(&self) -> bool {
+        self.start_timed_out
+    }
+}
+
+/// Returns the reset flags from RCC_CSR (see interface::reset) and clears them, so the next boot
+/// only sees the flags of the next reset.
+/// After a watchdog reset, boot() counts the last boot as failed, even if the OS confirmed it.
+pub fn take_reset_flags(rcc: &stm32l4r5::RCC) -> u32 {
+    let flags = rcc.csr.read().bits() & RCC_CSR_RESET_FLAGS;
+    rcc.csr.modify(|_, w| w.rmvf().set_bit());
+
+    flags
+}
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+use clap::Parser;
+use interface::delta::{Operation, Patch, PatchHeader};
+use interface::trailer::MAX_IMAGE_LENGTH;
+use interface::RAM_ADDR;
+
+use crate::byte_utils::{self, struct_to_bytes};
+use crate::generate::check_os_image;
+
+// Matches are looked up by blocks of this length in the old image. A copy takes 9 bytes, so
+// shorter matches are not worth it anyway
+const BLOCK_SIZE: usize = 16;
+
+// How many positions of the same block are remembered. Large erased or zeroed areas would
+// otherwise make every lookup try thousands of positions
+const MAX_CANDIDATES: usize = 8;
+
+#[derive(Parser, Debug)]
+pub struct DiffPackageArguments {
+    /// The path to the image that is already in a slot on the device, as it is stored there
+    #[arg(short, long)]
+    source_path: std::path::PathBuf,
+
+    /// The path to the new image
+    #[arg(short, long)]
+    target_path: std::path::PathBuf,
+
+    /// The path to the output file
+    #[arg(short, long, default_value = "patch.bin")]
+    output_path: std::path::PathBuf,
+}
+
+/// Create a patch that turns an image on the device into a new one (see interface::delta)
+pub fn diff_package(options: DiffPackageArguments) -> Result<(), Error> {
+    let source = byte_utils::read_file(&options.source_path)?;
+    let target = byte_utils::read_file(&options.target_path)?;
+
+    if source.len() > MAX_IMAGE_LENGTH as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Old image size is too large: {} > {}", source.len(), MAX_IMAGE_LENGTH),
+        ));
+    }
+    // The OS writes the patched image like `write_slot`, so it is copied to RAM at boot
+    check_os_image(&target, "New image", RAM_ADDR)?;
+
+    let patch = diff(&source, &target);
+
+    // Make sure the patch creates the new image, like the device will check it
+    let mut patched = vec![0u8; target.len()];
+    let applied = Patch::parse(&patch).and_then(|patch| patch.apply(&source, &mut patched));
+    if applied != Ok(target.len()) || patched != target {
+        return Err(Error::other(format!("Patch does not create the new image: {:?}", applied)));
+    }
+
+    std::fs::write(&options.output_path, &patch)?;
+    println!(
+        "Wrote patch of {} bytes for the new image of {} bytes ({:.1}%) to {}",
+        patch.len(),
+        target.len(),
+        100.0 * patch.len() as f64 / target.len().max(1) as f64,
+        options.output_path.display()
+    );
+
+    Ok(())
+}
+
+/// The patch (header and operations) that creates `target` from `source`.
+/// Each block of `target` that also appears in `source` is copied from there, with the match
+/// extended as far as possible, and everything in between is inserted.
+pub fn diff(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut index: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for offset in 0..(source.len() + 1).saturating_sub(BLOCK_SIZE) {
+        let candidates = index.entry(&source[offset..offset + BLOCK_SIZE]).or_default();
+        if candidates.len() < MAX_CANDIDATES {
+            candidates.push(offset);
+        }
+    }
+
+    let mut operations = Vec::new();
+    let mut insert_start = 0;
+    let mut pos = 0;
+    // Where the target would continue in the source after the last copy, e.g. after some bytes
+    // were replaced by new ones of the same length
+    let mut shift: isize = 0;
+
+    while pos + BLOCK_SIZE <= target.len() {
+        let expected = usize::try_from(pos as isize + shift).ok();
+        let candidates = index.get(&target[pos..pos + BLOCK_SIZE]).into_iter().flatten().copied();
+
+        let best = expected
+            .into_iter()
+            .chain(candidates)
+            .map(|offset| (offset, match_length(source, offset, &target[pos..])))
+            .max_by_key(|&(offset, length)| (length, Some(offset) == expected));
+
+        match best {
+            Some((offset, length)) if length >= BLOCK_SIZE => {
+                if insert_start < pos {
+                    operations.push(Operation::Insert(&target[insert_start..pos]));
+                }
+                operations.push(Operation::Copy { offset: offset as u32, length: length as u32 });
+
+                shift = offset as isize - pos as isize;
+                pos += length;
+                insert_start = pos;
+            }
+            _ => pos += 1,
+        }
+    }
+    if insert_start < target.len() {
+        operations.push(Operation::Insert(&target[insert_start..]));
+    }
+
+    let mut encoded = vec![0u8; operations.iter().map(|op| op.encoded_length()).sum()];
+    let mut length = 0;
+    for operation in operations {
+        length += operation.encode(&mut encoded[length..]).expect("Encoded length is too short");
+    }
+
+    let mut patch = struct_to_bytes(&PatchHeader::new(source, target, &encoded));
+    patch.extend(encoded);
+
+    patch
+}
+
+fn match_length(source: &[u8], offset: usize, target: &[u8]) -> usize {
+    let source = source.get(offset..).unwrap_or_default();
+    source.iter().zip(target).take_while(|(a, b)| a == b).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interface::delta::{COPY_SIZE, INSERT_HEADER_SIZE, PATCH_HEADER_SIZE};
+
+    // Pseudo-random bytes (xorshift), so blocks don't repeat within the image
+    fn test_image(seed: u32, length: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn apply(patch: &[u8], source: &[u8]) -> Vec<u8> {
+        let patch = Patch::parse(patch).unwrap();
+        let mut output = vec![0u8; patch.header.target_length as usize];
+        assert_eq!(patch.apply(source, &mut output), Ok(output.len()));
+        output
+    }
+
+    #[test]
+    fn identical_image_is_one_copy() {
+        let image = test_image(1, 0x8000);
+        let patch = diff(&image, &image);
+        assert_eq!(patch.len(), PATCH_HEADER_SIZE + COPY_SIZE);
+        assert_eq!(apply(&patch, &image), image);
+    }
+
+    #[test]
+    fn small_fix_gives_small_patch() {
+        let source = test_image(1, 0x10000);
+
+        // A replaced word, an inserted and a removed function and a new end
+        let mut target = source[..0x3000].to_vec();
+        target.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        target.extend_from_slice(&source[0x3004..0x8000]);
+        target.extend_from_slice(&test_image(9, 0x100));
+        target.extend_from_slice(&source[0x8000..0xc000]);
+        target.extend_from_slice(&source[0xd000..0xff00]);
+        target.extend_from_slice(&test_image(7, 0x20));
+
+        let patch = diff(&source, &target);
+        assert_eq!(apply(&patch, &source), target);
+        let expected =
+            PATCH_HEADER_SIZE + 4 * COPY_SIZE + 3 * INSERT_HEADER_SIZE + 4 + 0x100 + 0x20;
+        // A new byte may happen to match the old image as well
+        assert!(patch.len() <= expected, "{} > {}", patch.len(), expected);
+    }
+
+    #[test]
+    fn unrelated_image_is_inserted() {
+        let source = test_image(1, 0x1000);
+        let target = test_image(2, 0x1234);
+        let patch = diff(&source, &target);
+        assert_eq!(patch.len(), PATCH_HEADER_SIZE + INSERT_HEADER_SIZE + target.len());
+        assert_eq!(apply(&patch, &source), target);
+
+        // Shorter than a block, or nothing at all
+        for target in [&target[..BLOCK_SIZE - 1], &[]] {
+            assert_eq!(apply(&diff(&source, target), &source), target);
+        }
+        assert_eq!(apply(&diff(&[], &target), &[]), target);
+    }
+
+    #[test]
+    fn repeated_blocks_are_copied() {
+        // Erased flash in both images, with new data in between
+        let source = vec![0xffu8; 0x4000];
+        let mut target = vec![0xffu8; 0x5000];
+        target[0x2000..0x2010].copy_from_slice(&test_image(3, 0x10));
+
+        let patch = diff(&source, &target);
+        assert_eq!(apply(&patch, &source), target);
+        assert!(patch.len() < PATCH_HEADER_SIZE + 4 * COPY_SIZE + INSERT_HEADER_SIZE + 0x10);
+    }
+}
@@ -1,25 +1,70 @@
 use interface::crc::calc_crc32;
 use interface::{
-    ImageMetadata, Metadata, FLASH_SIZE, METADATA_1_ADDR, METADATA_2_ADDR, NUMBER_OF_IMAGES,
-    SLOT_1_ADDR, SLOT_2_ADDR, SLOT_3_ADDR, SLOT_SIZE,
+    ImageMetadata, Metadata, MetadataHeader, FLASH_SIZE, GOLDEN_METADATA_ADDR, GOLDEN_REGION_END,
+    GOLDEN_SLOT_ADDR, IMAGE_FLAG_LZ4, IMAGE_FLAG_XIP, MAX_RAM_IMAGE_LENGTH, METADATA_1_ADDR,
+    METADATA_2_ADDR, NUMBER_OF_IMAGES, RAM_ADDR, SLOT_1_ADDR, SLOT_2_ADDR, SLOT_3_ADDR,
 };
 use std::io::{Error, ErrorKind};
 
+use ed25519_compact::KeyPair;
+use interface::digest::ImageDigests;
+use interface::lz4::{self, CompressedImageHeader};
+use interface::sha256::sha256;
+use interface::signature::SIGNATURE_OFFSET;
+use interface::trailer::{ImageTrailer, IMAGE_TRAILER_OFFSET, MAX_IMAGE_LENGTH};
+
 use crate::byte_utils::{set_buf_from_to, struct_to_bytes};
+use crate::signing::signature_trailer;
 use crate::verification;
 
 pub fn calc_crc(data: &[u8]) -> u32 {
     calc_crc32(data.as_ptr(), data.len())
 }
 
+/// How an image is stored in its slot, and how the bootloader starts it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// Copied to RAM as it is
+    Ram,
+    /// Executed in place from the slot (see interface::IMAGE_FLAG_XIP)
+    Xip,
+    /// Stored LZ4 compressed and decompressed to RAM (see interface::IMAGE_FLAG_LZ4)
+    Lz4,
+}
+
+impl ImageFormat {
+    fn flags(self) -> u32 {
+        match self {
+            ImageFormat::Ram => 0,
+            ImageFormat::Xip => IMAGE_FLAG_XIP,
+            ImageFormat::Lz4 => IMAGE_FLAG_LZ4,
+        }
+    }
+
+    /// Where an image in this format is started from, if it is stored at `slot_addr`
+    pub fn load_address(self, slot_addr: u32) -> u32 {
+        ImageMetadata { flags: self.flags(), ..Default::default() }.load_address(slot_addr)
+    }
+}
+
 // Output a file with the following layout (end is exclusive):
 // These values are exemplary and are defined in the interface crate.
 // 0x0 - 0x1000: Binary blob of the bootloader
-// 0x1000 - 0x2000: Metadata 1 (padded until end)
-// 0x2000 - 0x3000: Metadata 2 (padded until end)
+// 0x1000 - 0x2000: Metadata 1 with its MetadataHeader (padded until end)
+// 0x2000 - 0x3000: Metadata 2 with its MetadataHeader (padded until end)
 // 3x image slots
+// Golden image metadata (padded until end)
+// Golden image slot (zeroed if there is no golden image)
+// Each slot ends with the trailer describing its image (see interface::trailer).
+// If a signing key is given, the trailer is followed by the signature of the image.
+// With `with_digests`, the metadata is followed by the SHA-256 digests of the images.
+// The images are stored in the given `formats`. The metadata, trailer, signature and digest of
+// a compressed image describe the compressed bytes in its slot.
+#[allow(clippy::too_many_arguments)]
 pub fn generate_buffer(
     bootloader_bin: &Vec<u8>, image_1_bin: &Vec<u8>, image_2_bin: &Vec<u8>, image_3_bin: &Vec<u8>,
+    golden_image_bin: Option<&Vec<u8>>, signing_key: Option<&KeyPair>, with_digests: bool,
+    formats: [ImageFormat; NUMBER_OF_IMAGES],
 ) -> Result<Vec<u8>, Error> {
     let mut data = vec![0u8; FLASH_SIZE as usize];
 
@@ -39,36 +84,17 @@ pub fn generate_buffer(
     let image_data: Vec<(&Vec<u8>, u32)> =
         vec![(&image_1_bin, SLOT_1_ADDR), (&image_2_bin, SLOT_2_ADDR), (&image_3_bin, SLOT_3_ADDR)];
 
-    let mut image_metadata: Vec<ImageMetadata> = vec![];
-
+    let mut stored_images = Vec::new();
     for (idx, &(image, addr)) in image_data.iter().enumerate() {
-        if image.len() > SLOT_SIZE as usize {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("Image size is too large: {} > {}", image.len(), SLOT_SIZE as usize),
-            ));
-        }
-
-        if let Err(e) = verification::is_likely_valid_binary_buf(image) {
-            return Err(Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Image {} (start={:#x}) is not a valid binary: {}", idx, addr, e),
-            ));
-        }
+        let description = format!("Image {} (start={:#x})", idx, addr);
+        stored_images.push(store_image(image, &description, addr, formats[idx])?);
+    }
 
-        if let Err(e) = verification::is_likely_valid_os_image_buf(image) {
-            return Err(Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Image {} (start={:#x}) is likely an invalid OS image: {}", idx, addr, e),
-            ));
-        }
+    let metadata = metadata_for_images(core::array::from_fn(|i| &stored_images[i][..]), formats);
 
-        image_metadata.push(ImageMetadata {
-            version: 1,
-            crc: calc_crc(image),
-            boot_counter: 0,
-            length: image.len() as u32,
-        });
+    for (idx, &(_, addr)) in image_data.iter().enumerate() {
+        let image = &stored_images[idx];
+        let load_address = metadata.images[idx].load_address(addr);
 
         set_buf_from_to(&mut data, addr, addr + image.len() as u32, image).map_err(|_| {
             Error::new(
@@ -76,30 +102,202 @@ pub fn generate_buffer(
                 format!("Failed to write image to output buffer at address {:#x}", addr),
             )
         })?;
-    }
 
-    let mut images: [ImageMetadata; NUMBER_OF_IMAGES] = Default::default();
-    images.copy_from_slice(&image_metadata[..]);
-
-    let mut metadata = Metadata { version: 1, bootcounter: 0, preferred_image: 0, images, crc: 0 };
-    metadata.set_crc();
+        write_trailer(&mut data, addr, image, load_address);
+        if let Some(key_pair) = signing_key {
+            write_signature(&mut data, key_pair, addr, image)?;
+        }
+    }
 
-    let metadata_bytes = struct_to_bytes(&metadata);
+    let mut metadata_bytes = struct_to_bytes(&MetadataHeader::current());
+    metadata_bytes.extend(struct_to_bytes(&metadata));
+    if with_digests {
+        let digests = core::array::from_fn(|i| Some(sha256(&stored_images[i])));
+        metadata_bytes.extend(struct_to_bytes(&ImageDigests::new(&metadata, digests)));
+    }
     set_buf_from_to(&mut data, METADATA_1_ADDR, METADATA_2_ADDR, &metadata_bytes)
         .map_err(|_| Error::new(ErrorKind::Other, "Failed to write metadata to output buffer"))?;
 
     set_buf_from_to(&mut data, METADATA_2_ADDR, SLOT_1_ADDR, &metadata_bytes)
         .map_err(|_| Error::new(ErrorKind::Other, "Failed to write metadata to output buffer"))?;
 
+    // The golden image has its own metadata. Without a golden image, the region stays zeroed,
+    // which the bootloader never accepts as golden image
+    if let Some(golden_image) = golden_image_bin {
+        // The bootloader always copies the golden image to RAM
+        let description = format!("Golden image (start={:#x})", GOLDEN_SLOT_ADDR);
+        check_os_image(golden_image, &description, RAM_ADDR)?;
+
+        let golden_metadata = image_metadata_for(golden_image, ImageFormat::Ram);
+        let golden_metadata_bytes = struct_to_bytes(&golden_metadata);
+        set_buf_from_to(&mut data, GOLDEN_METADATA_ADDR, GOLDEN_SLOT_ADDR, &golden_metadata_bytes)
+            .map_err(|_| {
+                Error::new(ErrorKind::Other, "Failed to write golden metadata to output buffer")
+            })?;
+
+        set_buf_from_to(&mut data, GOLDEN_SLOT_ADDR, GOLDEN_REGION_END, golden_image).map_err(
+            |_| Error::new(ErrorKind::Other, "Failed to write golden image to output buffer"),
+        )?;
+
+        write_trailer(&mut data, GOLDEN_SLOT_ADDR, golden_image, RAM_ADDR);
+        if let Some(key_pair) = signing_key {
+            write_signature(&mut data, key_pair, GOLDEN_SLOT_ADDR, golden_image)?;
+        }
+    }
+
     Ok(data)
 }
 
+// Writes the image trailer in front of the signature, after the image was written
+fn write_trailer(data: &mut [u8], slot_addr: u32, image: &[u8], load_address: u32) {
+    let trailer = struct_to_bytes(&ImageTrailer::with_load_address(image, 1, load_address));
+    let start = (slot_addr + IMAGE_TRAILER_OFFSET) as usize;
+    data[start..start + trailer.len()].copy_from_slice(&trailer);
+}
+
+// Writes the signature trailer at the end of the slot, after the image was written
+fn write_signature(
+    data: &mut [u8], key_pair: &KeyPair, slot_addr: u32, image: &[u8],
+) -> Result<(), Error> {
+    let trailer = signature_trailer(key_pair, image)?;
+    let start = (slot_addr + SIGNATURE_OFFSET) as usize;
+    data[start..start + trailer.len()].copy_from_slice(&trailer);
+
+    Ok(())
+}
+
+/// The initial metadata for the given images as they are stored in their slots, preferring the
+/// first one
+pub fn metadata_for_images(
+    images: [&[u8]; NUMBER_OF_IMAGES], formats: [ImageFormat; NUMBER_OF_IMAGES],
+) -> Metadata {
+    let mut metadata = Metadata {
+        version: 1,
+        bootcounter: 0,
+        preferred_image: 0,
+        images: core::array::from_fn(|i| image_metadata_for(images[i], formats[i])),
+        crc: 0,
+    };
+    metadata.set_crc();
+
+    metadata
+}
+
+fn image_metadata_for(image: &[u8], format: ImageFormat) -> ImageMetadata {
+    let flags = format.flags();
+    ImageMetadata { version: 1, crc: calc_crc(image), flags, length: image.len() as u32 }
+}
+
+/// The format of each slot, from the slot numbers given on the command line (1-3, like the image
+/// arguments) that are executed in place. With `compress`, all other images are compressed.
+pub fn image_formats(
+    xip_slots: &[usize], compress: bool,
+) -> Result<[ImageFormat; NUMBER_OF_IMAGES], Error> {
+    let default = if compress { ImageFormat::Lz4 } else { ImageFormat::Ram };
+    let mut formats = [default; NUMBER_OF_IMAGES];
+    for &slot in xip_slots {
+        if !(1..=NUMBER_OF_IMAGES).contains(&slot) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("There is no slot {}, expected 1 to {}", slot, NUMBER_OF_IMAGES),
+            ));
+        }
+        formats[slot - 1] = ImageFormat::Xip;
+    }
+
+    Ok(formats)
+}
+
+/// Checks the image like `check_os_image` and returns the bytes to store in the slot at
+/// `slot_addr`. A compressed image may be larger than a slot, as long as it fits into RAM.
+pub fn store_image(
+    image: &[u8], description: &str, slot_addr: u32, format: ImageFormat,
+) -> Result<Vec<u8>, Error> {
+    let load_address = format.load_address(slot_addr);
+    if format != ImageFormat::Lz4 {
+        check_os_image(image, description, load_address)?;
+        return Ok(image.to_vec());
+    }
+
+    check_length(image, MAX_RAM_IMAGE_LENGTH)?;
+    check_os_image_contents(image, description, load_address)?;
+
+    let stored = compress_image(image);
+    check_length(&stored, MAX_IMAGE_LENGTH)?;
+
+    Ok(stored)
+}
+
+/// The CompressedImageHeader followed by the LZ4 block of the image
+pub fn compress_image(image: &[u8]) -> Vec<u8> {
+    let mut stored = struct_to_bytes(&CompressedImageHeader::new(image));
+    stored.extend(lz4_flex::block::compress(image));
+
+    stored
+}
+
+/// Decompresses a stored image like the bootloader does
+pub fn decompress_image(stored: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut image = vec![0u8; MAX_RAM_IMAGE_LENGTH as usize];
+    let length = lz4::decompress_image(stored, &mut image, || {}).map_err(|e| {
+        Error::new(ErrorKind::InvalidData, format!("Failed to decompress image: {:?}", e))
+    })?;
+    image.truncate(length);
+
+    Ok(image)
+}
+
+/// Makes sure an image fits into a slot and looks like an OS image we can boot from
+/// `load_address` (see ImageMetadata::load_address)
+pub fn check_os_image(image: &[u8], description: &str, load_address: u32) -> Result<(), Error> {
+    check_length(image, MAX_IMAGE_LENGTH)?;
+    check_os_image_contents(image, description, load_address)
+}
+
+fn check_length(image: &[u8], max_length: u32) -> Result<(), Error> {
+    if image.len() > max_length as usize {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("Image size is too large: {} > {}", image.len(), max_length as usize),
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_os_image_contents(
+    image: &[u8], description: &str, load_address: u32,
+) -> Result<(), Error> {
+    if let Err(e) = verification::is_likely_valid_binary_buf(image) {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{} is not a valid binary: {}", description, e),
+        ));
+    }
+
+    if let Err(e) = verification::is_likely_valid_os_image_buf(image, load_address) {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{} is likely an invalid OS image: {}", description, e),
+        ));
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use interface::{METADATA_IMAGE_DATA_OFFSET, SLOT_SIZE};
+    use crate::byte_utils::bytes_to_struct;
+    use interface::digest::DIGESTS_OFFSET;
+    use interface::{
+        MetadataLayout, FLASH_ADDR, METADATA_IMAGE_DATA_OFFSET, METADATA_OFFSET, MIN_PAGE_SIZE,
+        SLOT_SIZE,
+    };
     use std::mem;
 
+    const NO_XIP: [ImageFormat; NUMBER_OF_IMAGES] = [ImageFormat::Ram; NUMBER_OF_IMAGES];
+
     fn generate_bootloader_binary(len: usize) -> Vec<u8> {
         let mut real_bootloader = include_bytes!("../testdata/bootloader.bin").to_vec();
         if len < real_bootloader.len() {
@@ -121,22 +319,24 @@ mod tests {
     #[test]
     fn reject_too_large_bootloader() {
         let bootloader = generate_bootloader_binary(METADATA_1_ADDR as usize + 1);
-        let image_1 = vec![2u8; SLOT_SIZE as usize];
-        let image_2 = vec![3u8; SLOT_SIZE as usize];
-        let image_3 = vec![4u8; SLOT_SIZE as usize];
+        let image_1 = vec![2u8; MAX_IMAGE_LENGTH as usize];
+        let image_2 = vec![3u8; MAX_IMAGE_LENGTH as usize];
+        let image_3 = vec![4u8; MAX_IMAGE_LENGTH as usize];
 
-        let result = generate_buffer(&bootloader, &image_1, &image_2, &image_3);
+        let result =
+            generate_buffer(&bootloader, &image_1, &image_2, &image_3, None, None, false, NO_XIP);
         assert!(result.is_err());
     }
 
     #[test]
     fn reject_too_large_image() {
         let bootloader = generate_bootloader_binary(METADATA_1_ADDR as usize - 5);
-        let image_1 = vec![2u8; SLOT_SIZE as usize + 1];
-        let image_2 = vec![3u8; SLOT_SIZE as usize];
-        let image_3 = vec![4u8; SLOT_SIZE as usize];
+        let image_1 = vec![2u8; MAX_IMAGE_LENGTH as usize + 1];
+        let image_2 = vec![3u8; MAX_IMAGE_LENGTH as usize];
+        let image_3 = vec![4u8; MAX_IMAGE_LENGTH as usize];
 
-        let result = generate_buffer(&bootloader, &image_1, &image_2, &image_3);
+        let result =
+            generate_buffer(&bootloader, &image_1, &image_2, &image_3, None, None, false, NO_XIP);
         assert!(result.is_err());
     }
 
@@ -145,11 +345,11 @@ mod tests {
         let bootloader = generate_bootloader_binary(6105);
 
         let real_binary = include_bytes!("../testdata/main_ram.bin");
-        let mut image_1 = vec![2u8; SLOT_SIZE as usize];
+        let mut image_1 = vec![2u8; MAX_IMAGE_LENGTH as usize];
         image_1[..real_binary.len()].copy_from_slice(real_binary);
-        let mut image_2 = vec![3u8; SLOT_SIZE as usize];
+        let mut image_2 = vec![3u8; MAX_IMAGE_LENGTH as usize];
         image_2[..real_binary.len()].copy_from_slice(real_binary);
-        let mut image_3 = vec![4u8; SLOT_SIZE as usize];
+        let mut image_3 = vec![4u8; MAX_IMAGE_LENGTH as usize];
         image_3[..real_binary.len()].copy_from_slice(real_binary);
 
         verify_generated_buffer(bootloader, image_1, image_2, image_3)
@@ -163,7 +363,7 @@ mod tests {
         let mut image_1 = vec![2u8; real_binary.len() + 12345];
         image_1[..real_binary.len()].copy_from_slice(real_binary);
 
-        let mut image_2 = vec![3u8; SLOT_SIZE as usize];
+        let mut image_2 = vec![3u8; MAX_IMAGE_LENGTH as usize];
         image_2[..real_binary.len()].copy_from_slice(real_binary);
 
         let mut image_3 = vec![4u8; 319581];
@@ -182,7 +382,17 @@ mod tests {
         assert!(verification::is_likely_valid_binary_buf(&real_binary).is_ok());
         assert!(verification::is_likely_valid_binary_buf(&fake_binary).is_err());
 
-        assert!(generate_buffer(&bootloader_bin, &real_binary, &real_binary, &fake_binary).is_err());
+        assert!(generate_buffer(
+            &bootloader_bin,
+            &real_binary,
+            &real_binary,
+            &fake_binary,
+            None,
+            None,
+            false,
+            NO_XIP
+        )
+        .is_err());
     }
 
     #[test]
@@ -195,7 +405,17 @@ mod tests {
         assert!(verification::is_likely_valid_binary_buf(&real_binary).is_ok());
         assert!(verification::is_likely_valid_binary_buf(&fake_binary).is_err());
 
-        assert!(generate_buffer(&bootloader_bin, &real_binary, &fake_binary, &real_binary).is_err());
+        assert!(generate_buffer(
+            &bootloader_bin,
+            &real_binary,
+            &fake_binary,
+            &real_binary,
+            None,
+            None,
+            false,
+            NO_XIP
+        )
+        .is_err());
     }
 
     #[test]
@@ -208,7 +428,305 @@ mod tests {
         assert!(verification::is_likely_valid_binary_buf(&real_binary).is_ok());
         assert!(verification::is_likely_valid_binary_buf(&fake_binary).is_err());
 
-        assert!(generate_buffer(&bootloader_bin, &real_binary, &real_binary, &fake_binary).is_err());
+        assert!(generate_buffer(
+            &bootloader_bin,
+            &real_binary,
+            &real_binary,
+            &fake_binary,
+            None,
+            None,
+            false,
+            NO_XIP
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn place_golden_image() -> Result<(), String> {
+        let bootloader = generate_bootloader_binary(6105);
+        let real_binary = include_bytes!("../testdata/main_ram.bin");
+
+        let mut image = vec![2u8; real_binary.len() + 1234];
+        image[..real_binary.len()].copy_from_slice(real_binary);
+        let mut golden_image = vec![5u8; real_binary.len() + 4321];
+        golden_image[..real_binary.len()].copy_from_slice(real_binary);
+
+        let buf = generate_buffer(
+            &bootloader,
+            &image,
+            &image,
+            &image,
+            Some(&golden_image),
+            None,
+            false,
+            NO_XIP,
+        )
+        .map_err(|e| format!("Failed to generate buffer: {}", e))?;
+
+        let metadata_end = GOLDEN_METADATA_ADDR as usize + mem::size_of::<ImageMetadata>();
+        let golden_metadata: ImageMetadata =
+            bytes_to_struct(&buf[GOLDEN_METADATA_ADDR as usize..metadata_end]);
+        assert_eq!(golden_metadata.length, golden_image.len() as u32);
+        assert_eq!(golden_metadata.crc, calc_crc(&golden_image));
+        assert!(buf[metadata_end..GOLDEN_SLOT_ADDR as usize].iter().all(|&b| b == 0));
+
+        let golden_start = GOLDEN_SLOT_ADDR as usize;
+        let golden_end = golden_start + golden_image.len();
+        assert_eq!(&buf[golden_start..golden_end], &golden_image[..]);
+        let trailer_start = (GOLDEN_SLOT_ADDR + IMAGE_TRAILER_OFFSET) as usize;
+        assert!(buf[golden_end..trailer_start].iter().all(|&b| b == 0));
+
+        let trailer: ImageTrailer = bytes_to_struct(&buf[trailer_start..]);
+        assert_eq!(trailer, ImageTrailer::new(&golden_image, 1));
+        let trailer_end = trailer_start + mem::size_of::<ImageTrailer>();
+        assert!(buf[trailer_end..GOLDEN_REGION_END as usize].iter().all(|&b| b == 0));
+
+        // The golden image doesn't change anything else
+        let without_golden =
+            generate_buffer(&bootloader, &image, &image, &image, None, None, false, NO_XIP)
+                .map_err(|e| format!("Failed to generate buffer: {}", e))?;
+        assert_eq!(
+            buf[..GOLDEN_METADATA_ADDR as usize],
+            without_golden[..GOLDEN_METADATA_ADDR as usize]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_golden_image() {
+        let bootloader = generate_bootloader_binary(METADATA_1_ADDR as usize - 5);
+        let real_binary = include_bytes!("../testdata/main_ram.bin").to_vec();
+
+        let mut too_large = vec![0u8; MAX_IMAGE_LENGTH as usize + 1];
+        too_large[..real_binary.len()].copy_from_slice(&real_binary);
+        let fake_binary = include_bytes!("../testdata/urandom.bin").to_vec();
+
+        for golden_image in [too_large, fake_binary] {
+            let result = generate_buffer(
+                &bootloader,
+                &real_binary,
+                &real_binary,
+                &real_binary,
+                Some(&golden_image),
+                None,
+                false,
+                NO_XIP,
+            );
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn sign_all_slots() -> Result<(), String> {
+        let bootloader = generate_bootloader_binary(6105);
+        let real_binary = include_bytes!("../testdata/main_ram.bin");
+        let key_pair = KeyPair::from_seed(ed25519_compact::Seed::new([1; 32]));
+
+        let mut image = vec![2u8; real_binary.len() + 1234];
+        image[..real_binary.len()].copy_from_slice(real_binary);
+        let mut golden_image = vec![5u8; real_binary.len() + 4321];
+        golden_image[..real_binary.len()].copy_from_slice(real_binary);
+
+        let buf = generate_buffer(
+            &bootloader,
+            &image,
+            &image,
+            &image,
+            Some(&golden_image),
+            Some(&key_pair),
+            false,
+            NO_XIP,
+        )
+        .map_err(|e| format!("Failed to generate buffer: {}", e))?;
+
+        for (addr, length) in [
+            (SLOT_1_ADDR, image.len()),
+            (SLOT_2_ADDR, image.len()),
+            (SLOT_3_ADDR, image.len()),
+            (GOLDEN_SLOT_ADDR, golden_image.len()),
+        ] {
+            let slot = &buf[addr as usize..(addr + SLOT_SIZE) as usize];
+            assert_eq!(crate::signing::verify_slot(slot, &key_pair.pk), Ok(length as u32));
+        }
+
+        // A signed image must leave space for the image trailer and the signature
+        let mut too_large = vec![2u8; MAX_IMAGE_LENGTH as usize + 1];
+        too_large[..real_binary.len()].copy_from_slice(real_binary);
+        assert!(generate_buffer(
+            &bootloader,
+            &too_large,
+            &image,
+            &image,
+            None,
+            Some(&key_pair),
+            false,
+            NO_XIP
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn place_digests_after_metadata() -> Result<(), String> {
+        let bootloader = generate_bootloader_binary(6105);
+        let real_binary = include_bytes!("../testdata/main_ram.bin");
+
+        let mut images = [2u8, 3, 4].map(|fill| vec![fill; real_binary.len() + 1234]);
+        for image in images.iter_mut() {
+            image[..real_binary.len()].copy_from_slice(real_binary);
+        }
+
+        let [image_1, image_2, image_3] = &images;
+        let buf = generate_buffer(&bootloader, image_1, image_2, image_3, None, None, true, NO_XIP)
+            .map_err(|e| format!("Failed to generate buffer: {}", e))?;
+        let without_digests =
+            generate_buffer(&bootloader, image_1, image_2, image_3, None, None, false, NO_XIP)
+                .map_err(|e| format!("Failed to generate buffer: {}", e))?;
+
+        for addr in [METADATA_1_ADDR, METADATA_2_ADDR] {
+            let start = addr as usize;
+            let metadata: Metadata = bytes_to_struct(&buf[start + METADATA_OFFSET as usize..]);
+            let digests: ImageDigests = bytes_to_struct(&buf[start + DIGESTS_OFFSET as usize..]);
+
+            assert!(digests.is_valid_for(&metadata));
+            for (i, image) in images.iter().enumerate() {
+                assert_eq!(digests.digest(i), Some(&sha256(image)));
+            }
+
+            // Only the digests were added
+            let digests_start = start + DIGESTS_OFFSET as usize;
+            let digests_end = digests_start + mem::size_of::<ImageDigests>();
+            assert_eq!(buf[start..digests_start], without_digests[start..digests_start]);
+            assert!(without_digests[digests_start..digests_end].iter().all(|&b| b == 0));
+            assert!(buf[digests_end..start + MIN_PAGE_SIZE as usize].iter().all(|&b| b == 0));
+        }
+        assert_eq!(buf[..METADATA_1_ADDR as usize], without_digests[..METADATA_1_ADDR as usize]);
+        assert_eq!(buf[SLOT_1_ADDR as usize..], without_digests[SLOT_1_ADDR as usize..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn place_xip_image() -> Result<(), String> {
+        let bootloader = generate_bootloader_binary(6105);
+        let ram_image = include_bytes!("../testdata/main_ram.bin").to_vec();
+
+        // main_flash.bin is linked to the start of the flash, move its entrypoint into slot 2
+        let mut xip_image = include_bytes!("../testdata/main_flash.bin").to_vec();
+        let entrypoint = u32::from_le_bytes(xip_image[4..8].try_into().unwrap());
+        let load_address = FLASH_ADDR + SLOT_2_ADDR;
+        xip_image[4..8].copy_from_slice(&(entrypoint + SLOT_2_ADDR).to_le_bytes());
+
+        let xip = image_formats(&[2], false).map_err(|e| e.to_string())?;
+        let buf = generate_buffer(
+            &bootloader,
+            &ram_image,
+            &xip_image,
+            &ram_image,
+            None,
+            None,
+            false,
+            xip,
+        )
+        .map_err(|e| format!("Failed to generate buffer: {}", e))?;
+
+        let metadata: Metadata =
+            bytes_to_struct(&buf[(METADATA_1_ADDR + METADATA_OFFSET) as usize..]);
+        assert_eq!(metadata.images.map(|image| image.is_xip()), [false, true, false]);
+        assert_eq!(metadata.images[1].load_address(SLOT_2_ADDR), load_address);
+
+        let trailer: ImageTrailer =
+            bytes_to_struct(&buf[(SLOT_2_ADDR + IMAGE_TRAILER_OFFSET) as usize..]);
+        assert_eq!(trailer.image_metadata(SLOT_2_ADDR), Some(metadata.images[1]));
+
+        // An XIP image must be linked against its own slot, and a RAM image can't run in place
+        let other_slot = [ImageFormat::Ram, ImageFormat::Ram, ImageFormat::Xip];
+        assert!(generate_buffer(
+            &bootloader,
+            &ram_image,
+            &ram_image,
+            &xip_image,
+            None,
+            None,
+            false,
+            other_slot
+        )
+        .is_err());
+        let ram_xip = [ImageFormat::Xip, ImageFormat::Xip, ImageFormat::Ram];
+        assert!(generate_buffer(
+            &bootloader,
+            &ram_image,
+            &xip_image,
+            &ram_image,
+            None,
+            None,
+            false,
+            ram_xip
+        )
+        .is_err());
+        assert!(image_formats(&[0], false).is_err());
+        assert!(image_formats(&[NUMBER_OF_IMAGES + 1], false).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn place_compressed_image() -> Result<(), String> {
+        let bootloader = generate_bootloader_binary(6105);
+        let real_binary = include_bytes!("../testdata/main_ram.bin");
+
+        // Too large for a slot, unless it is compressed
+        let mut large_image = vec![2u8; MAX_IMAGE_LENGTH as usize + 0x1000];
+        large_image[..real_binary.len()].copy_from_slice(real_binary);
+        let image = real_binary.to_vec();
+
+        let formats = image_formats(&[], true).map_err(|e| e.to_string())?;
+        assert_eq!(formats, [ImageFormat::Lz4; NUMBER_OF_IMAGES]);
+        let buf =
+            generate_buffer(&bootloader, &large_image, &image, &image, None, None, false, formats)
+                .map_err(|e| format!("Failed to generate buffer: {}", e))?;
+
+        let metadata: Metadata =
+            bytes_to_struct(&buf[(METADATA_1_ADDR + METADATA_OFFSET) as usize..]);
+        for (i, (addr, image)) in
+            [(SLOT_1_ADDR, &large_image), (SLOT_2_ADDR, &image)].into_iter().enumerate()
+        {
+            let image_meta = metadata.images[i];
+            assert!(image_meta.is_compressed());
+            assert_eq!(image_meta.load_address(addr), RAM_ADDR);
+
+            // The metadata and trailer describe the stored bytes, which decompress to the image
+            let stored = &buf[addr as usize..(addr + image_meta.length) as usize];
+            assert_eq!(image_meta.crc, calc_crc(stored));
+            let trailer: ImageTrailer =
+                bytes_to_struct(&buf[(addr + IMAGE_TRAILER_OFFSET) as usize..]);
+            assert_eq!(trailer, ImageTrailer::new(stored, 1));
+            assert_eq!(decompress_image(stored).map_err(|e| e.to_string())?, *image);
+        }
+
+        // XIP images are never compressed
+        let formats = image_formats(&[3], true).map_err(|e| e.to_string())?;
+        assert_eq!(formats, [ImageFormat::Lz4, ImageFormat::Lz4, ImageFormat::Xip]);
+
+        // The decompressed image must still fit into RAM
+        let mut too_large = vec![2u8; MAX_RAM_IMAGE_LENGTH as usize + 1];
+        too_large[..real_binary.len()].copy_from_slice(real_binary);
+        let formats = [ImageFormat::Lz4; NUMBER_OF_IMAGES];
+        assert!(generate_buffer(
+            &bootloader,
+            &too_large,
+            &image,
+            &image,
+            None,
+            None,
+            false,
+            formats
+        )
+        .is_err());
+
+        Ok(())
     }
 
     // This function tests the generated buffer against the expected layout
@@ -219,14 +737,15 @@ mod tests {
         let image_info: Vec<(&Vec<u8>, u32)> =
             vec![(&image_1, SLOT_1_ADDR), (&image_2, SLOT_2_ADDR), (&image_3, SLOT_3_ADDR)];
 
-        // Assert each image is <= SLOT_SIZE bytes
+        // Assert each image is <= MAX_IMAGE_LENGTH bytes
         for (image_data, _) in image_info.iter() {
-            if image_data.len() > SLOT_SIZE as usize {
+            if image_data.len() > MAX_IMAGE_LENGTH as usize {
                 return Err(format!("Image size is too large: {}", image_data.len()));
             }
         }
 
-        let buf = generate_buffer(&bootloader, &image_1, &image_2, &image_3);
+        let buf =
+            generate_buffer(&bootloader, &image_1, &image_2, &image_3, None, None, false, NO_XIP);
         let generated_buffer = buf.map_err(|e| format!("Failed to generate buffer: {}", e))?;
 
         if generated_buffer.len() != FLASH_SIZE as usize {
@@ -244,7 +763,13 @@ mod tests {
             }
         }
 
-        for &metadata_addr in &[METADATA_1_ADDR, METADATA_2_ADDR] {
+        for &page_addr in &[METADATA_1_ADDR, METADATA_2_ADDR] {
+            let header: MetadataHeader = bytes_to_struct(&generated_buffer[page_addr as usize..]);
+            if MetadataLayout::of(&header) != Some(MetadataLayout::Current) {
+                return Err("Metadata page does not use the current layout".to_string());
+            }
+
+            let metadata_addr = page_addr + METADATA_OFFSET;
             let first_bytes = [
                 1, 0, 0, 0, // version
                 0, 0, 0, 0, // bootcounter
@@ -268,7 +793,7 @@ mod tests {
                 let image_metadata = ImageMetadata {
                     version: 1,
                     crc: calc_crc(image_data),
-                    boot_counter: 0,
+                    flags: 0,
                     length: image_data.len() as u32,
                 };
 
@@ -310,11 +835,23 @@ mod tests {
                 }
             }
 
-            for j in image_data.len()..SLOT_SIZE as usize {
+            for j in image_data.len()..IMAGE_TRAILER_OFFSET as usize {
                 if generated_buffer[(image_addr + j as u32) as usize] != 0u8 {
                     return Err("Unexpected non-zero byte in image slot area".to_string());
                 }
             }
+
+            let trailer_start = (image_addr + IMAGE_TRAILER_OFFSET) as usize;
+            let trailer: ImageTrailer = bytes_to_struct(&generated_buffer[trailer_start..]);
+            if trailer != ImageTrailer::new(image_data, 1) {
+                return Err("Image trailer mismatch in generated buffer".to_string());
+            }
+
+            let trailer_end = trailer_start + mem::size_of::<ImageTrailer>();
+            let slot_end = (image_addr + SLOT_SIZE) as usize;
+            if generated_buffer[trailer_end..slot_end].iter().any(|&b| b != 0u8) {
+                return Err("Unexpected non-zero byte after the image trailer".to_string());
+            }
         }
 
         for i in SLOT_3_ADDR + SLOT_SIZE..FLASH_SIZE {
@@ -1,6 +1,9 @@
 mod byte_utils;
+mod diff;
 mod generate;
 mod read;
+mod signing;
+mod upload;
 mod verification;
 mod write;
 
@@ -18,6 +21,18 @@ enum Arguments {
     /// Read an image
     #[clap(name = "read")]
     Read(read::ReadArguments),
+
+    /// Upload images to a bootloader in recovery mode
+    #[clap(name = "upload")]
+    Upload(upload::UploadArguments),
+
+    /// Create a patch for a delta update of an image on the device
+    #[clap(name = "diff-package")]
+    DiffPackage(diff::DiffPackageArguments),
+
+    /// Generate a key pair for signing images
+    #[clap(name = "keygen")]
+    Keygen(signing::KeygenArguments),
 }
 
 fn main() -> Result<(), Error> {
@@ -26,6 +41,9 @@ fn main() -> Result<(), Error> {
     match options {
         Arguments::Write(write_options) => write::write(write_options)?,
         Arguments::Read(read_options) => read::read(read_options)?,
+        Arguments::Upload(upload_options) => upload::upload(upload_options)?,
+        Arguments::DiffPackage(diff_options) => diff::diff_package(diff_options)?,
+        Arguments::Keygen(keygen_options) => signing::keygen(keygen_options)?,
     }
 
     Ok(())
@@ -1,13 +1,17 @@
 use std::io::Error;
 
-use crate::generate::calc_crc;
-use crate::{byte_utils, verification};
+use crate::generate::{calc_crc, decompress_image};
+use crate::{byte_utils, signing, verification};
 use clap::Parser;
 
 use crate::byte_utils::bytes_to_struct;
+use interface::digest::{ImageDigests, DIGESTS_MAGIC};
+use interface::sha256::sha256;
+use interface::trailer::{ImageTrailer, IMAGE_TRAILER_MAGIC, IMAGE_TRAILER_OFFSET};
 use interface::{
-    Metadata, FLASH_SIZE, METADATA_1_ADDR, METADATA_2_ADDR, NUMBER_OF_IMAGES, SLOT_1_ADDR,
-    SLOT_2_ADDR, SLOT_3_ADDR,
+    ImageMetadata, Metadata, MetadataHeader, MetadataLayout, FLASH_SIZE, GOLDEN_METADATA_ADDR,
+    GOLDEN_SLOT_ADDR, METADATA_1_ADDR, METADATA_2_ADDR, NUMBER_OF_IMAGES, RAM_ADDR, SLOT_1_ADDR,
+    SLOT_2_ADDR, SLOT_3_ADDR, SLOT_SIZE,
 };
 
 #[derive(Parser, Debug)]
@@ -15,6 +19,11 @@ pub struct ReadArguments {
     /// The path to the generated image file
     #[arg(short, long, default_value = "output_image.bin")]
     image_file: std::path::PathBuf,
+
+    /// The path to a public key created with `keygen`. If given, the signatures of all images
+    /// are checked like the bootloader does
+    #[arg(short = 'k', long)]
+    public_key: Option<std::path::PathBuf>,
 }
 
 /// Read an image with the given options
@@ -30,29 +39,10 @@ pub fn read(options: ReadArguments) -> Result<(), Error> {
     }
 
     let mut errors: Vec<String> = Vec::new();
-    let metadata_1: Metadata = bytes_to_struct::<Metadata>(
-        &bootloader_bin
-            [METADATA_1_ADDR as usize..METADATA_1_ADDR as usize + std::mem::size_of::<Metadata>()],
-    );
-    let expected_crc = metadata_1.calc_crc();
-    if metadata_1.crc != expected_crc {
-        errors.push(format!(
-            "Metadata 1 CRC is invalid: image specified {:#x}, but calculated value is {:#x}",
-            metadata_1.crc, expected_crc
-        ));
-    }
-
-    let metadata_2: Metadata = bytes_to_struct::<Metadata>(
-        &bootloader_bin
-            [METADATA_2_ADDR as usize..METADATA_2_ADDR as usize + std::mem::size_of::<Metadata>()],
-    );
-    if metadata_2.crc != metadata_2.calc_crc() {
-        errors.push(format!(
-            "Metadata 2 CRC is invalid: image specified {:#x}, but calculated value is {:#x}",
-            metadata_2.crc,
-            metadata_2.calc_crc()
-        ));
-    }
+    let (metadata_1, layout_1) =
+        read_metadata_page(&bootloader_bin, METADATA_1_ADDR, 1, &mut errors);
+    let (metadata_2, layout_2) =
+        read_metadata_page(&bootloader_bin, METADATA_2_ADDR, 2, &mut errors);
 
     let slot_starts = [SLOT_1_ADDR, SLOT_2_ADDR, SLOT_3_ADDR];
 
@@ -85,10 +75,32 @@ pub fn read(options: ReadArguments) -> Result<(), Error> {
                 has_error = true;
             }
 
+            if !img_metadata.has_valid_flags() {
+                errors.push(format!(
+                    "Metadata {}: Image {} has invalid flags {:#x}",
+                    metadata_idx + 1,
+                    i,
+                    img_metadata.flags
+                ));
+                continue;
+            }
+
+            // A compressed image is checked like the bootloader starts it, after decompressing it
+            let vec: Vec<u8> = if img_metadata.is_compressed() {
+                match decompress_image(&bootloader_bin[start..end]) {
+                    Ok(image) => image,
+                    Err(e) => {
+                        errors.push(format!("Metadata {}: Image {}: {}", metadata_idx + 1, i, e));
+                        continue;
+                    }
+                }
+            } else {
+                bootloader_bin[start..end].to_vec()
+            };
+
             // Make sure we have the kind of instructions we expect and
             // not too many undefined instructions - a high ratio could indicate a
             // corrupted file
-            let vec: Vec<u8> = bootloader_bin[start..end].to_vec();
             if let Err(e) = verification::is_likely_valid_binary_buf(&vec) {
                 errors.push(format!(
                     "Metadata {}: Image {} is not a valid binary: {}",
@@ -99,7 +111,8 @@ pub fn read(options: ReadArguments) -> Result<(), Error> {
                 has_error = true;
             }
 
-            if let Err(e) = verification::is_likely_valid_os_image_buf(&vec) {
+            let load_address = img_metadata.load_address(slot_starts[i]);
+            if let Err(e) = verification::is_likely_valid_os_image_buf(&vec, load_address) {
                 errors.push(format!(
                     "Metadata {}: Image {} is not a valid OS image: {}",
                     metadata_idx + 1,
@@ -119,6 +132,150 @@ pub fn read(options: ReadArguments) -> Result<(), Error> {
         }
     }
 
+    // The digests are optional, but if they are there, they must match the images
+    let pages = [(metadata_1, METADATA_1_ADDR, layout_1), (metadata_2, METADATA_2_ADDR, layout_2)];
+    for (metadata_idx, &(metadata, addr, layout)) in pages.iter().enumerate() {
+        let start = (addr + layout.digests_offset()) as usize;
+        let digests: ImageDigests = bytes_to_struct(&bootloader_bin[start..]);
+
+        if digests.magic != DIGESTS_MAGIC {
+            println!("Metadata {}: No image digests", metadata_idx + 1);
+            continue;
+        }
+        if !digests.is_valid_for(&metadata) {
+            errors.push(format!(
+                "Metadata {}: Image digests are corrupted or belong to different metadata",
+                metadata_idx + 1
+            ));
+            continue;
+        }
+
+        for (i, &slot_start) in slot_starts.iter().enumerate() {
+            let Some(digest) = digests.digest(i) else {
+                continue;
+            };
+
+            // Out of bounds lengths were already reported above
+            let start = slot_start as usize;
+            let Some(image) = bootloader_bin.get(start..start + metadata.images[i].length as usize)
+            else {
+                continue;
+            };
+
+            if sha256(image) == *digest {
+                println!("Metadata {}: Image {} matches its SHA-256 digest", metadata_idx + 1, i);
+            } else {
+                errors.push(format!(
+                    "Metadata {}: Image {} does not match its SHA-256 digest",
+                    metadata_idx + 1,
+                    i
+                ));
+            }
+        }
+    }
+
+    // The golden image is optional. Without one, its metadata is zeroed
+    let golden_metadata: ImageMetadata = bytes_to_struct::<ImageMetadata>(
+        &bootloader_bin[GOLDEN_METADATA_ADDR as usize
+            ..GOLDEN_METADATA_ADDR as usize + std::mem::size_of::<ImageMetadata>()],
+    );
+    if golden_metadata.length == 0 {
+        println!("No golden image");
+    } else if golden_metadata.length > SLOT_SIZE {
+        errors.push(format!(
+            "Golden image length is out of bounds: image length: {:#x}, but slot size is {:#x}",
+            golden_metadata.length, SLOT_SIZE
+        ));
+    } else {
+        let start = GOLDEN_SLOT_ADDR as usize;
+        let golden_image = &bootloader_bin[start..start + golden_metadata.length as usize];
+        let mut has_error = false;
+
+        let crc = calc_crc(golden_image);
+        if crc != golden_metadata.crc {
+            errors.push(format!(
+                "Golden image CRC is invalid: metadata specified {:#x}, but calculated value is {:#x}",
+                golden_metadata.crc, crc
+            ));
+            has_error = true;
+        }
+
+        let vec: Vec<u8> = golden_image.to_vec();
+        if let Err(e) = verification::is_likely_valid_binary_buf(&vec) {
+            errors.push(format!("Golden image is not a valid binary: {}", e));
+            has_error = true;
+        }
+
+        // The bootloader never executes the golden image in place or decompresses it
+        if golden_metadata.flags != 0 {
+            errors.push(format!(
+                "Golden image has flags {:#x}, but it is always copied to RAM as it is",
+                golden_metadata.flags
+            ));
+            has_error = true;
+        } else if let Err(e) = verification::is_likely_valid_os_image_buf(&vec, RAM_ADDR) {
+            errors.push(format!("Golden image is not a valid OS image: {}", e));
+            has_error = true;
+        }
+
+        if !has_error {
+            println!("Golden image looks like a valid binary and matches its CRC");
+        }
+    }
+
+    // The image trailers identify the image in each slot, even if the metadata is lost
+    let trailer_slots = (0..NUMBER_OF_IMAGES)
+        .map(|i| (format!("Image {}", i), slot_starts[i]))
+        .chain([("Golden image".to_string(), GOLDEN_SLOT_ADDR)]);
+    for (name, start) in trailer_slots {
+        let trailer: ImageTrailer =
+            bytes_to_struct(&bootloader_bin[(start + IMAGE_TRAILER_OFFSET) as usize..]);
+
+        if trailer.magic != IMAGE_TRAILER_MAGIC {
+            println!("{} has no image trailer", name);
+            continue;
+        }
+        if !trailer.is_valid() {
+            errors.push(format!("{} trailer is corrupted", name));
+            continue;
+        }
+
+        let image = &bootloader_bin[start as usize..(start + trailer.length) as usize];
+        if calc_crc(image) != trailer.crc || sha256(image) != trailer.digest {
+            errors.push(format!("{} does not match its trailer", name));
+        } else {
+            println!(
+                "{} trailer: version {}, {:#x} bytes, loaded to {:#x}",
+                name, trailer.image_version, trailer.length, trailer.load_address
+            );
+        }
+    }
+
+    if let Some(public_key_path) = &options.public_key {
+        let public_key = signing::read_public_key(public_key_path)?;
+
+        let mut slots: Vec<(String, u32, u32)> = (0..NUMBER_OF_IMAGES)
+            .map(|i| (format!("Image {}", i), slot_starts[i], metadata_1.images[i].length))
+            .collect();
+        if golden_metadata.length != 0 {
+            slots.push(("Golden image".to_string(), GOLDEN_SLOT_ADDR, golden_metadata.length));
+        }
+
+        for (name, start, length) in slots {
+            let slot = &bootloader_bin[start as usize..(start + SLOT_SIZE) as usize];
+            match signing::verify_slot(slot, &public_key) {
+                Ok(signed_length) if signed_length == length => {
+                    println!("{} has a valid signature", name)
+                }
+                Ok(signed_length) => errors.push(format!(
+                    "{} signature covers {:#x} bytes, but the image length is {:#x}",
+                    name, signed_length, length
+                )),
+                Err(e) => errors.push(format!("{} signature is not valid: {}", name, e)),
+            }
+        }
+    }
+
     // Check if metadata is the same
     if metadata_1 != metadata_2 {
         errors.push(format!(
@@ -140,3 +297,38 @@ pub fn read(options: ReadArguments) -> Result<(), Error> {
     println!("All CRCs match the data they are pointing to.");
     Ok(())
 }
+
+// Decodes the metadata page at `addr` in either layout (see MetadataLayout) and checks its CRC.
+// A page with an unknown layout revision is decoded like the current layout.
+fn read_metadata_page(
+    bootloader_bin: &[u8], addr: u32, number: usize, errors: &mut Vec<String>,
+) -> (Metadata, MetadataLayout) {
+    let header: MetadataHeader = bytes_to_struct(&bootloader_bin[addr as usize..]);
+    let layout = match MetadataLayout::of(&header) {
+        Some(MetadataLayout::Legacy) => {
+            println!("Metadata {}: Legacy layout, the bootloader will upgrade it", number);
+            MetadataLayout::Legacy
+        }
+        Some(MetadataLayout::Current) => MetadataLayout::Current,
+        None => {
+            errors.push(format!(
+                "Metadata {}: Unknown layout revision {}",
+                number, header.layout_revision
+            ));
+            MetadataLayout::Current
+        }
+    };
+
+    let start = (addr + layout.metadata_offset()) as usize;
+    let metadata: Metadata =
+        bytes_to_struct(&bootloader_bin[start..start + std::mem::size_of::<Metadata>()]);
+    let expected_crc = metadata.calc_crc();
+    if metadata.crc != expected_crc {
+        errors.push(format!(
+            "Metadata {} CRC is invalid: image specified {:#x}, but calculated value is {:#x}",
+            number, metadata.crc, expected_crc
+        ));
+    }
+
+    (metadata, layout)
+}
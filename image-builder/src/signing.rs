@@ -0,0 +1,141 @@
+use std::io::{Error, ErrorKind};
+
+use clap::Parser;
+use ed25519_compact::{KeyPair, PublicKey, Seed, Signature};
+use interface::signature::{
+    ImageSignature, MAX_SIGNED_IMAGE_LENGTH, PUBLIC_KEY_SIZE, SIGNATURE_MAGIC, SIGNATURE_OFFSET,
+};
+use interface::SLOT_SIZE;
+
+use crate::byte_utils::{self, bytes_to_struct, struct_to_bytes};
+
+#[derive(Parser, Debug)]
+pub struct KeygenArguments {
+    /// Where to store the secret key. The public key is stored next to it with a .pub extension
+    #[arg(short, long, default_value = "signing_key")]
+    output_path: std::path::PathBuf,
+}
+
+/// Generate a new key pair for signing images
+pub fn keygen(options: KeygenArguments) -> Result<(), Error> {
+    if options.output_path.exists() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} already exists, refusing to overwrite it", options.output_path.display()),
+        ));
+    }
+
+    let key_pair = KeyPair::from_seed(Seed::generate());
+    let public_key_path = options.output_path.with_extension("pub");
+
+    std::fs::write(&options.output_path, key_pair.sk.seed().as_ref())?;
+    std::fs::write(&public_key_path, key_pair.pk.as_ref())?;
+
+    println!("Wrote secret key to {}", options.output_path.display());
+    println!("Wrote public key to {}", public_key_path.display());
+    println!("Use this as SIGNATURE_KEY in bootloader/src/main.rs:");
+    let bytes: Vec<String> = key_pair.pk.iter().map(|byte| format!("{:#04x}", byte)).collect();
+    println!("Some([{}])", bytes.join(", "));
+
+    Ok(())
+}
+
+/// Reads a secret key as written by `keygen` (the 32 byte seed)
+pub fn read_signing_key(path: &std::path::PathBuf) -> Result<KeyPair, Error> {
+    let seed = byte_utils::read_file(path)?;
+    let seed = Seed::from_slice(&seed).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is not a signing key, expected {} bytes", path.display(), Seed::BYTES),
+        )
+    })?;
+
+    Ok(KeyPair::from_seed(seed))
+}
+
+/// Reads a public key as written by `keygen`
+pub fn read_public_key(path: &std::path::PathBuf) -> Result<[u8; PUBLIC_KEY_SIZE], Error> {
+    byte_utils::read_file(path)?.try_into().map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is not a public key, expected {} bytes", path.display(), PUBLIC_KEY_SIZE),
+        )
+    })
+}
+
+/// The signature trailer for the image, to be written at SIGNATURE_OFFSET of its slot
+pub fn signature_trailer(key_pair: &KeyPair, image: &[u8]) -> Result<Vec<u8>, Error> {
+    if image.len() > MAX_SIGNED_IMAGE_LENGTH as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Image size is too large to be signed: {} > {}",
+                image.len(),
+                MAX_SIGNED_IMAGE_LENGTH
+            ),
+        ));
+    }
+
+    let trailer = ImageSignature {
+        magic: SIGNATURE_MAGIC,
+        length: image.len() as u32,
+        signature: *key_pair.sk.sign(image, None),
+    };
+
+    Ok(struct_to_bytes(&trailer))
+}
+
+/// Checks the signature trailer of a slot like the bootloader does.
+/// Returns the length of the signed image.
+pub fn verify_slot(slot: &[u8], public_key: &[u8; PUBLIC_KEY_SIZE]) -> Result<u32, String> {
+    assert_eq!(slot.len(), SLOT_SIZE as usize);
+
+    let trailer: ImageSignature = bytes_to_struct(&slot[SIGNATURE_OFFSET as usize..]);
+    if trailer.magic != SIGNATURE_MAGIC {
+        return Err("it is not signed".to_string());
+    }
+    if trailer.length > MAX_SIGNED_IMAGE_LENGTH {
+        return Err(format!("the signed length {:#x} is out of bounds", trailer.length));
+    }
+
+    PublicKey::new(*public_key)
+        .verify(&slot[..trailer.length as usize], &Signature::new(trailer.signature))
+        .map_err(|e| format!("the signature is invalid: {}", e))?;
+
+    Ok(trailer.length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify_slot() {
+        let key_pair = KeyPair::from_seed(Seed::new([3; 32]));
+        let image: Vec<u8> = (0..0x1234u32).map(|i| i as u8).collect();
+
+        let mut slot = vec![0u8; SLOT_SIZE as usize];
+        slot[..image.len()].copy_from_slice(&image);
+        assert!(verify_slot(&slot, &key_pair.pk).is_err());
+
+        let trailer = signature_trailer(&key_pair, &image).unwrap();
+        slot[SIGNATURE_OFFSET as usize..].copy_from_slice(&trailer);
+        assert_eq!(verify_slot(&slot, &key_pair.pk), Ok(image.len() as u32));
+
+        let other_key = KeyPair::from_seed(Seed::new([4; 32]));
+        assert!(verify_slot(&slot, &other_key.pk).is_err());
+
+        slot[0x100] ^= 1;
+        assert!(verify_slot(&slot, &key_pair.pk).is_err());
+    }
+
+    #[test]
+    fn reject_too_large_signed_image() {
+        let key_pair = KeyPair::from_seed(Seed::new([3; 32]));
+
+        assert!(signature_trailer(&key_pair, &vec![0u8; MAX_SIGNED_IMAGE_LENGTH as usize]).is_ok());
+        assert!(
+            signature_trailer(&key_pair, &vec![0u8; MAX_SIGNED_IMAGE_LENGTH as usize + 1]).is_err()
+        );
+    }
+}
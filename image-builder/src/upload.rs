@@ -0,0 +1,311 @@
+use std::io::{Error, ErrorKind, Read, Write};
+use std::time::Duration;
+
+use clap::Parser;
+use ed25519_compact::KeyPair;
+use interface::recovery::{
+    Command, FrameDecoder, NackReason, Response, MAX_CHUNK_SIZE, MAX_FRAME_SIZE, RECOVERY_BAUD_RATE,
+};
+use interface::signature::SIGNATURE_OFFSET;
+use interface::trailer::{ImageTrailer, IMAGE_TRAILER_OFFSET};
+use interface::{Metadata, NUMBER_OF_IMAGES, SLOT_ADDRS};
+
+use crate::generate::{calc_crc, image_formats, metadata_for_images, store_image};
+use crate::{byte_utils, signing};
+
+// Erasing a whole slot takes the longest, about 61 * 40ms in the worst case
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often a command is sent again if the bootloader received a broken frame
+const MAX_ATTEMPTS: usize = 3;
+
+#[derive(Parser, Debug)]
+pub struct UploadArguments {
+    /// The serial device connected to the bootloader's recovery UART, e.g. /dev/ttyACM0
+    #[arg(short, long)]
+    port: String,
+
+    /// The path to the first image binary.
+    /// This is usually a compiled RODOS image
+    #[arg(short = '1', long)]
+    image_1_path: std::path::PathBuf,
+
+    /// The path to the second image binary (if not given, use the first)
+    #[arg(short = '2', long)]
+    image_2_path: Option<std::path::PathBuf>,
+
+    /// The path to the third image binary (if not given, use the first)
+    #[arg(short = '3', long)]
+    image_3_path: Option<std::path::PathBuf>,
+
+    /// The path to a secret key created with `keygen`. If given, all images are signed
+    #[arg(short = 'k', long)]
+    signing_key: Option<std::path::PathBuf>,
+
+    /// The slots (1-3) whose images are executed in place, see `write`
+    #[arg(short = 'x', long, value_delimiter = ',')]
+    xip: Vec<usize>,
+
+    /// Store the images LZ4 compressed, see `write`
+    #[arg(short, long)]
+    compress: bool,
+}
+
+/// Upload images to a bootloader in recovery mode
+pub fn upload(options: UploadArguments) -> Result<(), Error> {
+    let image_1_bin = byte_utils::read_file(&options.image_1_path)?;
+    let image_2_bin = match options.image_2_path {
+        Some(path) => byte_utils::read_file(&path)?,
+        None => image_1_bin.clone(),
+    };
+    let image_3_bin = match options.image_3_path {
+        Some(path) => byte_utils::read_file(&path)?,
+        None => image_1_bin.clone(),
+    };
+
+    let formats = image_formats(&options.xip, options.compress)?;
+    let mut stored_images = Vec::new();
+    for (idx, image) in [image_1_bin, image_2_bin, image_3_bin].iter().enumerate() {
+        let description = format!("Image {}", idx);
+        stored_images.push(store_image(image, &description, SLOT_ADDRS[idx], formats[idx])?);
+    }
+    let images = [&stored_images[0][..], &stored_images[1][..], &stored_images[2][..]];
+    let metadata = metadata_for_images(images, formats);
+
+    let signing_key = match options.signing_key {
+        Some(path) => Some(signing::read_signing_key(&path)?),
+        None => None,
+    };
+
+    let port =
+        serialport::new(&options.port, RECOVERY_BAUD_RATE).timeout(RESPONSE_TIMEOUT).open()?;
+
+    RecoveryClient::new(port).upload(images, &metadata, signing_key.as_ref())?;
+
+    println!("Successfully uploaded all images, the bootloader is resetting");
+
+    Ok(())
+}
+
+/// Drives the recovery protocol (see interface::recovery) over a serial connection
+pub struct RecoveryClient<P: Read + Write> {
+    port: P,
+}
+
+impl<P: Read + Write> RecoveryClient<P> {
+    pub fn new(port: P) -> Self {
+        RecoveryClient { port }
+    }
+
+    /// Writes and verifies all images as they are stored in their slots, commits the metadata
+    /// and resets the bootloader.
+    /// Each slot gets its image trailer, and with a signing key, the signature trailer as well.
+    pub fn upload(
+        &mut self, images: [&[u8]; NUMBER_OF_IMAGES], metadata: &Metadata,
+        signing_key: Option<&KeyPair>,
+    ) -> Result<(), Error> {
+        self.send(&Command::Ping)?;
+
+        for (slot, image) in images.iter().enumerate() {
+            let slot = slot as u32;
+
+            println!("Erasing slot {}", slot);
+            self.send(&Command::EraseSlot { slot })?;
+
+            println!("Writing {} bytes to slot {}", image.len(), slot);
+            for (idx, chunk) in image.chunks(MAX_CHUNK_SIZE).enumerate() {
+                // Only whole double-words can be programmed
+                let mut data = chunk.to_vec();
+                data.resize(chunk.len().next_multiple_of(8), 0xff);
+
+                let offset = (idx * MAX_CHUNK_SIZE) as u32;
+                self.send(&Command::WriteChunk { slot, offset, data: &data })?;
+            }
+
+            let image_meta = &metadata.images[slot as usize];
+            let load_address = image_meta.load_address(SLOT_ADDRS[slot as usize]);
+            let trailer = ImageTrailer::with_load_address(image, image_meta.version, load_address);
+            let trailer = byte_utils::struct_to_bytes(&trailer);
+            let offset = IMAGE_TRAILER_OFFSET;
+            self.send(&Command::WriteChunk { slot, offset, data: &trailer })?;
+
+            if let Some(key_pair) = signing_key {
+                let trailer = signing::signature_trailer(key_pair, image)?;
+                let offset = SIGNATURE_OFFSET;
+                self.send(&Command::WriteChunk { slot, offset, data: &trailer })?;
+            }
+
+            let length = image.len() as u32;
+            self.send(&Command::Verify { slot, length, crc: calc_crc(image) })?;
+        }
+
+        println!("Committing metadata");
+        self.send(&Command::CommitMetadata { metadata: *metadata })?;
+
+        self.send(&Command::Reset)
+    }
+
+    // Sends the command and waits for an Ack
+    fn send(&mut self, command: &Command<'_>) -> Result<(), Error> {
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+        let length = command
+            .encode(&mut frame)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{:?}", e)))?;
+
+        for _ in 0..MAX_ATTEMPTS {
+            self.port.write_all(&frame[..length])?;
+            self.port.flush()?;
+
+            match self.receive()? {
+                Response::Ack => return Ok(()),
+                Response::Nack(NackReason::InvalidFrame) => continue,
+                Response::Nack(reason) => {
+                    return Err(Error::other(format!(
+                        "Bootloader rejected {}: {:?}",
+                        command_name(command),
+                        reason
+                    )))
+                }
+            }
+        }
+
+        Err(Error::other(format!(
+            "Bootloader did not receive {} after {} attempts",
+            command_name(command),
+            MAX_ATTEMPTS
+        )))
+    }
+
+    fn receive(&mut self) -> Result<Response, Error> {
+        let mut decoder = FrameDecoder::new();
+        let mut byte = [0u8];
+
+        loop {
+            self.port.read_exact(&mut byte)?;
+
+            if let Some(frame) = decoder.push(byte[0]) {
+                // We can't tell whether a command was executed if its response is broken,
+                // so it's not safe to send it again
+                return frame.and_then(|frame| Response::decode(&frame)).map_err(|e| {
+                    Error::new(ErrorKind::InvalidData, format!("Invalid response: {:?}", e))
+                });
+            }
+        }
+    }
+}
+
+fn command_name(command: &Command<'_>) -> &'static str {
+    match command {
+        Command::Ping => "Ping",
+        Command::EraseSlot { .. } => "EraseSlot",
+        Command::WriteChunk { .. } => "WriteChunk",
+        Command::Verify { .. } => "Verify",
+        Command::CommitMetadata { .. } => "CommitMetadata",
+        Command::Reset => "Reset",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::{compress_image, ImageFormat};
+    use boot_core::boot::{boot, BootTarget};
+    use boot_core::policy::GoldenOnly;
+    use boot_core::recovery::{run_recovery, Serial};
+    use boot_core::sim::{BankMode, SimBackupRegisters, SimFlash, SimWatchdog};
+    use boot_core::trailer::read_trailer;
+    use interface::{SLOT_ADDRS, SLOT_SIZE};
+    use serialport::{SerialPort, TTYPort};
+    use std::thread;
+
+    // The bootloader side of the pseudo-terminal
+    struct PtySerial(TTYPort);
+
+    impl Serial for PtySerial {
+        fn read_byte(&mut self) -> Option<u8> {
+            let mut byte = [0u8];
+            match self.0.read(&mut byte) {
+                Ok(1) => Some(byte[0]),
+                _ => None,
+            }
+        }
+
+        fn write_all(&mut self, data: &[u8]) {
+            self.0.write_all(data).unwrap();
+        }
+    }
+
+    fn test_image(seed: u8, length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    #[test]
+    fn upload_over_pty() {
+        let (mut host, mut device) = TTYPort::pair().expect("Failed to open pseudo-terminal");
+        host.set_timeout(RESPONSE_TIMEOUT).unwrap();
+        device.set_timeout(Duration::from_millis(10)).unwrap();
+
+        // A bootloader without anything to boot. The serial port is returned, as closing it
+        // early would break the pipe before the host got the last response.
+        let bootloader = thread::spawn(move || {
+            let mut flash = SimFlash::new(BankMode::DualBank);
+            let mut serial = PtySerial(device);
+            run_recovery(&mut flash, &mut serial, &mut SimWatchdog::default());
+            (flash, serial)
+        });
+
+        // The first image is compressed, so the bootloader decompresses it at boot
+        let compressed = test_image(1, 0x2345);
+        let images = [compress_image(&compressed), test_image(2, 0x801), test_image(3, 0x10)];
+        let images = [&images[0][..], &images[1][..], &images[2][..]];
+        let formats = [ImageFormat::Lz4, ImageFormat::Ram, ImageFormat::Ram];
+        let metadata = metadata_for_images(images, formats);
+        let key_pair = KeyPair::from_seed(ed25519_compact::Seed::new([5; 32]));
+        RecoveryClient::new(host)
+            .upload(images, &metadata, Some(&key_pair))
+            .expect("Upload failed");
+
+        let (mut flash, _) = bootloader.join().unwrap();
+        let mut ram = vec![0u8; SLOT_SIZE as usize];
+        let target = boot(
+            &mut flash,
+            &mut SimBackupRegisters::new(),
+            &mut SimWatchdog::default(),
+            &GoldenOnly,
+            Some(&key_pair.pk),
+            0,
+            &mut ram,
+        );
+        assert_eq!(target, BootTarget::Image(0));
+        assert_eq!(&ram[..compressed.len()], &compressed[..]);
+
+        for (i, image) in images.iter().enumerate() {
+            let trailer = read_trailer(&flash, SLOT_ADDRS[i]).expect("No image trailer");
+            assert_eq!(trailer, ImageTrailer::new(image, metadata.images[i].version));
+        }
+    }
+
+    #[test]
+    fn reports_rejected_command() {
+        let (mut host, mut device) = TTYPort::pair().expect("Failed to open pseudo-terminal");
+        host.set_timeout(RESPONSE_TIMEOUT).unwrap();
+        device.set_timeout(Duration::from_millis(10)).unwrap();
+
+        let bootloader = thread::spawn(move || {
+            let mut serial = PtySerial(device);
+            run_recovery(
+                &mut SimFlash::new(BankMode::SingleBank),
+                &mut serial,
+                &mut SimWatchdog::default(),
+            );
+            serial
+        });
+
+        let mut client = RecoveryClient::new(host);
+        let error = client.send(&Command::EraseSlot { slot: NUMBER_OF_IMAGES as u32 }).unwrap_err();
+        assert!(error.to_string().contains("InvalidArgument"), "{}", error);
+
+        client.send(&Command::Reset).unwrap();
+        bootloader.join().unwrap();
+    }
+}
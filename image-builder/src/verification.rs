@@ -7,6 +7,8 @@ pub enum BinaryFileError {
     ManyUndefinedInstructions { total_instructions: usize, undefined_lines: usize, ratio: f64 },
     NotEnoughUniqueInstructions { unique_instructions: usize },
     UnexpectedInterruptVectorTable { entrypoint_address: u32 },
+    EntrypointOutsideSlot { entrypoint_address: u32, load_address: u32 },
+    InvalidStackPointer { stack_pointer: u32 },
 }
 
 impl std::error::Error for BinaryFileError {}
@@ -39,6 +41,21 @@ impl std::fmt::Display for BinaryFileError {
                 "Error: Unexpected interrupt vector table, entrypoint address is at 0x{:08x}, but expected >= 0x{:08x} (RAM start) and within RAM of size 0x{:08x}",
                 entrypoint_address, RAM_ADDR, RAM_SIZE
             ),
+            BinaryFileError::EntrypointOutsideSlot {
+                entrypoint_address,
+                load_address
+            } => write!(
+                f,
+                "Error: Unexpected interrupt vector table, entrypoint address is at 0x{:08x}, but the image is executed in place and must stay within its slot (0x{:08x} - 0x{:08x})",
+                entrypoint_address, load_address, load_address + MAX_IMAGE_LENGTH
+            ),
+            BinaryFileError::InvalidStackPointer {
+                stack_pointer
+            } => write!(
+                f,
+                "Error: Initial stack pointer 0x{:08x} is not within RAM (0x{:08x} - 0x{:08x}) or not aligned to {} bytes",
+                stack_pointer, RAM_ADDR, RAM_ADDR + RAM_SIZE, STACK_POINTER_ALIGNMENT
+            ),
         }
     }
 }
@@ -84,7 +101,8 @@ use std::collections::HashMap;
 use std::io::Write;
 use std::process::{Command, Stdio};
 
-use interface::{RAM_ADDR, RAM_SIZE};
+use interface::trailer::MAX_IMAGE_LENGTH;
+use interface::{is_valid_stack_pointer, RAM_ADDR, RAM_SIZE, STACK_POINTER_ALIGNMENT};
 use once_cell::sync::Lazy;
 use regex::Regex;
 
@@ -170,14 +188,28 @@ fn get_thumb_instruction_stats(buffer: &[u8]) -> Result<InstructionStats, std::i
     Ok(stats)
 }
 
-/// Checks if the vtable of an OS image points towards a valid address.
+/// Checks if the vtable of an OS image started from `load_address` (see
+/// ImageMetadata::load_address) points towards a valid address.
 /// This function should be used **IN ADDITION TO THE OTHER BINARY CHECK** for OS images
-pub fn is_likely_valid_os_image_buf(bytes: &[u8]) -> Result<(), BinaryFileError> {
+pub fn is_likely_valid_os_image_buf(
+    bytes: &[u8], load_address: u32,
+) -> Result<(), BinaryFileError> {
     // The bootloader reads an address from the vtable and jumps to it,
     // so let's make sure that address is within RAM
     let entrypoint_address = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
-    if !(RAM_ADDR..RAM_ADDR + RAM_SIZE).contains(&entrypoint_address) {
-        return Err(BinaryFileError::UnexpectedInterruptVectorTable { entrypoint_address });
+    if load_address == RAM_ADDR {
+        if !(RAM_ADDR..RAM_ADDR + RAM_SIZE).contains(&entrypoint_address) {
+            return Err(BinaryFileError::UnexpectedInterruptVectorTable { entrypoint_address });
+        }
+    } else if !(load_address..load_address + MAX_IMAGE_LENGTH).contains(&entrypoint_address) {
+        // An XIP image runs from its slot, so it must be linked against exactly that slot
+        return Err(BinaryFileError::EntrypointOutsideSlot { entrypoint_address, load_address });
+    }
+
+    // The bootloader doesn't start an image with a stack pointer it can't use
+    let stack_pointer = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    if !is_valid_stack_pointer(stack_pointer) {
+        return Err(BinaryFileError::InvalidStackPointer { stack_pointer });
     }
 
     Ok(())
@@ -186,6 +218,7 @@ pub fn is_likely_valid_os_image_buf(bytes: &[u8]) -> Result<(), BinaryFileError>
 #[cfg(test)]
 mod tests {
     use super::*;
+    use interface::{FLASH_ADDR, SLOT_ADDRS};
 
     #[test]
     fn detect_invalid_binary() {
@@ -225,7 +258,7 @@ mod tests {
     fn wrong_address_in_flash() {
         let binary_data = include_bytes!("../testdata/main_flash.bin");
 
-        let result = is_likely_valid_os_image_buf(binary_data);
+        let result = is_likely_valid_os_image_buf(binary_data, RAM_ADDR);
 
         assert!(result.is_err());
 
@@ -244,7 +277,7 @@ mod tests {
         let nbytes = ENTRY_ADDR.to_le_bytes();
         binary_data[4..(4 + 4)].copy_from_slice(&nbytes[..4]);
 
-        let result = is_likely_valid_os_image_buf(&binary_data.to_vec());
+        let result = is_likely_valid_os_image_buf(&binary_data.to_vec(), RAM_ADDR);
 
         assert!(result.is_err());
 
@@ -258,11 +291,61 @@ mod tests {
         }
     }
 
+    #[test]
+    fn invalid_stack_pointer() {
+        let binary_data = include_bytes!("../testdata/main_ram.bin");
+
+        for stack_pointer in [RAM_ADDR, RAM_ADDR + RAM_SIZE + 8, 0x0800_0000, 0x2004_fffc] {
+            let mut binary_data = binary_data.to_vec();
+            binary_data[0..4].copy_from_slice(&stack_pointer.to_le_bytes());
+
+            match is_likely_valid_os_image_buf(&binary_data, RAM_ADDR) {
+                Err(BinaryFileError::InvalidStackPointer { stack_pointer: sp }) => {
+                    assert_eq!(sp, stack_pointer);
+                }
+                result => panic!("Unexpected result {:?}", result),
+            }
+        }
+
+        // The stack may start at the very end of RAM
+        let mut binary_data = binary_data.to_vec();
+        binary_data[0..4].copy_from_slice(&(RAM_ADDR + RAM_SIZE).to_le_bytes());
+        assert!(is_likely_valid_os_image_buf(&binary_data, RAM_ADDR).is_ok());
+    }
+
+    #[test]
+    fn xip_image_in_its_slot() {
+        let binary_data = include_bytes!("../testdata/main_flash.bin");
+
+        // main_flash.bin is linked to the start of the flash, so it is an XIP image for a slot
+        // at address 0 only
+        assert!(is_likely_valid_os_image_buf(binary_data, FLASH_ADDR).is_ok());
+
+        for slot_addr in SLOT_ADDRS {
+            let load_address = FLASH_ADDR + slot_addr;
+            match is_likely_valid_os_image_buf(binary_data, load_address) {
+                Err(BinaryFileError::EntrypointOutsideSlot { entrypoint_address, .. }) => {
+                    assert_eq!(entrypoint_address, FLASH_ADDR + 0x1b9);
+                }
+                result => panic!("Unexpected result {:?}", result),
+            }
+
+            // Relinked for the slot
+            let mut binary_data = binary_data.to_vec();
+            binary_data[4..8].copy_from_slice(&(load_address + 0x1b9).to_le_bytes());
+            assert!(is_likely_valid_os_image_buf(&binary_data, load_address).is_ok());
+        }
+
+        // A RAM image can't be executed in place
+        let binary_data = include_bytes!("../testdata/main_ram.bin");
+        assert!(is_likely_valid_os_image_buf(binary_data, FLASH_ADDR + SLOT_ADDRS[0]).is_err());
+    }
+
     #[test]
     fn correct_address_in_ram() {
         let binary_data = include_bytes!("../testdata/main_ram.bin");
 
-        let result = is_likely_valid_os_image_buf(binary_data);
+        let result = is_likely_valid_os_image_buf(binary_data, RAM_ADDR);
 
         assert!(result.is_ok());
     }
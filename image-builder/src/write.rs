@@ -1,6 +1,7 @@
 use std::io::Error;
 
-use crate::{byte_utils, generate::generate_buffer};
+use crate::generate::{generate_buffer, image_formats};
+use crate::{byte_utils, signing};
 use clap::Parser;
 
 #[derive(Parser, Debug)]
@@ -22,6 +23,31 @@ pub struct WriteArguments {
     #[arg(short = '3', long)]
     image_3_path: Option<std::path::PathBuf>,
 
+    /// The path to the golden image binary. It is only booted if both metadata pages are broken,
+    /// and its region should be write protected after flashing (if not given, there is no golden image)
+    #[arg(short = 'g', long)]
+    golden_image_path: Option<std::path::PathBuf>,
+
+    /// The path to a secret key created with `keygen`. If given, all images are signed
+    /// (this is required if the bootloader was built with a SIGNATURE_KEY)
+    #[arg(short = 'k', long)]
+    signing_key: Option<std::path::PathBuf>,
+
+    /// Store the SHA-256 digests of the images next to the metadata, so the bootloader checks
+    /// them in addition to the CRCs
+    #[arg(short, long)]
+    digests: bool,
+
+    /// The slots (1-3) whose images are executed in place from the flash instead of being
+    /// copied to RAM. Their images must be linked against the address of their slot
+    #[arg(short = 'x', long, value_delimiter = ',')]
+    xip: Vec<usize>,
+
+    /// Store the images LZ4 compressed, so images larger than a slot fit as long as they fit into
+    /// RAM. The bootloader decompresses them at boot. XIP images are never compressed
+    #[arg(short, long)]
+    compress: bool,
+
     /// The path to the output file
     #[arg(short, long, default_value = "output_image.bin")]
     output_path: std::path::PathBuf,
@@ -53,7 +79,36 @@ pub fn write(options: WriteArguments) -> Result<(), Error> {
         image_1_bin.clone()
     };
 
-    let data = generate_buffer(&bootloader_bin, &image_1_bin, &image_2_bin, &image_3_bin)?;
+    let golden_image_bin = if let Some(golden_image_path) = options.golden_image_path {
+        let img = byte_utils::read_file(&golden_image_path)?;
+        println!("Read golden image of size {}", img.len());
+        Some(img)
+    } else {
+        println!("No golden image provided");
+        None
+    };
+
+    let signing_key = if let Some(signing_key_path) = options.signing_key {
+        let key_pair = signing::read_signing_key(&signing_key_path)?;
+        println!("Signing images with public key {:02x?}", key_pair.pk.as_ref());
+        Some(key_pair)
+    } else {
+        println!("No signing key provided, images are not signed");
+        None
+    };
+
+    let formats = image_formats(&options.xip, options.compress)?;
+
+    let data = generate_buffer(
+        &bootloader_bin,
+        &image_1_bin,
+        &image_2_bin,
+        &image_3_bin,
+        golden_image_bin.as_ref(),
+        signing_key.as_ref(),
+        options.digests,
+        formats,
+    )?;
 
     std::fs::write(&options.output_path, &data)?;
 
@@ -0,0 +1,338 @@
+// Generates include/moveloader.h from the interface crate.
+// The constants, sizes and offsets are taken from the Rust definitions, so the header can't
+// drift from them. Only the struct fields and the function prototypes are listed here:
+// if they don't match anymore, the static_asserts in the header fail to compile.
+
+use std::fmt::Write;
+use std::mem::{align_of, offset_of, size_of};
+
+use interface::backup::*;
+use interface::bootinfo::*;
+use interface::crc::{CRC_FINAL_XOR_VALUE, CRC_INITIAL_VALUE, DEFAULT_POLYNOM};
+use interface::lz4::*;
+use interface::mailbox::*;
+use interface::reset::*;
+use interface::sha256::DIGEST_SIZE;
+use interface::signature::SIGNATURE_OFFSET;
+use interface::trailer::*;
+use interface::*;
+
+const HEADER_PATH: &str = "include/moveloader.h";
+
+struct Struct {
+    name: &'static str,
+    size: usize,
+    align: usize,
+    // (declaration, field name, offset)
+    fields: Vec<(String, &'static str, usize)>,
+}
+
+macro_rules! field {
+    ($ty:ident, $c_type:expr, $name:ident) => {
+        (format!("{} {}", $c_type, stringify!($name)), stringify!($name), offset_of!($ty, $name))
+    };
+    ($ty:ident, $c_type:expr, $name:ident[$len:expr]) => {
+        (
+            format!("{} {}[{}]", $c_type, stringify!($name), $len),
+            stringify!($name),
+            offset_of!($ty, $name),
+        )
+    };
+}
+
+macro_rules! c_struct {
+    ($ty:ident, $name:expr, [$($field:tt)*]) => {
+        Struct {
+            name: $name,
+            size: size_of::<$ty>(),
+            align: align_of::<$ty>(),
+            fields: c_struct!(@fields $ty, [] $($field)*),
+        }
+    };
+    (@fields $ty:ident, [$($done:expr,)*] $c_type:literal $name:ident[$len:expr], $($rest:tt)*) => {
+        c_struct!(@fields $ty, [$($done,)* field!($ty, $c_type, $name[$len]),] $($rest)*)
+    };
+    (@fields $ty:ident, [$($done:expr,)*] $c_type:literal $name:ident, $($rest:tt)*) => {
+        c_struct!(@fields $ty, [$($done,)* field!($ty, $c_type, $name),] $($rest)*)
+    };
+    (@fields $ty:ident, [$($done:expr,)*]) => {
+        vec![$($done),*]
+    };
+}
+
+fn structs() -> Vec<Struct> {
+    vec![
+        c_struct!(ImageMetadata, "moveloader_image_metadata_t", [
+            "uint32_t" version,
+            "uint32_t" crc,
+            "uint32_t" flags,
+            "uint32_t" length,
+        ]),
+        c_struct!(Metadata, "moveloader_metadata_t", [
+            "uint32_t" version,
+            "uint32_t" bootcounter,
+            "uint32_t" preferred_image,
+            "moveloader_image_metadata_t" images[NUMBER_OF_IMAGES],
+            "uint32_t" crc,
+        ]),
+        c_struct!(MetadataHeader, "moveloader_metadata_header_t", [
+            "uint32_t" magic,
+            "uint32_t" layout_revision,
+        ]),
+        c_struct!(ImageTrailer, "moveloader_image_trailer_t", [
+            "uint32_t" magic,
+            "uint32_t" trailer_version,
+            "uint32_t" length,
+            "uint32_t" image_version,
+            "uint32_t" crc,
+            "uint32_t" load_address,
+            "uint8_t" digest[DIGEST_SIZE],
+            "uint32_t" trailer_crc,
+            "uint32_t" reserved,
+        ]),
+        c_struct!(CompressedImageHeader, "moveloader_compressed_image_header_t", [
+            "uint32_t" magic,
+            "uint32_t" format_version,
+            "uint32_t" length,
+            "uint32_t" crc,
+        ]),
+        c_struct!(BootInfo, "moveloader_boot_info_t", [
+            "uint32_t" magic,
+            "uint32_t" version,
+            "uint32_t" reset_flags,
+            "uint32_t" slot,
+            "uint32_t" reason,
+            "uint32_t" metadata_version,
+            "uint32_t" metadata_status,
+            "uint32_t" slot_status[NUMBER_OF_IMAGES],
+            "uint32_t" ecc_fault_address,
+            "uint32_t" ecc_corrections,
+            "uint32_t" timeouts,
+            "uint32_t" crc,
+        ]),
+    ]
+}
+
+fn constants() -> Vec<(&'static str, u32)> {
+    vec![
+        ("NUMBER_OF_IMAGES", NUMBER_OF_IMAGES as u32),
+        ("FLASH_SIZE", FLASH_SIZE),
+        ("SINGLE_BANK_PAGE_SIZE", SINGLE_BANK_PAGE_SIZE),
+        ("DUAL_BANK_PAGE_SIZE", DUAL_BANK_PAGE_SIZE),
+        ("BOOTLOADER_SIZE", BOOTLOADER_SIZE),
+        ("SLOT_SIZE", SLOT_SIZE),
+        ("METADATA_1_ADDR", METADATA_1_ADDR),
+        ("METADATA_2_ADDR", METADATA_2_ADDR),
+        ("SLOT_1_ADDR", SLOT_1_ADDR),
+        ("SLOT_2_ADDR", SLOT_2_ADDR),
+        ("SLOT_3_ADDR", SLOT_3_ADDR),
+        ("GOLDEN_METADATA_ADDR", GOLDEN_METADATA_ADDR),
+        ("GOLDEN_SLOT_ADDR", GOLDEN_SLOT_ADDR),
+        ("GOLDEN_REGION_END", GOLDEN_REGION_END),
+        ("FLASH_ADDR", FLASH_ADDR),
+        ("RAM_ADDR", RAM_ADDR),
+        ("RAM_SIZE", RAM_SIZE),
+        ("MAX_RAM_IMAGE_LENGTH", MAX_RAM_IMAGE_LENGTH),
+        ("STACK_POINTER_ALIGNMENT", STACK_POINTER_ALIGNMENT),
+        ("IMAGE_FLAG_XIP", IMAGE_FLAG_XIP),
+        ("IMAGE_FLAG_LZ4", IMAGE_FLAG_LZ4),
+        ("COMPRESSED_IMAGE_MAGIC", COMPRESSED_IMAGE_MAGIC),
+        ("COMPRESSED_IMAGE_VERSION", COMPRESSED_IMAGE_VERSION),
+        ("COMPRESSED_DATA_OFFSET", COMPRESSED_DATA_OFFSET as u32),
+        ("METADATA_MAGIC", METADATA_MAGIC),
+        ("METADATA_LAYOUT_REVISION", METADATA_LAYOUT_REVISION),
+        ("METADATA_OFFSET", METADATA_OFFSET),
+        ("METADATA_IMAGE_DATA_OFFSET", METADATA_IMAGE_DATA_OFFSET),
+        ("IMAGE_TRAILER_MAGIC", IMAGE_TRAILER_MAGIC),
+        ("IMAGE_TRAILER_VERSION", IMAGE_TRAILER_VERSION),
+        ("IMAGE_TRAILER_OFFSET", IMAGE_TRAILER_OFFSET),
+        ("MAX_IMAGE_LENGTH", MAX_IMAGE_LENGTH),
+        ("SIGNATURE_OFFSET", SIGNATURE_OFFSET),
+        ("CRC_POLYNOM", DEFAULT_POLYNOM),
+        ("CRC_INITIAL_VALUE", CRC_INITIAL_VALUE),
+        ("CRC_FINAL_XOR_VALUE", CRC_FINAL_XOR_VALUE),
+        ("MAILBOX_REG", MAILBOX_REG as u32),
+        ("MAILBOX_SIZE", MAILBOX_SIZE as u32),
+        ("MAILBOX_MAGIC", MAILBOX_MAGIC),
+        ("MAILBOX_BOOT_ONCE", MAILBOX_BOOT_ONCE),
+        ("MAILBOX_ENTER_RECOVERY", MAILBOX_ENTER_RECOVERY),
+        ("MAILBOX_CLEAR_BOOT_COUNTERS", MAILBOX_CLEAR_BOOT_COUNTERS),
+        ("MAILBOX_CONFIRM_BOOT", MAILBOX_CONFIRM_BOOT),
+        ("BOOT_CONFIRM_REG", BOOT_CONFIRM_REG as u32),
+        ("BOOT_CONFIRM_MAGIC", BOOT_CONFIRM_MAGIC),
+        ("MAX_BOOT_ATTEMPTS", MAX_BOOT_ATTEMPTS),
+        ("BOOT_INFO_REG", BOOT_INFO_REG as u32),
+        ("BOOT_INFO_SIZE", BOOT_INFO_SIZE as u32),
+        ("BOOT_INFO_MAGIC", BOOT_INFO_MAGIC),
+        ("BOOT_INFO_VERSION", BOOT_INFO_VERSION),
+        ("BOOT_INFO_NO_SLOT", BOOT_INFO_NO_SLOT),
+        ("BOOT_INFO_NO_ECC_FAULT", BOOT_INFO_NO_ECC_FAULT),
+        ("BOOT_INFO_FLASH_TIMEOUT", BOOT_INFO_FLASH_TIMEOUT),
+        ("BOOT_INFO_WATCHDOG_TIMEOUT", BOOT_INFO_WATCHDOG_TIMEOUT),
+        ("RCC_CSR_LPWRRSTF", RCC_CSR_LPWRRSTF),
+        ("RCC_CSR_WWDGRSTF", RCC_CSR_WWDGRSTF),
+        ("RCC_CSR_IWDGRSTF", RCC_CSR_IWDGRSTF),
+        ("RCC_CSR_SFTRSTF", RCC_CSR_SFTRSTF),
+        ("RCC_CSR_BORRSTF", RCC_CSR_BORRSTF),
+        ("RCC_CSR_PINRSTF", RCC_CSR_PINRSTF),
+        ("RCC_CSR_OBLRSTF", RCC_CSR_OBLRSTF),
+        ("RCC_CSR_FWRSTF", RCC_CSR_FWRSTF),
+        ("BOOT_REASON_PREFERRED", BootReason::Preferred as u32),
+        ("BOOT_REASON_FALLBACK", BootReason::Fallback as u32),
+        ("BOOT_REASON_UNVERIFIED", BootReason::Unverified as u32),
+        ("BOOT_REASON_TRIAL", BootReason::Trial as u32),
+        ("BOOT_REASON_TRAILER", BootReason::Trailer as u32),
+        ("BOOT_REASON_GOLDEN", BootReason::Golden as u32),
+        ("METADATA_STATUS_VALID", MetadataStatus::Valid as u32),
+        ("METADATA_STATUS_REPAIRED", MetadataStatus::Repaired as u32),
+        ("METADATA_STATUS_REPAIR_FAILED", MetadataStatus::RepairFailed as u32),
+        ("METADATA_STATUS_INVALID", MetadataStatus::Invalid as u32),
+        ("METADATA_STATUS_WRITE_PROTECTED", MetadataStatus::WriteProtected as u32),
+        ("SLOT_STATUS_NOT_CHECKED", SlotStatus::NotChecked as u32),
+        ("SLOT_STATUS_VALID", SlotStatus::Valid as u32),
+        ("SLOT_STATUS_INVALID", SlotStatus::Invalid as u32),
+        ("SLOT_STATUS_EXHAUSTED", SlotStatus::Exhausted as u32),
+        ("SLOT_STATUS_UNTRUSTED", SlotStatus::Untrusted as u32),
+        ("SLOT_STATUS_ECC_ERROR", SlotStatus::EccError as u32),
+        ("SLOT_STATUS_LOAD_FAILED", SlotStatus::LoadFailed as u32),
+    ]
+}
+
+// Must match the extern "C" functions in src/lib.rs
+const FUNCTIONS: &str = r#"/* CRC32-C of `length` bytes at `data`, like the bootloader calculates it. Returns 0 for NULL. */
+uint32_t moveloader_crc32(const uint8_t *data, size_t length);
+
+/* The metadata of an image with its CRC and length, for an image copied to RAM (flags is 0). */
+void moveloader_image_metadata(const uint8_t *image, size_t length, uint32_t version,
+                               moveloader_image_metadata_t *out);
+
+/* The trailer to be written at MOVELOADER_IMAGE_TRAILER_OFFSET of the image's slot. */
+void moveloader_image_trailer(const uint8_t *image, size_t length, uint32_t version,
+                              moveloader_image_trailer_t *out);
+
+/* Recalculates the CRC of the metadata after it was modified. */
+void moveloader_metadata_set_crc(moveloader_metadata_t *metadata);
+
+/* Whether the CRC of the metadata is valid. */
+bool moveloader_metadata_is_valid(const moveloader_metadata_t *metadata);
+
+/*
+ * Builds the metadata that replaces `current`: `slot` gets `image` and becomes the preferred
+ * image, and the version is bumped. Returns MOVELOADER_OK or one of the errors below.
+ */
+int32_t moveloader_next_metadata(const moveloader_metadata_t *current, uint32_t slot,
+                                 const moveloader_image_metadata_t *image,
+                                 moveloader_metadata_t *next);
+
+/*
+ * Like moveloader_next_metadata, but the preferred image stays the same, so the new image can be
+ * tried once with MOVELOADER_MAILBOX_BOOT_ONCE before it is made permanent. `slot` must not be
+ * the preferred image.
+ */
+int32_t moveloader_staged_metadata(const moveloader_metadata_t *current, uint32_t slot,
+                                   const moveloader_image_metadata_t *image,
+                                   moveloader_metadata_t *next);
+
+/*
+ * The values of the MOVELOADER_MAILBOX_SIZE mailbox registers, starting at
+ * MOVELOADER_MAILBOX_REG, for a command (MOVELOADER_MAILBOX_BOOT_ONCE etc.). The argument is the
+ * slot for MOVELOADER_MAILBOX_BOOT_ONCE and 0 otherwise. Write the registers in order.
+ */
+int32_t moveloader_mailbox_encode(uint32_t command, uint32_t argument, uint32_t *registers);
+
+/*
+ * Decodes the MOVELOADER_BOOT_INFO_SIZE registers starting at MOVELOADER_BOOT_INFO_REG, which the
+ * bootloader wrote before it started the image. Returns MOVELOADER_ERROR_INVALID_BOOT_INFO if
+ * they don't hold an intact boot info with MOVELOADER_BOOT_INFO_VERSION.
+ */
+int32_t moveloader_boot_info(const uint32_t *registers, moveloader_boot_info_t *out);
+"#;
+
+include!("src/errors.rs");
+
+const ERRORS: [(&str, i32); 6] = [
+    ("OK", MOVELOADER_OK),
+    ("ERROR_NULL", MOVELOADER_ERROR_NULL),
+    ("ERROR_INVALID_SLOT", MOVELOADER_ERROR_INVALID_SLOT),
+    ("ERROR_INVALID_VERSION", MOVELOADER_ERROR_INVALID_VERSION),
+    ("ERROR_INVALID_COMMAND", MOVELOADER_ERROR_INVALID_COMMAND),
+    ("ERROR_INVALID_BOOT_INFO", MOVELOADER_ERROR_INVALID_BOOT_INFO),
+];
+
+fn generate() -> Result<String, std::fmt::Error> {
+    let mut h = String::new();
+
+    writeln!(h, "/*")?;
+    writeln!(h, " * Generated by interface-c/build.rs from the interface crate, DO NOT EDIT.")?;
+    writeln!(h, " * The bootloader's flash layout and the structs it shares with the OS.")?;
+    writeln!(h, " * Link against libinterface_c.a for the functions declared at the end.")?;
+    writeln!(h, " */")?;
+    writeln!(h, "#ifndef MOVELOADER_H")?;
+    writeln!(h, "#define MOVELOADER_H\n")?;
+    writeln!(h, "#include <stdbool.h>")?;
+    writeln!(h, "#include <stddef.h>")?;
+    writeln!(h, "#include <stdint.h>\n")?;
+    writeln!(h, "#ifdef __cplusplus")?;
+    writeln!(h, "#define MOVELOADER_STATIC_ASSERT(expr, msg) static_assert(expr, msg)")?;
+    writeln!(h, "#define MOVELOADER_ALIGNOF(type) alignof(type)")?;
+    writeln!(h, "extern \"C\" {{")?;
+    writeln!(h, "#else")?;
+    writeln!(h, "#define MOVELOADER_STATIC_ASSERT(expr, msg) _Static_assert(expr, msg)")?;
+    writeln!(h, "#define MOVELOADER_ALIGNOF(type) _Alignof(type)")?;
+    writeln!(h, "#endif\n")?;
+
+    for (name, value) in constants() {
+        if value < 0x100 {
+            writeln!(h, "#define MOVELOADER_{} {}u", name, value)?;
+        } else {
+            writeln!(h, "#define MOVELOADER_{} {:#x}u", name, value)?;
+        }
+    }
+    writeln!(h)?;
+    for (name, value) in ERRORS {
+        writeln!(h, "#define MOVELOADER_{} ({})", name, value)?;
+    }
+
+    for s in structs() {
+        writeln!(h, "\ntypedef struct __attribute__((aligned({}))) {{", s.align)?;
+        for (declaration, _, _) in &s.fields {
+            writeln!(h, "    {};", declaration)?;
+        }
+        writeln!(h, "}} {};\n", s.name)?;
+
+        let message = format!("\"{} does not match the interface crate\"", s.name);
+        writeln!(h, "MOVELOADER_STATIC_ASSERT(sizeof({}) == {}, {});", s.name, s.size, message)?;
+        writeln!(
+            h,
+            "MOVELOADER_STATIC_ASSERT(MOVELOADER_ALIGNOF({}) == {}, {});",
+            s.name, s.align, message
+        )?;
+        for (_, field, offset) in &s.fields {
+            writeln!(
+                h,
+                "MOVELOADER_STATIC_ASSERT(offsetof({}, {}) == {}, {});",
+                s.name, field, offset, message
+            )?;
+        }
+    }
+
+    writeln!(h, "\n{}", FUNCTIONS)?;
+    writeln!(h, "#ifdef __cplusplus")?;
+    writeln!(h, "}}")?;
+    writeln!(h, "#endif\n")?;
+    writeln!(h, "#endif /* MOVELOADER_H */")?;
+
+    Ok(h)
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/errors.rs");
+    println!("cargo:rerun-if-changed=../interface/src");
+
+    let header = generate().expect("Failed to generate the header");
+    // Only write it if it changed, so the C side doesn't rebuild everything on every build
+    if std::fs::read_to_string(HEADER_PATH).ok().as_deref() != Some(header.as_str()) {
+        std::fs::write(HEADER_PATH, header).expect("Failed to write the header");
+    }
+}
@@ -0,0 +1,270 @@
+/*
+ * Generated by interface-c/build.rs from the interface crate, DO NOT EDIT.
+ * The bootloader's flash layout and the structs it shares with the OS.
+ * Link against libinterface_c.a for the functions declared at the end.
+ */
+#ifndef MOVELOADER_H
+#define MOVELOADER_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+#define MOVELOADER_STATIC_ASSERT(expr, msg) static_assert(expr, msg)
+#define MOVELOADER_ALIGNOF(type) alignof(type)
+extern "C" {
+#else
+#define MOVELOADER_STATIC_ASSERT(expr, msg) _Static_assert(expr, msg)
+#define MOVELOADER_ALIGNOF(type) _Alignof(type)
+#endif
+
+#define MOVELOADER_NUMBER_OF_IMAGES 3u
+#define MOVELOADER_FLASH_SIZE 0x200000u
+#define MOVELOADER_SINGLE_BANK_PAGE_SIZE 0x2000u
+#define MOVELOADER_DUAL_BANK_PAGE_SIZE 0x1000u
+#define MOVELOADER_BOOTLOADER_SIZE 0x10000u
+#define MOVELOADER_SLOT_SIZE 0x7a000u
+#define MOVELOADER_METADATA_1_ADDR 0x10000u
+#define MOVELOADER_METADATA_2_ADDR 0x12000u
+#define MOVELOADER_SLOT_1_ADDR 0x14000u
+#define MOVELOADER_SLOT_2_ADDR 0x8e000u
+#define MOVELOADER_SLOT_3_ADDR 0x108000u
+#define MOVELOADER_GOLDEN_METADATA_ADDR 0x182000u
+#define MOVELOADER_GOLDEN_SLOT_ADDR 0x184000u
+#define MOVELOADER_GOLDEN_REGION_END 0x1fe000u
+#define MOVELOADER_FLASH_ADDR 0x8000000u
+#define MOVELOADER_RAM_ADDR 0x20000000u
+#define MOVELOADER_RAM_SIZE 0xa0000u
+#define MOVELOADER_MAX_RAM_IMAGE_LENGTH 0x98000u
+#define MOVELOADER_STACK_POINTER_ALIGNMENT 8u
+#define MOVELOADER_IMAGE_FLAG_XIP 1u
+#define MOVELOADER_IMAGE_FLAG_LZ4 2u
+#define MOVELOADER_COMPRESSED_IMAGE_MAGIC 0x4c5a3442u
+#define MOVELOADER_COMPRESSED_IMAGE_VERSION 1u
+#define MOVELOADER_COMPRESSED_DATA_OFFSET 16u
+#define MOVELOADER_METADATA_MAGIC 0x4d455441u
+#define MOVELOADER_METADATA_LAYOUT_REVISION 1u
+#define MOVELOADER_METADATA_OFFSET 8u
+#define MOVELOADER_METADATA_IMAGE_DATA_OFFSET 12u
+#define MOVELOADER_IMAGE_TRAILER_MAGIC 0x494d4147u
+#define MOVELOADER_IMAGE_TRAILER_VERSION 1u
+#define MOVELOADER_IMAGE_TRAILER_OFFSET 0x79f78u
+#define MOVELOADER_MAX_IMAGE_LENGTH 0x79f78u
+#define MOVELOADER_SIGNATURE_OFFSET 0x79fb8u
+#define MOVELOADER_CRC_POLYNOM 0x82f63b78u
+#define MOVELOADER_CRC_INITIAL_VALUE 0xffffffffu
+#define MOVELOADER_CRC_FINAL_XOR_VALUE 0xffffffffu
+#define MOVELOADER_MAILBOX_REG 8u
+#define MOVELOADER_MAILBOX_SIZE 3u
+#define MOVELOADER_MAILBOX_MAGIC 0x4d42u
+#define MOVELOADER_MAILBOX_BOOT_ONCE 1u
+#define MOVELOADER_MAILBOX_ENTER_RECOVERY 2u
+#define MOVELOADER_MAILBOX_CLEAR_BOOT_COUNTERS 3u
+#define MOVELOADER_MAILBOX_CONFIRM_BOOT 4u
+#define MOVELOADER_BOOT_CONFIRM_REG 4u
+#define MOVELOADER_BOOT_CONFIRM_MAGIC 0x600db007u
+#define MOVELOADER_MAX_BOOT_ATTEMPTS 3u
+#define MOVELOADER_BOOT_INFO_REG 11u
+#define MOVELOADER_BOOT_INFO_SIZE 14u
+#define MOVELOADER_BOOT_INFO_MAGIC 0x424f4f54u
+#define MOVELOADER_BOOT_INFO_VERSION 1u
+#define MOVELOADER_BOOT_INFO_NO_SLOT 0xffffffffu
+#define MOVELOADER_BOOT_INFO_NO_ECC_FAULT 0xffffffffu
+#define MOVELOADER_BOOT_INFO_FLASH_TIMEOUT 1u
+#define MOVELOADER_BOOT_INFO_WATCHDOG_TIMEOUT 2u
+#define MOVELOADER_RCC_CSR_LPWRRSTF 0x80000000u
+#define MOVELOADER_RCC_CSR_WWDGRSTF 0x40000000u
+#define MOVELOADER_RCC_CSR_IWDGRSTF 0x20000000u
+#define MOVELOADER_RCC_CSR_SFTRSTF 0x10000000u
+#define MOVELOADER_RCC_CSR_BORRSTF 0x8000000u
+#define MOVELOADER_RCC_CSR_PINRSTF 0x4000000u
+#define MOVELOADER_RCC_CSR_OBLRSTF 0x2000000u
+#define MOVELOADER_RCC_CSR_FWRSTF 0x1000000u
+#define MOVELOADER_BOOT_REASON_PREFERRED 1u
+#define MOVELOADER_BOOT_REASON_FALLBACK 2u
+#define MOVELOADER_BOOT_REASON_UNVERIFIED 3u
+#define MOVELOADER_BOOT_REASON_TRIAL 4u
+#define MOVELOADER_BOOT_REASON_TRAILER 5u
+#define MOVELOADER_BOOT_REASON_GOLDEN 6u
+#define MOVELOADER_METADATA_STATUS_VALID 1u
+#define MOVELOADER_METADATA_STATUS_REPAIRED 2u
+#define MOVELOADER_METADATA_STATUS_REPAIR_FAILED 3u
+#define MOVELOADER_METADATA_STATUS_INVALID 4u
+#define MOVELOADER_METADATA_STATUS_WRITE_PROTECTED 5u
+#define MOVELOADER_SLOT_STATUS_NOT_CHECKED 0u
+#define MOVELOADER_SLOT_STATUS_VALID 1u
+#define MOVELOADER_SLOT_STATUS_INVALID 2u
+#define MOVELOADER_SLOT_STATUS_EXHAUSTED 3u
+#define MOVELOADER_SLOT_STATUS_UNTRUSTED 4u
+#define MOVELOADER_SLOT_STATUS_ECC_ERROR 5u
+#define MOVELOADER_SLOT_STATUS_LOAD_FAILED 6u
+
+#define MOVELOADER_OK (0)
+#define MOVELOADER_ERROR_NULL (-1)
+#define MOVELOADER_ERROR_INVALID_SLOT (-2)
+#define MOVELOADER_ERROR_INVALID_VERSION (-3)
+#define MOVELOADER_ERROR_INVALID_COMMAND (-4)
+#define MOVELOADER_ERROR_INVALID_BOOT_INFO (-5)
+
+typedef struct __attribute__((aligned(4))) {
+    uint32_t version;
+    uint32_t crc;
+    uint32_t flags;
+    uint32_t length;
+} moveloader_image_metadata_t;
+
+MOVELOADER_STATIC_ASSERT(sizeof(moveloader_image_metadata_t) == 16, "moveloader_image_metadata_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(MOVELOADER_ALIGNOF(moveloader_image_metadata_t) == 4, "moveloader_image_metadata_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_image_metadata_t, version) == 0, "moveloader_image_metadata_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_image_metadata_t, crc) == 4, "moveloader_image_metadata_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_image_metadata_t, flags) == 8, "moveloader_image_metadata_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_image_metadata_t, length) == 12, "moveloader_image_metadata_t does not match the interface crate");
+
+typedef struct __attribute__((aligned(8))) {
+    uint32_t version;
+    uint32_t bootcounter;
+    uint32_t preferred_image;
+    moveloader_image_metadata_t images[3];
+    uint32_t crc;
+} moveloader_metadata_t;
+
+MOVELOADER_STATIC_ASSERT(sizeof(moveloader_metadata_t) == 64, "moveloader_metadata_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(MOVELOADER_ALIGNOF(moveloader_metadata_t) == 8, "moveloader_metadata_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_metadata_t, version) == 0, "moveloader_metadata_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_metadata_t, bootcounter) == 4, "moveloader_metadata_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_metadata_t, preferred_image) == 8, "moveloader_metadata_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_metadata_t, images) == 12, "moveloader_metadata_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_metadata_t, crc) == 60, "moveloader_metadata_t does not match the interface crate");
+
+typedef struct __attribute__((aligned(8))) {
+    uint32_t magic;
+    uint32_t layout_revision;
+} moveloader_metadata_header_t;
+
+MOVELOADER_STATIC_ASSERT(sizeof(moveloader_metadata_header_t) == 8, "moveloader_metadata_header_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(MOVELOADER_ALIGNOF(moveloader_metadata_header_t) == 8, "moveloader_metadata_header_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_metadata_header_t, magic) == 0, "moveloader_metadata_header_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_metadata_header_t, layout_revision) == 4, "moveloader_metadata_header_t does not match the interface crate");
+
+typedef struct __attribute__((aligned(4))) {
+    uint32_t magic;
+    uint32_t trailer_version;
+    uint32_t length;
+    uint32_t image_version;
+    uint32_t crc;
+    uint32_t load_address;
+    uint8_t digest[32];
+    uint32_t trailer_crc;
+    uint32_t reserved;
+} moveloader_image_trailer_t;
+
+MOVELOADER_STATIC_ASSERT(sizeof(moveloader_image_trailer_t) == 64, "moveloader_image_trailer_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(MOVELOADER_ALIGNOF(moveloader_image_trailer_t) == 4, "moveloader_image_trailer_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_image_trailer_t, magic) == 0, "moveloader_image_trailer_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_image_trailer_t, trailer_version) == 4, "moveloader_image_trailer_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_image_trailer_t, length) == 8, "moveloader_image_trailer_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_image_trailer_t, image_version) == 12, "moveloader_image_trailer_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_image_trailer_t, crc) == 16, "moveloader_image_trailer_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_image_trailer_t, load_address) == 20, "moveloader_image_trailer_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_image_trailer_t, digest) == 24, "moveloader_image_trailer_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_image_trailer_t, trailer_crc) == 56, "moveloader_image_trailer_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_image_trailer_t, reserved) == 60, "moveloader_image_trailer_t does not match the interface crate");
+
+typedef struct __attribute__((aligned(4))) {
+    uint32_t magic;
+    uint32_t format_version;
+    uint32_t length;
+    uint32_t crc;
+} moveloader_compressed_image_header_t;
+
+MOVELOADER_STATIC_ASSERT(sizeof(moveloader_compressed_image_header_t) == 16, "moveloader_compressed_image_header_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(MOVELOADER_ALIGNOF(moveloader_compressed_image_header_t) == 4, "moveloader_compressed_image_header_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_compressed_image_header_t, magic) == 0, "moveloader_compressed_image_header_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_compressed_image_header_t, format_version) == 4, "moveloader_compressed_image_header_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_compressed_image_header_t, length) == 8, "moveloader_compressed_image_header_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_compressed_image_header_t, crc) == 12, "moveloader_compressed_image_header_t does not match the interface crate");
+
+typedef struct __attribute__((aligned(4))) {
+    uint32_t magic;
+    uint32_t version;
+    uint32_t reset_flags;
+    uint32_t slot;
+    uint32_t reason;
+    uint32_t metadata_version;
+    uint32_t metadata_status;
+    uint32_t slot_status[3];
+    uint32_t ecc_fault_address;
+    uint32_t ecc_corrections;
+    uint32_t timeouts;
+    uint32_t crc;
+} moveloader_boot_info_t;
+
+MOVELOADER_STATIC_ASSERT(sizeof(moveloader_boot_info_t) == 56, "moveloader_boot_info_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(MOVELOADER_ALIGNOF(moveloader_boot_info_t) == 4, "moveloader_boot_info_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_boot_info_t, magic) == 0, "moveloader_boot_info_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_boot_info_t, version) == 4, "moveloader_boot_info_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_boot_info_t, reset_flags) == 8, "moveloader_boot_info_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_boot_info_t, slot) == 12, "moveloader_boot_info_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_boot_info_t, reason) == 16, "moveloader_boot_info_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_boot_info_t, metadata_version) == 20, "moveloader_boot_info_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_boot_info_t, metadata_status) == 24, "moveloader_boot_info_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_boot_info_t, slot_status) == 28, "moveloader_boot_info_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_boot_info_t, ecc_fault_address) == 40, "moveloader_boot_info_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_boot_info_t, ecc_corrections) == 44, "moveloader_boot_info_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_boot_info_t, timeouts) == 48, "moveloader_boot_info_t does not match the interface crate");
+MOVELOADER_STATIC_ASSERT(offsetof(moveloader_boot_info_t, crc) == 52, "moveloader_boot_info_t does not match the interface crate");
+
+/* CRC32-C of `length` bytes at `data`, like the bootloader calculates it. Returns 0 for NULL. */
+uint32_t moveloader_crc32(const uint8_t *data, size_t length);
+
+/* The metadata of an image with its CRC and length, for an image copied to RAM (flags is 0). */
+void moveloader_image_metadata(const uint8_t *image, size_t length, uint32_t version,
+                               moveloader_image_metadata_t *out);
+
+/* The trailer to be written at MOVELOADER_IMAGE_TRAILER_OFFSET of the image's slot. */
+void moveloader_image_trailer(const uint8_t *image, size_t length, uint32_t version,
+                              moveloader_image_trailer_t *out);
+
+/* Recalculates the CRC of the metadata after it was modified. */
+void moveloader_metadata_set_crc(moveloader_metadata_t *metadata);
+
+/* Whether the CRC of the metadata is valid. */
+bool moveloader_metadata_is_valid(const moveloader_metadata_t *metadata);
+
+/*
+ * Builds the metadata that replaces `current`: `slot` gets `image` and becomes the preferred
+ * image, and the version is bumped. Returns MOVELOADER_OK or one of the errors below.
+ */
+int32_t moveloader_next_metadata(const moveloader_metadata_t *current, uint32_t slot,
+                                 const moveloader_image_metadata_t *image,
+                                 moveloader_metadata_t *next);
+
+/*
+ * Like moveloader_next_metadata, but the preferred image stays the same, so the new image can be
+ * tried once with MOVELOADER_MAILBOX_BOOT_ONCE before it is made permanent. `slot` must not be
+ * the preferred image.
+ */
+int32_t moveloader_staged_metadata(const moveloader_metadata_t *current, uint32_t slot,
+                                   const moveloader_image_metadata_t *image,
+                                   moveloader_metadata_t *next);
+
+/*
+ * The values of the MOVELOADER_MAILBOX_SIZE mailbox registers, starting at
+ * MOVELOADER_MAILBOX_REG, for a command (MOVELOADER_MAILBOX_BOOT_ONCE etc.). The argument is the
+ * slot for MOVELOADER_MAILBOX_BOOT_ONCE and 0 otherwise. Write the registers in order.
+ */
+int32_t moveloader_mailbox_encode(uint32_t command, uint32_t argument, uint32_t *registers);
+
+/*
+ * Decodes the MOVELOADER_BOOT_INFO_SIZE registers starting at MOVELOADER_BOOT_INFO_REG, which the
+ * bootloader wrote before it started the image. Returns MOVELOADER_ERROR_INVALID_BOOT_INFO if
+ * they don't hold an intact boot info with MOVELOADER_BOOT_INFO_VERSION.
+ */
+int32_t moveloader_boot_info(const uint32_t *registers, moveloader_boot_info_t *out);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MOVELOADER_H */
@@ -0,0 +1,7 @@
+// The error codes returned to C. Shared with build.rs, which puts them into the header.
+pub const MOVELOADER_OK: i32 = 0;
+pub const MOVELOADER_ERROR_NULL: i32 = -1;
+pub const MOVELOADER_ERROR_INVALID_SLOT: i32 = -2;
+pub const MOVELOADER_ERROR_INVALID_VERSION: i32 = -3;
+pub const MOVELOADER_ERROR_INVALID_COMMAND: i32 = -4;
+pub const MOVELOADER_ERROR_INVALID_BOOT_INFO: i32 = -5;
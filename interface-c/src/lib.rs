@@ -0,0 +1,272 @@
+#![no_std]
+
+// A C ABI for the interface crate, for an OS that is not written in Rust (e.g. RODOS).
+// The build script generates include/moveloader.h with the flash layout and the structs, and the
+// functions here are the same code the bootloader and os-client use, so the OS can't compute
+// a CRC or build metadata any different from them.
+//
+// All pointers must be valid for the size of what they point to. The functions only check
+// for NULL where they can report an error.
+
+#[cfg(test)]
+extern crate std;
+
+use interface::bootinfo::{BootInfo, BOOT_INFO_SIZE};
+use interface::crc::calc_crc32;
+use interface::mailbox::{MailboxCommand, MAILBOX_SIZE};
+use interface::trailer::ImageTrailer;
+use interface::{ImageMetadata, Metadata};
+
+include!("errors.rs");
+
+unsafe fn image<'a>(data: *const u8, length: usize) -> &'a [u8] {
+    if data.is_null() {
+        &[]
+    } else {
+        core::slice::from_raw_parts(data, length)
+    }
+}
+
+/// CRC32-C of `length` bytes at `data`, see interface::crc
+///
+/// # Safety
+/// `data` must be NULL or valid for reads of `length` bytes
+#[no_mangle]
+pub unsafe extern "C" fn moveloader_crc32(data: *const u8, length: usize) -> u32 {
+    calc_crc32(data, length)
+}
+
+/// The ImageMetadata for an image, see os_client::image_metadata
+///
+/// # Safety
+/// `image_data` must be NULL or valid for reads of `length` bytes, `out` must be valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn moveloader_image_metadata(
+    image_data: *const u8, length: usize, version: u32, out: *mut ImageMetadata,
+) {
+    out.write(os_client::image_metadata(image(image_data, length), version));
+}
+
+/// The trailer of an image, see interface::trailer
+///
+/// # Safety
+/// `image_data` must be NULL or valid for reads of `length` bytes, `out` must be valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn moveloader_image_trailer(
+    image_data: *const u8, length: usize, version: u32, out: *mut ImageTrailer,
+) {
+    out.write(ImageTrailer::new(image(image_data, length), version));
+}
+
+/// # Safety
+/// `metadata` must be valid for reads and writes
+#[no_mangle]
+pub unsafe extern "C" fn moveloader_metadata_set_crc(metadata: *mut Metadata) {
+    (*metadata).set_crc();
+}
+
+/// # Safety
+/// `metadata` must be valid for reads
+#[no_mangle]
+pub unsafe extern "C" fn moveloader_metadata_is_valid(metadata: *const Metadata) -> bool {
+    (*metadata).is_valid()
+}
+
+/// See os_client::next_metadata. `next` is only written on success.
+///
+/// # Safety
+/// All pointers must be NULL or valid, `next` for writes and the others for reads
+#[no_mangle]
+pub unsafe extern "C" fn moveloader_next_metadata(
+    current: *const Metadata, slot: u32, image_meta: *const ImageMetadata, next: *mut Metadata,
+) -> i32 {
+    build_metadata(current, slot, image_meta, next, os_client::next_metadata)
+}
+
+/// See os_client::staged_metadata. `next` is only written on success.
+///
+/// # Safety
+/// All pointers must be NULL or valid, `next` for writes and the others for reads
+#[no_mangle]
+pub unsafe extern "C" fn moveloader_staged_metadata(
+    current: *const Metadata, slot: u32, image_meta: *const ImageMetadata, next: *mut Metadata,
+) -> i32 {
+    build_metadata(current, slot, image_meta, next, os_client::staged_metadata)
+}
+
+unsafe fn build_metadata(
+    current: *const Metadata, slot: u32, image_meta: *const ImageMetadata, next: *mut Metadata,
+    build: fn(&Metadata, usize, ImageMetadata) -> Result<Metadata, os_client::Error>,
+) -> i32 {
+    if current.is_null() || image_meta.is_null() || next.is_null() {
+        return MOVELOADER_ERROR_NULL;
+    }
+
+    match build(&*current, slot as usize, *image_meta) {
+        Ok(metadata) => {
+            next.write(metadata);
+            MOVELOADER_OK
+        }
+        Err(os_client::Error::InvalidSlot) => MOVELOADER_ERROR_INVALID_SLOT,
+        Err(_) => MOVELOADER_ERROR_INVALID_VERSION,
+    }
+}
+
+/// See interface::mailbox. `registers` is only written for a valid command.
+///
+/// # Safety
+/// `registers` must be NULL or valid for writes of MAILBOX_SIZE words
+#[no_mangle]
+pub unsafe extern "C" fn moveloader_mailbox_encode(
+    command: u32, argument: u32, registers: *mut [u32; MAILBOX_SIZE],
+) -> i32 {
+    if registers.is_null() {
+        return MOVELOADER_ERROR_NULL;
+    }
+
+    match MailboxCommand::new(command, argument) {
+        Some(command) => {
+            registers.write(command.encode());
+            MOVELOADER_OK
+        }
+        None => MOVELOADER_ERROR_INVALID_COMMAND,
+    }
+}
+
+/// See interface::bootinfo. `out` is only written for an intact BootInfo.
+///
+/// # Safety
+/// `registers` must be NULL or valid for reads of BOOT_INFO_SIZE words,
+/// `out` must be NULL or valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn moveloader_boot_info(
+    registers: *const [u32; BOOT_INFO_SIZE], out: *mut BootInfo,
+) -> i32 {
+    if registers.is_null() || out.is_null() {
+        return MOVELOADER_ERROR_NULL;
+    }
+
+    match BootInfo::from_registers(&*registers) {
+        Some(info) => {
+            out.write(info);
+            MOVELOADER_OK
+        }
+        None => MOVELOADER_ERROR_INVALID_BOOT_INFO,
+    }
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+#[cfg(test)]
+mod tests {
+    use core::mem::MaybeUninit;
+    use core::ptr::null;
+
+    use interface::bootinfo::{BootReason, MetadataStatus, SlotStatus};
+    use interface::mailbox::{MAILBOX_BOOT_ONCE, MAILBOX_CONFIRM_BOOT};
+    use interface::NUMBER_OF_IMAGES;
+
+    use super::*;
+
+    #[test]
+    fn matches_rust_implementation() {
+        let image = [0x61, 0x65, 0x6e, 0x67, 0x65, 0x6c, 0x6b, 0x65];
+        unsafe {
+            assert_eq!(moveloader_crc32(image.as_ptr(), image.len()), 0x7909E7C4);
+            assert_eq!(moveloader_crc32(null(), 0), 0);
+
+            let mut image_meta = MaybeUninit::uninit();
+            moveloader_image_metadata(image.as_ptr(), image.len(), 3, image_meta.as_mut_ptr());
+            assert_eq!(image_meta.assume_init(), os_client::image_metadata(&image, 3));
+
+            let mut trailer = MaybeUninit::uninit();
+            moveloader_image_trailer(image.as_ptr(), image.len(), 3, trailer.as_mut_ptr());
+            assert_eq!(trailer.assume_init(), ImageTrailer::new(&image, 3));
+        }
+    }
+
+    #[test]
+    fn builds_next_metadata() {
+        let mut current = Metadata {
+            version: 4,
+            bootcounter: 0,
+            preferred_image: 0,
+            images: [ImageMetadata::default(); NUMBER_OF_IMAGES],
+            crc: 0,
+        };
+        let image_meta = os_client::image_metadata(&[1, 2, 3], 2);
+
+        unsafe {
+            assert!(!moveloader_metadata_is_valid(&current));
+            moveloader_metadata_set_crc(&mut current);
+            assert!(moveloader_metadata_is_valid(&current));
+
+            let mut next = MaybeUninit::uninit();
+            let result = moveloader_next_metadata(&current, 1, &image_meta, next.as_mut_ptr());
+            assert_eq!(result, MOVELOADER_OK);
+            assert_eq!(
+                next.assume_init(),
+                os_client::next_metadata(&current, 1, image_meta).unwrap()
+            );
+
+            let result = moveloader_next_metadata(&current, 3, &image_meta, next.as_mut_ptr());
+            assert_eq!(result, MOVELOADER_ERROR_INVALID_SLOT);
+            let result = moveloader_next_metadata(&current, 0, null(), next.as_mut_ptr());
+            assert_eq!(result, MOVELOADER_ERROR_NULL);
+
+            // The trial image must not replace the preferred one
+            let result = moveloader_staged_metadata(&current, 0, &image_meta, next.as_mut_ptr());
+            assert_eq!(result, MOVELOADER_ERROR_INVALID_SLOT);
+            let result = moveloader_staged_metadata(&current, 2, &image_meta, next.as_mut_ptr());
+            assert_eq!(result, MOVELOADER_OK);
+            assert_eq!(next.assume_init().preferred_image, 0);
+
+            current.version = u32::MAX - 1;
+            let result = moveloader_next_metadata(&current, 0, &image_meta, next.as_mut_ptr());
+            assert_eq!(result, MOVELOADER_ERROR_INVALID_VERSION);
+        }
+    }
+
+    #[test]
+    fn decodes_boot_info() {
+        let info = BootInfo::new(
+            0,
+            2,
+            BootReason::Trial,
+            5,
+            MetadataStatus::Valid,
+            [SlotStatus::NotChecked, SlotStatus::NotChecked, SlotStatus::Valid],
+        );
+        let mut registers = info.to_registers();
+        let mut out = MaybeUninit::uninit();
+        unsafe {
+            assert_eq!(moveloader_boot_info(&registers, out.as_mut_ptr()), MOVELOADER_OK);
+            assert_eq!(out.assume_init(), info);
+
+            registers[3] = 1;
+            let result = moveloader_boot_info(&registers, out.as_mut_ptr());
+            assert_eq!(result, MOVELOADER_ERROR_INVALID_BOOT_INFO);
+            assert_eq!(moveloader_boot_info(null(), out.as_mut_ptr()), MOVELOADER_ERROR_NULL);
+        }
+    }
+
+    #[test]
+    fn encodes_mailbox_commands() {
+        let mut registers = [0; MAILBOX_SIZE];
+        unsafe {
+            let result = moveloader_mailbox_encode(MAILBOX_BOOT_ONCE, 2, &mut registers);
+            assert_eq!(result, MOVELOADER_OK);
+            assert_eq!(
+                MailboxCommand::decode(&registers),
+                Some(MailboxCommand::BootOnce { slot: 2 })
+            );
+
+            let result = moveloader_mailbox_encode(MAILBOX_CONFIRM_BOOT, 2, &mut registers);
+            assert_eq!(result, MOVELOADER_ERROR_INVALID_COMMAND);
+        }
+    }
+}
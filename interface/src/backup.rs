@@ -0,0 +1,52 @@
+// Layout of the RTC backup registers that are shared between the bootloader and the OS.
+// Backup registers keep their value across resets (but not across a loss of VDD/VBAT),
+// which makes them a good place for state that changes on every boot: unlike the
+// metadata pages, writing them does not wear out the flash.
+
+/// Number of 32-bit backup registers on the STM32L4R5 (RTC_BKP0R - RTC_BKP31R)
+pub const NUMBER_OF_BACKUP_REGISTERS: usize = 32;
+
+// Registers 0 and 1 were used for soft reboot requests before there was the mailbox (see
+// mailbox.rs, which has the commands for the bootloader). They are not used anymore, so an
+// old request is simply ignored.
+
+// Boot attempt counting.
+// Before jumping to an image, the bootloader increments the attempt counter of its slot.
+// Once the OS is up and running, it MUST confirm the boot by writing BOOT_CONFIRM_MAGIC
+// to BOOT_CONFIRM_REG, which resets the counter of the booted slot on the next boot.
+// A slot that reaches MAX_BOOT_ATTEMPTS unconfirmed boots in a row is skipped in favor of
+// the next slot with a valid image.
+
+/// Contains BOOT_STATE_MAGIC if the registers below were written by the bootloader.
+/// Anything else (e.g. after a power-on reset) means all counters are reset to zero.
+pub const BOOT_STATE_REG: usize = 2;
+pub const BOOT_STATE_MAGIC: u32 = 0xB007_C0DE;
+
+/// The slot index that was booted last
+pub const BOOT_SLOT_REG: usize = 3;
+
+/// Written by the OS to confirm that the last boot was successful
+pub const BOOT_CONFIRM_REG: usize = 4;
+pub const BOOT_CONFIRM_MAGIC: u32 = 0x600D_B007;
+
+/// First of NUMBER_OF_IMAGES registers with the number of unconfirmed boot attempts per slot
+pub const BOOT_ATTEMPTS_REG: usize = 5;
+
+/// Number of unconfirmed boots after which an image is considered broken
+pub const MAX_BOOT_ATTEMPTS: u32 = 3;
+
+mod asserts {
+    use super::*;
+    use crate::mailbox::MAILBOX_REG;
+    use crate::NUMBER_OF_IMAGES;
+    use static_assertions::const_assert;
+
+    // Registers 0 and 1 stay unused, see above
+    const_assert!(1 < BOOT_STATE_REG);
+    const_assert!(BOOT_STATE_REG < BOOT_SLOT_REG);
+    const_assert!(BOOT_SLOT_REG < BOOT_CONFIRM_REG);
+    const_assert!(BOOT_CONFIRM_REG < BOOT_ATTEMPTS_REG);
+    const_assert!(BOOT_ATTEMPTS_REG + NUMBER_OF_IMAGES <= MAILBOX_REG);
+
+    const_assert!(MAX_BOOT_ATTEMPTS > 0);
+}
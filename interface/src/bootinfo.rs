@@ -0,0 +1,326 @@
+// Before jumping to an image, the bootloader leaves a BootInfo in the RTC backup registers (see
+// backup.rs), so the OS can find out which slot it was booted from and why. The registers
+// survive the jump and resets, but the BootInfo is written again on every boot.
+//
+// The BootInfo has its own magic number, version and CRC, so the OS can tell it apart from the
+// garbage after a power-on reset and from a BootInfo in a layout it doesn't know.
+
+use core::mem::size_of;
+
+use crate::backup::NUMBER_OF_BACKUP_REGISTERS;
+use crate::crc::calc_crc32;
+use crate::reset::ResetCause;
+use crate::NUMBER_OF_IMAGES;
+
+pub const BOOT_INFO_MAGIC: u32 = 0x424f_4f54; // "BOOT"
+
+// Increment this if the layout of BootInfo changes
+pub const BOOT_INFO_VERSION: u32 = 1;
+
+/// First of the BOOT_INFO_SIZE registers that hold the BootInfo
+pub const BOOT_INFO_REG: usize = 11;
+pub const BOOT_INFO_SIZE: usize = size_of::<BootInfo>() / size_of::<u32>();
+
+/// The slot of a BootInfo for the golden image, which is not in one of the slots
+pub const BOOT_INFO_NO_SLOT: u32 = u32::MAX;
+
+/// The ECC fault address of a BootInfo if the flash reported no uncorrectable ECC error
+pub const BOOT_INFO_NO_ECC_FAULT: u32 = u32::MAX;
+
+/// Flag in BootInfo::timeouts: a flash operation timed out, e.g. while the metadata was repaired
+pub const BOOT_INFO_FLASH_TIMEOUT: u32 = 1 << 0;
+/// Flag in BootInfo::timeouts: the watchdog didn't start in time, so it might not reset the image
+/// if it hangs
+pub const BOOT_INFO_WATCHDOG_TIMEOUT: u32 = 1 << 1;
+
+/// Why the bootloader booted the image
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootReason {
+    /// The preferred image from the metadata
+    Preferred = 1,
+    /// Another image from the metadata, as the preferred one was invalid or used up its attempts
+    Fallback = 2,
+    /// An image the boot policy chose without verification (see boot_core::policy)
+    Unverified = 3,
+    /// A trial boot requested with MailboxCommand::BootOnce
+    Trial = 4,
+    /// The newest image trailer, as there was no valid metadata
+    Trailer = 5,
+    /// The golden image
+    Golden = 6,
+}
+
+/// The state of the metadata pages when the bootloader selected the metadata
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataStatus {
+    /// Both pages were valid and up to date
+    Valid = 1,
+    /// A page was corrupted, outdated or in the legacy layout, and was rewritten
+    Repaired = 2,
+    /// A page needed to be rewritten, but writing it failed
+    RepairFailed = 3,
+    /// Neither page was valid
+    Invalid = 4,
+    /// A page needed to be rewritten, but the flash is write protected
+    WriteProtected = 5,
+}
+
+/// What the bootloader found out about a slot while selecting the image
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotStatus {
+    /// The slot was not needed, e.g. because the preferred image was valid
+    NotChecked = 0,
+    /// The image matches its metadata (or its trailer)
+    Valid = 1,
+    /// The image doesn't match its CRC or digest
+    Invalid = 2,
+    /// The image used up its boot attempts, so it was not checked
+    Exhausted = 3,
+    /// The image matches its CRC, but its signature is missing or invalid
+    Untrusted = 4,
+    /// The flash reported an uncorrectable ECC error in the slot while it was read
+    EccError = 5,
+    /// The image was selected, but copying or decompressing it into RAM failed
+    LoadFailed = 6,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootInfo {
+    // Must be BOOT_INFO_MAGIC
+    pub magic: u32,
+    pub version: u32,
+    // The reset flags from RCC_CSR the bootloader found, see reset.rs
+    pub reset_flags: u32,
+    // The booted slot, or BOOT_INFO_NO_SLOT for the golden image
+    pub slot: u32,
+    pub reason: BootReason,
+    // The version of the selected metadata, 0 if there was none
+    pub metadata_version: u32,
+    pub metadata_status: MetadataStatus,
+    pub slot_status: [SlotStatus; NUMBER_OF_IMAGES],
+    // The address of the last uncorrectable ECC error the flash reported, or
+    // BOOT_INFO_NO_ECC_FAULT. Any slot it is in was not booted.
+    pub ecc_fault_address: u32,
+    // How often the flash corrected a single-bit error, an early warning that it wears out
+    pub ecc_corrections: u32,
+    // The BOOT_INFO_*_TIMEOUT flags of the hardware that didn't respond in time. The image was
+    // booted anyway, as resetting wouldn't make the hardware work again.
+    pub timeouts: u32,
+    // a CRC over the previous part of the struct, but not the CRC field
+    pub crc: u32,
+}
+
+mod asserts {
+    use super::*;
+    use crate::mailbox::{MAILBOX_REG, MAILBOX_SIZE};
+    use static_assertions::const_assert;
+
+    const_assert!(size_of::<BootInfo>() == 4 * (11 + NUMBER_OF_IMAGES));
+    const_assert!(MAILBOX_REG + MAILBOX_SIZE <= BOOT_INFO_REG);
+    const_assert!(BOOT_INFO_REG + BOOT_INFO_SIZE <= NUMBER_OF_BACKUP_REGISTERS);
+}
+
+impl BootInfo {
+    /// Creates a BootInfo with a valid CRC, without any ECC errors or timeouts
+    pub fn new(
+        reset_flags: u32, slot: u32, reason: BootReason, metadata_version: u32,
+        metadata_status: MetadataStatus, slot_status: [SlotStatus; NUMBER_OF_IMAGES],
+    ) -> Self {
+        let mut info = BootInfo {
+            magic: BOOT_INFO_MAGIC,
+            version: BOOT_INFO_VERSION,
+            reset_flags,
+            slot,
+            reason,
+            metadata_version,
+            metadata_status,
+            slot_status,
+            ecc_fault_address: BOOT_INFO_NO_ECC_FAULT,
+            ecc_corrections: 0,
+            timeouts: 0,
+            crc: 0,
+        };
+        info.set_crc();
+        info
+    }
+
+    pub fn calc_crc(&self) -> u32 {
+        let registers = self.to_registers();
+        let length = (BOOT_INFO_SIZE - 1) * size_of::<u32>();
+        calc_crc32(registers.as_ptr() as *const u8, length)
+    }
+
+    pub fn set_crc(&mut self) {
+        self.crc = self.calc_crc();
+    }
+
+    pub fn reset_cause(&self) -> ResetCause {
+        ResetCause::from_rcc_csr(self.reset_flags)
+    }
+
+    /// The values of the backup registers, starting at BOOT_INFO_REG
+    pub fn to_registers(&self) -> [u32; BOOT_INFO_SIZE] {
+        let mut registers = [0; BOOT_INFO_SIZE];
+        registers[..7].copy_from_slice(&[
+            self.magic,
+            self.version,
+            self.reset_flags,
+            self.slot,
+            self.reason as u32,
+            self.metadata_version,
+            self.metadata_status as u32,
+        ]);
+        for (register, status) in registers[7..].iter_mut().zip(self.slot_status) {
+            *register = status as u32;
+        }
+        registers[7 + NUMBER_OF_IMAGES] = self.ecc_fault_address;
+        registers[8 + NUMBER_OF_IMAGES] = self.ecc_corrections;
+        registers[9 + NUMBER_OF_IMAGES] = self.timeouts;
+        registers[BOOT_INFO_SIZE - 1] = self.crc;
+        registers
+    }
+
+    /// Decodes the values of the backup registers.
+    /// Returns None if they don't hold an intact BootInfo in a layout we know.
+    pub fn from_registers(registers: &[u32; BOOT_INFO_SIZE]) -> Option<Self> {
+        let [magic, version, reset_flags, slot, reason, metadata_version, metadata_status, ..] =
+            *registers;
+        if magic != BOOT_INFO_MAGIC || version != BOOT_INFO_VERSION {
+            return None;
+        }
+
+        let mut slot_status = [SlotStatus::NotChecked; NUMBER_OF_IMAGES];
+        for (status, &register) in slot_status.iter_mut().zip(&registers[7..]) {
+            *status = SlotStatus::from_u32(register)?;
+        }
+
+        let info = BootInfo {
+            magic,
+            version,
+            reset_flags,
+            slot,
+            reason: BootReason::from_u32(reason)?,
+            metadata_version,
+            metadata_status: MetadataStatus::from_u32(metadata_status)?,
+            slot_status,
+            ecc_fault_address: registers[7 + NUMBER_OF_IMAGES],
+            ecc_corrections: registers[8 + NUMBER_OF_IMAGES],
+            timeouts: registers[9 + NUMBER_OF_IMAGES],
+            crc: registers[BOOT_INFO_SIZE - 1],
+        };
+
+        if info.crc == info.calc_crc() {
+            Some(info)
+        } else {
+            None
+        }
+    }
+}
+
+impl BootReason {
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(BootReason::Preferred),
+            2 => Some(BootReason::Fallback),
+            3 => Some(BootReason::Unverified),
+            4 => Some(BootReason::Trial),
+            5 => Some(BootReason::Trailer),
+            6 => Some(BootReason::Golden),
+            _ => None,
+        }
+    }
+}
+
+impl MetadataStatus {
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(MetadataStatus::Valid),
+            2 => Some(MetadataStatus::Repaired),
+            3 => Some(MetadataStatus::RepairFailed),
+            4 => Some(MetadataStatus::Invalid),
+            5 => Some(MetadataStatus::WriteProtected),
+            _ => None,
+        }
+    }
+}
+
+impl SlotStatus {
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(SlotStatus::NotChecked),
+            1 => Some(SlotStatus::Valid),
+            2 => Some(SlotStatus::Invalid),
+            3 => Some(SlotStatus::Exhausted),
+            4 => Some(SlotStatus::Untrusted),
+            5 => Some(SlotStatus::EccError),
+            6 => Some(SlotStatus::LoadFailed),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_info() -> BootInfo {
+        BootInfo::new(
+            0x0c00_0000,
+            1,
+            BootReason::Fallback,
+            42,
+            MetadataStatus::Repaired,
+            [SlotStatus::Invalid, SlotStatus::Valid, SlotStatus::NotChecked],
+        )
+    }
+
+    #[test]
+    fn boot_info_roundtrip() {
+        let mut info = test_info();
+        assert_eq!(BootInfo::from_registers(&info.to_registers()), Some(info));
+
+        info.slot_status[0] = SlotStatus::EccError;
+        info.ecc_fault_address = 0x0c01_2340;
+        info.ecc_corrections = 3;
+        info.timeouts = BOOT_INFO_FLASH_TIMEOUT | BOOT_INFO_WATCHDOG_TIMEOUT;
+        info.set_crc();
+        assert_eq!(BootInfo::from_registers(&info.to_registers()), Some(info));
+        assert_eq!(info.reset_cause(), ResetCause::PowerOn);
+
+        let golden = BootInfo::new(
+            0,
+            BOOT_INFO_NO_SLOT,
+            BootReason::Golden,
+            0,
+            MetadataStatus::Invalid,
+            [SlotStatus::NotChecked; NUMBER_OF_IMAGES],
+        );
+        assert_eq!(BootInfo::from_registers(&golden.to_registers()), Some(golden));
+    }
+
+    #[test]
+    fn reject_invalid_boot_info() {
+        // After a power-on reset
+        assert_eq!(BootInfo::from_registers(&[0; BOOT_INFO_SIZE]), None);
+
+        let mut registers = test_info().to_registers();
+        registers[5] = 43;
+        assert_eq!(BootInfo::from_registers(&registers), None);
+
+        // A newer layout
+        let mut registers = test_info().to_registers();
+        registers[1] = BOOT_INFO_VERSION + 1;
+        assert_eq!(BootInfo::from_registers(&registers), None);
+
+        // An unknown status must not end up in an enum, even with a valid CRC
+        let mut registers = test_info().to_registers();
+        registers[7] = 7;
+        registers[BOOT_INFO_SIZE - 1] =
+            calc_crc32(registers.as_ptr() as *const u8, (BOOT_INFO_SIZE - 1) * 4);
+        assert_eq!(BootInfo::from_registers(&registers), None);
+    }
+}
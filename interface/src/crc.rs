@@ -1,6 +1,7 @@
-const DEFAULT_POLYNOM: u32 = 0x82F63B78;
-const CRC_INITIAL_VALUE: u32 = 0xFFFFFFFF;
-const CRC_FINAL_XOR_VALUE: u32 = 0xFFFFFFFF;
+// CRC32-C, processed LSB first, so the polynomial is the bit reverse of 0x1EDC6F41
+pub const DEFAULT_POLYNOM: u32 = 0x82F63B78;
+pub const CRC_INITIAL_VALUE: u32 = 0xFFFFFFFF;
+pub const CRC_FINAL_XOR_VALUE: u32 = 0xFFFFFFFF;
 
 /// Calculate CRC32-C on a memory buffer
 pub fn calc_crc32(message: *const u8, length: usize) -> u32 {
@@ -0,0 +1,361 @@
+// Delta updates: instead of a whole image, only a patch against an image that is already in
+// another slot is sent to the device. The patch is a PatchHeader followed by a list of
+// operations, which build the new image from pieces of the old one and new bytes:
+//
+// - Copy: OP_COPY, followed by the offset in the old image and the length
+// - Insert: OP_INSERT, followed by the length and the bytes themselves
+//
+// All numbers are u32 in little endian. The header has the length and CRC of the old image the
+// patch was made for and of the new image it creates, so a patch is never applied to the wrong
+// image, and the result is checked before its metadata is committed.
+// The image-builder creates patches with `diff-package`, os_client::apply_patch applies them.
+
+use core::mem::size_of;
+
+use crate::crc::calc_crc32;
+
+pub const PATCH_MAGIC: u32 = 0x5041_5443; // "PATC"
+
+// Increment this if the layout of PatchHeader or the operations change
+pub const PATCH_VERSION: u32 = 1;
+
+pub const OP_COPY: u8 = 1;
+pub const OP_INSERT: u8 = 2;
+
+// The encoded length of a copy, and of an insert without its bytes
+pub const COPY_SIZE: usize = 1 + 2 * size_of::<u32>();
+pub const INSERT_HEADER_SIZE: usize = 1 + size_of::<u32>();
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatchHeader {
+    pub magic: u32,
+    pub format_version: u32,
+    // Length and CRC32-C of the image the patch applies to
+    pub source_length: u32,
+    pub source_crc: u32,
+    // Length and CRC32-C of the image the patch creates
+    pub target_length: u32,
+    pub target_crc: u32,
+    // Length and CRC32-C of the operations following the header
+    pub operations_length: u32,
+    pub operations_crc: u32,
+}
+
+/// Offset of the operations from the start of a patch
+pub const PATCH_HEADER_SIZE: usize = size_of::<PatchHeader>();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchError {
+    // The patch doesn't start with a PatchHeader we know
+    InvalidHeader,
+    // The operations are cut off or don't match their CRC
+    Corrupted,
+    // An operation we don't know, or one ending in the middle
+    InvalidOperation,
+    // A copy from outside of the old image
+    CopyOutOfRange,
+    // The operations create more bytes than the new image has
+    TooLarge,
+    // The operations create fewer bytes than the new image has
+    TooShort,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation<'a> {
+    /// Copy `length` bytes from `offset` in the old image
+    Copy { offset: u32, length: u32 },
+    /// Insert new bytes
+    Insert(&'a [u8]),
+}
+
+impl PatchHeader {
+    /// Creates the header for a patch from `source` to `target` with the encoded `operations`
+    pub fn new(source: &[u8], target: &[u8], operations: &[u8]) -> Self {
+        PatchHeader {
+            magic: PATCH_MAGIC,
+            format_version: PATCH_VERSION,
+            source_length: source.len() as u32,
+            source_crc: calc_crc32(source.as_ptr(), source.len()),
+            target_length: target.len() as u32,
+            target_crc: calc_crc32(target.as_ptr(), target.len()),
+            operations_length: operations.len() as u32,
+            operations_crc: calc_crc32(operations.as_ptr(), operations.len()),
+        }
+    }
+
+    /// Reads the header at the start of `patch`, if there is one we know
+    pub fn read(patch: &[u8]) -> Option<Self> {
+        let bytes = patch.get(..PATCH_HEADER_SIZE)?;
+        // The patch is usually received into a byte buffer without any alignment
+        let header = unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const Self) };
+
+        (header.magic == PATCH_MAGIC && header.format_version == PATCH_VERSION).then_some(header)
+    }
+}
+
+impl Operation<'_> {
+    /// The number of bytes the operation adds to the new image
+    pub fn output_length(&self) -> u32 {
+        match self {
+            Operation::Copy { length, .. } => *length,
+            Operation::Insert(bytes) => bytes.len() as u32,
+        }
+    }
+
+    pub fn encoded_length(&self) -> usize {
+        match self {
+            Operation::Copy { .. } => COPY_SIZE,
+            Operation::Insert(bytes) => INSERT_HEADER_SIZE + bytes.len(),
+        }
+    }
+
+    /// Encodes the operation at the start of `out`. Returns the encoded length, or None if it
+    /// doesn't fit.
+    pub fn encode(&self, out: &mut [u8]) -> Option<usize> {
+        let out = out.get_mut(..self.encoded_length())?;
+        match *self {
+            Operation::Copy { offset, length } => {
+                out[0] = OP_COPY;
+                out[1..5].copy_from_slice(&offset.to_le_bytes());
+                out[5..9].copy_from_slice(&length.to_le_bytes());
+            }
+            Operation::Insert(bytes) => {
+                out[0] = OP_INSERT;
+                out[1..5].copy_from_slice(&(bytes.len() as u32).to_le_bytes());
+                out[INSERT_HEADER_SIZE..].copy_from_slice(bytes);
+            }
+        }
+
+        Some(out.len())
+    }
+}
+
+/// A patch whose header and operations CRC were checked
+#[derive(Debug, Clone, Copy)]
+pub struct Patch<'a> {
+    pub header: PatchHeader,
+    operations: &'a [u8],
+}
+
+impl<'a> Patch<'a> {
+    pub fn parse(patch: &'a [u8]) -> Result<Self, PatchError> {
+        let header = PatchHeader::read(patch).ok_or(PatchError::InvalidHeader)?;
+        let operations = patch
+            .get(PATCH_HEADER_SIZE..PATCH_HEADER_SIZE + header.operations_length as usize)
+            .ok_or(PatchError::Corrupted)?;
+        if calc_crc32(operations.as_ptr(), operations.len()) != header.operations_crc {
+            return Err(PatchError::Corrupted);
+        }
+
+        Ok(Patch { header, operations })
+    }
+
+    /// The operations in order. Each one is checked against the lengths in the header, and
+    /// the last item is an error if they don't add up to the new image.
+    pub fn operations(&self) -> Operations<'a> {
+        Operations {
+            input: self.operations,
+            source_length: self.header.source_length,
+            target_length: self.header.target_length,
+            output_length: 0,
+            done: false,
+        }
+    }
+
+    /// Applies the patch to `source` in memory and returns the length of the new image.
+    /// The new image is not checked against the CRC in the header.
+    pub fn apply(&self, source: &[u8], output: &mut [u8]) -> Result<usize, PatchError> {
+        let source =
+            source.get(..self.header.source_length as usize).ok_or(PatchError::TooShort)?;
+        let mut output_pos = 0;
+
+        for operation in self.operations() {
+            let bytes = match operation? {
+                Operation::Copy { offset, length } => {
+                    &source[offset as usize..(offset + length) as usize]
+                }
+                Operation::Insert(bytes) => bytes,
+            };
+            output
+                .get_mut(output_pos..output_pos + bytes.len())
+                .ok_or(PatchError::TooLarge)?
+                .copy_from_slice(bytes);
+            output_pos += bytes.len();
+        }
+
+        Ok(output_pos)
+    }
+}
+
+pub struct Operations<'a> {
+    input: &'a [u8],
+    source_length: u32,
+    target_length: u32,
+    output_length: u32,
+    done: bool,
+}
+
+impl<'a> Operations<'a> {
+    fn next_operation(&mut self) -> Result<Operation<'a>, PatchError> {
+        let (&op, rest) = self.input.split_first().ok_or(PatchError::InvalidOperation)?;
+
+        let (operation, rest) = match op {
+            OP_COPY => {
+                let (offset, rest) = read_u32(rest)?;
+                let (length, rest) = read_u32(rest)?;
+                if offset.checked_add(length).is_none_or(|end| end > self.source_length) {
+                    return Err(PatchError::CopyOutOfRange);
+                }
+                (Operation::Copy { offset, length }, rest)
+            }
+            OP_INSERT => {
+                let (length, rest) = read_u32(rest)?;
+                let bytes = rest.get(..length as usize).ok_or(PatchError::InvalidOperation)?;
+                (Operation::Insert(bytes), &rest[length as usize..])
+            }
+            _ => return Err(PatchError::InvalidOperation),
+        };
+
+        self.output_length = self
+            .output_length
+            .checked_add(operation.output_length())
+            .filter(|&output_length| output_length <= self.target_length)
+            .ok_or(PatchError::TooLarge)?;
+        self.input = rest;
+
+        Ok(operation)
+    }
+}
+
+impl<'a> Iterator for Operations<'a> {
+    type Item = Result<Operation<'a>, PatchError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        if self.input.is_empty() {
+            self.done = true;
+            return (self.output_length != self.target_length).then_some(Err(PatchError::TooShort));
+        }
+
+        let operation = self.next_operation();
+        self.done = operation.is_err();
+        Some(operation)
+    }
+}
+
+fn read_u32(input: &[u8]) -> Result<(u32, &[u8]), PatchError> {
+    let bytes = input.get(..4).ok_or(PatchError::InvalidOperation)?;
+    Ok((u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]), &input[4..]))
+}
+
+mod asserts {
+    use super::*;
+    use static_assertions::const_assert_eq;
+
+    const_assert_eq!(PATCH_HEADER_SIZE, 32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: [u8; 16] = *b"0123456789abcdef";
+    const TARGET: [u8; 14] = *b"0123xyz89abcde";
+
+    // Encodes the operations and the header for them into `patch`, returns the patch length
+    fn encode_patch(target: &[u8], operations: &[Operation], patch: &mut [u8]) -> usize {
+        let mut length = PATCH_HEADER_SIZE;
+        for operation in operations {
+            length += operation.encode(&mut patch[length..]).unwrap();
+        }
+
+        let header = PatchHeader::new(&SOURCE, target, &patch[PATCH_HEADER_SIZE..length]);
+        patch[..PATCH_HEADER_SIZE].copy_from_slice(unsafe {
+            core::slice::from_raw_parts(&header as *const _ as *const u8, PATCH_HEADER_SIZE)
+        });
+
+        length
+    }
+
+    const OPERATIONS: [Operation; 3] = [
+        Operation::Copy { offset: 0, length: 4 },
+        Operation::Insert(b"xyz"),
+        Operation::Copy { offset: 8, length: 7 },
+    ];
+
+    #[test]
+    fn apply_patch() {
+        let mut patch = [0u8; 128];
+        let length = encode_patch(&TARGET, &OPERATIONS, &mut patch);
+        assert_eq!(length, PATCH_HEADER_SIZE + 2 * COPY_SIZE + INSERT_HEADER_SIZE + 3);
+
+        let patch = Patch::parse(&patch[..length]).unwrap();
+        let mut operations = patch.operations();
+        for operation in OPERATIONS {
+            assert_eq!(operations.next(), Some(Ok(operation)));
+        }
+        assert_eq!(operations.next(), None);
+
+        let mut output = [0u8; 32];
+        assert_eq!(patch.apply(&SOURCE, &mut output), Ok(TARGET.len()));
+        assert_eq!(output[..TARGET.len()], TARGET);
+        assert_eq!(patch.header.target_crc, calc_crc32(TARGET.as_ptr(), TARGET.len()));
+
+        // The old image must be complete
+        assert_eq!(patch.apply(&SOURCE[..15], &mut output), Err(PatchError::TooShort));
+    }
+
+    #[test]
+    fn reject_corrupted_patch() {
+        let mut patch = [0u8; 128];
+        let length = encode_patch(&TARGET, &OPERATIONS, &mut patch);
+
+        let mut broken = patch;
+        broken[PATCH_HEADER_SIZE + 2] ^= 1;
+        assert_eq!(Patch::parse(&broken[..length]).err(), Some(PatchError::Corrupted));
+        assert_eq!(Patch::parse(&patch[..length - 1]).err(), Some(PatchError::Corrupted));
+
+        let mut broken = patch;
+        broken[0] = 0;
+        assert_eq!(Patch::parse(&broken[..length]).err(), Some(PatchError::InvalidHeader));
+    }
+
+    #[test]
+    fn reject_invalid_operations() {
+        let mut patch = [0u8; 128];
+        let mut check = |target: &[u8], operations: &[Operation], error| {
+            let length = encode_patch(target, operations, &mut patch);
+            let patch = Patch::parse(&patch[..length]).unwrap();
+            assert_eq!(patch.operations().last(), Some(Err(error)));
+            assert_eq!(patch.operations().filter(|operation| operation.is_err()).count(), 1);
+        };
+
+        check(&TARGET, &[Operation::Copy { offset: 10, length: 7 }], PatchError::CopyOutOfRange);
+        check(
+            &TARGET,
+            &[Operation::Copy { offset: u32::MAX, length: 2 }],
+            PatchError::CopyOutOfRange,
+        );
+        check(&TARGET[..5], &OPERATIONS, PatchError::TooLarge);
+        check(&TARGET, &OPERATIONS[..2], PatchError::TooShort);
+
+        // An unknown operation, and one that is cut off
+        let mut patch = [0u8; 128];
+        for operations in [&[3u8, 0, 0, 0, 0][..], &[OP_INSERT, 4, 0, 0, 0, b'x'][..]] {
+            patch[PATCH_HEADER_SIZE..PATCH_HEADER_SIZE + operations.len()]
+                .copy_from_slice(operations);
+            let length = PATCH_HEADER_SIZE + operations.len();
+            let header = PatchHeader::new(&SOURCE, &TARGET, &patch[PATCH_HEADER_SIZE..length]);
+            patch[..PATCH_HEADER_SIZE].copy_from_slice(unsafe {
+                core::slice::from_raw_parts(&header as *const _ as *const u8, PATCH_HEADER_SIZE)
+            });
+
+            let parsed = Patch::parse(&patch[..length]).unwrap();
+            assert_eq!(parsed.operations().next(), Some(Err(PatchError::InvalidOperation)));
+        }
+    }
+}
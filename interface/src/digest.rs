@@ -0,0 +1,155 @@
+// Optionally, the metadata pages also store a SHA-256 digest for each image. The CRC only
+// protects against random bit errors, the digest against anything else that changes an image.
+//
+// The digests are stored in their own versioned record directly after the Metadata on the same
+// page (in both metadata page layouts, see MetadataLayout), so the Metadata stays the same.
+// Bootloaders and OS images that don't know about digests simply ignore the record. The record
+// belongs to exactly one Metadata (metadata_crc), so a digest is never checked against the images
+// of another metadata version.
+
+use core::mem::size_of;
+
+use crate::crc::calc_crc32;
+use crate::sha256::DIGEST_SIZE;
+use crate::{Metadata, MetadataLayout, NUMBER_OF_IMAGES};
+
+pub const DIGESTS_MAGIC: u32 = 0x4447_5354; // "DGST"
+
+// Increment this if the layout of ImageDigests changes
+pub const DIGESTS_FORMAT_VERSION: u32 = 1;
+
+/// Offset of the ImageDigests from the start of a metadata page, as it is written now
+pub const DIGESTS_OFFSET: u32 = MetadataLayout::Current.digests_offset();
+
+#[repr(C, align(8))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageDigests {
+    // Must be DIGESTS_MAGIC, anything else means there are no digests
+    pub magic: u32,
+    pub format_version: u32,
+    // The CRC of the Metadata these digests belong to
+    pub metadata_crc: u32,
+    // Bit i is set if images[i] has a digest, all other digests are ignored
+    pub present: u32,
+    pub digests: [[u8; DIGEST_SIZE]; NUMBER_OF_IMAGES],
+    // a CRC over the previous part of the struct, but not the CRC field
+    pub crc: u32,
+    pub reserved: u32,
+}
+
+impl ImageDigests {
+    /// Creates the digest record for `metadata`, with a digest for every image that has one
+    pub fn new(
+        metadata: &Metadata, digests: [Option<[u8; DIGEST_SIZE]>; NUMBER_OF_IMAGES],
+    ) -> Self {
+        let mut record = ImageDigests {
+            magic: DIGESTS_MAGIC,
+            format_version: DIGESTS_FORMAT_VERSION,
+            metadata_crc: metadata.crc,
+            present: 0,
+            digests: [[0; DIGEST_SIZE]; NUMBER_OF_IMAGES],
+            crc: 0,
+            reserved: 0,
+        };
+
+        for (i, digest) in digests.iter().enumerate() {
+            if let Some(digest) = digest {
+                record.present |= 1 << i;
+                record.digests[i] = *digest;
+            }
+        }
+
+        record.crc = record.calc_crc();
+        record
+    }
+
+    /// Whether this is an intact record that belongs to `metadata`
+    pub fn is_valid_for(&self, metadata: &Metadata) -> bool {
+        self.magic == DIGESTS_MAGIC
+            && self.format_version == DIGESTS_FORMAT_VERSION
+            && self.metadata_crc == metadata.crc
+            && self.crc == self.calc_crc()
+    }
+
+    /// The digest of the image with the given index, if it has one
+    pub fn digest(&self, index: usize) -> Option<&[u8; DIGEST_SIZE]> {
+        if index < NUMBER_OF_IMAGES && self.present & (1 << index) != 0 {
+            Some(&self.digests[index])
+        } else {
+            None
+        }
+    }
+
+    pub fn calc_crc(&self) -> u32 {
+        const CRC_OFFSET: usize = size_of::<ImageDigests>() - 2 * size_of::<u32>();
+        calc_crc32(self as *const _ as *const u8, CRC_OFFSET)
+    }
+}
+
+mod asserts {
+    use super::*;
+    use core::mem::align_of;
+    use static_assertions::const_assert;
+
+    use crate::MIN_PAGE_SIZE;
+
+    const_assert!(size_of::<ImageDigests>() == 120);
+    const_assert!(size_of::<ImageDigests>() == 16 + DIGEST_SIZE * NUMBER_OF_IMAGES + 8);
+
+    // Both are programmed as double-words right after each other
+    const_assert!(align_of::<ImageDigests>() == 8);
+    const_assert!(DIGESTS_OFFSET.is_multiple_of(8));
+    const_assert!(size_of::<ImageDigests>().is_multiple_of(8));
+
+    const_assert!(DIGESTS_OFFSET as usize + size_of::<ImageDigests>() <= MIN_PAGE_SIZE as usize);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ImageMetadata;
+
+    fn metadata(version: u32) -> Metadata {
+        let mut metadata = Metadata {
+            version,
+            bootcounter: 0,
+            preferred_image: 0,
+            images: [ImageMetadata::default(); NUMBER_OF_IMAGES],
+            crc: 0,
+        };
+        metadata.set_crc();
+        metadata
+    }
+
+    #[test]
+    fn digests_belong_to_metadata() {
+        let digests =
+            ImageDigests::new(&metadata(1), [Some([1; DIGEST_SIZE]), None, Some([3; 32])]);
+
+        assert!(digests.is_valid_for(&metadata(1)));
+        assert!(!digests.is_valid_for(&metadata(2)));
+
+        assert_eq!(digests.digest(0), Some(&[1; DIGEST_SIZE]));
+        assert_eq!(digests.digest(1), None);
+        assert_eq!(digests.digest(2), Some(&[3; DIGEST_SIZE]));
+        assert_eq!(digests.digest(NUMBER_OF_IMAGES), None);
+    }
+
+    #[test]
+    fn reject_corrupted_digests() {
+        let valid = ImageDigests::new(&metadata(1), [Some([1; DIGEST_SIZE]); NUMBER_OF_IMAGES]);
+
+        let mut corrupted = valid;
+        corrupted.digests[1][5] ^= 1;
+        assert!(!corrupted.is_valid_for(&metadata(1)));
+
+        let mut newer_format = valid;
+        newer_format.format_version += 1;
+        newer_format.crc = newer_format.calc_crc();
+        assert!(!newer_format.is_valid_for(&metadata(1)));
+
+        // Erased flash
+        let erased = unsafe { core::mem::transmute::<[u8; 120], ImageDigests>([0xff; 120]) };
+        assert!(!erased.is_valid_for(&metadata(1)));
+    }
+}
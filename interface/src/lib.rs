@@ -1,6 +1,17 @@
 #![no_std]
 
+pub mod backup;
+pub mod bootinfo;
 pub mod crc;
+pub mod delta;
+pub mod digest;
+pub mod lz4;
+pub mod mailbox;
+pub mod recovery;
+pub mod reset;
+pub mod sha256;
+pub mod signature;
+pub mod trailer;
 
 // This is the page size in single-bank mode
 pub const SINGLE_BANK_PAGE_SIZE: u32 = 0x2000;
@@ -40,51 +51,105 @@ pub const NUMBER_OF_IMAGES: usize = 3;
 // We have 2MB of flash.
 pub const FLASH_SIZE: u32 = 0x200000;
 
-// 504KB is the Maximum size for an image.
+// All flash addresses here are offsets, as the flash is aliased at address 0 after a reset.
+// An XIP image (see IMAGE_FLAG_XIP) runs from its slot, so it is linked against this address
+// plus the address of the slot.
+pub const FLASH_ADDR: u32 = 0x0800_0000;
+
+// 488KB is the Maximum size for an image.
 // TODO: Test if we can actually use an image of that size when copied
 // into RAM
-pub const SLOT_SIZE: u32 = 63 * MAX_PAGE_SIZE;
+pub const SLOT_SIZE: u32 = 61 * MAX_PAGE_SIZE;
+
+// The bootloader code starts at address 0 and must not be larger than this.
+// Verifying image signatures (see signature.rs) takes about 16kB, and a debug build that verifies
+// them about 48kB, which has to fit as well.
+pub const BOOTLOADER_SIZE: u32 = 8 * MAX_PAGE_SIZE;
 
 // This is where the metadata is stored, like CRCs, image info etc.
 // We keep two copies on different pages to ensure reliability when we overwrite one of them
 // Note that with both single- and dual-bank mode, we choose the same address
-pub const METADATA_1_ADDR: u32 = MAX_PAGE_SIZE;
-pub const METADATA_2_ADDR: u32 = 2 * MAX_PAGE_SIZE;
+pub const METADATA_1_ADDR: u32 = BOOTLOADER_SIZE;
+pub const METADATA_2_ADDR: u32 = METADATA_1_ADDR + MAX_PAGE_SIZE;
 
 // This is where images are copied to before being executed.
 // This is a RAM address, so it's not persistent across reboots.
 pub const RAM_ADDR: u32 = 0x20000000;
 pub const RAM_SIZE: u32 = 0xa0000; // 640KB
 
+// The bootloader's own stack is at the end of RAM. Images are copied or decompressed in front of
+// it, so a decompressed image may be larger than a slot, but not larger than this.
+pub const BOOTLOADER_STACK_SIZE: u32 = 0x8000;
+pub const MAX_RAM_IMAGE_LENGTH: u32 = RAM_SIZE - BOOTLOADER_STACK_SIZE;
+
+// Word 0 of an image's vector table is its initial stack pointer, which must point into RAM.
+// The stack grows downwards, so the end of RAM is a valid value, but the start is not.
+// The AAPCS requires the stack to be aligned to 8 bytes.
+pub const STACK_POINTER_ALIGNMENT: u32 = 8;
+
+pub const fn is_valid_stack_pointer(sp: u32) -> bool {
+    sp > RAM_ADDR && sp <= RAM_ADDR + RAM_SIZE && sp.is_multiple_of(STACK_POINTER_ALIGNMENT)
+}
+
 // Start addresses where we copy the images to
-pub const SLOT_1_ADDR: u32 = 3 * MAX_PAGE_SIZE;
+pub const SLOT_1_ADDR: u32 = METADATA_2_ADDR + MAX_PAGE_SIZE;
 pub const SLOT_2_ADDR: u32 = SLOT_1_ADDR + SLOT_SIZE;
 pub const SLOT_3_ADDR: u32 = SLOT_2_ADDR + SLOT_SIZE;
 
 //Array with all the addresses of the slots.
 pub const SLOT_ADDRS: [u32; NUMBER_OF_IMAGES] = [SLOT_1_ADDR, SLOT_2_ADDR, SLOT_3_ADDR];
 
+// The golden image is a known good image that is only booted if both metadata pages are broken.
+// It is written once when the device is provisioned and the whole region is write protected
+// afterwards (WRP option bytes), so neither the OS nor the bootloader can overwrite it.
+// It does not use the metadata pages, but has its own ImageMetadata on the page in front of it.
+pub const GOLDEN_METADATA_ADDR: u32 = SLOT_3_ADDR + SLOT_SIZE;
+pub const GOLDEN_SLOT_ADDR: u32 = GOLDEN_METADATA_ADDR + MAX_PAGE_SIZE;
+// The end of the write protected region (exclusive)
+pub const GOLDEN_REGION_END: u32 = GOLDEN_SLOT_ADDR + SLOT_SIZE;
+
 mod asserts {
     use super::*;
     use core::mem::size_of;
     use static_assertions::const_assert;
 
-    const_assert!(MIN_PAGE_SIZE > size_of::<Metadata>() as u32);
+    const_assert!(MIN_PAGE_SIZE > METADATA_OFFSET + size_of::<Metadata>() as u32);
+
+    const_assert!(METADATA_1_ADDR + METADATA_OFFSET + size_of::<Metadata>() as u32 <= SLOT_1_ADDR);
+    const_assert!(
+        METADATA_1_ADDR + METADATA_OFFSET + size_of::<Metadata>() as u32 <= METADATA_2_ADDR
+    );
+    const_assert!(METADATA_2_ADDR + METADATA_OFFSET + size_of::<Metadata>() as u32 <= SLOT_1_ADDR);
 
-    const_assert!(METADATA_1_ADDR + size_of::<Metadata>() as u32 <= SLOT_1_ADDR);
-    const_assert!(METADATA_1_ADDR + size_of::<Metadata>() as u32 <= METADATA_2_ADDR);
-    const_assert!(METADATA_2_ADDR + size_of::<Metadata>() as u32 <= SLOT_1_ADDR);
+    // The header and the Metadata are programmed as double-words right after each other
+    const_assert!(size_of::<MetadataHeader>() == 8);
+    const_assert!(METADATA_OFFSET.is_multiple_of(8));
 
     const_assert!(SLOT_1_ADDR + SLOT_SIZE <= SLOT_2_ADDR);
     const_assert!(SLOT_2_ADDR + SLOT_SIZE <= SLOT_3_ADDR);
     const_assert!(SLOT_3_ADDR + SLOT_SIZE <= FLASH_SIZE);
 
+    const_assert!(SLOT_3_ADDR + SLOT_SIZE <= GOLDEN_METADATA_ADDR);
+    const_assert!(GOLDEN_METADATA_ADDR + size_of::<ImageMetadata>() as u32 <= GOLDEN_SLOT_ADDR);
+    const_assert!(GOLDEN_REGION_END <= FLASH_SIZE);
+
+    // The flash must not overlap RAM, or we couldn't tell XIP images apart from the others
+    const_assert!(FLASH_ADDR + FLASH_SIZE <= RAM_ADDR);
+
+    // An uncompressed image is copied to RAM as well
+    const_assert!(SLOT_SIZE <= MAX_RAM_IMAGE_LENGTH);
+
     const_assert!(MAX_PAGE_SIZE % MIN_PAGE_SIZE == 0);
 
     const_assert!(SLOT_1_ADDR % MIN_PAGE_SIZE == 0);
     const_assert!(SLOT_2_ADDR % MIN_PAGE_SIZE == 0);
     const_assert!(SLOT_3_ADDR % MIN_PAGE_SIZE == 0);
 
+    // Write protection works on whole pages, in both bank modes
+    const_assert!(GOLDEN_METADATA_ADDR.is_multiple_of(MAX_PAGE_SIZE));
+    const_assert!(GOLDEN_SLOT_ADDR.is_multiple_of(MAX_PAGE_SIZE));
+    const_assert!(GOLDEN_REGION_END.is_multiple_of(MAX_PAGE_SIZE));
+
     const_assert!(SLOT_SIZE % MIN_PAGE_SIZE == 0);
 }
 
@@ -107,10 +172,45 @@ Note that both the bootloader and image builder must use the same endianness."#
 pub struct ImageMetadata {
     pub version: u32,
     pub crc: u32,
-    pub boot_counter: u32,
+    // IMAGE_FLAG_*, this was an unused boot counter before, which was always 0
+    pub flags: u32,
     pub length: u32,
 }
 
+// The image is executed in place (XIP) from its slot instead of being copied to RAM_ADDR
+pub const IMAGE_FLAG_XIP: u32 = 1 << 0;
+// The slot holds the LZ4 compressed image, which is decompressed to RAM_ADDR (see lz4.rs).
+// The length and CRC are those of the compressed image as it is stored in the slot.
+pub const IMAGE_FLAG_LZ4: u32 = 1 << 1;
+// All flags we know, an image with any other flag is not booted
+pub const IMAGE_FLAGS_KNOWN: u32 = IMAGE_FLAG_XIP | IMAGE_FLAG_LZ4;
+
+impl ImageMetadata {
+    pub fn is_xip(&self) -> bool {
+        self.flags & IMAGE_FLAG_XIP != 0
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.flags & IMAGE_FLAG_LZ4 != 0
+    }
+
+    /// Whether we know how to start the image: all flags are known, and an XIP image can't be
+    /// decompressed
+    pub fn has_valid_flags(&self) -> bool {
+        self.flags & !IMAGE_FLAGS_KNOWN == 0 && !(self.is_xip() && self.is_compressed())
+    }
+
+    /// Where the image in the slot at `slot_addr` is started from, which is also where its
+    /// vector table must point to
+    pub fn load_address(&self, slot_addr: u32) -> u32 {
+        if self.is_xip() {
+            FLASH_ADDR + slot_addr
+        } else {
+            RAM_ADDR
+        }
+    }
+}
+
 #[repr(C, align(8))]
 #[cfg_attr(not(target = "thumbv7em-none-eabihf"), derive(Debug, Clone, Copy, PartialEq, Eq))]
 pub struct Metadata {
@@ -124,6 +224,68 @@ pub struct Metadata {
     pub crc: u32,
 }
 
+// Since layout revision 1, each metadata page starts with a MetadataHeader, followed by the
+// Metadata. Pages written before that have no header, the Metadata is at the start of the page
+// (MetadataLayout::Legacy). The bootloader still accepts those and upgrades them in place.
+// A legacy page could only be mistaken for the current layout if its version was METADATA_MAGIC.
+pub const METADATA_MAGIC: u32 = 0x4d45_5441; // "META"
+
+// Increment this if the layout of a metadata page changes
+pub const METADATA_LAYOUT_REVISION: u32 = 1;
+
+#[repr(C, align(8))]
+#[cfg_attr(not(target = "thumbv7em-none-eabihf"), derive(Debug, Clone, Copy, PartialEq, Eq))]
+pub struct MetadataHeader {
+    // Must be METADATA_MAGIC, anything else means the page uses the legacy layout
+    pub magic: u32,
+    pub layout_revision: u32,
+}
+
+impl MetadataHeader {
+    /// The header of pages in the current layout
+    pub const fn current() -> Self {
+        MetadataHeader { magic: METADATA_MAGIC, layout_revision: METADATA_LAYOUT_REVISION }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataLayout {
+    // Only the Metadata, as written before there was a MetadataHeader
+    Legacy,
+    // A MetadataHeader with METADATA_LAYOUT_REVISION, followed by the Metadata
+    Current,
+}
+
+impl MetadataLayout {
+    /// The layout of a page that starts with `header`.
+    /// Returns None for a layout revision we don't know, e.g. from a newer image-builder.
+    pub fn of(header: &MetadataHeader) -> Option<Self> {
+        if header.magic != METADATA_MAGIC {
+            Some(MetadataLayout::Legacy)
+        } else if header.layout_revision == METADATA_LAYOUT_REVISION {
+            Some(MetadataLayout::Current)
+        } else {
+            None
+        }
+    }
+
+    /// Offset of the Metadata from the start of the page
+    pub const fn metadata_offset(self) -> u32 {
+        match self {
+            MetadataLayout::Legacy => 0,
+            MetadataLayout::Current => core::mem::size_of::<MetadataHeader>() as u32,
+        }
+    }
+
+    /// Offset of the ImageDigests (see digest.rs) from the start of the page
+    pub const fn digests_offset(self) -> u32 {
+        self.metadata_offset() + core::mem::size_of::<Metadata>() as u32
+    }
+}
+
+/// Offset of the Metadata from the start of a metadata page, as it is written now
+pub const METADATA_OFFSET: u32 = MetadataLayout::Current.metadata_offset();
+
 #[cfg(kani)]
 impl kani::Arbitrary for Metadata {
     fn any() -> Self {
@@ -142,7 +304,7 @@ impl kani::Arbitrary for ImageMetadata {
     fn any() -> Self {
         ImageMetadata {
             version: kani::any(),
-            boot_counter: kani::any(),
+            flags: kani::any(),
             length: kani::any(),
             crc: kani::any(),
         }
@@ -205,6 +367,23 @@ mod tests {
 
         assert_eq!(crc_prev, crc_new);
     }
+
+    #[test]
+    fn metadata_layout() {
+        let header = MetadataHeader::current();
+        assert_eq!(MetadataLayout::of(&header), Some(MetadataLayout::Current));
+        assert_eq!(MetadataLayout::Current.metadata_offset(), 8);
+        assert_eq!(MetadataLayout::Current.digests_offset(), 72);
+
+        // A legacy page starts with the metadata version
+        let legacy = MetadataHeader { magic: 7, layout_revision: 0 };
+        assert_eq!(MetadataLayout::of(&legacy), Some(MetadataLayout::Legacy));
+        assert_eq!(MetadataLayout::Legacy.metadata_offset(), 0);
+        assert_eq!(MetadataLayout::Legacy.digests_offset(), 64);
+
+        let newer = MetadataHeader { magic: METADATA_MAGIC, layout_revision: 2 };
+        assert_eq!(MetadataLayout::of(&newer), None);
+    }
 }
 
 // ------------------
@@ -239,8 +418,9 @@ mod base_checks {
             == size_of::<Metadata>()
     );
 
-    // Hardware testing scripts rely on this size
+    // Hardware testing scripts rely on this size and offset
     const_assert!(size_of::<Metadata>() == 64);
+    const_assert!(crate::METADATA_OFFSET == 8);
 
     const_assert!(align_of::<ImageMetadata>() == 4);
     const_assert!(align_of::<Metadata>() == 8);
@@ -0,0 +1,272 @@
+// Images can be stored LZ4 compressed (see IMAGE_FLAG_LZ4), so larger images fit into a slot.
+// The bootloader decompresses them into RAM before they are started, so this is not possible
+// for XIP images.
+//
+// A compressed slot starts with a CompressedImageHeader with the length and CRC of the
+// decompressed image, followed by a single LZ4 block (the block format, not the frame format).
+// The ImageMetadata, trailer and signature of the slot all describe the stored bytes including
+// the header, so the slot is verified exactly like an uncompressed one before it is decompressed.
+// Only the decoder is here, as it runs in the bootloader. The image-builder compresses the images.
+
+use core::mem::size_of;
+
+use crate::crc::calc_crc32;
+use crate::MIN_PAGE_SIZE;
+
+pub const COMPRESSED_IMAGE_MAGIC: u32 = 0x4c5a_3442; // "LZ4B"
+
+// Increment this if the layout of CompressedImageHeader or the compression changes
+pub const COMPRESSED_IMAGE_VERSION: u32 = 1;
+
+// The decoder reports its progress after every this many decompressed bytes, e.g. to feed the
+// watchdog
+pub const PROGRESS_INTERVAL: usize = MIN_PAGE_SIZE as usize;
+
+// A match is at least this long, shorter ones are stored as literals
+const MIN_MATCH: usize = 4;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressedImageHeader {
+    // Must be COMPRESSED_IMAGE_MAGIC, anything else means the slot is not compressed
+    pub magic: u32,
+    pub format_version: u32,
+    // Length and CRC32-C of the decompressed image
+    pub length: u32,
+    pub crc: u32,
+}
+
+/// Offset of the LZ4 block from the start of a compressed slot
+pub const COMPRESSED_DATA_OFFSET: usize = size_of::<CompressedImageHeader>();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecompressError {
+    // The slot doesn't start with a CompressedImageHeader we know
+    InvalidHeader,
+    // The decompressed image doesn't fit into the output
+    TooLarge,
+    // The block ends in the middle of a sequence
+    Truncated,
+    // A match refers to data in front of the start of the image
+    InvalidOffset,
+    // The decompressed image doesn't match the length or CRC of the header
+    Mismatch,
+}
+
+impl CompressedImageHeader {
+    /// Creates the header for the decompressed `image`
+    pub fn new(image: &[u8]) -> Self {
+        CompressedImageHeader {
+            magic: COMPRESSED_IMAGE_MAGIC,
+            format_version: COMPRESSED_IMAGE_VERSION,
+            length: image.len() as u32,
+            crc: calc_crc32(image.as_ptr(), image.len()),
+        }
+    }
+
+    /// Reads the header at the start of `stored`, if there is one we know
+    pub fn read(stored: &[u8]) -> Option<Self> {
+        let bytes = stored.get(..COMPRESSED_DATA_OFFSET)?;
+        // The flash read is not necessarily aligned when it doesn't come from the actual hardware
+        let header = unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const Self) };
+
+        (header.magic == COMPRESSED_IMAGE_MAGIC
+            && header.format_version == COMPRESSED_IMAGE_VERSION)
+            .then_some(header)
+    }
+}
+
+/// Decompresses the image stored in a compressed slot (header and LZ4 block) into `output` and
+/// checks it against the header. Returns the length of the decompressed image.
+/// `progress` is called after every PROGRESS_INTERVAL decompressed bytes.
+pub fn decompress_image(
+    stored: &[u8], output: &mut [u8], progress: impl FnMut(),
+) -> Result<usize, DecompressError> {
+    let header = CompressedImageHeader::read(stored).ok_or(DecompressError::InvalidHeader)?;
+    let length = header.length as usize;
+    let output = output.get_mut(..length).ok_or(DecompressError::TooLarge)?;
+
+    let decompressed = decompress(&stored[COMPRESSED_DATA_OFFSET..], output, progress)?;
+    if decompressed != length || calc_crc32(output.as_ptr(), length) != header.crc {
+        return Err(DecompressError::Mismatch);
+    }
+
+    Ok(length)
+}
+
+/// Decompresses a single LZ4 block into `output` and returns the decompressed length.
+/// `progress` is called after every PROGRESS_INTERVAL decompressed bytes.
+pub fn decompress(
+    input: &[u8], output: &mut [u8], mut progress: impl FnMut(),
+) -> Result<usize, DecompressError> {
+    let mut input_pos = 0;
+    let mut output_pos = 0;
+    let mut next_progress = PROGRESS_INTERVAL;
+
+    while input_pos < input.len() {
+        let token = input[input_pos];
+        input_pos += 1;
+
+        // Literals, copied as they are
+        let literal_length = read_length(input, &mut input_pos, (token >> 4) as usize)?;
+        let literals =
+            input.get(input_pos..input_pos + literal_length).ok_or(DecompressError::Truncated)?;
+        output
+            .get_mut(output_pos..output_pos + literal_length)
+            .ok_or(DecompressError::TooLarge)?
+            .copy_from_slice(literals);
+        input_pos += literal_length;
+        output_pos += literal_length;
+        report_progress(output_pos, &mut next_progress, &mut progress);
+
+        // The last sequence has no match
+        if input_pos == input.len() {
+            break;
+        }
+
+        let offset = input.get(input_pos..input_pos + 2).ok_or(DecompressError::Truncated)?;
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        input_pos += 2;
+        if offset == 0 || offset > output_pos {
+            return Err(DecompressError::InvalidOffset);
+        }
+
+        // The match may overlap the bytes it writes, so it is copied byte by byte
+        let match_length = read_length(input, &mut input_pos, (token & 0xf) as usize)? + MIN_MATCH;
+        if output_pos + match_length > output.len() {
+            return Err(DecompressError::TooLarge);
+        }
+        for i in output_pos..output_pos + match_length {
+            output[i] = output[i - offset];
+        }
+        output_pos += match_length;
+        report_progress(output_pos, &mut next_progress, &mut progress);
+    }
+
+    Ok(output_pos)
+}
+
+fn report_progress(output_pos: usize, next_progress: &mut usize, progress: &mut impl FnMut()) {
+    while output_pos >= *next_progress {
+        progress();
+        *next_progress += PROGRESS_INTERVAL;
+    }
+}
+
+// A length of 15 in the token continues in the following bytes, until one is not 255
+fn read_length(input: &[u8], pos: &mut usize, length: usize) -> Result<usize, DecompressError> {
+    let mut length = length;
+    if length != 0xf {
+        return Ok(length);
+    }
+
+    loop {
+        let byte = *input.get(*pos).ok_or(DecompressError::Truncated)?;
+        *pos += 1;
+        length += byte as usize;
+        if byte != 0xff {
+            return Ok(length);
+        }
+    }
+}
+
+mod asserts {
+    use super::*;
+    use static_assertions::const_assert_eq;
+
+    // The LZ4 block starts double-word aligned, like everything else we program
+    const_assert_eq!(COMPRESSED_DATA_OFFSET, 16);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "abcabcabcabc...", compressed by hand: 3 literals and a match of 61 at offset 3
+    const REPEATED: [u8; 8] = [0x3f, b'a', b'b', b'c', 0x03, 0x00, 0x2a, 0x00];
+
+    #[test]
+    fn decompress_overlapping_match() {
+        let mut output = [0u8; 128];
+        let length = decompress(&REPEATED[..7], &mut output, || {}).unwrap();
+        assert_eq!(length, 3 + 15 + 42 + MIN_MATCH);
+        for (i, &b) in output[..length].iter().enumerate() {
+            assert_eq!(b, b"abc"[i % 3]);
+        }
+
+        // Followed by a last sequence without literals
+        let mut output = [0u8; 128];
+        assert_eq!(decompress(&REPEATED[..8], &mut output, || {}), Ok(length));
+    }
+
+    #[test]
+    fn reject_broken_blocks() {
+        let mut output = [0u8; 128];
+
+        // The match is longer than the output
+        assert_eq!(
+            decompress(&REPEATED[..7], &mut output[..32], || {}),
+            Err(DecompressError::TooLarge)
+        );
+        // The length of the match is cut off
+        assert_eq!(decompress(&REPEATED[..6], &mut output, || {}), Err(DecompressError::Truncated));
+        // The match refers to data in front of the image
+        let block = [0x30, b'a', b'b', b'c', 0x04, 0x00];
+        assert_eq!(decompress(&block, &mut output, || {}), Err(DecompressError::InvalidOffset));
+        let block = [0x30, b'a', b'b', b'c', 0x00, 0x00];
+        assert_eq!(decompress(&block, &mut output, || {}), Err(DecompressError::InvalidOffset));
+    }
+
+    #[test]
+    fn decompress_image_checks_header() {
+        let image: [u8; 64] = core::array::from_fn(|i| b"abc"[i % 3]);
+        let header = CompressedImageHeader::new(&image);
+
+        let mut stored = [0u8; COMPRESSED_DATA_OFFSET + 7];
+        stored[..COMPRESSED_DATA_OFFSET].copy_from_slice(unsafe {
+            core::slice::from_raw_parts(&header as *const _ as *const u8, COMPRESSED_DATA_OFFSET)
+        });
+        stored[COMPRESSED_DATA_OFFSET..].copy_from_slice(&REPEATED[..7]);
+        assert_eq!(CompressedImageHeader::read(&stored), Some(header));
+
+        let mut output = [0u8; PROGRESS_INTERVAL];
+        assert_eq!(decompress_image(&stored, &mut output, || {}), Ok(image.len()));
+        assert_eq!(output[..image.len()], image);
+
+        // The output must hold the whole image
+        assert_eq!(
+            decompress_image(&stored, &mut output[..63], || {}),
+            Err(DecompressError::TooLarge)
+        );
+
+        // A different image
+        let mut broken = stored;
+        broken[COMPRESSED_DATA_OFFSET + 2] = b'x';
+        assert_eq!(decompress_image(&broken, &mut output, || {}), Err(DecompressError::Mismatch));
+
+        // Not compressed at all
+        let mut broken = stored;
+        broken[0] = 0;
+        assert_eq!(
+            decompress_image(&broken, &mut output, || {}),
+            Err(DecompressError::InvalidHeader)
+        );
+    }
+
+    #[test]
+    fn reports_progress_per_interval() {
+        // A literal, followed by a match of 2 * PROGRESS_INTERVAL + 99
+        let match_length = 2 * PROGRESS_INTERVAL + 99 - MIN_MATCH - 15;
+        let mut block = [0xff; 64];
+        block[..4].copy_from_slice(&[0x1f, 0x55, 0x01, 0x00]);
+        let end = 4 + match_length / 255;
+        block[end] = (match_length % 255) as u8;
+        let block = &block[..=end];
+
+        let mut output = [0u8; 3 * PROGRESS_INTERVAL];
+        let mut calls = 0;
+        assert_eq!(decompress(block, &mut output, || calls += 1), Ok(2 * PROGRESS_INTERVAL + 100));
+        assert_eq!(calls, 2);
+        assert!(output[..2 * PROGRESS_INTERVAL + 100].iter().all(|&b| b == 0x55));
+    }
+}
@@ -0,0 +1,144 @@
+// The OS sends commands to the bootloader through a mailbox in the RTC backup registers (see
+// backup.rs). A command is executed on the next boot, and the bootloader clears the mailbox
+// before it does anything else, so every command is executed at most once.
+//
+// The mailbox consists of MAILBOX_SIZE registers, starting at MAILBOX_REG:
+//   MAILBOX_MAGIC (upper 16 bits) | command code (lower 16 bits)
+//   argument (the slot index for BootOnce, 0 for all other commands)
+//   CRC32-C of the two words above, as little endian bytes
+// Anything else, e.g. a mailbox the OS didn't finish writing before the reset, is ignored.
+// The OS should write the checksum last.
+
+use crate::backup::NUMBER_OF_BACKUP_REGISTERS;
+use crate::crc::calc_crc32;
+use crate::NUMBER_OF_IMAGES;
+
+/// First of the MAILBOX_SIZE mailbox registers
+pub const MAILBOX_REG: usize = 8;
+pub const MAILBOX_SIZE: usize = 3;
+
+pub const MAILBOX_MAGIC: u32 = 0x4d42; // "MB"
+
+// Command codes, see MailboxCommand
+pub const MAILBOX_BOOT_ONCE: u32 = 0x01;
+pub const MAILBOX_ENTER_RECOVERY: u32 = 0x02;
+pub const MAILBOX_CLEAR_BOOT_COUNTERS: u32 = 0x03;
+pub const MAILBOX_CONFIRM_BOOT: u32 = 0x04;
+
+mod asserts {
+    use super::*;
+    use static_assertions::const_assert;
+
+    const_assert!(MAILBOX_MAGIC <= 0xffff);
+    const_assert!(MAILBOX_REG + MAILBOX_SIZE <= NUMBER_OF_BACKUP_REGISTERS);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MailboxCommand {
+    /// Boot the image in this slot once, without making it the preferred image.
+    /// On the following reset, the bootloader selects the image from the metadata again.
+    BootOnce { slot: u32 },
+    /// Enter the recovery mode (see recovery.rs) instead of booting an image
+    EnterRecovery,
+    /// Forget all boot attempts, so every slot may be booted again
+    ClearBootCounters,
+    /// Confirm the boot of the running image, like writing BOOT_CONFIRM_MAGIC to BOOT_CONFIRM_REG
+    ConfirmBoot,
+}
+
+impl MailboxCommand {
+    /// The values of the mailbox registers for this command
+    pub fn encode(&self) -> [u32; MAILBOX_SIZE] {
+        let (code, argument) = match *self {
+            MailboxCommand::BootOnce { slot } => (MAILBOX_BOOT_ONCE, slot),
+            MailboxCommand::EnterRecovery => (MAILBOX_ENTER_RECOVERY, 0),
+            MailboxCommand::ClearBootCounters => (MAILBOX_CLEAR_BOOT_COUNTERS, 0),
+            MailboxCommand::ConfirmBoot => (MAILBOX_CONFIRM_BOOT, 0),
+        };
+
+        let command = (MAILBOX_MAGIC << 16) | code;
+        [command, argument, checksum(command, argument)]
+    }
+
+    /// The command with this code and argument, if it is a valid one
+    pub fn new(code: u32, argument: u32) -> Option<Self> {
+        // Commands without an argument must not have one
+        match (code, argument) {
+            (MAILBOX_BOOT_ONCE, slot) if slot < NUMBER_OF_IMAGES as u32 => {
+                Some(MailboxCommand::BootOnce { slot })
+            }
+            (MAILBOX_ENTER_RECOVERY, 0) => Some(MailboxCommand::EnterRecovery),
+            (MAILBOX_CLEAR_BOOT_COUNTERS, 0) => Some(MailboxCommand::ClearBootCounters),
+            (MAILBOX_CONFIRM_BOOT, 0) => Some(MailboxCommand::ConfirmBoot),
+            _ => None,
+        }
+    }
+
+    /// Decodes the values of the mailbox registers.
+    /// Returns None for an empty mailbox and for anything that is not a valid command.
+    pub fn decode(registers: &[u32; MAILBOX_SIZE]) -> Option<Self> {
+        let [command, argument, crc] = *registers;
+        if command >> 16 != MAILBOX_MAGIC || crc != checksum(command, argument) {
+            return None;
+        }
+
+        Self::new(command & 0xffff, argument)
+    }
+}
+
+fn checksum(command: u32, argument: u32) -> u32 {
+    let mut bytes = [0u8; 8];
+    bytes[..4].copy_from_slice(&command.to_le_bytes());
+    bytes[4..].copy_from_slice(&argument.to_le_bytes());
+    calc_crc32(bytes.as_ptr(), bytes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_roundtrip() {
+        let commands = [
+            MailboxCommand::BootOnce { slot: 0 },
+            MailboxCommand::BootOnce { slot: NUMBER_OF_IMAGES as u32 - 1 },
+            MailboxCommand::EnterRecovery,
+            MailboxCommand::ClearBootCounters,
+            MailboxCommand::ConfirmBoot,
+        ];
+
+        for command in commands {
+            assert_eq!(MailboxCommand::decode(&command.encode()), Some(command));
+        }
+    }
+
+    #[test]
+    fn reject_invalid_mailbox() {
+        // Empty after a power-on reset or after the bootloader cleared it
+        assert_eq!(MailboxCommand::decode(&[0; MAILBOX_SIZE]), None);
+
+        // The old soft reboot request
+        assert_eq!(MailboxCommand::decode(&[0x5457, 1, 0]), None);
+
+        // Only partially written
+        let mut registers = MailboxCommand::ConfirmBoot.encode();
+        registers[2] = 0;
+        assert_eq!(MailboxCommand::decode(&registers), None);
+
+        // A bit flip in the argument
+        let mut registers = MailboxCommand::BootOnce { slot: 1 }.encode();
+        registers[1] ^= 2;
+        assert_eq!(MailboxCommand::decode(&registers), None);
+
+        // A valid checksum doesn't make an invalid slot or an unknown command valid
+        let command = (MAILBOX_MAGIC << 16) | MAILBOX_BOOT_ONCE;
+        let argument = NUMBER_OF_IMAGES as u32;
+        assert_eq!(MailboxCommand::decode(&[command, argument, checksum(command, argument)]), None);
+
+        let command = (MAILBOX_MAGIC << 16) | 0x42;
+        assert_eq!(MailboxCommand::decode(&[command, 0, checksum(command, 0)]), None);
+
+        let command = (MAILBOX_MAGIC << 16) | MAILBOX_ENTER_RECOVERY;
+        assert_eq!(MailboxCommand::decode(&[command, 1, checksum(command, 1)]), None);
+    }
+}
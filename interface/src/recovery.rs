@@ -0,0 +1,498 @@
+// The recovery protocol is used to flash images over UART if the bootloader has nothing to boot.
+// The host (image-builder upload) sends commands, and the bootloader answers every command with
+// exactly one response, so there is never more than one frame in flight.
+//
+// Every frame looks like this (all integers are little endian):
+//   FRAME_MAGIC (2 bytes) | kind (1 byte) | payload length (2 bytes) | payload | CRC32-C (4 bytes)
+// The CRC covers everything from kind to the end of the payload.
+
+use crate::crc::calc_crc32;
+use crate::{ImageMetadata, Metadata, NUMBER_OF_IMAGES};
+
+pub const FRAME_MAGIC: [u8; 2] = [0x4d, 0x4c]; // "ML"
+pub const FRAME_HEADER_SIZE: usize = 5;
+pub const FRAME_CRC_SIZE: usize = 4;
+
+// The bootloader has to keep a whole frame in RAM
+pub const MAX_CHUNK_SIZE: usize = 1024;
+// WriteChunk has slot and offset in front of the data
+pub const MAX_PAYLOAD_SIZE: usize = 8 + MAX_CHUNK_SIZE;
+pub const MAX_FRAME_SIZE: usize = FRAME_HEADER_SIZE + MAX_PAYLOAD_SIZE + FRAME_CRC_SIZE;
+
+// The only supported UART configuration is 8N1 at this baud rate
+pub const RECOVERY_BAUD_RATE: u32 = 115200;
+
+// Frame kinds. Commands are sent by the host, responses by the bootloader
+const KIND_PING: u8 = 0x01;
+const KIND_ERASE_SLOT: u8 = 0x02;
+const KIND_WRITE_CHUNK: u8 = 0x03;
+const KIND_VERIFY: u8 = 0x04;
+const KIND_COMMIT_METADATA: u8 = 0x05;
+const KIND_RESET: u8 = 0x06;
+const KIND_ACK: u8 = 0x80;
+const KIND_NACK: u8 = 0x81;
+
+const METADATA_SIZE: usize = core::mem::size_of::<Metadata>();
+
+mod asserts {
+    use super::*;
+    use static_assertions::const_assert;
+
+    // The payload length is sent as u16
+    const_assert!(MAX_PAYLOAD_SIZE <= u16::MAX as usize);
+    const_assert!(METADATA_SIZE <= MAX_PAYLOAD_SIZE);
+    // See read_metadata
+    const_assert!(METADATA_SIZE == 4 * (4 + 4 * NUMBER_OF_IMAGES));
+
+    // Chunks are programmed as double-words
+    const_assert!(MAX_CHUNK_SIZE.is_multiple_of(8));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The output buffer can't hold the frame
+    BufferTooSmall,
+    /// The payload is larger than MAX_PAYLOAD_SIZE
+    PayloadTooLarge,
+    /// The CRC does not match the frame content
+    InvalidCrc,
+    /// The frame kind is not known (or not expected in this direction)
+    UnknownKind,
+    /// The payload does not fit the frame kind
+    InvalidPayload,
+}
+
+/// A frame with a valid CRC, but not yet decoded into a command or response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub kind: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Parses exactly one complete frame, including magic and CRC
+    pub fn parse(data: &'a [u8]) -> Result<Self, FrameError> {
+        if data.len() < FRAME_HEADER_SIZE + FRAME_CRC_SIZE || data[..2] != FRAME_MAGIC {
+            return Err(FrameError::InvalidPayload);
+        }
+
+        let payload_length = u16::from_le_bytes([data[3], data[4]]) as usize;
+        if payload_length > MAX_PAYLOAD_SIZE {
+            return Err(FrameError::PayloadTooLarge);
+        }
+        if data.len() != FRAME_HEADER_SIZE + payload_length + FRAME_CRC_SIZE {
+            return Err(FrameError::InvalidPayload);
+        }
+
+        let crc_start = FRAME_HEADER_SIZE + payload_length;
+        let crc = read_u32(&data[crc_start..]);
+        if crc != calc_crc32(data[2..].as_ptr(), crc_start - 2) {
+            return Err(FrameError::InvalidCrc);
+        }
+
+        Ok(Frame { kind: data[2], payload: &data[FRAME_HEADER_SIZE..crc_start] })
+    }
+}
+
+/// Writes a frame with the concatenation of `payload` as payload into `out`.
+/// Returns the length of the frame.
+fn encode_frame(kind: u8, payload: &[&[u8]], out: &mut [u8]) -> Result<usize, FrameError> {
+    let payload_length: usize = payload.iter().map(|part| part.len()).sum();
+    if payload_length > MAX_PAYLOAD_SIZE {
+        return Err(FrameError::PayloadTooLarge);
+    }
+
+    let frame_length = FRAME_HEADER_SIZE + payload_length + FRAME_CRC_SIZE;
+    if out.len() < frame_length {
+        return Err(FrameError::BufferTooSmall);
+    }
+
+    out[..2].copy_from_slice(&FRAME_MAGIC);
+    out[2] = kind;
+    out[3..5].copy_from_slice(&(payload_length as u16).to_le_bytes());
+
+    let mut position = FRAME_HEADER_SIZE;
+    for part in payload {
+        out[position..position + part.len()].copy_from_slice(part);
+        position += part.len();
+    }
+
+    let crc = calc_crc32(out[2..].as_ptr(), position - 2);
+    out[position..position + FRAME_CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+
+    Ok(frame_length)
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+// Reads the repr(C) layout of Metadata field by field, as the payload is not aligned.
+// This is a lot smaller in the bootloader than an unaligned read of the whole struct.
+fn read_metadata(payload: &[u8]) -> Metadata {
+    let word = |index: usize| read_u32(&payload[4 * index..]);
+    let image = |index: usize| ImageMetadata {
+        version: word(3 + 4 * index),
+        crc: word(4 + 4 * index),
+        flags: word(5 + 4 * index),
+        length: word(6 + 4 * index),
+    };
+
+    Metadata {
+        version: word(0),
+        bootcounter: word(1),
+        preferred_image: word(2),
+        images: core::array::from_fn(image),
+        crc: word(3 + 4 * NUMBER_OF_IMAGES),
+    }
+}
+
+/// Collects received bytes until a complete frame is available.
+/// Bytes in front of the frame magic are dropped, so a receiver can synchronize on the next frame
+/// after garbage was received.
+pub struct FrameDecoder {
+    buffer: [u8; MAX_FRAME_SIZE],
+    length: usize,
+    complete: bool,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder {
+    pub const fn new() -> Self {
+        FrameDecoder { buffer: [0; MAX_FRAME_SIZE], length: 0, complete: false }
+    }
+
+    /// Adds a received byte. Once it completes a frame, the frame (or why it is broken) is returned
+    /// and the next byte starts a new frame.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Frame<'_>, FrameError>> {
+        if self.complete {
+            self.length = 0;
+            self.complete = false;
+        }
+
+        // Wait for the magic
+        if self.length < FRAME_MAGIC.len() {
+            if byte == FRAME_MAGIC[self.length] {
+                self.buffer[self.length] = byte;
+                self.length += 1;
+            } else if byte == FRAME_MAGIC[0] {
+                self.buffer[0] = byte;
+                self.length = 1;
+            } else {
+                self.length = 0;
+            }
+            return None;
+        }
+
+        self.buffer[self.length] = byte;
+        self.length += 1;
+        if self.length < FRAME_HEADER_SIZE {
+            return None;
+        }
+
+        let payload_length = u16::from_le_bytes([self.buffer[3], self.buffer[4]]) as usize;
+        if payload_length > MAX_PAYLOAD_SIZE {
+            // We can't know where this frame ends, so start looking for the next magic
+            self.complete = true;
+            return Some(Err(FrameError::PayloadTooLarge));
+        }
+
+        let frame_length = FRAME_HEADER_SIZE + payload_length + FRAME_CRC_SIZE;
+        if self.length < frame_length {
+            return None;
+        }
+
+        self.complete = true;
+        Some(Frame::parse(&self.buffer[..frame_length]))
+    }
+}
+
+/// Commands sent by the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command<'a> {
+    /// Checks that the bootloader is in recovery mode
+    Ping,
+    /// Erases all pages of a slot
+    EraseSlot { slot: u32 },
+    /// Programs `data` at `offset` bytes into the (erased) slot.
+    /// Both offset and data length must be a multiple of 8 bytes.
+    WriteChunk { slot: u32, offset: u32, data: &'a [u8] },
+    /// Checks that the first `length` bytes of the slot match `crc`
+    Verify { slot: u32, length: u32, crc: u32 },
+    /// Writes valid metadata to both metadata pages
+    CommitMetadata { metadata: Metadata },
+    /// Leaves the recovery mode by resetting, which boots the new images
+    Reset,
+}
+
+impl<'a> Command<'a> {
+    /// Writes the command as frame into `out` and returns the length of the frame
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, FrameError> {
+        match self {
+            Command::Ping => encode_frame(KIND_PING, &[], out),
+            Command::EraseSlot { slot } => {
+                encode_frame(KIND_ERASE_SLOT, &[&slot.to_le_bytes()], out)
+            }
+            Command::WriteChunk { slot, offset, data } => encode_frame(
+                KIND_WRITE_CHUNK,
+                &[&slot.to_le_bytes(), &offset.to_le_bytes(), data],
+                out,
+            ),
+            Command::Verify { slot, length, crc } => encode_frame(
+                KIND_VERIFY,
+                &[&slot.to_le_bytes(), &length.to_le_bytes(), &crc.to_le_bytes()],
+                out,
+            ),
+            Command::CommitMetadata { metadata } => {
+                let bytes = unsafe {
+                    core::slice::from_raw_parts(
+                        metadata as *const Metadata as *const u8,
+                        METADATA_SIZE,
+                    )
+                };
+                encode_frame(KIND_COMMIT_METADATA, &[bytes], out)
+            }
+            Command::Reset => encode_frame(KIND_RESET, &[], out),
+        }
+    }
+
+    pub fn decode(frame: &Frame<'a>) -> Result<Self, FrameError> {
+        let payload = frame.payload;
+        let expect_length = |length: usize| {
+            if payload.len() == length {
+                Ok(())
+            } else {
+                Err(FrameError::InvalidPayload)
+            }
+        };
+
+        match frame.kind {
+            KIND_PING => expect_length(0).map(|_| Command::Ping),
+            KIND_ERASE_SLOT => {
+                expect_length(4).map(|_| Command::EraseSlot { slot: read_u32(payload) })
+            }
+            KIND_WRITE_CHUNK => {
+                if payload.len() < 8 {
+                    return Err(FrameError::InvalidPayload);
+                }
+                Ok(Command::WriteChunk {
+                    slot: read_u32(payload),
+                    offset: read_u32(&payload[4..]),
+                    data: &payload[8..],
+                })
+            }
+            KIND_VERIFY => expect_length(12).map(|_| Command::Verify {
+                slot: read_u32(payload),
+                length: read_u32(&payload[4..]),
+                crc: read_u32(&payload[8..]),
+            }),
+            KIND_COMMIT_METADATA => {
+                expect_length(METADATA_SIZE)?;
+                Ok(Command::CommitMetadata { metadata: read_metadata(payload) })
+            }
+            KIND_RESET => expect_length(0).map(|_| Command::Reset),
+            _ => Err(FrameError::UnknownKind),
+        }
+    }
+}
+
+/// Why the bootloader rejected a command
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NackReason {
+    /// The command frame was broken, e.g. it had an invalid CRC. It's safe to send it again.
+    InvalidFrame = 1,
+    UnknownCommand = 2,
+    /// A slot number, offset or length is out of range or misaligned
+    InvalidArgument = 3,
+    /// Erasing or programming the flash failed
+    FlashError = 4,
+    /// The slot content does not match the CRC
+    VerifyFailed = 5,
+    /// The metadata CRC or version is invalid
+    InvalidMetadata = 6,
+    /// The flash controller didn't finish erasing or programming in time, it probably hangs.
+    /// Only a reset (Command::Reset) might get it working again.
+    FlashTimeout = 7,
+}
+
+impl NackReason {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(NackReason::InvalidFrame),
+            2 => Some(NackReason::UnknownCommand),
+            3 => Some(NackReason::InvalidArgument),
+            4 => Some(NackReason::FlashError),
+            5 => Some(NackReason::VerifyFailed),
+            6 => Some(NackReason::InvalidMetadata),
+            7 => Some(NackReason::FlashTimeout),
+            _ => None,
+        }
+    }
+}
+
+impl From<FrameError> for NackReason {
+    fn from(error: FrameError) -> Self {
+        match error {
+            FrameError::UnknownKind => NackReason::UnknownCommand,
+            FrameError::InvalidPayload => NackReason::InvalidArgument,
+            FrameError::BufferTooSmall | FrameError::PayloadTooLarge | FrameError::InvalidCrc => {
+                NackReason::InvalidFrame
+            }
+        }
+    }
+}
+
+/// Responses sent by the bootloader, one for every command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Response {
+    Ack,
+    Nack(NackReason),
+}
+
+// A response frame is always small
+pub const MAX_RESPONSE_FRAME_SIZE: usize = FRAME_HEADER_SIZE + 1 + FRAME_CRC_SIZE;
+
+impl Response {
+    /// Writes the response as frame into `out` and returns the length of the frame
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, FrameError> {
+        match self {
+            Response::Ack => encode_frame(KIND_ACK, &[], out),
+            Response::Nack(reason) => encode_frame(KIND_NACK, &[&[*reason as u8]], out),
+        }
+    }
+
+    pub fn decode(frame: &Frame<'_>) -> Result<Self, FrameError> {
+        match (frame.kind, frame.payload) {
+            (KIND_ACK, []) => Ok(Response::Ack),
+            (KIND_NACK, &[reason]) => {
+                NackReason::from_u8(reason).map(Response::Nack).ok_or(FrameError::InvalidPayload)
+            }
+            (KIND_ACK, _) | (KIND_NACK, _) => Err(FrameError::InvalidPayload),
+            _ => Err(FrameError::UnknownKind),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feeds all bytes into the decoder and decodes the frame completed by the last byte
+    fn decode_command<'a>(
+        decoder: &'a mut FrameDecoder, bytes: &[u8],
+    ) -> Result<Command<'a>, FrameError> {
+        let (last, rest) = bytes.split_last().unwrap();
+        for &byte in rest {
+            assert!(decoder.push(byte).is_none(), "Frame completed too early");
+        }
+        let frame = decoder.push(*last).expect("Frame not complete")?;
+        Command::decode(&frame)
+    }
+
+    fn round_trip(command: Command<'_>) {
+        let mut buffer = [0u8; MAX_FRAME_SIZE];
+        let length = command.encode(&mut buffer).unwrap();
+        assert_eq!(decode_command(&mut FrameDecoder::new(), &buffer[..length]), Ok(command));
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let mut metadata = Metadata {
+            version: 7,
+            bootcounter: 0,
+            preferred_image: 2,
+            images: [ImageMetadata { version: 1, crc: 0x1234, flags: 0, length: 0x100 };
+                NUMBER_OF_IMAGES],
+            crc: 0,
+        };
+        metadata.set_crc();
+        let data = [0x42u8; MAX_CHUNK_SIZE];
+
+        round_trip(Command::Ping);
+        round_trip(Command::EraseSlot { slot: 2 });
+        round_trip(Command::WriteChunk { slot: 1, offset: 0x800, data: &data });
+        round_trip(Command::Verify { slot: 0, length: 0x4321, crc: 0xdeadbeef });
+        round_trip(Command::CommitMetadata { metadata });
+        round_trip(Command::Reset);
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        for response in [
+            Response::Ack,
+            Response::Nack(NackReason::InvalidFrame),
+            Response::Nack(NackReason::InvalidMetadata),
+            Response::Nack(NackReason::FlashTimeout),
+        ] {
+            let mut buffer = [0u8; MAX_RESPONSE_FRAME_SIZE];
+            let length = response.encode(&mut buffer).unwrap();
+            assert_eq!(Response::decode(&Frame::parse(&buffer[..length]).unwrap()), Ok(response));
+        }
+    }
+
+    #[test]
+    fn detects_corrupted_frame() {
+        let mut buffer = [0u8; MAX_FRAME_SIZE];
+        let length = Command::Verify { slot: 0, length: 1, crc: 2 }.encode(&mut buffer).unwrap();
+
+        buffer[7] ^= 0x10;
+        assert_eq!(
+            decode_command(&mut FrameDecoder::new(), &buffer[..length]),
+            Err(FrameError::InvalidCrc)
+        );
+    }
+
+    #[test]
+    fn synchronizes_on_magic() {
+        let mut buffer = [0u8; 64];
+        buffer[..5].copy_from_slice(&[0x00, 0x4d, 0x4d, 0x12, 0x4c]);
+        let length = Command::EraseSlot { slot: 1 }.encode(&mut buffer[5..]).unwrap();
+
+        assert_eq!(
+            decode_command(&mut FrameDecoder::new(), &buffer[..5 + length]),
+            Ok(Command::EraseSlot { slot: 1 })
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_payload() {
+        let data = [0u8; MAX_CHUNK_SIZE + 8];
+        let mut buffer = [0u8; 2 * MAX_FRAME_SIZE];
+        assert_eq!(
+            Command::WriteChunk { slot: 0, offset: 0, data: &data }.encode(&mut buffer),
+            Err(FrameError::PayloadTooLarge)
+        );
+
+        // The decoder gives up as soon as it sees the length
+        let mut decoder = FrameDecoder::new();
+        for byte in [0x4d, 0x4c, KIND_WRITE_CHUNK, 0xff, 0xff] {
+            if let Some(result) = decoder.push(byte) {
+                assert_eq!(result, Err(FrameError::PayloadTooLarge));
+                return;
+            }
+        }
+        panic!("Decoder accepted an oversized frame");
+    }
+
+    #[test]
+    fn rejects_wrong_payload_length() {
+        let mut buffer = [0u8; MAX_FRAME_SIZE];
+        let length = encode_frame(KIND_ERASE_SLOT, &[&[1, 2]], &mut buffer).unwrap();
+        assert_eq!(
+            decode_command(&mut FrameDecoder::new(), &buffer[..length]),
+            Err(FrameError::InvalidPayload)
+        );
+
+        let length = encode_frame(0x42, &[], &mut buffer).unwrap();
+        assert_eq!(
+            decode_command(&mut FrameDecoder::new(), &buffer[..length]),
+            Err(FrameError::UnknownKind)
+        );
+    }
+}
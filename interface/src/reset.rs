@@ -0,0 +1,106 @@
+// Decoding of the reset flags in RCC_CSR (see 6.4.29 in the reference manual).
+// The bootloader passes the flags on to the OS in the BootInfo (see bootinfo.rs) and clears them
+// afterwards with RCC_CSR_RMVF, so they always describe the last reset only.
+
+// The reset flags in RCC_CSR
+pub const RCC_CSR_LPWRRSTF: u32 = 1 << 31;
+pub const RCC_CSR_WWDGRSTF: u32 = 1 << 30;
+pub const RCC_CSR_IWDGRSTF: u32 = 1 << 29;
+pub const RCC_CSR_SFTRSTF: u32 = 1 << 28;
+pub const RCC_CSR_BORRSTF: u32 = 1 << 27;
+pub const RCC_CSR_PINRSTF: u32 = 1 << 26;
+pub const RCC_CSR_OBLRSTF: u32 = 1 << 25;
+pub const RCC_CSR_FWRSTF: u32 = 1 << 24;
+pub const RCC_CSR_RESET_FLAGS: u32 = 0xff00_0000;
+
+/// Writing this bit clears all reset flags
+pub const RCC_CSR_RMVF: u32 = 1 << 23;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetCause {
+    /// Power-on or brown-out reset
+    PowerOn,
+    /// The NRST pin, e.g. the reset button or a debugger
+    Pin,
+    /// SYSRESETREQ, e.g. the OS resets the device to execute a mailbox command
+    Software,
+    /// The independent watchdog (IWDG). A lockup of the core has no flag of its own, it ends in
+    /// a watchdog reset, as the IWDG keeps running.
+    IndependentWatchdog,
+    /// The window watchdog (WWDG)
+    WindowWatchdog,
+    /// Entering Stop 0/1/2, Standby or Shutdown mode while it is not allowed by the option bytes
+    LowPower,
+    /// An access that violated the firewall
+    Firewall,
+    /// Loading the option bytes (OBL_LAUNCH)
+    OptionByteLoader,
+    /// No reset flag is set, e.g. because they were cleared without a reset in between
+    Unknown,
+}
+
+impl ResetCause {
+    /// Decodes the reset flags of RCC_CSR, other bits are ignored.
+    /// Every internal reset drives the NRST pin as well, so PINRSTF is set together with the
+    /// flag of the actual cause and only counts if there is no other flag.
+    pub fn from_rcc_csr(csr: u32) -> Self {
+        // The flags that cause a reset of their own come first, as the flags are only cleared
+        // by the bootloader, so a reset in between (e.g. of a debugger) might leave more than one
+        const CAUSES: [(u32, ResetCause); 8] = [
+            (RCC_CSR_IWDGRSTF, ResetCause::IndependentWatchdog),
+            (RCC_CSR_WWDGRSTF, ResetCause::WindowWatchdog),
+            (RCC_CSR_FWRSTF, ResetCause::Firewall),
+            (RCC_CSR_LPWRRSTF, ResetCause::LowPower),
+            (RCC_CSR_SFTRSTF, ResetCause::Software),
+            (RCC_CSR_OBLRSTF, ResetCause::OptionByteLoader),
+            (RCC_CSR_BORRSTF, ResetCause::PowerOn),
+            (RCC_CSR_PINRSTF, ResetCause::Pin),
+        ];
+
+        CAUSES
+            .iter()
+            .find(|&&(flag, _)| csr & flag != 0)
+            .map_or(ResetCause::Unknown, |&(_, cause)| cause)
+    }
+
+    /// Whether the reset means that the running image failed, so its boot must not count as
+    /// confirmed, even if the OS confirmed it before
+    pub fn is_failed_boot(&self) -> bool {
+        matches!(
+            self,
+            ResetCause::IndependentWatchdog | ResetCause::WindowWatchdog | ResetCause::Firewall
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_reset_flags() {
+        assert_eq!(ResetCause::from_rcc_csr(0), ResetCause::Unknown);
+        assert_eq!(ResetCause::from_rcc_csr(RCC_CSR_PINRSTF), ResetCause::Pin);
+        assert_eq!(
+            ResetCause::from_rcc_csr(RCC_CSR_BORRSTF | RCC_CSR_PINRSTF),
+            ResetCause::PowerOn
+        );
+        assert_eq!(
+            ResetCause::from_rcc_csr(RCC_CSR_IWDGRSTF | RCC_CSR_PINRSTF),
+            ResetCause::IndependentWatchdog
+        );
+        assert_eq!(
+            ResetCause::from_rcc_csr(RCC_CSR_SFTRSTF | RCC_CSR_PINRSTF | 0x0c00),
+            ResetCause::Software
+        );
+        // A watchdog reset is not hidden by a later one of the debugger
+        assert_eq!(
+            ResetCause::from_rcc_csr(RCC_CSR_WWDGRSTF | RCC_CSR_SFTRSTF | RCC_CSR_PINRSTF),
+            ResetCause::WindowWatchdog
+        );
+
+        assert!(ResetCause::IndependentWatchdog.is_failed_boot());
+        assert!(!ResetCause::PowerOn.is_failed_boot());
+        assert!(!ResetCause::Software.is_failed_boot());
+    }
+}
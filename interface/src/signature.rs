@@ -0,0 +1,42 @@
+// Images can optionally be signed with Ed25519. The signature is stored in a trailer in the last
+// bytes of the slot, so it doesn't depend on the metadata and can also be checked if there is no
+// valid metadata. A signed image must not overlap the trailer.
+//
+// The signed message is the image itself (the first `length` bytes of the slot). If the
+// bootloader was built with a public key, it only boots images with a valid signature.
+
+use core::mem::size_of;
+
+use crate::SLOT_SIZE;
+
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const SIGNATURE_SIZE: usize = 64;
+
+pub const SIGNATURE_MAGIC: u32 = 0x5349_474e; // "SIGN"
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageSignature {
+    // Must be SIGNATURE_MAGIC, anything else means the image is not signed
+    pub magic: u32,
+    // The length of the signed image
+    pub length: u32,
+    pub signature: [u8; SIGNATURE_SIZE],
+}
+
+/// Offset of the ImageSignature from the start of a slot
+pub const SIGNATURE_OFFSET: u32 = SLOT_SIZE - size_of::<ImageSignature>() as u32;
+
+/// The maximum length of a signed image, as it must not overlap its signature
+pub const MAX_SIGNED_IMAGE_LENGTH: u32 = SIGNATURE_OFFSET;
+
+mod asserts {
+    use super::*;
+    use static_assertions::{const_assert, const_assert_eq};
+
+    const_assert_eq!(size_of::<ImageSignature>(), 72);
+
+    // The trailer is programmed as double-words, e.g. by the recovery protocol
+    const_assert!(SIGNATURE_OFFSET.is_multiple_of(8));
+    const_assert!(size_of::<ImageSignature>().is_multiple_of(8));
+}
@@ -0,0 +1,166 @@
+// Every slot written by the image-builder ends with an ImageTrailer that describes the image in it.
+// The metadata pages are the only source of truth for which image to boot, but with the trailer,
+// the contents of a slot can still be identified and verified if both metadata pages are lost.
+//
+// The trailer is placed right in front of the signature trailer (see signature.rs), so the image
+// itself still starts at the beginning of the slot and can be copied to RAM as it is.
+// Its load address tells XIP images (see IMAGE_FLAG_XIP) apart from the others.
+
+use core::mem::size_of;
+
+use crate::crc::calc_crc32;
+use crate::sha256::{sha256, DIGEST_SIZE};
+use crate::signature::SIGNATURE_OFFSET;
+use crate::{ImageMetadata, FLASH_ADDR, IMAGE_FLAG_XIP, RAM_ADDR};
+
+pub const IMAGE_TRAILER_MAGIC: u32 = 0x494d_4147; // "IMAG"
+
+// Increment this if the layout of ImageTrailer changes
+pub const IMAGE_TRAILER_VERSION: u32 = 1;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageTrailer {
+    // Must be IMAGE_TRAILER_MAGIC, anything else means the slot has no trailer
+    pub magic: u32,
+    pub trailer_version: u32,
+    pub length: u32,
+    // The same as ImageMetadata::version
+    pub image_version: u32,
+    // CRC32-C of the image
+    pub crc: u32,
+    // Where the image is copied to before it is started
+    pub load_address: u32,
+    // SHA-256 of the image
+    pub digest: [u8; DIGEST_SIZE],
+    // a CRC over the previous part of the struct, but not the CRC field
+    pub trailer_crc: u32,
+    pub reserved: u32,
+}
+
+/// Offset of the ImageTrailer from the start of a slot
+pub const IMAGE_TRAILER_OFFSET: u32 = SIGNATURE_OFFSET - size_of::<ImageTrailer>() as u32;
+
+/// The maximum length of an image, as it must not overlap its trailer
+pub const MAX_IMAGE_LENGTH: u32 = IMAGE_TRAILER_OFFSET;
+
+impl ImageTrailer {
+    /// Creates the trailer for an image that is copied to RAM_ADDR
+    pub fn new(image: &[u8], image_version: u32) -> Self {
+        Self::with_load_address(image, image_version, RAM_ADDR)
+    }
+
+    /// Creates the trailer for an image that is started from `load_address`,
+    /// see ImageMetadata::load_address
+    pub fn with_load_address(image: &[u8], image_version: u32, load_address: u32) -> Self {
+        let mut trailer = ImageTrailer {
+            magic: IMAGE_TRAILER_MAGIC,
+            trailer_version: IMAGE_TRAILER_VERSION,
+            length: image.len() as u32,
+            image_version,
+            crc: calc_crc32(image.as_ptr(), image.len()),
+            load_address,
+            digest: sha256(image),
+            trailer_crc: 0,
+            reserved: 0,
+        };
+        trailer.trailer_crc = trailer.calc_crc();
+        trailer
+    }
+
+    /// Whether this is an intact trailer we understand. It says nothing about the image.
+    pub fn is_valid(&self) -> bool {
+        self.magic == IMAGE_TRAILER_MAGIC
+            && self.trailer_version == IMAGE_TRAILER_VERSION
+            && self.trailer_crc == self.calc_crc()
+            && self.length <= MAX_IMAGE_LENGTH
+    }
+
+    /// The ImageMetadata of the image in the slot at `slot_addr`, as the metadata pages would
+    /// have it. Returns None if the image can neither be copied to RAM_ADDR nor run in place.
+    pub fn image_metadata(&self, slot_addr: u32) -> Option<ImageMetadata> {
+        let flags = if self.load_address == RAM_ADDR {
+            0
+        } else if self.load_address == FLASH_ADDR + slot_addr {
+            IMAGE_FLAG_XIP
+        } else {
+            return None;
+        };
+
+        Some(ImageMetadata {
+            version: self.image_version,
+            crc: self.crc,
+            flags,
+            length: self.length,
+        })
+    }
+
+    pub fn calc_crc(&self) -> u32 {
+        const CRC_OFFSET: usize = size_of::<ImageTrailer>() - 2 * size_of::<u32>();
+        calc_crc32(self as *const _ as *const u8, CRC_OFFSET)
+    }
+}
+
+mod asserts {
+    use super::*;
+    use static_assertions::const_assert;
+
+    const_assert!(size_of::<ImageTrailer>() == 64);
+
+    // The trailer is programmed as double-words, e.g. by the recovery protocol
+    const_assert!(IMAGE_TRAILER_OFFSET.is_multiple_of(8));
+    const_assert!(size_of::<ImageTrailer>().is_multiple_of(8));
+    const_assert!(IMAGE_TRAILER_OFFSET + size_of::<ImageTrailer>() as u32 == SIGNATURE_OFFSET);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trailer_describes_image() {
+        let image = [0x61, 0x65, 0x6e, 0x67, 0x65, 0x6c, 0x6b, 0x65];
+        let trailer = ImageTrailer::new(&image, 7);
+
+        assert!(trailer.is_valid());
+        assert_eq!(trailer.length, 8);
+        assert_eq!(trailer.image_version, 7);
+        assert_eq!(trailer.crc, 0x7909E7C4);
+        assert_eq!(trailer.digest, sha256(&image));
+        assert_eq!(trailer.load_address, RAM_ADDR);
+        assert_eq!(trailer.image_metadata(0xc000).map(|meta| meta.flags), Some(0));
+    }
+
+    #[test]
+    fn trailer_of_xip_image() {
+        let image = [1, 2, 3];
+        let trailer = ImageTrailer::with_load_address(&image, 2, FLASH_ADDR + 0xc000);
+        assert!(trailer.is_valid());
+
+        let image_meta = trailer.image_metadata(0xc000).unwrap();
+        assert!(image_meta.is_xip());
+        assert_eq!(image_meta.load_address(0xc000), trailer.load_address);
+        assert_eq!(image_meta.crc, trailer.crc);
+
+        // Linked for another slot
+        assert_eq!(trailer.image_metadata(0x88000), None);
+    }
+
+    #[test]
+    fn reject_invalid_trailer() {
+        let valid = ImageTrailer::new(&[1, 2, 3], 1);
+
+        let mut corrupted = valid;
+        corrupted.image_version = 2;
+        assert!(!corrupted.is_valid());
+
+        let mut too_long = valid;
+        too_long.length = MAX_IMAGE_LENGTH + 1;
+        too_long.trailer_crc = too_long.calc_crc();
+        assert!(!too_long.is_valid());
+
+        // Erased flash
+        let erased = unsafe { core::mem::transmute::<[u8; 64], ImageTrailer>([0xff; 64]) };
+        assert!(!erased.is_valid());
+    }
+}
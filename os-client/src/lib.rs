@@ -0,0 +1,639 @@
+#![no_std]
+
+// The OS side of an image update, so every OS doesn't have to reimplement it.
+// An update always takes the same steps (see doc/User-Guide.md):
+//
+// 1. `write_slot` erases a slot and programs the new image, followed by its trailer
+// 2. `next_metadata` builds the metadata that boots the new image, with a bumped version
+// 3. `commit_metadata` writes it to the page with the older copy first, then to the other one
+//
+// One metadata page always stays valid, so if the power is lost at any point, the bootloader
+// either boots with the old metadata or completes the commit with the new one.
+// The flash is accessed through the same FlashDevice trait as in the bootloader, so the whole
+// update can be tested on the host against boot_core::sim::SimFlash.
+//
+// `send_command` puts a command for the next boot into the mailbox in the RTC backup registers.
+// To try a new image before making it permanent, commit `staged_metadata` instead of
+// `next_metadata` in step 3 and send MailboxCommand::BootOnce. If the new image works, it
+// commits `next_metadata` itself, otherwise the next reset boots the preferred image again.
+// `boot_info` tells the running image which slot it was booted from and why.
+//
+// For a delta update, `apply_patch` replaces step 1: it builds the new image from a patch (see
+// interface::delta) and the image in another slot, and checks it against the patch before the
+// metadata for it is built.
+
+#[cfg(test)]
+extern crate std;
+
+use boot_core::backup::{read_boot_info, BackupRegisters};
+use boot_core::flash::{self, FlashDevice, UnlockedFlash};
+use boot_core::metadata::{read_valid_metadata, verify_image, write_metadata};
+use boot_core::pages::page_span;
+use interface::bootinfo::BootInfo;
+use interface::crc::calc_crc32;
+use interface::delta::{self, Operation, Patch};
+use interface::mailbox::{MailboxCommand, MAILBOX_REG};
+use interface::trailer::{ImageTrailer, IMAGE_TRAILER_OFFSET, MAX_IMAGE_LENGTH};
+use interface::{
+    ImageMetadata, Metadata, METADATA_1_ADDR, METADATA_2_ADDR, NUMBER_OF_IMAGES, SLOT_ADDRS,
+    SLOT_SIZE,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    Flash(flash::Error),
+    InvalidSlot,
+    ImageTooLarge,
+    // The flash content doesn't match what we programmed
+    VerifyFailed,
+    // Neither metadata page is valid, so there is nothing to build the next metadata on
+    NoValidMetadata,
+    // The metadata is not newer than the committed one, or its version can't be bumped anymore
+    InvalidVersion,
+    // The CRC or the preferred image of the metadata is invalid
+    InvalidMetadata,
+    // The patch is corrupted, or its operations don't fit the lengths in its header
+    InvalidPatch(delta::PatchError),
+    // The source slot doesn't hold the image the patch was made for
+    SourceMismatch,
+}
+
+impl From<flash::Error> for Error {
+    fn from(error: flash::Error) -> Self {
+        Error::Flash(error)
+    }
+}
+
+impl From<delta::PatchError> for Error {
+    fn from(error: delta::PatchError) -> Self {
+        Error::InvalidPatch(error)
+    }
+}
+
+/// The ImageMetadata for `image`, with its CRC and length
+pub fn image_metadata(image: &[u8], version: u32) -> ImageMetadata {
+    ImageMetadata {
+        version,
+        crc: calc_crc32(image.as_ptr(), image.len()),
+        flags: 0,
+        length: image.len() as u32,
+    }
+}
+
+/// Erases the whole slot, programs `image` and its trailer (see interface::trailer) and checks
+/// that the image was written correctly. Returns the ImageMetadata for `next_metadata`.
+///
+/// The slot is not bootable while it is written, so this should not be the preferred slot.
+pub fn write_slot<F: FlashDevice>(
+    flash: &mut F, slot: usize, image: &[u8], version: u32,
+) -> Result<ImageMetadata, Error> {
+    let address = *SLOT_ADDRS.get(slot).ok_or(Error::InvalidSlot)?;
+    if image.len() > MAX_IMAGE_LENGTH as usize {
+        return Err(Error::ImageTooLarge);
+    }
+
+    let trailer = ImageTrailer::new(image, version);
+
+    with_unlocked(flash, |flash| {
+        erase_slot(flash, address)?;
+        program(flash, address, image)?;
+        Ok(program(flash, address + IMAGE_TRAILER_OFFSET, trailer_bytes(&trailer))?)
+    })?;
+
+    let image_meta = image_metadata(image, version);
+    if !verify_image(flash, &image_meta, address, None) {
+        return Err(Error::VerifyFailed);
+    }
+
+    Ok(image_meta)
+}
+
+/// Applies a delta `patch` (see interface::delta) to the image in `source_slot` and writes the
+/// new image to `target_slot` like `write_slot`. The patch is checked against the source image
+/// before anything is erased, and the new image against the length and CRC the patch promises
+/// before its trailer is written. Returns the ImageMetadata for `next_metadata`.
+///
+/// Like with `write_slot`, the target slot should not be the preferred slot.
+pub fn apply_patch<F: FlashDevice>(
+    flash: &mut F, source_slot: usize, target_slot: usize, patch: &[u8], version: u32,
+) -> Result<ImageMetadata, Error> {
+    let source_address = *SLOT_ADDRS.get(source_slot).ok_or(Error::InvalidSlot)?;
+    let address = *SLOT_ADDRS.get(target_slot).ok_or(Error::InvalidSlot)?;
+    if source_slot == target_slot {
+        return Err(Error::InvalidSlot);
+    }
+
+    let patch = Patch::parse(patch)?;
+    let header = patch.header;
+    if header.target_length > MAX_IMAGE_LENGTH {
+        return Err(Error::ImageTooLarge);
+    }
+    let source_length = header.source_length.min(MAX_IMAGE_LENGTH) as usize;
+    let source = flash.read(source_address, source_length);
+    if source_length != header.source_length as usize
+        || calc_crc32(source.as_ptr(), source_length) != header.source_crc
+    {
+        return Err(Error::SourceMismatch);
+    }
+
+    with_unlocked(flash, |flash| {
+        erase_slot(flash, address)?;
+
+        let mut writer = SlotWriter::new(address);
+        for operation in patch.operations() {
+            match operation? {
+                Operation::Copy { offset, length } => {
+                    writer.copy(flash, source_address + offset, length as usize)?
+                }
+                Operation::Insert(bytes) => writer.insert(flash, bytes)?,
+            }
+        }
+        Ok(writer.finish(flash)?)
+    })?;
+
+    let image_meta =
+        ImageMetadata { version, crc: header.target_crc, flags: 0, length: header.target_length };
+    if !verify_image(flash, &image_meta, address, None) {
+        return Err(Error::VerifyFailed);
+    }
+
+    let trailer = ImageTrailer::new(flash.read(address, header.target_length as usize), version);
+    with_unlocked(flash, |flash| {
+        Ok(program(flash, address + IMAGE_TRAILER_OFFSET, trailer_bytes(&trailer))?)
+    })?;
+
+    Ok(image_meta)
+}
+
+/// The metadata the bootloader currently uses: the newer one of the valid metadata pages
+pub fn read_metadata<F: FlashDevice>(flash: &F) -> Result<Metadata, Error> {
+    match read_pages(flash) {
+        [Some(one), Some(two)] if two.version > one.version => Ok(two),
+        [Some(one), _] => Ok(one),
+        [None, Some(two)] => Ok(two),
+        [None, None] => Err(Error::NoValidMetadata),
+    }
+}
+
+/// Builds the metadata that replaces `current`: `slot` gets the image described by `image`
+/// and becomes the preferred image, and the version is bumped.
+/// To change anything else, modify the result and call `Metadata::set_crc` again.
+pub fn next_metadata(
+    current: &Metadata, slot: usize, image: ImageMetadata,
+) -> Result<Metadata, Error> {
+    if slot >= NUMBER_OF_IMAGES {
+        return Err(Error::InvalidSlot);
+    }
+
+    // 0xffffffff looks like erased flash, see Metadata
+    let version = current.version.checked_add(1).filter(|&version| version != u32::MAX);
+
+    let mut next = *current;
+    next.version = version.ok_or(Error::InvalidVersion)?;
+    next.preferred_image = slot as u32;
+    next.images[slot] = image;
+    next.set_crc();
+
+    Ok(next)
+}
+
+/// Like `next_metadata`, but the preferred image stays the same, so the new image is only
+/// booted on request (MailboxCommand::BootOnce). The bootloader only boots an image that
+/// matches the metadata, so the new image must be committed like this before a trial boot.
+/// `slot` must not be the preferred image, as that would make the new image permanent.
+pub fn staged_metadata(
+    current: &Metadata, slot: usize, image: ImageMetadata,
+) -> Result<Metadata, Error> {
+    if slot == current.preferred_image as usize {
+        return Err(Error::InvalidSlot);
+    }
+
+    let mut staged = next_metadata(current, slot, image)?;
+    staged.preferred_image = current.preferred_image;
+    staged.set_crc();
+
+    Ok(staged)
+}
+
+/// Writes `metadata` to both metadata pages, the one with the older copy first.
+/// The newer copy stays intact until the new metadata is on the flash, and if the power is
+/// lost before the second page is written, the bootloader copies the new metadata over.
+pub fn commit_metadata<F: FlashDevice>(flash: &mut F, metadata: &Metadata) -> Result<(), Error> {
+    let valid_preferred = (metadata.preferred_image as usize) < NUMBER_OF_IMAGES;
+    if !metadata.is_valid() || !valid_preferred {
+        return Err(Error::InvalidMetadata);
+    }
+
+    // The bootloader would overwrite metadata that isn't newer during its fixup
+    let pages = read_pages(flash);
+    let committed = read_metadata(flash).map_or(0, |committed| committed.version);
+    if metadata.version <= committed || metadata.version == u32::MAX {
+        return Err(Error::InvalidVersion);
+    }
+
+    let order = match pages {
+        [Some(one), Some(two)] if one.version > two.version => [METADATA_2_ADDR, METADATA_1_ADDR],
+        [Some(_), None] => [METADATA_2_ADDR, METADATA_1_ADDR],
+        _ => [METADATA_1_ADDR, METADATA_2_ADDR],
+    };
+
+    for addr in order {
+        if write_metadata(flash, metadata, None, addr)? != *metadata {
+            return Err(Error::VerifyFailed);
+        }
+    }
+
+    Ok(())
+}
+
+/// Puts `command` into the mailbox (see interface::mailbox), so the bootloader executes it on the
+/// next boot. Resetting the device is up to the OS, as is enabling write access to the backup
+/// domain before.
+pub fn send_command<B: BackupRegisters>(backup: &mut B, command: MailboxCommand) {
+    // The checksum is written last, so an interrupted write leaves no valid command behind
+    for (i, value) in command.encode().into_iter().enumerate() {
+        backup.write(MAILBOX_REG + i, value);
+    }
+}
+
+/// The BootInfo the bootloader left for the running image (see interface::bootinfo), e.g. to
+/// find out whether it runs as a trial boot or after a fallback. None if there is no intact one.
+pub fn boot_info<B: BackupRegisters>(backup: &B) -> Option<BootInfo> {
+    read_boot_info(backup)
+}
+
+fn read_pages<F: FlashDevice>(flash: &F) -> [Option<Metadata>; 2] {
+    [METADATA_1_ADDR, METADATA_2_ADDR].map(|addr| read_valid_metadata(flash, addr))
+}
+
+// Runs `operation` on the unlocked flash, which is locked again afterwards
+fn with_unlocked<F: FlashDevice>(
+    flash: &mut F, operation: impl FnOnce(&mut UnlockedFlash<F>) -> Result<(), Error>,
+) -> Result<(), Error> {
+    operation(&mut flash.unlock()?)
+}
+
+// The whole slot is erased, so no stale trailer or signature of the old image is left
+fn erase_slot<F: FlashDevice>(
+    flash: &mut UnlockedFlash<F>, address: u32,
+) -> Result<(), flash::Error> {
+    let first_page = flash.address_to_page_number(address);
+    let pages = page_span(SLOT_SIZE, flash.page_size());
+    for page in first_page..first_page + pages {
+        flash.erase_page(page)?;
+    }
+
+    Ok(())
+}
+
+fn trailer_bytes(trailer: &ImageTrailer) -> &[u8] {
+    unsafe {
+        core::slice::from_raw_parts(
+            trailer as *const ImageTrailer as *const u8,
+            core::mem::size_of::<ImageTrailer>(),
+        )
+    }
+}
+
+// Programs `data` to the erased flash at `address`.
+// Only whole double-words can be programmed, so the last one is padded like erased flash.
+fn program<F: FlashDevice>(
+    flash: &mut UnlockedFlash<F>, address: u32, data: &[u8],
+) -> Result<(), flash::Error> {
+    let mut dwords = [0u64; 32];
+    for (index, chunk) in data.chunks(dwords.len() * 8).enumerate() {
+        for (dword, bytes) in dwords.iter_mut().zip(chunk.chunks(8)) {
+            let mut padded = [0xff; 8];
+            padded[..bytes.len()].copy_from_slice(bytes);
+            *dword = u64::from_le_bytes(padded);
+        }
+
+        let offset = (index * dwords.len() * 8) as u32;
+        flash.write_dwords(address + offset, &dwords[..chunk.len().div_ceil(8)])?;
+    }
+
+    Ok(())
+}
+
+// Programs the new image of a patch piece by piece. The pieces are collected into whole
+// double-words, and copies are read from the source slot only right before they are programmed.
+struct SlotWriter {
+    address: u32,
+    buffer: [u8; 256],
+    length: usize,
+}
+
+impl SlotWriter {
+    fn new(address: u32) -> Self {
+        SlotWriter { address, buffer: [0; 256], length: 0 }
+    }
+
+    fn copy<F: FlashDevice>(
+        &mut self, flash: &mut UnlockedFlash<F>, address: u32, length: usize,
+    ) -> Result<(), flash::Error> {
+        let mut done = 0;
+        while done < length {
+            let chunk = (length - done).min(self.buffer.len() - self.length);
+            let bytes = flash.read(address + done as u32, chunk);
+            self.buffer[self.length..self.length + chunk].copy_from_slice(bytes);
+            self.length += chunk;
+            done += chunk;
+            self.program_full(flash)?;
+        }
+
+        Ok(())
+    }
+
+    fn insert<F: FlashDevice>(
+        &mut self, flash: &mut UnlockedFlash<F>, bytes: &[u8],
+    ) -> Result<(), flash::Error> {
+        for chunk in bytes.chunks(self.buffer.len()) {
+            let free = (self.buffer.len() - self.length).min(chunk.len());
+            self.buffer[self.length..self.length + free].copy_from_slice(&chunk[..free]);
+            self.length += free;
+            self.program_full(flash)?;
+
+            let rest = &chunk[free..];
+            self.buffer[..rest.len()].copy_from_slice(rest);
+            self.length += rest.len();
+        }
+
+        Ok(())
+    }
+
+    fn program_full<F: FlashDevice>(
+        &mut self, flash: &mut UnlockedFlash<F>,
+    ) -> Result<(), flash::Error> {
+        if self.length == self.buffer.len() {
+            program(flash, self.address, &self.buffer)?;
+            self.address += self.buffer.len() as u32;
+            self.length = 0;
+        }
+
+        Ok(())
+    }
+
+    // Programs the rest, padded like erased flash
+    fn finish<F: FlashDevice>(self, flash: &mut UnlockedFlash<F>) -> Result<(), flash::Error> {
+        program(flash, self.address, &self.buffer[..self.length])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec;
+    use std::vec::Vec;
+
+    use boot_core::boot::{boot, BootTarget};
+    use boot_core::policy::GoldenOnly;
+    use boot_core::sim::{BankMode, Interruption, SimBackupRegisters, SimFlash, SimWatchdog};
+    use boot_core::trailer::read_trailer;
+    use interface::bootinfo::BootReason;
+    use interface::delta::PatchHeader;
+
+    use super::*;
+
+    fn test_image(seed: u8, length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i as u8).wrapping_mul(29).wrapping_add(seed)).collect()
+    }
+
+    fn flash_with_images(bank_mode: BankMode) -> (SimFlash, Metadata) {
+        let images = [test_image(1, 0x1234), test_image(2, 0x800), test_image(3, 0x2100)];
+        SimFlash::with_images(bank_mode, [&images[0], &images[1], &images[2]])
+    }
+
+    fn run_boot(flash: &mut SimFlash) -> (BootTarget, Vec<u8>) {
+        run_boot_with_backup(flash, &mut SimBackupRegisters::new())
+    }
+
+    fn run_boot_with_backup(
+        flash: &mut SimFlash, backup: &mut SimBackupRegisters,
+    ) -> (BootTarget, Vec<u8>) {
+        let mut ram = vec![0u8; SLOT_SIZE as usize];
+        let target =
+            boot(flash, backup, &mut SimWatchdog::default(), &GoldenOnly, None, 0, &mut ram);
+        (target, ram)
+    }
+
+    #[test]
+    fn update_boots_new_image() {
+        for bank_mode in [BankMode::SingleBank, BankMode::DualBank] {
+            let (mut flash, metadata) = flash_with_images(bank_mode);
+            let image = test_image(42, 0x3003);
+
+            let image_meta = write_slot(&mut flash, 2, &image, 7).unwrap();
+            assert_eq!(image_meta, image_metadata(&image, 7));
+            assert_eq!(read_trailer(&flash, SLOT_ADDRS[2]), Some(ImageTrailer::new(&image, 7)));
+            assert!(flash.is_locked());
+
+            let next = next_metadata(&read_metadata(&flash).unwrap(), 2, image_meta).unwrap();
+            assert_eq!(next.version, metadata.version + 1);
+            assert_eq!(next.images[..2], metadata.images[..2]);
+            commit_metadata(&mut flash, &next).unwrap();
+            assert_eq!(read_pages(&flash), [Some(next), Some(next)]);
+
+            let (target, ram) = run_boot(&mut flash);
+            assert_eq!(target, BootTarget::Image(2));
+            assert_eq!(&ram[..image.len()], &image[..]);
+        }
+    }
+
+    #[test]
+    fn tries_new_image_before_commit() {
+        let (mut flash, metadata) = flash_with_images(BankMode::SingleBank);
+        let mut backup = SimBackupRegisters::new();
+        let image = test_image(42, 0x3003);
+        let image_meta = write_slot(&mut flash, 1, &image, 7).unwrap();
+
+        // The bootloader doesn't try an image the metadata doesn't know about
+        send_command(&mut backup, MailboxCommand::BootOnce { slot: 1 });
+        let (target, _) = run_boot_with_backup(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(0));
+
+        assert_eq!(staged_metadata(&metadata, 0, image_meta), Err(Error::InvalidSlot));
+        let staged = staged_metadata(&metadata, 1, image_meta).unwrap();
+        assert_eq!(staged.preferred_image, metadata.preferred_image);
+        commit_metadata(&mut flash, &staged).unwrap();
+
+        send_command(&mut backup, MailboxCommand::BootOnce { slot: 1 });
+        let (target, ram) = run_boot_with_backup(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(1));
+        assert_eq!(&ram[..image.len()], &image[..]);
+        let info = boot_info(&backup).unwrap();
+        assert_eq!((info.slot, info.reason), (1, BootReason::Trial));
+        assert_eq!(info.metadata_version, staged.version);
+
+        // The new image didn't commit, so the next boot uses the preferred image again
+        let (target, _) = run_boot_with_backup(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Image(metadata.preferred_image));
+        assert_eq!(boot_info(&backup).unwrap().reason, BootReason::Preferred);
+
+        send_command(&mut backup, MailboxCommand::EnterRecovery);
+        let (target, _) = run_boot_with_backup(&mut flash, &mut backup);
+        assert_eq!(target, BootTarget::Recovery);
+    }
+
+    #[test]
+    fn rejects_invalid_updates() {
+        let (mut flash, metadata) = flash_with_images(BankMode::SingleBank);
+        let image = test_image(42, 0x100);
+        let image_meta = image_metadata(&image, 2);
+
+        assert_eq!(write_slot(&mut flash, NUMBER_OF_IMAGES, &image, 2), Err(Error::InvalidSlot));
+        let too_large = vec![0; MAX_IMAGE_LENGTH as usize + 1];
+        assert_eq!(write_slot(&mut flash, 1, &too_large, 2), Err(Error::ImageTooLarge));
+        assert_eq!(next_metadata(&metadata, NUMBER_OF_IMAGES, image_meta), Err(Error::InvalidSlot));
+
+        let mut last = metadata;
+        last.version = u32::MAX - 1;
+        assert_eq!(next_metadata(&last, 1, image_meta), Err(Error::InvalidVersion));
+
+        // Not newer than the committed metadata
+        let mut stale = next_metadata(&metadata, 1, image_meta).unwrap();
+        stale.version = metadata.version;
+        stale.set_crc();
+        assert_eq!(commit_metadata(&mut flash, &stale), Err(Error::InvalidVersion));
+
+        let mut corrupted = next_metadata(&metadata, 1, image_meta).unwrap();
+        corrupted.preferred_image = 0;
+        assert_eq!(commit_metadata(&mut flash, &corrupted), Err(Error::InvalidMetadata));
+
+        // Nothing was written
+        assert_eq!(read_pages(&flash), [Some(metadata), Some(metadata)]);
+    }
+
+    // The patch from `source` with `operations`, which must create `target`
+    fn encode_patch(source: &[u8], target: &[u8], operations: &[Operation]) -> Vec<u8> {
+        let mut encoded = vec![0u8; operations.iter().map(|op| op.encoded_length()).sum()];
+        let mut length = 0;
+        for operation in operations {
+            length += operation.encode(&mut encoded[length..]).unwrap();
+        }
+
+        let header = PatchHeader::new(source, target, &encoded);
+        let mut patch = header_bytes(&header).to_vec();
+        patch.extend_from_slice(&encoded);
+        patch
+    }
+
+    fn header_bytes(header: &PatchHeader) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                header as *const PatchHeader as *const u8,
+                core::mem::size_of::<PatchHeader>(),
+            )
+        }
+    }
+
+    // A small fix: most of the old image, with some new bytes in the middle
+    fn patched_image(source: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let inserted = test_image(77, 0x321);
+        let operations = [
+            Operation::Copy { offset: 0, length: 0x1003 },
+            Operation::Insert(&inserted),
+            Operation::Copy { offset: 0x800, length: source.len() as u32 - 0x800 },
+        ];
+        let target = [&source[..0x1003], &inserted, &source[0x800..]].concat();
+
+        (encode_patch(source, &target, &operations), target)
+    }
+
+    #[test]
+    fn delta_update_boots_patched_image() {
+        for bank_mode in [BankMode::SingleBank, BankMode::DualBank] {
+            let (mut flash, metadata) = flash_with_images(bank_mode);
+            let source = test_image(1, 0x1234);
+            let (patch, target) = patched_image(&source);
+            assert!(patch.len() < target.len() / 2);
+
+            let image_meta = apply_patch(&mut flash, 0, 2, &patch, 7).unwrap();
+            assert_eq!(image_meta, image_metadata(&target, 7));
+            assert_eq!(read_trailer(&flash, SLOT_ADDRS[2]), Some(ImageTrailer::new(&target, 7)));
+            assert!(flash.is_locked());
+
+            let next = next_metadata(&metadata, 2, image_meta).unwrap();
+            commit_metadata(&mut flash, &next).unwrap();
+            let (target_booted, ram) = run_boot(&mut flash);
+            assert_eq!(target_booted, BootTarget::Image(2));
+            assert_eq!(&ram[..target.len()], &target[..]);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_patches() {
+        let (mut flash, metadata) = flash_with_images(BankMode::SingleBank);
+        let source = test_image(1, 0x1234);
+        let (patch, target) = patched_image(&source);
+
+        // Made for the image in slot 0
+        assert_eq!(apply_patch(&mut flash, 1, 2, &patch, 7), Err(Error::SourceMismatch));
+        assert_eq!(apply_patch(&mut flash, 0, 0, &patch, 7), Err(Error::InvalidSlot));
+        assert_eq!(
+            apply_patch(&mut flash, 0, NUMBER_OF_IMAGES, &patch, 7),
+            Err(Error::InvalidSlot)
+        );
+        let mut corrupted = patch.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            apply_patch(&mut flash, 0, 2, &corrupted, 7),
+            Err(Error::InvalidPatch(delta::PatchError::Corrupted))
+        );
+
+        // Nothing was erased so far
+        assert!(verify_image(&flash, &metadata.images[2], SLOT_ADDRS[2], None));
+
+        // A copy beyond the end of the old image
+        let operations = [Operation::Copy { offset: 0, length: target.len() as u32 }];
+        let out_of_range = encode_patch(&source, &target, &operations);
+        assert_eq!(
+            apply_patch(&mut flash, 0, 2, &out_of_range, 7),
+            Err(Error::InvalidPatch(delta::PatchError::CopyOutOfRange))
+        );
+        // The operations don't create the image the header promises
+        let mut other = target.clone();
+        other[0x1100] ^= 1;
+        let operations =
+            [Operation::Copy { offset: 0, length: 0x1003 }, Operation::Insert(&other[0x1003..])];
+        let wrong_crc = encode_patch(&source, &target, &operations);
+        assert_eq!(apply_patch(&mut flash, 0, 2, &wrong_crc, 7), Err(Error::VerifyFailed));
+        assert_eq!(read_trailer(&flash, SLOT_ADDRS[2]), None);
+        assert!(flash.is_locked());
+    }
+
+    #[test]
+    fn commit_keeps_newer_copy_until_written() {
+        for newer in [METADATA_1_ADDR, METADATA_2_ADDR] {
+            let (mut initial, metadata) = flash_with_images(BankMode::DualBank);
+            let image_meta = metadata.images[1];
+            let current = next_metadata(&metadata, 1, image_meta).unwrap();
+            initial.load_metadata(newer, &current);
+            let next = next_metadata(&current, 0, metadata.images[0]).unwrap();
+
+            let mut uninterrupted = initial.clone();
+            commit_metadata(&mut uninterrupted, &next).unwrap();
+            let operations = uninterrupted.operation_count() - initial.operation_count();
+
+            for cut in 0..=operations {
+                for interruption in [Interruption::BetweenOperations, Interruption::DuringOperation]
+                {
+                    let mut flash = initial.clone();
+                    flash.cut_power_after(cut, interruption);
+                    let _ = commit_metadata(&mut flash, &next);
+                    let mut flash = flash.power_cycle();
+
+                    // The newer copy is only overwritten once the new metadata is complete
+                    let kept = read_valid_metadata(&flash, newer);
+                    let other =
+                        if newer == METADATA_1_ADDR { METADATA_2_ADDR } else { METADATA_1_ADDR };
+                    assert!(
+                        kept == Some(current) || read_valid_metadata(&flash, other) == Some(next)
+                    );
+
+                    let (target, _) = run_boot(&mut flash);
+                    assert!(matches!(target, BootTarget::Image(0 | 1)), "{:?}", target);
+                    assert!([current, next].contains(&read_metadata(&flash).unwrap()));
+                }
+            }
+        }
+    }
+}
@@ -13,6 +13,11 @@ if [[ ! -d interface ]]; then
     exit 1
 fi
 
+if [[ ! -d boot-core ]]; then
+    echo "Error: boot-core dir not found. Please make sure you run this script from the root of the project directory."
+    exit 1
+fi
+
 # Could think about adding -v "$(pwd)/.cache/cargo-registry:/root/.cargo/registry" to cache build dependencies
 "$CONTAINER_RUNTIME" run \
     -v "$(pwd)":/build \
@@ -134,16 +134,16 @@ test_single_bank_correct() {
 test_single_bank_md1_broken() {
 	output "TEST 2: Single bank flash broken metadata page one, wait for bootloader to fix it, and read it back"
 
-	# Copy correct_image.bin, but set 17 bytes at 0x2000 to 0xff
+	# Copy correct_image.bin, but set 17 bytes at 0x10000 to 0xff
 	# This will break the first metadata page, but leaves the second one intact
 	cp -f correct_image.bin broken_image_md1.bin
-	printf '\xff%.0s' {1..17} | dd of=broken_image_md1.bin bs=1 seek=8192 count=17 conv=notrunc
+	printf '\xff%.0s' {1..17} | dd of=broken_image_md1.bin bs=1 seek=65536 count=17 conv=notrunc
 	ensure_image_is_broken broken_image_md1.bin
 
 	# Bootloader fixup should result in correct_image, except that everything on
-	# the first metadata page after the correct metadata is 0xff - the reset value of flash memory
+	# the first metadata page after the header and the correct metadata is 0xff - the reset value of flash memory
 	cp -f correct_image.bin expected_image_md1.bin
-	printf '\xff%.0s' {1..8128} | dd of=expected_image_md1.bin bs=1 seek=8256 count=8128 conv=notrunc
+	printf '\xff%.0s' {1..8120} | dd of=expected_image_md1.bin bs=1 seek=65608 count=8120 conv=notrunc
 	ensure_image_is_valid expected_image_md1.bin
 
 	# Now for the more interesting tests: we flash a broken image, wait for the bootloader to fix it, and then read it back
@@ -164,15 +164,15 @@ test_single_bank_md1_broken() {
 test_single_bank_md2_broken() {
 	output "TEST 3: Single bank flash broken metadata page two, wait for bootloader to fix it, and read it back"
 
-	# Similar for the second metadata page at 0x4000, but different bytes
+	# Similar for the second metadata page at 0x12000, but different bytes
 	cp -f correct_image.bin broken_image_md2.bin
-	printf '\xff%.0s' {1..5} | dd of=broken_image_md2.bin bs=1 seek=16384 count=5 conv=notrunc
+	printf '\xff%.0s' {1..5} | dd of=broken_image_md2.bin bs=1 seek=73728 count=5 conv=notrunc
 	ensure_image_is_broken broken_image_md2.bin
 
 	# Bootloader fixup should result in correct_image, except that everything on
-	# the second metadata page after the correct metadata is 0xff - the reset value of flash memory
+	# the second metadata page after the header and the correct metadata is 0xff - the reset value of flash memory
 	cp -f correct_image.bin expected_image_md2.bin
-	printf '\xff%.0s' {1..8128} | dd of=expected_image_md2.bin bs=1 seek=16448 count=8128 conv=notrunc
+	printf '\xff%.0s' {1..8120} | dd of=expected_image_md2.bin bs=1 seek=73800 count=8120 conv=notrunc
 	ensure_image_is_valid expected_image_md2.bin
 
 	stflash write broken_image_md2.bin 0x8000000
@@ -210,17 +210,17 @@ test_dual_bank_correct() {
 test_dual_bank_md1_broken() {
 	output "TEST 5: Dual bank flash broken metadata page one, wait for bootloader to fix it, and read it back"
 
-	# Copy correct_image.bin, but set 17 bytes at 0x2000 to 0xff
+	# Copy correct_image.bin, but set 17 bytes at 0x10000 to 0xff
 	# This will break the first metadata page, but leaves the second one intact
 	cp -f correct_image.bin broken_image_md1.bin
-	printf '\xff%.0s' {1..17} | dd of=broken_image_md1.bin bs=1 seek=8192 count=17 conv=notrunc
+	printf '\xff%.0s' {1..17} | dd of=broken_image_md1.bin bs=1 seek=65536 count=17 conv=notrunc
 	ensure_image_is_broken broken_image_md1.bin
 
 	# Bootloader fixup should result in correct_image, except that everything on
-	# the first metadata page after the correct metadata is 0xff - the reset value of flash memory
+	# the first metadata page after the header and the correct metadata is 0xff - the reset value of flash memory
 	# In this case, the page is 0x1000 bytes in length
 	cp -f correct_image.bin expected_image_md1.bin
-	printf '\xff%.0s' {1..4032} | dd of=expected_image_md1.bin bs=1 seek=8256 count=4032 conv=notrunc
+	printf '\xff%.0s' {1..4024} | dd of=expected_image_md1.bin bs=1 seek=65608 count=4024 conv=notrunc
 	ensure_image_is_valid expected_image_md1.bin
 
 	# Now for the more interesting tests: we flash a broken image, wait for the bootloader to fix it, and then read it back
@@ -242,15 +242,15 @@ test_dual_bank_md1_broken() {
 test_dual_bank_md2_broken() {
 	output "TEST 6: Single bank flash broken metadata page two, wait for bootloader to fix it, and read it back"
 
-	# Similar for the second metadata page at 0x4000, but different bytes
+	# Similar for the second metadata page at 0x12000, but different bytes
 	cp -f correct_image.bin broken_image_md2.bin
-	printf '\xff%.0s' {1..5} | dd of=broken_image_md2.bin bs=1 seek=16384 count=5 conv=notrunc
+	printf '\xff%.0s' {1..5} | dd of=broken_image_md2.bin bs=1 seek=73728 count=5 conv=notrunc
 	ensure_image_is_broken broken_image_md2.bin
 
 	# Bootloader fixup should result in correct_image, except that everything on
-	# the second metadata page after the correct metadata is 0xff - the reset value of flash memory
+	# the second metadata page after the header and the correct metadata is 0xff - the reset value of flash memory
 	cp -f correct_image.bin expected_image_md2.bin
-	printf '\xff%.0s' {1..4032} | dd of=expected_image_md2.bin bs=1 seek=16448 count=4032 conv=notrunc
+	printf '\xff%.0s' {1..4024} | dd of=expected_image_md2.bin bs=1 seek=73800 count=4024 conv=notrunc
 	ensure_image_is_valid expected_image_md2.bin
 
 	stflash write broken_image_md2.bin 0x8000000